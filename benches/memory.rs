@@ -0,0 +1,115 @@
+//! Compares the throughput of the single and sharded memory stores, and of several actors on a
+//! single store, when several web workers, each running in its own arbiter, hit the store
+//! concurrently.
+//!
+//! Run with `cargo bench --bench memory`.
+use std::time::{Duration, Instant};
+
+use actix::dev::ToEnvelope;
+use actix::prelude::*;
+use actix_ratelimit::{
+    ActorMessage, ActorResponse, MemoryStore, MemoryStoreActor, ShardedMemoryStore,
+};
+use futures::channel::oneshot;
+use futures::future::join_all;
+
+const WORKERS: usize = 4;
+const CLIENTS: usize = 250;
+const REQUESTS: usize = 100;
+
+/// Simulates a client issuing `REQUESTS` get/update round trips, like the middleware does
+async fn client<A>(addr: Addr<A>, key: String)
+where
+    A: Handler<ActorMessage>,
+    A::Context: ToEnvelope<A, ActorMessage>,
+{
+    let res = addr
+        .send(ActorMessage::Set {
+            key: key.clone(),
+            value: REQUESTS,
+            expiry: Duration::from_secs(60),
+        })
+        .await
+        .unwrap();
+    if let ActorResponse::Set(c) = res {
+        c.await.unwrap();
+    }
+    for _ in 0..REQUESTS {
+        let res = addr.send(ActorMessage::Get(key.clone())).await.unwrap();
+        if let ActorResponse::Get(c) = res {
+            c.await.unwrap();
+        }
+        let res = addr
+            .send(ActorMessage::Update {
+                key: key.clone(),
+                value: 1,
+            })
+            .await
+            .unwrap();
+        if let ActorResponse::Update(c) = res {
+            c.await.unwrap();
+        }
+    }
+}
+
+/// Runs `CLIENTS` concurrent clients on each of `WORKERS` arbiters, every client talking to one
+/// of the provided store actors as the middleware would
+async fn run<A>(stores: Vec<Addr<A>>, workers: &[Arbiter]) -> Duration
+where
+    A: Handler<ActorMessage>,
+    A::Context: ToEnvelope<A, ActorMessage>,
+{
+    let start = Instant::now();
+    let done = workers.iter().enumerate().map(|(worker, arbiter)| {
+        let (tx, rx) = oneshot::channel();
+        let stores = stores.clone();
+        arbiter.send(Box::pin(async move {
+            let clients = (0..CLIENTS).map(|client| {
+                let addr = stores[client % stores.len()].clone();
+                self::client(addr, format!("{}-{}", worker, client))
+            });
+            join_all(clients).await;
+            let _ = tx.send(());
+        }));
+        rx
+    });
+    join_all(done).await;
+    start.elapsed()
+}
+
+fn report(name: &str, elapsed: Duration) {
+    let ops = (WORKERS * CLIENTS * (REQUESTS * 2 + 1)) as f64;
+    println!(
+        "{:<24} {:>10.2?} {:>12.0} ops/s",
+        name,
+        elapsed,
+        ops / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    System::new("bench").block_on(async {
+        let workers: Vec<Arbiter> = (0..WORKERS).map(|_| Arbiter::new()).collect();
+        let single = MemoryStoreActor::from(MemoryStore::new()).start();
+        report("memory store", run(vec![single], &workers).await);
+        for &(instances, capacity) in &[(4, 16), (4, 256)] {
+            let actors = MemoryStoreActor::from(MemoryStore::new())
+                .with_mailbox_capacity(capacity)
+                .start_many(instances);
+            report(
+                &format!("{} actors, mailbox {}", instances, capacity),
+                run(actors, &workers).await,
+            );
+        }
+        for &shards in &[4, 16] {
+            let sharded = ShardedMemoryStore::new(shards).start();
+            report(
+                &format!("sharded store ({})", shards),
+                run(sharded, &workers).await,
+            );
+        }
+        for worker in workers {
+            worker.stop();
+        }
+    });
+}
@@ -0,0 +1,87 @@
+//! Clocks the stores compute expiries with
+//!
+//! Stores read the current time through a [Clock](trait.Clock.html), which defaults to the
+//! system time. A [ManualClock](struct.ManualClock.html) only moves when told to, which makes
+//! expiry deterministic in tests.
+//!
+//! # Example
+//! ```rust
+//! # #[cfg(feature = "memory")] {
+//! use actix_ratelimit::clock::ManualClock;
+//! use actix_ratelimit::MemoryStore;
+//! use std::time::Duration;
+//!
+//! let clock = ManualClock::new();
+//! let store = MemoryStore::new().with_clock(clock.clone());
+//! // Every window the store has handed out is now over
+//! clock.advance(Duration::from_secs(3600));
+//! # }
+//! ```
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Source of the current time
+pub trait Clock: Send + Sync + 'static {
+    /// Time elapsed since the unix epoch
+    fn now(&self) -> Duration;
+}
+
+/// Reads the system time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+    }
+}
+
+/// Clock which starts at the current system time and only moves on `advance`. Clones share the
+/// same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    nanos: Arc<AtomicU64>,
+}
+
+impl ManualClock {
+    /// Creates a clock set to the current system time
+    pub fn new() -> Self {
+        ManualClock {
+            nanos: Arc::new(AtomicU64::new(SystemClock.now().as_nanos() as u64)),
+        }
+    }
+
+    /// Moves the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        self.nanos.fetch_add(by.as_nanos() as u64, Ordering::SeqCst);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        assert!(start <= SystemClock.now());
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(clock.now(), start);
+        clock.clone().advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(5));
+    }
+}
@@ -1,12 +1,16 @@
 //! Errors that can occur during middleware processing stage
-use actix_web::error::Error as AWError;
+use actix_web::http::StatusCode;
 use actix_web::web::HttpResponse;
+use actix_web::ResponseError;
 use failure::{self, Fail};
 use log::*;
+use std::io;
 
 /// Custom error type. Useful for logging and debugging different kinds of errors.
 /// This type can be converted to Actix Error, which defaults to
-/// InternalServerError
+/// InternalServerError, except for `RateLimitError` and `ConcurrencyLimitError` which render as
+/// TooManyRequests, `Denied` which renders as Forbidden, `ForwardedMismatch` and `Unidentifiable`
+/// which render as BadRequest and `StoreTimeout` which renders as ServiceUnavailable
 ///
 #[derive(Debug, Fail)]
 pub enum ARError {
@@ -18,10 +22,46 @@ pub enum ARError {
     #[fail(display = "store disconnected")]
     Disconnected,
 
+    /// Store has been shut down and no longer accepts messages
+    #[fail(display = "store shut down")]
+    ShutDown,
+
+    /// The store failed too often in a row and is not being contacted for a while, see
+    /// `RateLimiter::with_circuit_breaker`
+    #[fail(display = "store circuit open")]
+    CircuitOpen,
+
+    /// The store did not answer within the time set with `RateLimiter::with_store_timeout`. The
+    /// response asks the client to retry after a second.
+    #[fail(display = "store timed out")]
+    StoreTimeout,
+
     /// Read/Write error on store
     #[fail(display = "read/write operatiion failed: {}", _0)]
     ReadWriteError(String),
 
+    /// The store turned the operation down without carrying it out, e.g. because it refused the
+    /// connection or is still loading its data
+    #[fail(display = "store unavailable: {}", _0)]
+    StoreUnavailable(String),
+
+    /// Store operation `op` on `key` failed
+    #[fail(display = "{} failed for key '{}': {}", op, key, reason)]
+    OperationError {
+        op: &'static str,
+        key: String,
+        reason: String,
+    },
+
+    /// The store answered a message with a response of another kind, naming the one expected
+    #[fail(display = "store returned an unexpected response, expected {}", _0)]
+    UnexpectedResponse(&'static str),
+
+    /// The store does not handle the message of the given kind, see
+    /// `ActorResponse::unsupported`
+    #[fail(display = "store does not support {} messages", _0)]
+    Unsupported(&'static str),
+
     /// Could be any kind of IO error
     #[fail(display = "unknown error: {}", _0)]
     UnknownError(std::io::Error),
@@ -29,11 +69,141 @@ pub enum ARError {
     /// Identifier error
     #[fail(display = "client identification failed")]
     IdentificationError,
+
+    /// The request lacks the part its client is identified by, e.g. the query parameter of
+    /// `RateLimiter::with_query_identifier`, which is up to the client rather than the server
+    #[fail(display = "client identification failed: {}", _0)]
+    Unidentifiable(String),
+
+    /// The forwarded chain of the request names `found` addresses where `expected` trusted
+    /// proxies should have added one each, see `RateLimiter::with_forwarded_identifier`
+    #[fail(
+        display = "forwarded chain has {} addresses, expected {}",
+        found, expected
+    )]
+    ForwardedMismatch { expected: usize, found: usize },
+
+    /// The client has exhausted its limit. `c` is the number of requests left and `reset` the
+    /// number of seconds until the limit resets.
+    #[fail(display = "rate limit exceeded")]
+    RateLimitError {
+        max_requests: usize,
+        c: usize,
+        reset: u64,
+    },
+
+    /// The client is on the denylist of the store, see `RateLimiter::with_access_lists`
+    #[fail(display = "client denied")]
+    Denied,
+
+    /// The client already has `max_concurrent` requests in flight
+    #[fail(display = "concurrency limit exceeded")]
+    ConcurrencyLimitError { max_concurrent: usize },
 }
 
-impl From<ARError> for AWError {
-    fn from(err: ARError) -> AWError {
-        error!("{}", &err);
-        HttpResponse::InternalServerError().into()
+impl ARError {
+    /// Whether the error may go away by itself, so that the operation is worth retrying, see
+    /// `RateLimiter::with_store_retries`. These are lost connections and stores which are
+    /// unavailable for now, but not operations the store refused for good, like missing keys
+    /// or unsupported messages, nor any of the errors about the client.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            ARError::NotConnected
+            | ARError::Disconnected
+            | ARError::ReadWriteError(_)
+            | ARError::StoreUnavailable(_) => true,
+            ARError::UnknownError(e) => matches!(
+                e.kind(),
+                io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::TimedOut
+                    | io::ErrorKind::Interrupted
+                    | io::ErrorKind::UnexpectedEof
+            ),
+            _ => false,
+        }
+    }
+
+    /// Whether the store reports that it did not carry the operation out, as opposed to errors
+    /// which may have come up after it did, like a connection dropping before the answer came
+    /// back. Only these are retried for operations which must not be applied twice.
+    pub fn is_unapplied(&self) -> bool {
+        match self {
+            ARError::NotConnected | ARError::Disconnected | ARError::StoreUnavailable(_) => true,
+            ARError::UnknownError(e) => e.kind() == io::ErrorKind::ConnectionRefused,
+            _ => false,
+        }
+    }
+
+    pub(crate) fn operation<R: std::fmt::Display>(op: &'static str, key: &str, reason: R) -> Self {
+        ARError::OperationError {
+            op,
+            key: key.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+
+    /// Store operation `op` on `key` was turned down by a store which is unavailable for now
+    #[cfg(any(
+        feature = "redis-store",
+        feature = "memcached",
+        feature = "dynamodb-store"
+    ))]
+    pub(crate) fn unavailable<R: std::fmt::Display>(
+        op: &'static str,
+        key: &str,
+        reason: R,
+    ) -> Self {
+        ARError::StoreUnavailable(format!("{} failed for key '{}': {}", op, key, reason))
+    }
+}
+
+impl ResponseError for ARError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ARError::RateLimitError { .. } | ARError::ConcurrencyLimitError { .. } => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            ARError::Denied => StatusCode::FORBIDDEN,
+            ARError::ForwardedMismatch { .. } | ARError::Unidentifiable(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ARError::StoreTimeout => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            ARError::RateLimitError {
+                max_requests,
+                c,
+                reset,
+            } => HttpResponse::TooManyRequests()
+                .header("x-ratelimit-limit", max_requests.to_string())
+                .header("x-ratelimit-remaining", c.to_string())
+                .header("x-ratelimit-reset", reset.to_string())
+                .header("retry-after", reset.to_string())
+                .finish(),
+            ARError::ConcurrencyLimitError { .. } => HttpResponse::TooManyRequests().finish(),
+            ARError::Denied => HttpResponse::Forbidden().finish(),
+            ARError::ForwardedMismatch { .. } | ARError::Unidentifiable(_) => {
+                warn!("{}", self);
+                HttpResponse::BadRequest().finish()
+            }
+            ARError::StoreTimeout => {
+                warn!("{}", self);
+                HttpResponse::ServiceUnavailable()
+                    .header("retry-after", "1")
+                    .finish()
+            }
+            _ => {
+                error!("{}", self);
+                HttpResponse::InternalServerError().finish()
+            }
+        }
     }
 }
@@ -76,11 +76,16 @@
 //! _ratelimit_ data associated with a _client_. A _store actor_ which acts on this store is
 //! responsible for performiing all sorts of operations(SET, GET, DEL, etc). It is Important to
 //! note that there are multiple store actors acting on a _single_ store.
+//! The memory store can be driven by several actors at once with
+//! `MemoryStoreActor::start_many`, which spreads busy clients over more than one mailbox.
 //!
 //!
 //! ## Supported
 //! - In-memory (based on concurrent [hashmap](https://github.com/xacrimon/dashmap))
+//! - Sharded in-memory, spreading clients over several hashmaps and actors to reduce contention
 //! - Redis (based on [redis-rs](https://github.com/mitsuhiko/redis-rs))
+//! - DynamoDB (based on [rusoto](https://github.com/rusoto/rusoto), behind the `dynamodb-store`
+//!   feature)
 //!
 //! ## Planned
 //! - Memcached (not yet implemented)
@@ -107,6 +112,7 @@
 //! # use std::time::Duration;
 //! # use actix_web::{web, App, HttpRequest, HttpServer, Responder};
 //! # use actix_ratelimit::{RateLimiter, MemoryStore, MemoryStoreActor};
+//! use actix_ratelimit::errors::ARError;
 //! # async fn greet(req: HttpRequest) -> impl Responder{
 //! #     let name = req.match_info().get("name").unwrap_or("World!");
 //! #     format!("Hello {}!", &name)
@@ -123,8 +129,9 @@
 //!                     .with_interval(Duration::from_secs(60))
 //!                     .with_max_requests(100)
 //!                     .with_identifier(|req| {
-//!                         let key = req.headers().get("x-api-key").unwrap();
-//!                         let key = key.to_str().unwrap();
+//!                         let key = req.headers().get("x-api-key")
+//!                             .ok_or(ARError::IdentificationError)?;
+//!                         let key = key.to_str().map_err(|_| ARError::IdentificationError)?;
 //!                         Ok(key.to_string())
 //!                     })
 //!             )
@@ -174,6 +181,31 @@
 //! created for each worker. Since redis store is based on Multiplexed connection, sharing once
 //! connection across multiple store actors should suffice for most use cases.
 //!
+//! A configured `RateLimiter` is `Clone` and `Send`, so it can also be built once and cloned
+//! into each worker. Its clones share the store actor, as well as the state kept by the limiter
+//! itself, such as the average latency of `with_adaptive`:
+//! ```no_run
+//! # #[cfg(feature = "default")] {
+//! # use std::time::Duration;
+//! # use actix_web::{web, App, HttpResponse, HttpServer};
+//! # use actix_ratelimit::{RateLimiter, MemoryStore, MemoryStoreActor};
+//! #[actix_web::main]
+//! async fn main() -> std::io::Result<()> {
+//!     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+//!         .with_interval(Duration::from_secs(60))
+//!         .with_max_requests(100);
+//!     HttpServer::new(move ||{
+//!         App::new()
+//!             .wrap(ratelimiter.clone())
+//!             .route("/", web::get().to(HttpResponse::Ok))
+//!     })
+//!     .bind("127.0.0.1:8000")?
+//!     .run()
+//!     .await
+//! }
+//! # }
+//! ```
+//!
 //!
 //! # Status
 //! This project has not reached v1.0, so some instability and breaking changes are to be expected
@@ -184,18 +216,28 @@
 //! # LICENSE
 //! This project is licensed under MIT license.
 
+pub mod clock;
 pub mod errors;
 pub mod middleware;
 pub mod stores;
 use errors::ARError;
-pub use middleware::RateLimiter;
+pub use middleware::{
+    bypass_token, Algorithm, CountingMode, HeaderStyle, HopMismatch, PathFilter, RateLimiter,
+    RateLimiterBuilder, RateLimiterConfig, RegistrationMode, RetryAfterFormat, Rule, SharedLimits,
+    WindowAnchor,
+};
+pub use stores::kv::{KvStore, Store};
 
 #[cfg(feature = "memory")]
-pub use stores::memory::{MemoryStore, MemoryStoreActor};
+pub use stores::memory::{MemoryStore, MemoryStoreActor, TestMemoryStore};
+#[cfg(feature = "memory")]
+pub use stores::sharded::ShardedMemoryStore;
 #[cfg(feature = "redis-store")]
 pub use stores::redis::{RedisStore, RedisStoreActor};
 #[cfg(feature = "memcached")]
 pub use stores::memcached::{MemcacheStore, MemcacheStoreActor};
+#[cfg(feature = "dynamodb-store")]
+pub use stores::dynamodb::{DynamoStore, DynamoStoreActor};
 
 use std::future::Future;
 use std::marker::Send;
@@ -205,6 +247,12 @@ use std::time::Duration;
 use actix::dev::*;
 
 /// Represents message that can be handled by a `StoreActor`
+///
+/// New kinds of messages are added from time to time, so stores outside of this crate have to
+/// answer the ones they don't know with a catch-all arm, usually returning
+/// [ActorResponse::unsupported](enum.ActorResponse.html#method.unsupported).
+#[derive(Clone)]
+#[non_exhaustive]
 pub enum ActorMessage {
     /// Get the remaining count based on the provided identifier
     Get(String),
@@ -216,10 +264,60 @@ pub enum ActorMessage {
     },
     /// Change the value of count for the client identified by `key` by `value`
     Update { key: String, value: usize },
+    /// Increase the count of `key` by `value`, starting from 0 if it does not exist, and have it
+    /// expire after `expiry` from now. Returns the new count.
+    Increment {
+        key: String,
+        value: usize,
+        expiry: Duration,
+    },
     /// Get the expiration time for the client.
     Expire(String),
     /// Remove the client from the store
     Remove(String),
+    /// Get the health and size of the store
+    Stats,
+    /// Finish the work queued before this message, then stop accepting new messages
+    Shutdown,
+    /// Remove every key starting with the given prefix, returning how many were removed
+    Clear(String),
+    /// Look the client identified by the given key up on the allow and deny lists of the store
+    CheckList(String),
+    /// Get the moving average of the requests per second counted against the given key, or
+    /// `None` if the store does not track it
+    Rate(String),
+    /// Give the client identified by `key` back `value` requests, without raising its count
+    /// above `max`. Returns the new count, or `None` if the key does not exist.
+    Grant {
+        key: String,
+        value: usize,
+        max: usize,
+    },
+    /// Count `cost` requests against the client identified by `key` under
+    /// [Algorithm::SlidingWindowCounter](middleware/enum.Algorithm.html), if the sliding window
+    /// has room for them within `max` requests per `interval`, opening a new window when the
+    /// current one is over. Checking and counting happen in a single step, so concurrent
+    /// requests can't overrun the limit. Returns the counts afterwards.
+    Slide {
+        key: String,
+        cost: usize,
+        max: usize,
+        interval: Duration,
+    },
+    /// Count `cost` requests of `tier` against the pool of `max` requests per `interval` shared
+    /// by every client at `key`, see `RateLimiter::with_tier_reservations`. What each tier of
+    /// `reserved` has left of its reservation is kept under `key` suffixed by `:` and the tier,
+    /// and only the tier itself may draw on it. If the pool does not exist, a new window is
+    /// opened with every reservation restored. Checking and counting happen in a single step, so
+    /// concurrent requests can't overrun the pool. Returns the pool afterwards.
+    Draw {
+        key: String,
+        tier: String,
+        cost: usize,
+        max: usize,
+        interval: Duration,
+        reserved: Vec<(String, usize)>,
+    },
 }
 
 impl Message for ActorMessage {
@@ -230,6 +328,7 @@ impl Message for ActorMessage {
 pub type Output<T> = Pin<Box<dyn Future<Output = Result<T, ARError>> + Send>>;
 
 /// Represents data returned in response to `Messages` by a `StoreActor`
+#[non_exhaustive]
 pub enum ActorResponse {
     /// Returned in response to [Messages::Get](enum.Messages.html)
     Get(Output<Option<usize>>),
@@ -237,10 +336,247 @@ pub enum ActorResponse {
     Set(Output<()>),
     /// Returned in response to [Messages::Update](enum.Messages.html)
     Update(Output<usize>),
+    /// Returned in response to [Messages::Increment](enum.Messages.html)
+    Increment(Output<usize>),
     /// Returned in response to [Messages::Expire](enum.Messages.html)
     Expire(Output<Duration>),
     /// Returned in response to [Messages::Remove](enum.Messages.html)
     Remove(Output<usize>),
+    /// Returned in response to [Messages::Stats](enum.Messages.html)
+    Stats(Output<StoreStats>),
+    /// Returned in response to [Messages::Shutdown](enum.Messages.html)
+    Shutdown(Output<()>),
+    /// Returned in response to [Messages::Clear](enum.Messages.html)
+    Clear(Output<usize>),
+    /// Returned in response to [Messages::CheckList](enum.Messages.html)
+    CheckList(Output<ListStatus>),
+    /// Returned in response to [Messages::Rate](enum.Messages.html)
+    Rate(Output<Option<f64>>),
+    /// Returned in response to [Messages::Grant](enum.Messages.html)
+    Grant(Output<Option<usize>>),
+    /// Returned in response to [Messages::Slide](enum.Messages.html)
+    Slide(Output<SlidingCount>),
+    /// Returned in response to [Messages::Draw](enum.Messages.html)
+    Draw(Output<PoolDraw>),
+}
+
+impl ActorResponse {
+    /// Builds the response matching `msg` which resolves to `err`. Useful for stores that
+    /// have to turn a message down, e.g. after they have been shut down.
+    pub fn failed(msg: &ActorMessage, err: ARError) -> Self {
+        match msg {
+            ActorMessage::Get(_) => ActorResponse::Get(Box::pin(async move { Err(err) })),
+            ActorMessage::Set { .. } => ActorResponse::Set(Box::pin(async move { Err(err) })),
+            ActorMessage::Update { .. } => {
+                ActorResponse::Update(Box::pin(async move { Err(err) }))
+            }
+            ActorMessage::Increment { .. } => {
+                ActorResponse::Increment(Box::pin(async move { Err(err) }))
+            }
+            ActorMessage::Expire(_) => ActorResponse::Expire(Box::pin(async move { Err(err) })),
+            ActorMessage::Remove(_) => ActorResponse::Remove(Box::pin(async move { Err(err) })),
+            ActorMessage::Stats => ActorResponse::Stats(Box::pin(async move { Err(err) })),
+            ActorMessage::Shutdown => ActorResponse::Shutdown(Box::pin(async move { Err(err) })),
+            ActorMessage::Clear(_) => ActorResponse::Clear(Box::pin(async move { Err(err) })),
+            ActorMessage::CheckList(_) => {
+                ActorResponse::CheckList(Box::pin(async move { Err(err) }))
+            }
+            ActorMessage::Rate(_) => ActorResponse::Rate(Box::pin(async move { Err(err) })),
+            ActorMessage::Grant { .. } => ActorResponse::Grant(Box::pin(async move { Err(err) })),
+            ActorMessage::Slide { .. } => ActorResponse::Slide(Box::pin(async move { Err(err) })),
+            ActorMessage::Draw { .. } => ActorResponse::Draw(Box::pin(async move { Err(err) })),
+        }
+    }
+
+    /// Builds the response matching `msg` which resolves to `ARError::Unsupported`, for the
+    /// catch-all arm of stores which don't handle every kind of message
+    pub fn unsupported(msg: &ActorMessage) -> Self {
+        let kind = match msg {
+            ActorMessage::Get(_) => "Get",
+            ActorMessage::Set { .. } => "Set",
+            ActorMessage::Update { .. } => "Update",
+            ActorMessage::Increment { .. } => "Increment",
+            ActorMessage::Expire(_) => "Expire",
+            ActorMessage::Remove(_) => "Remove",
+            ActorMessage::Stats => "Stats",
+            ActorMessage::Shutdown => "Shutdown",
+            ActorMessage::Clear(_) => "Clear",
+            ActorMessage::CheckList(_) => "CheckList",
+            ActorMessage::Rate(_) => "Rate",
+            ActorMessage::Grant { .. } => "Grant",
+            ActorMessage::Slide { .. } => "Slide",
+            ActorMessage::Draw { .. } => "Draw",
+        };
+        Self::failed(msg, ARError::Unsupported(kind))
+    }
+
+    /// Output of a `Get` response. Any other response resolves to
+    /// `ARError::UnexpectedResponse` instead, so that a faulty store can't bring down the caller.
+    pub fn expect_get(self) -> Output<Option<usize>> {
+        match self {
+            ActorResponse::Get(c) => c,
+            _ => unexpected("Get"),
+        }
+    }
+
+    /// Output of a `Set` response, see [expect_get](#method.expect_get)
+    pub fn expect_set(self) -> Output<()> {
+        match self {
+            ActorResponse::Set(c) => c,
+            _ => unexpected("Set"),
+        }
+    }
+
+    /// Output of an `Update` response, see [expect_get](#method.expect_get)
+    pub fn expect_update(self) -> Output<usize> {
+        match self {
+            ActorResponse::Update(c) => c,
+            _ => unexpected("Update"),
+        }
+    }
+
+    /// Output of an `Increment` response, see [expect_get](#method.expect_get)
+    pub fn expect_increment(self) -> Output<usize> {
+        match self {
+            ActorResponse::Increment(c) => c,
+            _ => unexpected("Increment"),
+        }
+    }
+
+    /// Output of an `Expire` response, see [expect_get](#method.expect_get)
+    pub fn expect_expire(self) -> Output<Duration> {
+        match self {
+            ActorResponse::Expire(c) => c,
+            _ => unexpected("Expire"),
+        }
+    }
+
+    /// Output of a `Remove` response, see [expect_get](#method.expect_get)
+    pub fn expect_remove(self) -> Output<usize> {
+        match self {
+            ActorResponse::Remove(c) => c,
+            _ => unexpected("Remove"),
+        }
+    }
+
+    /// Output of a `Stats` response, see [expect_get](#method.expect_get)
+    pub fn expect_stats(self) -> Output<StoreStats> {
+        match self {
+            ActorResponse::Stats(c) => c,
+            _ => unexpected("Stats"),
+        }
+    }
+
+    /// Output of a `Shutdown` response, see [expect_get](#method.expect_get)
+    pub fn expect_shutdown(self) -> Output<()> {
+        match self {
+            ActorResponse::Shutdown(c) => c,
+            _ => unexpected("Shutdown"),
+        }
+    }
+
+    /// Output of a `Clear` response, see [expect_get](#method.expect_get)
+    pub fn expect_clear(self) -> Output<usize> {
+        match self {
+            ActorResponse::Clear(c) => c,
+            _ => unexpected("Clear"),
+        }
+    }
+
+    /// Output of a `CheckList` response, see [expect_get](#method.expect_get)
+    pub fn expect_check_list(self) -> Output<ListStatus> {
+        match self {
+            ActorResponse::CheckList(c) => c,
+            _ => unexpected("CheckList"),
+        }
+    }
+
+    /// Output of a `Rate` response, see [expect_get](#method.expect_get)
+    pub fn expect_rate(self) -> Output<Option<f64>> {
+        match self {
+            ActorResponse::Rate(c) => c,
+            _ => unexpected("Rate"),
+        }
+    }
+
+    /// Output of a `Grant` response, see [expect_get](#method.expect_get)
+    pub fn expect_grant(self) -> Output<Option<usize>> {
+        match self {
+            ActorResponse::Grant(c) => c,
+            _ => unexpected("Grant"),
+        }
+    }
+
+    /// Output of a `Slide` response, see [expect_get](#method.expect_get)
+    pub fn expect_slide(self) -> Output<SlidingCount> {
+        match self {
+            ActorResponse::Slide(c) => c,
+            _ => unexpected("Slide"),
+        }
+    }
+
+    /// Output of a `Draw` response, see [expect_get](#method.expect_get)
+    pub fn expect_draw(self) -> Output<PoolDraw> {
+        match self {
+            ActorResponse::Draw(c) => c,
+            _ => unexpected("Draw"),
+        }
+    }
+}
+
+fn unexpected<T>(expected: &'static str) -> Output<T> {
+    Box::pin(async move { Err(ARError::UnexpectedResponse(expected)) })
+}
+
+/// Health and size information reported by a store, useful for monitoring
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StoreStats {
+    /// Whether the store actor currently holds a working connection to its backend
+    pub connected: bool,
+    /// Number of keys tracked by the store, if the backend can report it. Stores shared with
+    /// other data may count that as well: redis counts every key of the database unless the
+    /// actor was given `RedisStoreActor::with_stats_prefix`, and memcached every item.
+    pub key_count: Option<usize>,
+}
+
+/// Counts of a client under the sliding window counter, in response to
+/// [ActorMessage::Slide](enum.ActorMessage.html)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlidingCount {
+    /// Whether the requests were counted, rather than turned down for want of room
+    pub counted: bool,
+    /// Requests counted in the current window
+    pub current: usize,
+    /// Requests counted in the previous window
+    pub previous: usize,
+    /// Time left in the current window
+    pub left: Duration,
+}
+
+/// Standing of the pool shared by every client, in response to
+/// [ActorMessage::Draw](enum.ActorMessage.html)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolDraw {
+    /// Whether the requests were counted, rather than turned down for want of room
+    pub counted: bool,
+    /// Requests the tier may still draw, its own reservation included
+    pub available: usize,
+    /// Requests taken from the reservation of the tier, which the pool was charged for as well
+    pub reserved: usize,
+    /// Time left in the window of the pool
+    pub left: Duration,
+}
+
+/// Standing of a client on the allow and deny lists of a store, see
+/// [RateLimiter::with_access_lists](middleware/struct.RateLimiter.html#method.with_access_lists)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListStatus {
+    /// The client is never rate limited
+    Allow,
+    /// Every request of the client is rejected
+    Deny,
+    /// The client is on neither list and limited as usual
+    Normal,
 }
 
 impl<A, M> MessageResponse<A, M> for ActorResponse
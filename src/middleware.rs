@@ -1,24 +1,46 @@
 //! RateLimiter middleware for actix application
 use actix::dev::*;
 use actix_web::{
-    dev::{Service, ServiceRequest, ServiceResponse, Transform},
+    dev::{Body, Payload, ResponseBody, Service, ServiceRequest, ServiceResponse, Transform},
     error::Error as AWError,
-    http::{HeaderName, HeaderValue},
-    HttpResponse,
+    http::{
+        header::{
+            ContentType, HttpDate, InvalidHeaderValue, CONNECTION, CONTENT_TYPE, RETRY_AFTER,
+            UPGRADE,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    },
+    web::{Bytes, BytesMut, Query},
+    HttpMessage,
 };
-use futures::future::{ok, Ready};
+use futures::future::{self, ok, Either, Ready};
+use futures::stream::{self, StreamExt};
+use hmac::{Hmac, Mac, NewMac};
 use log::*;
+use sha1::Sha1;
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, HashMap},
+    convert::TryFrom,
+    fmt,
     future::Future,
+    hash::{Hash, Hasher},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     ops::Fn,
+    panic::{self, AssertUnwindSafe},
     pin::Pin,
     rc::Rc,
+    sync::{Arc, Mutex, RwLock},
     task::{Context, Poll},
-    time::Duration,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{errors::ARError, ActorMessage, ActorResponse};
+use crate::stores::kv::{KvBackend, KvStore, Store};
+use crate::stores::Readiness;
+use crate::{
+    errors::ARError, ActorMessage, ActorResponse, ListStatus, Output, PoolDraw, StoreStats,
+};
 
 /// Type that implements the ratelimit middleware.
 ///
@@ -27,6 +49,9 @@ use crate::{errors::ARError, ActorMessage, ActorResponse};
 /// _store_ which is essentially a data store used to store client access information. Entry is removed from
 /// the store after _interval_.
 ///
+/// A configured limiter is `Clone` and `Send`, so one instance can be cloned into each worker.
+/// The functions it is given, such as the identifier or hooks, must be `Send + Sync` for that.
+///
 /// # Example
 /// ```rust
 /// # use std::time::Duration;
@@ -49,8 +74,126 @@ where
 {
     interval: Duration,
     max_requests: usize,
-    store: Addr<T>,
-    identifier: Rc<Box<dyn Fn(&ServiceRequest) -> Result<String, ARError>>>,
+    algorithm: Algorithm,
+    counting_mode: CountingMode,
+    registration_mode: RegistrationMode,
+    window_anchor: WindowAnchor,
+    warmup: Option<(usize, usize)>,
+    policies: Vec<(usize, Duration)>,
+    rules: Vec<Rule>,
+    skipped_methods: Vec<Method>,
+    method_limits: HashMap<Method, (usize, Duration)>,
+    upgrade_limit: Option<(usize, Duration)>,
+    path_filter: Option<PathFilter>,
+    excluded_paths: Vec<String>,
+    bypass: Option<Bypass>,
+    header_names: HeaderNames,
+    success_headers: bool,
+    reject_headers: bool,
+    json_errors: bool,
+    problem_json: bool,
+    rejection_body: Option<(Bytes, HeaderValue)>,
+    fail_open: bool,
+    fail_open_headers: bool,
+    dry_run: bool,
+    options_report: bool,
+    store_timeout: Option<Duration>,
+    store_retries: Option<(usize, Duration)>,
+    readiness: Option<Readiness>,
+    circuit_breaker: Option<(usize, Duration)>,
+    max_concurrent: Option<usize>,
+    access_lists: bool,
+    upstream_refund: bool,
+    refund_on_error: bool,
+    tiers: Option<Arc<TierPool>>,
+    stores: Vec<StoreHandle<T>>,
+    prefix: String,
+    fallback_key: Option<String>,
+    identifier: Identifier,
+    body_limit: Option<usize>,
+    limit: Option<Limit>,
+    interval_fn: Option<IntervalFn>,
+    latency: Option<Arc<Latency>>,
+    key_hasher: Option<KeyHasher>,
+    max_key_length: Option<usize>,
+    header_hook: Option<HeaderHook>,
+    count_hook: Option<CountHook>,
+    cost: Cost,
+}
+
+impl<T> Clone for RateLimiter<T>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    fn clone(&self) -> Self {
+        RateLimiter {
+            interval: self.interval,
+            max_requests: self.max_requests,
+            algorithm: self.algorithm,
+            counting_mode: self.counting_mode,
+            registration_mode: self.registration_mode,
+            window_anchor: self.window_anchor,
+            warmup: self.warmup,
+            policies: self.policies.clone(),
+            rules: self.rules.clone(),
+            skipped_methods: self.skipped_methods.clone(),
+            method_limits: self.method_limits.clone(),
+            upgrade_limit: self.upgrade_limit,
+            path_filter: self.path_filter.clone(),
+            excluded_paths: self.excluded_paths.clone(),
+            bypass: self.bypass.clone(),
+            header_names: self.header_names.clone(),
+            success_headers: self.success_headers,
+            reject_headers: self.reject_headers,
+            json_errors: self.json_errors,
+            problem_json: self.problem_json,
+            rejection_body: self.rejection_body.clone(),
+            fail_open: self.fail_open,
+            fail_open_headers: self.fail_open_headers,
+            dry_run: self.dry_run,
+            options_report: self.options_report,
+            store_timeout: self.store_timeout,
+            store_retries: self.store_retries,
+            readiness: self.readiness.clone(),
+            circuit_breaker: self.circuit_breaker,
+            max_concurrent: self.max_concurrent,
+            access_lists: self.access_lists,
+            upstream_refund: self.upstream_refund,
+            refund_on_error: self.refund_on_error,
+            tiers: self.tiers.clone(),
+            stores: self.stores.clone(),
+            prefix: self.prefix.clone(),
+            fallback_key: self.fallback_key.clone(),
+            identifier: self.identifier.clone(),
+            body_limit: self.body_limit,
+            limit: self.limit.clone(),
+            interval_fn: self.interval_fn.clone(),
+            latency: self.latency.clone(),
+            key_hasher: self.key_hasher.clone(),
+            max_key_length: self.max_key_length,
+            header_hook: self.header_hook.clone(),
+            count_hook: self.count_hook.clone(),
+            cost: self.cost.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for RateLimiter<T>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimiter")
+            .field("interval", &self.interval)
+            .field("max_requests", &self.max_requests)
+            .field("algorithm", &self.algorithm)
+            .field("policies", &self.policies)
+            .field("stores", &self.stores.len())
+            .field("prefix", &self.prefix)
+            .finish()
+    }
 }
 
 impl<T> RateLimiter<T>
@@ -60,19 +203,186 @@ where
 {
     /// Creates a new instance of `RateLimiter` with the provided address of `StoreActor`.
     pub fn new(store: Addr<T>) -> Self {
+        Self::sharded(vec![store])
+    }
+
+    /// Starts a [RateLimiterBuilder](struct.RateLimiterBuilder.html), which only builds the
+    /// limiter once both the limit and the interval have been given. Use `new` for limiters
+    /// whose limit comes from elsewhere, e.g. `with_shared_limits`.
+    pub fn builder(store: Addr<T>) -> RateLimiterBuilder<T> {
+        RateLimiterBuilder {
+            limiter: Self::new(store),
+            max_requests: (),
+            interval: (),
+        }
+    }
+
+    /// Creates a new instance of `RateLimiter` spreading clients over several store actors, such
+    /// as the ones started by [ShardedMemoryStore](../stores/sharded/struct.ShardedMemoryStore.html).
+    /// A client is always routed to the same store, based on a hash of its identifier.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{RateLimiter, ShardedMemoryStore};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = ShardedMemoryStore::new(4);
+    ///     let ratelimiter = RateLimiter::sharded(store.start())
+    ///                         .with_interval(Duration::from_secs(60))
+    ///                         .with_max_requests(100);
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if `stores` is empty.
+    pub fn sharded(stores: Vec<Addr<T>>) -> Self {
+        assert!(!stores.is_empty(), "at least one store is required");
+        Self::with_stores(stores.into_iter().map(StoreHandle::Actor).collect())
+    }
+
+    fn with_stores(stores: Vec<StoreHandle<T>>) -> Self {
         let identifier = |req: &ServiceRequest| {
             let connection_info = req.connection_info();
             let ip = connection_info
                 .remote_addr()
                 .ok_or(ARError::IdentificationError)?;
-            Ok(String::from(ip))
+            Ok(Some(String::from(ip)))
         };
         RateLimiter {
             interval: Duration::from_secs(0),
             max_requests: 0,
-            store: store,
-            identifier: Rc::new(Box::new(identifier)),
+            algorithm: Algorithm::default(),
+            counting_mode: CountingMode::default(),
+            registration_mode: RegistrationMode::default(),
+            window_anchor: WindowAnchor::default(),
+            warmup: None,
+            policies: Vec::new(),
+            rules: Vec::new(),
+            skipped_methods: Vec::new(),
+            method_limits: HashMap::new(),
+            upgrade_limit: None,
+            path_filter: None,
+            excluded_paths: Vec::new(),
+            bypass: None,
+            header_names: HeaderNames::default(),
+            success_headers: true,
+            reject_headers: true,
+            json_errors: false,
+            problem_json: false,
+            rejection_body: None,
+            fail_open: false,
+            fail_open_headers: false,
+            dry_run: false,
+            options_report: false,
+            store_timeout: None,
+            store_retries: None,
+            readiness: None,
+            circuit_breaker: None,
+            max_concurrent: None,
+            access_lists: false,
+            upstream_refund: false,
+            refund_on_error: false,
+            tiers: None,
+            stores,
+            prefix: String::new(),
+            fallback_key: None,
+            identifier: Arc::new(Box::new(identifier)),
+            body_limit: None,
+            limit: None,
+            interval_fn: None,
+            latency: None,
+            key_hasher: None,
+            max_key_length: None,
+            header_hook: None,
+            count_hook: None,
+            cost: Arc::new(Box::new(|_| 1)),
+        }
+    }
+
+    /// Creates a new instance of `RateLimiter` from the settings in `config`, the same as
+    /// applying each of them with its `with_*` method. Settings taking functions, such as the
+    /// identifier, can still be chained afterwards.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter, RateLimiterConfig};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let config = RateLimiterConfig {
+    ///         interval: Duration::from_secs(60),
+    ///         max_requests: 100,
+    ///         prefix: "api:".to_string(),
+    ///         fail_open: true,
+    ///         ..RateLimiterConfig::default()
+    ///     };
+    ///     let ratelimiter =
+    ///         RateLimiter::from_config(MemoryStoreActor::from(store.clone()).start(), config);
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if one of the header names is not valid.
+    pub fn from_config(store: Addr<T>, config: RateLimiterConfig) -> Self {
+        let mut limiter = Self::new(store)
+            .with_interval(config.interval)
+            .with_max_requests(config.max_requests)
+            .with_algorithm(config.algorithm)
+            .with_counting_mode(config.counting_mode)
+            .with_registration_mode(config.registration_mode)
+            .with_window_anchor(config.window_anchor)
+            .with_policies(config.policies)
+            .with_skipped_methods(config.skipped_methods)
+            .with_method_limits(config.method_limits)
+            .with_limit_header(&config.limit_header)
+            .with_remaining_header(&config.remaining_header)
+            .with_reset_header(&config.reset_header)
+            .with_header_style(config.header_style)
+            .with_retry_after_format(config.retry_after_format)
+            .with_success_headers(config.success_headers)
+            .with_reject_headers(config.reject_headers)
+            .with_prefix(&config.prefix)
+            .with_json_errors(config.json_errors)
+            .with_problem_json(config.problem_json)
+            .with_fail_open(config.fail_open)
+            .with_fail_open_headers(config.fail_open_headers)
+            .with_dry_run(config.dry_run)
+            .with_options_report(config.options_report)
+            .with_access_lists(config.access_lists)
+            .with_upstream_refund(config.upstream_refund)
+            .with_refund_on_error(config.refund_on_error);
+        if let Some((extra_requests, windows)) = config.warmup {
+            limiter = limiter.with_warmup(extra_requests, windows);
+        }
+        if let Some(max_concurrent) = config.max_concurrent {
+            limiter = limiter.with_max_concurrent(max_concurrent);
+        }
+        if let Some(max_key_length) = config.max_key_length {
+            limiter = limiter.with_max_key_length(max_key_length);
         }
+        if let Some((failures, cooldown)) = config.circuit_breaker {
+            limiter = limiter.with_circuit_breaker(failures, cooldown);
+        }
+        if let Some(store_timeout) = config.store_timeout {
+            limiter = limiter.with_store_timeout(store_timeout);
+        }
+        if let Some((retries, backoff)) = config.store_retries {
+            limiter = limiter.with_store_retries(retries, backoff);
+        }
+        if let Some((max_requests, interval)) = config.upgrade_limit {
+            limiter = limiter.with_upgrade_limit(max_requests, interval);
+        }
+        if let Some(fallback_key) = &config.fallback_key {
+            limiter = limiter.with_fallback_key(fallback_key);
+        }
+        if let Some(path_filter) = config.path_filter {
+            limiter = limiter.with_path_filter(path_filter);
+        }
+        limiter.with_exclusions(&config.excluded_paths)
     }
 
     /// Specify the interval. The counter for a client is reset after this interval
@@ -81,178 +391,8147 @@ where
         self
     }
 
+    /// Function picking the interval for a request, e.g. by the plan of the client, instead of
+    /// the one set by `with_interval`. The interval is read when a client opens a window, which
+    /// then lasts for that interval; a window opened under a longer interval is cut down to the
+    /// one returned for later requests. Has no effect along with `with_policies` or `with_rules`.
+    /// Requests the function panics for get the `with_interval` interval.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Trial users get 100 requests per hour, everyone else per minute
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_max_requests(100)
+    ///         .with_interval_fn(|req| {
+    ///             if req.headers().contains_key("x-trial") {
+    ///                 Duration::from_secs(3600)
+    ///             } else {
+    ///                 Duration::from_secs(60)
+    ///             }
+    ///         });
+    /// }
+    /// ```
+    pub fn with_interval_fn<F>(mut self, interval: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Duration + Send + Sync + 'static,
+    {
+        self.interval_fn = Some(Arc::new(Box::new(move |req| Some(interval(req)))));
+        self
+    }
+
     /// Specify the maximum number of requests allowed in the given interval.
     pub fn with_max_requests(mut self, max_requests: usize) -> Self {
         self.max_requests = max_requests;
         self
     }
 
-    /// Function to get the identifier for the client request
-    pub fn with_identifier<F: Fn(&ServiceRequest) -> Result<String, ARError> + 'static>(
+    /// Specify how requests are counted, `Algorithm::FixedWindow` by default.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{Algorithm, MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Bursts of up to 20 requests, draining at 10 requests per second
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(1))
+    ///         .with_max_requests(10)
+    ///         .with_algorithm(Algorithm::LeakyBucket { capacity: 20 });
+    /// }
+    /// ```
+    pub fn with_algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Specify whether the remaining count reported for an allowed request already accounts for
+    /// the request itself, `CountingMode::Preemptive` by default
+    pub fn with_counting_mode(mut self, counting_mode: CountingMode) -> Self {
+        self.counting_mode = counting_mode;
+        self
+    }
+
+    /// Specify whether clients the store has no entry for are given one,
+    /// `RegistrationMode::AutoCreate` by default
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter, RegistrationMode};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Keys are provisioned by another process sharing the store
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_registration_mode(RegistrationMode::DenyUnknown);
+    /// }
+    /// ```
+    pub fn with_registration_mode(mut self, registration_mode: RegistrationMode) -> Self {
+        self.registration_mode = registration_mode;
+        self
+    }
+
+    /// Specify when the windows of `Algorithm::FixedWindow` start, `WindowAnchor::FirstRequest`
+    /// by default
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter, WindowAnchor};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Every client's budget starts over at the top of the hour
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(3600))
+    ///         .with_max_requests(1000)
+    ///         .with_window_anchor(WindowAnchor::ClockAligned);
+    /// }
+    /// ```
+    pub fn with_window_anchor(mut self, window_anchor: WindowAnchor) -> Self {
+        self.window_anchor = window_anchor;
+        self
+    }
+
+    /// Grant `extra_requests` on top of the limit in the first window of a client, decaying
+    /// linearly to the steady limit over `windows` consecutive windows. Useful for legitimate
+    /// bursts after a cold start. The number of windows is kept under a second key, suffixed by
+    /// `:windows`, and starts over once the client has been idle for a whole interval. Only
+    /// applies to `Algorithm::FixedWindow`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // 150, 125 then 100 requests per minute
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_warmup(50, 2);
+    /// }
+    /// ```
+    pub fn with_warmup(mut self, extra_requests: usize, windows: usize) -> Self {
+        self.warmup = Some((extra_requests, windows));
+        self
+    }
+
+    /// Enforce several limits at once, each given as `(max_requests, interval)`, for example a
+    /// burst limit of 10 requests per second along with 1000 requests per hour.
+    ///
+    /// A request is rejected as soon as any of the limits is exhausted. Every limit is tracked
+    /// under its own key, suffixed by the interval, and the response headers report the most
+    /// restrictive one along with a `ratelimit-policy` header listing all of them. This overrides
+    /// the values set by `with_interval` and `with_max_requests`.
+    pub fn with_policies(mut self, policies: Vec<(usize, Duration)>) -> Self {
+        self.policies = policies;
+        self
+    }
+
+    /// Enforce several limits at once, each counting requests under a key of its own, for
+    /// example 1000 requests per hour for every address along with 100 requests per minute for
+    /// every api key. Requests are rejected as soon as any of the rules is exhausted, and the
+    /// headers report the most restrictive one, along with a `ratelimit-policy` header listing
+    /// all of them like `with_policies`. A rule whose identifier returns `None` doesn't apply to
+    /// the request. This overrides `with_interval`, `with_max_requests` and `with_policies`; the
+    /// identifier of the limiter is still used for `with_access_lists` and `with_max_concurrent`.
+    /// Every rule keeps its own limit and interval: the overrides computed per request, such as
+    /// `with_interval_fn`, `with_method_limits` or `with_adaptive`, don't apply to rules.
+    ///
+    /// Keys of different rules should not collide, e.g. by prefixing them with the kind of
+    /// identifier, and every rule should have a different interval, as entries are suffixed by
+    /// the interval.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter, Rule};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_rules(vec![
+    ///             Rule::new(
+    ///                 |req| Some(format!("ip:{}", req.connection_info().remote_addr()?)),
+    ///                 1000,
+    ///                 Duration::from_secs(3600),
+    ///             ),
+    ///             Rule::new(
+    ///                 |req| {
+    ///                     let key = req.headers().get("x-api-key")?.to_str().ok()?;
+    ///                     Some(format!("key:{}", key))
+    ///                 },
+    ///                 100,
+    ///                 Duration::from_secs(60),
+    ///             ),
+    ///         ]);
+    /// }
+    /// ```
+    pub fn with_rules(mut self, rules: Vec<Rule>) -> Self {
+        self.rules = rules;
+        self
+    }
+
+    /// Requests with these methods, such as CORS preflight `OPTIONS` requests, are passed through
+    /// without touching the store or consuming the client's budget.
+    pub fn with_skipped_methods(mut self, methods: Vec<Method>) -> Self {
+        self.skipped_methods = methods;
+        self
+    }
+
+    /// Give requests with these methods limits of their own, each as `(max_requests, interval)`,
+    /// e.g. to allow more of the cheap `GET` requests than `POST` ones. Every listed method is
+    /// counted under its own key, suffixed by the method, while the other methods share the
+    /// limits set by `with_max_requests` and `with_interval`. Like the other limit overrides,
+    /// these have no effect along with `with_policies` or `with_rules`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    /// use actix_web::http::Method;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let mut limits = HashMap::new();
+    ///     limits.insert(Method::GET, (1000, Duration::from_secs(60)));
+    ///     limits.insert(Method::POST, (100, Duration::from_secs(60)));
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_method_limits(limits);
+    /// }
+    /// ```
+    pub fn with_method_limits(mut self, limits: HashMap<Method, (usize, Duration)>) -> Self {
+        self.method_limits = limits;
+        self
+    }
+
+    /// Give connection upgrades, such as websocket handshakes, a limit of their own, e.g. to cap
+    /// how many websockets a client opens apart from its other requests. Upgrades are told
+    /// apart by their `Upgrade` header along with a `Connection: upgrade` header, as they are
+    /// plain `GET` requests otherwise, and counted under the key of the client suffixed by
+    /// `upgrade`. This takes precedence over `with_method_limits`, and like it has no effect
+    /// along with `with_policies` or `with_rules`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // 1000 requests a minute, of which at most 10 may open a websocket
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(1000)
+    ///         .with_upgrade_limit(10, Duration::from_secs(60));
+    /// }
+    /// ```
+    pub fn with_upgrade_limit(mut self, max_requests: usize, interval: Duration) -> Self {
+        self.upgrade_limit = Some((max_requests, interval));
+        self
+    }
+
+    /// Limit only the requests whose path the filter selects, e.g. the api of an app which also
+    /// serves static assets, without moving the limiter onto a scope. Other requests are passed
+    /// through without touching the store or getting limit headers.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, PathFilter, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_path_filter(PathFilter::include(vec!["/api", "/login"]));
+    /// }
+    /// ```
+    pub fn with_path_filter(mut self, path_filter: PathFilter) -> Self {
+        self.path_filter = Some(path_filter);
+        self
+    }
+
+    /// Never limit the health check and metrics endpoints listed in
+    /// [DEFAULT_EXCLUSIONS](constant.DEFAULT_EXCLUSIONS.html), such as `/health` and `/metrics`.
+    /// Use `with_exclusions` for endpoints named differently.
+    pub fn with_default_exclusions(self) -> Self {
+        self.with_exclusions(DEFAULT_EXCLUSIONS)
+    }
+
+    /// Never limit requests whose path matches one of `paths`, given as the patterns of
+    /// [PathFilter](enum.PathFilter.html). Such requests are passed through before anything else
+    /// is looked at, whatever `with_path_filter` selects. Calling this again adds to the paths
+    /// excluded so far.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_default_exclusions()
+    ///         .with_exclusions(&["/status", "/internal/**"]);
+    /// }
+    /// ```
+    pub fn with_exclusions<P: AsRef<str>>(mut self, paths: &[P]) -> Self {
+        self.excluded_paths
+            .extend(paths.iter().map(|path| path.as_ref().to_string()));
+        self
+    }
+
+    /// Function letting trusted requests, e.g. from load tests or internal tooling, bypass rate
+    /// limiting. It is called before anything else, and requests it returns `true` for are passed
+    /// to the service without touching the store, consuming any budget or getting limit headers.
+    /// A panic counts as `false`.
+    ///
+    /// Anyone able to produce what the function checks for is not rate limited at all, so never
+    /// trust a plain header a client can set. Check a secret token in constant time, or better a
+    /// signature which expires as with `with_signed_bypass`, and keep the secret out of logs.
+    /// Strip the header at the edge if the service is exposed, so that the token does not leak to
+    /// upstreams either.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// # fn valid_signature(token: &[u8]) -> bool { unimplemented!() }
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_bypass(|req| {
+    ///             req.headers()
+    ///                 .get("x-ratelimit-bypass")
+    ///                 .map_or(false, |token| valid_signature(token.as_bytes()))
+    ///         });
+    /// }
+    /// ```
+    pub fn with_bypass<F: Fn(&ServiceRequest) -> bool + Send + Sync + 'static>(
         mut self,
-        identifier: F,
+        bypass: F,
     ) -> Self {
-        self.identifier = Rc::new(Box::new(identifier));
+        self.bypass = Some(Arc::new(Box::new(bypass)));
         self
     }
-}
-
-impl<T, S, B> Transform<S> for RateLimiter<T>
-where
-    T: Handler<ActorMessage> + Send + Sync + 'static,
-    T::Context: ToEnvelope<T, ActorMessage>,
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError> + 'static,
-    S::Future: 'static,
-    B: 'static,
-{
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<B>;
-    type Error = S::Error;
-    type InitError = ();
-    type Transform = RateLimitMiddleware<S, T>;
-    type Future = Ready<Result<Self::Transform, Self::InitError>>;
 
-    fn new_transform(&self, service: S) -> Self::Future {
-        ok(RateLimitMiddleware {
-            service: Rc::new(RefCell::new(service)),
-            store: self.store.clone(),
-            max_requests: self.max_requests,
-            interval: self.interval.as_secs(),
-            identifier: self.identifier.clone(),
+    /// Let requests carrying a token signed with `secret` in the `x-ratelimit-bypass` header
+    /// bypass rate limiting, see `with_bypass`, which this replaces. Tokens are made with
+    /// `bypass_token` for the path of the request, and are only accepted within `max_age` of the
+    /// time they were signed at, so that a leaked token is of little use for long. Invalid or
+    /// stale tokens are ignored, and the request is limited as usual.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::{Duration, SystemTime};
+    /// use actix_ratelimit::{bypass_token, MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_signed_bypass("load-test-secret", Duration::from_secs(300));
+    ///     // Sent by the load tests as `x-ratelimit-bypass`
+    ///     let token = bypass_token("load-test-secret", "/search", SystemTime::now());
+    /// }
+    /// ```
+    pub fn with_signed_bypass<S: AsRef<[u8]>>(self, secret: S, max_age: Duration) -> Self {
+        let secret = secret.as_ref().to_vec();
+        self.with_bypass(move |req| {
+            req.headers()
+                .get("x-ratelimit-bypass")
+                .map_or(false, |token| {
+                    valid_bypass_token(
+                        &secret,
+                        req.path(),
+                        token.as_bytes(),
+                        max_age,
+                        SystemTime::now(),
+                    )
+                })
         })
     }
-}
 
-/// Service factory for RateLimiter
-pub struct RateLimitMiddleware<S, T>
-where
-    S: 'static,
-    T: Handler<ActorMessage> + 'static,
-{
-    service: Rc<RefCell<S>>,
-    store: Addr<T>,
-    // Exists here for the sole purpose of knowing the max_requests and interval from RateLimiter
-    max_requests: usize,
-    interval: u64,
-    identifier: Rc<Box<dyn Fn(&ServiceRequest) -> Result<String, ARError> + 'static>>,
-}
+    /// Name of the header carrying the maximum number of requests, `x-ratelimit-limit` by
+    /// default.
+    ///
+    /// # Panics
+    /// Panics if `name` is not a valid header name.
+    pub fn with_limit_header(mut self, name: &str) -> Self {
+        self.header_names.limit = header_name(name);
+        self
+    }
 
-impl<T, S, B> Service for RateLimitMiddleware<S, T>
-where
-    T: Handler<ActorMessage> + 'static,
-    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError> + 'static,
-    S::Future: 'static,
-    B: 'static,
-    T::Context: ToEnvelope<T, ActorMessage>,
-{
-    type Request = ServiceRequest;
-    type Response = ServiceResponse<B>;
-    type Error = S::Error;
-    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+    /// Name of the header carrying the number of requests left, `x-ratelimit-remaining` by
+    /// default.
+    ///
+    /// # Panics
+    /// Panics if `name` is not a valid header name.
+    pub fn with_remaining_header(mut self, name: &str) -> Self {
+        self.header_names.remaining = header_name(name);
+        self
+    }
 
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.borrow_mut().poll_ready(cx)
+    /// Name of the header carrying the seconds until the limit resets, `x-ratelimit-reset` by
+    /// default. For intervals under a second, the milliseconds until the reset are sent as well,
+    /// under the same name suffixed by `-ms`.
+    ///
+    /// # Panics
+    /// Panics if `name` is not a valid header name.
+    pub fn with_reset_header(mut self, name: &str) -> Self {
+        self.header_names.reset = header_name(name);
+        self.header_names.reset_ms = header_name(&format!("{}-ms", name));
+        self
     }
 
-    fn call(&mut self, req: ServiceRequest) -> Self::Future {
-        let store = self.store.clone();
-        let mut srv = self.service.clone();
-        let max_requests = self.max_requests;
-        let interval = Duration::from_secs(self.interval);
-        let identifier = self.identifier.clone();
-        Box::pin(async move {
-            let identifier: String = (identifier)(&req)?;
-            let remaining: ActorResponse = store
-                .send(ActorMessage::Get(String::from(&identifier)))
-                .await?;
-            match remaining {
-                ActorResponse::Get(opt) => {
-                    let opt = opt.await?;
-                    if let Some(c) = opt {
-                        // Existing entry in store
-                        let expiry = store
-                            .send(ActorMessage::Expire(String::from(&identifier)))
-                            .await?;
-                        let reset: Duration = match expiry {
-                            ActorResponse::Expire(dur) => dur.await?,
-                            _ => unreachable!(),
-                        };
-                        if c == 0 {
-                            info!("Limit exceeded for client: {}", &identifier);
-                            let mut response = HttpResponse::TooManyRequests();
-                            // let mut response = (error_callback)(&mut response);
-                            response.set_header("x-ratelimit-limit", max_requests.to_string());
-                            response.set_header("x-ratelimit-remaining", c.to_string());
-                            response.set_header("x-ratelimit-reset", reset.as_secs().to_string());
-                            Err(response.into())
-                        } else {
-                            // Decrement value
-                            let res: ActorResponse = store
-                                .send(ActorMessage::Update {
-                                    key: identifier,
-                                    value: 1,
-                                })
-                                .await?;
-                            let updated_value: usize = match res {
-                                ActorResponse::Update(c) => c.await?,
-                                _ => unreachable!(),
-                            };
-                            // Execute the request
-                            let fut = srv.call(req);
-                            let mut res = fut.await?;
-                            let headers = res.headers_mut();
-                            // Safe unwraps, since usize is always convertible to string
-                            headers.insert(
-                                HeaderName::from_static("x-ratelimit-limit"),
-                                HeaderValue::from_str(max_requests.to_string().as_str())?,
-                            );
-                            headers.insert(
-                                HeaderName::from_static("x-ratelimit-remaining"),
-                                HeaderValue::from_str(updated_value.to_string().as_str())?,
-                            );
-                            headers.insert(
-                                HeaderName::from_static("x-ratelimit-reset"),
-                                HeaderValue::from_str(reset.as_secs().to_string().as_str())?,
-                            );
-                            Ok(res)
-                        }
-                    } else {
-                        // New client, create entry in store
-                        let current_value = max_requests - 1;
-                        let res = store
-                            .send(ActorMessage::Set {
-                                key: String::from(&identifier),
-                                value: current_value,
-                                expiry: interval,
-                            })
-                            .await?;
-                        match res {
-                            ActorResponse::Set(c) => c.await?,
-                            _ => unreachable!(),
-                        }
-                        let fut = srv.call(req);
-                        let mut res = fut.await?;
-                        let headers = res.headers_mut();
-                        // Safe unwraps, since usize is always convertible to string
-                        headers.insert(
-                            HeaderName::from_static("x-ratelimit-limit"),
-                            HeaderValue::from_str(max_requests.to_string().as_str()).unwrap(),
-                        );
-                        headers.insert(
-                            HeaderName::from_static("x-ratelimit-remaining"),
-                            HeaderValue::from_str(current_value.to_string().as_str()).unwrap(),
-                        );
-                        headers.insert(
-                            HeaderName::from_static("x-ratelimit-reset"),
-                            HeaderValue::from_str(interval.as_secs().to_string().as_str()).unwrap(),
-                        );
-                        Ok(res)
-                    }
-                }
-                _ => {
-                    unreachable!();
-                }
+    /// How the state of the limit is reported, `HeaderStyle::Separate` by default
+    pub fn with_header_style(mut self, header_style: HeaderStyle) -> Self {
+        self.header_names.style = header_style;
+        self
+    }
+
+    /// How the `Retry-After` header of rejected requests is written, `RetryAfterFormat::Seconds`
+    /// by default
+    pub fn with_retry_after_format(mut self, format: RetryAfterFormat) -> Self {
+        self.header_names.retry_after = format;
+        self
+    }
+
+    /// Add the rate limit headers to responses of accepted requests, enabled by default. Turning
+    /// them off keeps clients from learning how close they are to the limit, while rejected
+    /// requests still tell them how long to wait, see `with_reject_headers`.
+    pub fn with_success_headers(mut self, success_headers: bool) -> Self {
+        self.success_headers = success_headers;
+        self
+    }
+
+    /// Add the rate limit headers to responses of rejected requests, enabled by default. These
+    /// always carry `Retry-After`.
+    pub fn with_reject_headers(mut self, reject_headers: bool) -> Self {
+        self.reject_headers = reject_headers;
+        self
+    }
+
+    /// Prepend `prefix` to every key this limiter stores. Several limiters can share one store,
+    /// each with its own limits, as long as they use distinct prefixes; otherwise they would
+    /// count against the same entries for a given client.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let addr = MemoryStoreActor::from(store.clone()).start();
+    ///     let api = RateLimiter::new(addr.clone())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_prefix("api:");
+    ///     let login = RateLimiter::new(addr)
+    ///         .with_interval(Duration::from_secs(3600))
+    ///         .with_max_requests(5)
+    ///         .with_prefix("login:");
+    /// }
+    /// ```
+    pub fn with_prefix(mut self, prefix: &str) -> Self {
+        self.prefix = prefix.to_string();
+        self
+    }
+
+    /// Describe the limit in a JSON body of rejected requests, such as
+    /// `{"error":"too_many_requests","limit":100,"remaining":0,"retry_after":30}`, instead of
+    /// leaving the body empty.
+    pub fn with_json_errors(mut self, json_errors: bool) -> Self {
+        self.json_errors = json_errors;
+        self
+    }
+
+    /// Describe rejected requests with an `application/problem+json` document as of RFC 7807,
+    /// such as
+    /// `{"type":"about:blank","title":"Too Many Requests","status":429,"retryAfter":30}`, where
+    /// `retryAfter` is the number of seconds to wait, the same as in the `Retry-After` header.
+    /// Takes precedence over `with_json_errors`.
+    pub fn with_problem_json(mut self, problem_json: bool) -> Self {
+        self.problem_json = problem_json;
+        self
+    }
+
+    /// Answer rejected requests with `body` of type `content_type`, e.g. a branded error page,
+    /// instead of leaving the body empty. The rate limit headers are set as usual. Takes
+    /// precedence over `with_json_errors` and `with_problem_json`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    /// use actix_web::http::header::ContentType;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_rejection_body("<h1>Slow down</h1>", ContentType::html());
+    /// }
+    /// ```
+    pub fn with_rejection_body<B: Into<Bytes>>(
+        mut self,
+        body: B,
+        content_type: ContentType,
+    ) -> Self {
+        let content_type = HeaderValue::from_str(content_type.0.as_ref())
+            .expect("a media type is a valid header value");
+        self.rejection_body = Some((body.into(), content_type));
+        self
+    }
+
+    /// Let requests through when the store fails, e.g. because the connection to it was lost,
+    /// instead of answering them with an internal server error. Such requests are not counted
+    /// and carry no rate limit headers, unless `with_fail_open_headers` is enabled.
+    pub fn with_fail_open(mut self, fail_open: bool) -> Self {
+        self.fail_open = fail_open;
+        self
+    }
+
+    /// Add placeholder rate limit headers to requests let through by `with_fail_open`, so that
+    /// responses look the same whether or not the store is available. They report the full
+    /// `max_requests` as remaining and the `interval` as the time until reset. Has no effect when
+    /// `with_success_headers` is disabled.
+    pub fn with_fail_open_headers(mut self, fail_open_headers: bool) -> Self {
+        self.fail_open_headers = fail_open_headers;
+        self
+    }
+
+    /// Count requests and report the limits in the headers as usual, but serve the requests over
+    /// the limit instead of rejecting them, e.g. to observe what a limit would throttle before
+    /// enforcing it. Such requests are logged, report no requests remaining, carry
+    /// [Throttled(true)](struct.Throttled.html) and are handed to the header hook as throttled.
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Tell clients about their limits in response to `OPTIONS` requests, which are passed to the
+    /// service without being counted. The response carries the rate limit headers with the
+    /// requests currently left, and a `RateLimit-Policy` header listing every applicable policy.
+    /// Takes precedence over skipping `OPTIONS` with `with_skipped_methods`.
+    pub fn with_options_report(mut self, options_report: bool) -> Self {
+        self.options_report = options_report;
+        self
+    }
+
+    /// Give up on the store if looking up and counting a request takes longer than `timeout`.
+    /// Checking the access lists, taking an in-flight slot and refunding a request are bounded
+    /// by `timeout` each as well. The request then fails with `ARError::StoreTimeout`, which
+    /// renders as 503 Service Unavailable with a `Retry-After`, or goes through if
+    /// `with_fail_open` is enabled. Timeouts count as failures for `with_circuit_breaker`.
+    /// Operations the store already carried out are not undone, so a request which timed out
+    /// may still have been counted.
+    pub fn with_store_timeout(mut self, timeout: Duration) -> Self {
+        self.store_timeout = Some(timeout);
+        self
+    }
+
+    /// Send a message to the store again when it fails with a transient error, such as a redis
+    /// server still loading its data after a failover, up to `retries` times. The first retry
+    /// waits `backoff`, and every further one twice as long as the one before. Errors which
+    /// retrying won't fix are returned right away, see `ARError::is_transient`. The time spent
+    /// retrying counts towards `with_store_timeout`. Messages counting requests are only sent
+    /// again if the store reports it did not carry them out, see `ARError::is_unapplied`, so
+    /// that a connection dropping before the answer came back doesn't count a request twice.
+    pub fn with_store_retries(mut self, retries: usize, backoff: Duration) -> Self {
+        self.store_retries = Some((retries, backoff));
+        self
+    }
+
+    /// Hold requests back while `readiness` is not set, e.g. while the redis store reconnects,
+    /// see `RedisStoreActor::readiness`. The middleware then reports itself as not ready to
+    /// take requests, so actix waits for the store instead of failing requests one by one.
+    pub fn with_readiness(mut self, readiness: Readiness) -> Self {
+        self.readiness = Some(readiness);
+        self
+    }
+
+    /// Look every client up on the allow and deny lists of the store before applying the limits.
+    /// Allowed clients are let through without being counted, and denied ones are answered with
+    /// `ARError::Denied`, a 403 Forbidden, again without being counted. Clients are looked up by
+    /// their identifier, after `with_key_hasher` but without the prefix. The memory stores keep
+    /// the lists themselves, see `MemoryStore::allow`, and the redis store in the `allowlist` and
+    /// `denylist` sets; other stores have no lists.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     store.deny("203.0.113.7");
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_access_lists(true);
+    /// }
+    /// ```
+    pub fn with_access_lists(mut self, access_lists: bool) -> Self {
+        self.access_lists = access_lists;
+        self
+    }
+
+    /// Give the request back to the client when the wrapped service answers it with 429 Too Many
+    /// Requests itself, e.g. when it sits in front of an upstream with limits of its own. Such
+    /// responses keep the rate limit headers of the service either way, the middleware never
+    /// adds its own to them. What the request drew on the pool of `with_tier_reservations`,
+    /// reservation included, is given back as well.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_upstream_refund(true);
+    /// }
+    /// ```
+    pub fn with_upstream_refund(mut self, upstream_refund: bool) -> Self {
+        self.upstream_refund = upstream_refund;
+        self
+    }
+
+    /// Give the request back to the client when the wrapped service fails with an error instead
+    /// of producing a response, e.g. an inner middleware bailing out before the handler ran.
+    /// Errors the handler returns are turned into responses by actix and are still counted.
+    /// Refunds never raise the count of a client above its limit, and give back what the request
+    /// drew on the pool of `with_tier_reservations` as well.
+    pub fn with_refund_on_error(mut self, refund_on_error: bool) -> Self {
+        self.refund_on_error = refund_on_error;
+        self
+    }
+
+    /// Reject requests with `ARError::ConcurrencyLimitError` while the client already has
+    /// `max_concurrent` requests in flight, e.g. to keep slow clients from tying up the workers.
+    /// The count is kept in the store under the client's key suffixed by `:active`, and a
+    /// request gives its slot back once it is done, whether it succeeded, failed or panicked.
+    /// Requests are counted against the rate limit as well.
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = Some(max_concurrent);
+        self
+    }
+
+    /// Stop contacting the store for `cooldown` once it failed for `failures` requests in a row,
+    /// so that requests don't all wait on a dead backend. Meanwhile requests fail right away with
+    /// `ARError::CircuitOpen`, or go through if `with_fail_open` is enabled. After the cooldown,
+    /// a single request probes the store and closes the circuit again if it succeeds. Every
+    /// worker keeps track of the failures on its own.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_fail_open(true)
+    ///         .with_circuit_breaker(5, Duration::from_secs(10));
+    /// }
+    /// ```
+    pub fn with_circuit_breaker(mut self, failures: usize, cooldown: Duration) -> Self {
+        self.circuit_breaker = Some((failures, cooldown));
+        self
+    }
+
+    /// Count requests which can't be identified against the shared key `key` instead of failing
+    /// them with `ARError::IdentificationError`, e.g. requests built with `actix_web::test`
+    /// without a peer address, or ones arriving on a Unix socket. Applies to every identifier
+    /// failing with that error or with `ARError::Unidentifiable`, also when it panicked. The
+    /// prefix is still prepended.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_fallback_key("unknown-client");
+    /// }
+    /// ```
+    pub fn with_fallback_key(mut self, key: &str) -> Self {
+        self.fallback_key = Some(key.to_string());
+        self
+    }
+
+    /// Function to get the identifier for the client request. Return
+    /// `Err(ARError::IdentificationError)` for requests which can't be identified rather than
+    /// panicking; a panic is turned into the same error, but still reported by the panic hook.
+    pub fn with_identifier<
+        F: Fn(&ServiceRequest) -> Result<String, ARError> + Send + Sync + 'static,
+    >(
+        mut self,
+        identifier: F,
+    ) -> Self {
+        self.identifier = Arc::new(Box::new(move |req| identifier(req).map(Some)));
+        self.body_limit = None;
+        self
+    }
+
+    /// Function to get the identifier for the client request, where `Ok(None)` lets the request
+    /// through without rate limiting it, e.g. for requests which have no meaningful key.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Only limit requests carrying an api key
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_optional_identifier(|req| {
+    ///             Ok(req
+    ///                 .headers()
+    ///                 .get("x-api-key")
+    ///                 .and_then(|key| key.to_str().ok())
+    ///                 .map(String::from))
+    ///         });
+    /// }
+    /// ```
+    pub fn with_optional_identifier<F>(mut self, identifier: F) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Result<Option<String>, ARError> + Send + Sync + 'static,
+    {
+        self.identifier = Arc::new(Box::new(identifier));
+        self.body_limit = None;
+        self
+    }
+
+    /// Limit every client separately on each route, with keys such as `127.0.0.1|/users/{id}`.
+    /// The route pattern is used rather than the concrete path, so that path parameters don't
+    /// open a new bucket for every value. Requests which match no route share a single bucket
+    /// per address.
+    pub fn with_ip_and_path_identifier(self) -> Self {
+        self.with_identifier(|req| {
+            let connection_info = req.connection_info();
+            let ip = connection_info
+                .remote_addr()
+                .ok_or(ARError::IdentificationError)?;
+            Ok(format!(
+                "{}|{}",
+                ip,
+                req.match_pattern().unwrap_or_default()
+            ))
+        })
+    }
+
+    /// Limit each route as a whole, across all clients, with keys such as `route:/users/{id}`.
+    /// The route pattern is used rather than the concrete path, so that `/users/123` and
+    /// `/users/456` count against the same bucket. Requests which match no route are keyed by
+    /// their path instead.
+    pub fn with_route_identifier(self) -> Self {
+        self.with_identifier(|req| {
+            let route = req
+                .match_pattern()
+                .unwrap_or_else(|| req.path().to_string());
+            Ok(format!("route:{}", route))
+        })
+    }
+
+    /// Identify clients by the value of the `name` header, such as an api key, and fall back to
+    /// their address when the header is missing or empty. Keys look like `key:<value>` and
+    /// `ip:<address>`, so that a header value can't pass for an address. IPv6 addresses are
+    /// masked to their /64 network, which is usually handed out to a single client as a whole.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_header_or_ip("x-api-key");
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if `name` is not a valid header name.
+    pub fn with_header_or_ip(self, name: &str) -> Self {
+        let name = header_name(name);
+        self.with_identifier(move |req| {
+            let value = req
+                .headers()
+                .get(&name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::trim)
+                .filter(|value| !value.is_empty());
+            if let Some(value) = value {
+                return Ok(format!("key:{}", value));
             }
+            let connection_info = req.connection_info();
+            let addr = connection_info
+                .remote_addr()
+                .ok_or(ARError::IdentificationError)?;
+            Ok(format!("ip:{}", masked_ip(addr)))
         })
     }
+
+    /// Identify devices rather than addresses, by a hash of the values of the `names` headers,
+    /// such as `User-Agent` and `Accept-Language`, together with the client's address without
+    /// its port. Keys look like `fp:<hash>`. A missing header counts as a value of its own,
+    /// different from an empty one, so requests lacking the same headers still share a key.
+    ///
+    /// The hash is not keyed, and may change with the Rust version the server is built with,
+    /// starting the clients over once in a while.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_fingerprint_identifier(&["user-agent", "accept-language"]);
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if any of `names` is not a valid header name.
+    pub fn with_fingerprint_identifier(self, names: &[&str]) -> Self {
+        let names: Vec<HeaderName> = names.iter().map(|name| header_name(name)).collect();
+        self.with_identifier(move |req| {
+            let connection_info = req.connection_info();
+            let addr = connection_info
+                .remote_addr()
+                .ok_or(ARError::IdentificationError)?;
+            let mut hasher = DefaultHasher::new();
+            for name in &names {
+                let values: Vec<&[u8]> = req
+                    .headers()
+                    .get_all(name)
+                    .map(|value| value.as_bytes())
+                    .collect();
+                values.hash(&mut hasher);
+            }
+            masked_ip(addr).hash(&mut hasher);
+            Ok(format!("fp:{:016x}", hasher.finish()))
+        })
+    }
+
+    /// Identify clients behind `trusted_proxies` reverse proxies by the address the outermost
+    /// of them added to `X-Forwarded-For`, with keys such as `ip:203.0.113.7`. Each proxy
+    /// appends the address it received the request from, so the chain of a legitimate request
+    /// holds exactly one address per proxy, while entries a client forged itself show up as
+    /// extra addresses in front. `on_mismatch` decides what happens to requests with more or
+    /// fewer addresses. Addresses are masked like in `with_header_or_ip`.
+    ///
+    /// The `Forwarded` header, which `ConnectionInfo` prefers, is not looked at.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{HopMismatch, MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Behind a CDN and a load balancer
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_forwarded_identifier(2, HopMismatch::Reject);
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if `trusted_proxies` is 0.
+    pub fn with_forwarded_identifier(
+        self,
+        trusted_proxies: usize,
+        on_mismatch: HopMismatch,
+    ) -> Self {
+        assert!(
+            trusted_proxies > 0,
+            "at least one trusted proxy is required"
+        );
+        self.with_identifier(move |req| {
+            let chain: Vec<String> = req
+                .headers()
+                .get_all("x-forwarded-for")
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(|addr| addr.trim().to_string())
+                .filter(|addr| !addr.is_empty())
+                .collect();
+            let found = chain.len();
+            let addr = match on_mismatch {
+                _ if found == trusted_proxies => Some(&chain[0]),
+                HopMismatch::Ignore if found > trusted_proxies => {
+                    Some(&chain[found - trusted_proxies])
+                }
+                HopMismatch::Reject => {
+                    return Err(ARError::ForwardedMismatch {
+                        expected: trusted_proxies,
+                        found,
+                    })
+                }
+                _ => None,
+            };
+            match addr {
+                Some(addr) => Ok(format!("ip:{}", masked_ip(addr))),
+                None => {
+                    let peer = req.peer_addr().ok_or(ARError::IdentificationError)?;
+                    Ok(format!("ip:{}", masked_ip(&peer.to_string())))
+                }
+            }
+        })
+    }
+
+    /// Identify clients by the value of the query parameter `name`, e.g. to limit the requests
+    /// for every `?user_id=` regardless of where they come from. Keys look like `name:<value>`.
+    /// Requests without the parameter fail with `ARError::Unidentifiable`, which renders as
+    /// 400 Bad Request, unless they are counted against `with_fallback_key`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_query_identifier("user_id");
+    /// }
+    /// ```
+    pub fn with_query_identifier(self, name: &str) -> Self {
+        let name = name.to_string();
+        self.with_identifier(move |req| {
+            let query = Query::<HashMap<String, String>>::from_query(req.query_string())
+                .map_err(|_| ARError::Unidentifiable("malformed query".to_string()))?;
+            match query.get(&name) {
+                Some(value) => Ok(format!("{}:{}", &name, value)),
+                None => Err(ARError::Unidentifiable(format!(
+                    "no query parameter {}",
+                    &name
+                ))),
+            }
+        })
+    }
+
+    /// Identify clients by the value of the path parameter `name`, e.g. to limit the requests
+    /// for every `/users/{id}` regardless of where they come from. Keys look like `name:<value>`.
+    ///
+    /// Path parameters are only known once the request has been routed, so the limiter has to
+    /// wrap the resource, or a scope whose path holds the parameter, rather than the whole `App`.
+    /// Requests without the parameter fail with `ARError::Unidentifiable`, like those of
+    /// [with_query_identifier](#method.with_query_identifier).
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    /// use actix_web::{web, App, HttpResponse};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let app = App::new().service(
+    ///         web::resource("/users/{id}")
+    ///             .wrap(
+    ///                 RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///                     .with_interval(Duration::from_secs(60))
+    ///                     .with_max_requests(100)
+    ///                     .with_path_identifier("id"),
+    ///             )
+    ///             .route(web::get().to(HttpResponse::Ok)),
+    ///     );
+    /// }
+    /// ```
+    pub fn with_path_identifier(self, name: &str) -> Self {
+        let name = name.to_string();
+        self.with_identifier(move |req| match req.match_info().get(&name) {
+            Some(value) => Ok(format!("{}:{}", &name, value)),
+            None => Err(ARError::Unidentifiable(format!(
+                "no path parameter {}",
+                &name
+            ))),
+        })
+    }
+
+    /// Identify clients by the body of their requests, e.g. by a field of a JSON payload.
+    /// Bodies of up to `limit` bytes are read ahead and passed to `identifier`; they are then
+    /// handed on to the service as they came. Longer bodies are handed on without being looked
+    /// at, and their requests fail with `ARError::Unidentifiable`, like those of
+    /// [with_query_identifier](#method.with_query_identifier).
+    ///
+    /// Reading the body holds the request back until all of it has arrived, so keep `limit`
+    /// small. Like [with_identifier](#method.with_identifier), this replaces the identifier set
+    /// before; setting another one afterwards stops bodies from being read.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{errors::ARError, MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         // Bodies look like `account=<id>`
+    ///         .with_body_identifier(1024, |_, body| {
+    ///             let body = std::str::from_utf8(body).map_err(|_| ARError::IdentificationError)?;
+    ///             match body.strip_prefix("account=") {
+    ///                 Some(account) => Ok(format!("account:{}", account)),
+    ///                 None => Err(ARError::Unidentifiable("no account".to_string())),
+    ///             }
+    ///         });
+    /// }
+    /// ```
+    pub fn with_body_identifier<F>(self, limit: usize, identifier: F) -> Self
+    where
+        F: Fn(&ServiceRequest, &[u8]) -> Result<String, ARError> + Send + Sync + 'static,
+    {
+        let mut limiter = self.with_identifier(move |req| {
+            let body = req
+                .extensions()
+                .get::<BufferedBody>()
+                .and_then(|body| body.0.clone());
+            match body {
+                Some(body) => identifier(req, &body),
+                None => Err(ARError::Unidentifiable(format!(
+                    "body over {} bytes",
+                    limit
+                ))),
+            }
+        });
+        limiter.body_limit = Some(limit);
+        limiter
+    }
+
+    /// Identify clients by a field of the TLS certificate they presented, for APIs behind mutual
+    /// TLS where addresses say little about who is calling. Keys look like `cert:<value>`.
+    ///
+    /// The certificate is read from the [ClientCertificate](struct.ClientCertificate.html)
+    /// extension, which the server has to set from the TLS stream in `HttpServer::on_connect`,
+    /// so TLS must be terminated in-process. actix-web only hands connection data to the first
+    /// request of a connection, so keep-alive should be disabled along with this identifier.
+    /// Requests without a certificate, or whose certificate lacks the field, fail with
+    /// `ARError::IdentificationError`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::middleware::CertField;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_client_cert_identifier(CertField::Attribute("CN".to_string()));
+    /// }
+    /// ```
+    pub fn with_client_cert_identifier(self, field: CertField) -> Self {
+        self.with_identifier(move |req| {
+            let extensions = req.extensions();
+            let cert = extensions
+                .get::<ClientCertificate>()
+                .ok_or(ARError::IdentificationError)?;
+            let value = match &field {
+                CertField::Serial => Some(cert.serial.clone()),
+                CertField::Attribute(name) => cert.subject_attribute(name),
+            };
+            match value.filter(|value| !value.is_empty()) {
+                Some(value) => Ok(format!("cert:{}", value)),
+                None => Err(ARError::IdentificationError),
+            }
+        })
+    }
+
+    /// Share a budget of `max_requests` per interval among all clients, on top of their own
+    /// limits, while reserving part of it for some tiers so that busy tiers cannot starve the
+    /// others. `tier` picks the tier of each request, and `reserved` lists how many requests of
+    /// the budget each tier is guaranteed. A tier may use its reservation plus whatever is not
+    /// reserved; tiers missing from `reserved` only get the latter, as do requests `tier` panics
+    /// for. The budget is counted over the `with_interval` interval.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Premium users always have 200 of the 1000 requests per minute to themselves
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_tier_reservations(
+    ///             1000,
+    ///             |req| {
+    ///                 if req.headers().contains_key("x-premium") {
+    ///                     "premium".to_string()
+    ///                 } else {
+    ///                     "free".to_string()
+    ///                 }
+    ///             },
+    ///             vec![("premium", 200)],
+    ///         );
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if more than `max_requests` are reserved.
+    pub fn with_tier_reservations<F>(
+        mut self,
+        max_requests: usize,
+        tier: F,
+        reserved: Vec<(&str, usize)>,
+    ) -> Self
+    where
+        F: Fn(&ServiceRequest) -> String + Send + Sync + 'static,
+    {
+        assert!(
+            reserved.iter().map(|(_, reserved)| reserved).sum::<usize>() <= max_requests,
+            "tiers reserve more than max_requests"
+        );
+        self.tiers = Some(Arc::new(TierPool {
+            max_requests,
+            tier: Box::new(tier),
+            reserved: reserved
+                .into_iter()
+                .map(|(tier, reserved)| (tier.to_string(), reserved))
+                .collect(),
+        }));
+        self
+    }
+
+    /// Identify and limit authenticated and anonymous requests differently, e.g. by user id with
+    /// a high limit and by IP address with a low one. `is_authenticated` picks the tier of each
+    /// request, and each tier is given as an identifier function along with its maximum number
+    /// of requests per interval. This replaces the identifier and the `with_max_requests` limit;
+    /// limits set with `with_policies` or `with_rules` are left as they are.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{errors::ARError, MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_auth_tiers(
+    ///             |req| req.headers().contains_key("x-user-id"),
+    ///             (
+    ///                 |req| {
+    ///                     let user = req.headers().get("x-user-id");
+    ///                     let user = user.and_then(|user| user.to_str().ok());
+    ///                     Ok(format!("user:{}", user.ok_or(ARError::IdentificationError)?))
+    ///                 },
+    ///                 1000,
+    ///             ),
+    ///             (
+    ///                 |req| {
+    ///                     let info = req.connection_info();
+    ///                     let ip = info.remote_addr().ok_or(ARError::IdentificationError)?;
+    ///                     Ok(format!("ip:{}", ip))
+    ///                 },
+    ///                 100,
+    ///             ),
+    ///         );
+    /// }
+    /// ```
+    pub fn with_auth_tiers<P, A, N>(
+        mut self,
+        is_authenticated: P,
+        authenticated: (A, usize),
+        anonymous: (N, usize),
+    ) -> Self
+    where
+        P: Fn(&ServiceRequest) -> bool + Send + Sync + 'static,
+        A: Fn(&ServiceRequest) -> Result<String, ARError> + Send + Sync + 'static,
+        N: Fn(&ServiceRequest) -> Result<String, ARError> + Send + Sync + 'static,
+    {
+        let is_authenticated = Arc::new(is_authenticated);
+        let predicate = is_authenticated.clone();
+        let (user_identifier, user_limit) = authenticated;
+        let (anonymous_identifier, anonymous_limit) = anonymous;
+        self.identifier = Arc::new(Box::new(move |req| {
+            if predicate(req) {
+                user_identifier(req).map(Some)
+            } else {
+                anonymous_identifier(req).map(Some)
+            }
+        }));
+        self.body_limit = None;
+        self.limit = Some(Arc::new(Box::new(move |req| {
+            if is_authenticated(req) {
+                Some(user_limit)
+            } else {
+                Some(anonymous_limit)
+            }
+        })));
+        self
+    }
+
+    /// Identify clients along with a kind, such as an address or an api key, and apply the limit
+    /// `limits` gives for that kind as `(max_requests, interval)`. Kinds missing from `limits`
+    /// get the `with_max_requests` and `with_interval` limit. This replaces the identifier, the
+    /// `with_interval_fn` interval and the `with_auth_tiers` and `with_adaptive` limits, and has
+    /// no effect on the limits set with `with_policies` or `with_rules`.
+    ///
+    /// The function is called more than once per request, so it should be cheap and give the
+    /// same answer each time. Keys of different kinds should not collide, e.g. by prefixing them
+    /// with the kind.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{errors::ARError, MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[derive(PartialEq, Eq, Hash)]
+    /// enum KeyKind {
+    ///     Ip,
+    ///     ApiKey,
+    /// }
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let mut limits = HashMap::new();
+    ///     limits.insert(KeyKind::Ip, (100, Duration::from_secs(3600)));
+    ///     limits.insert(KeyKind::ApiKey, (1000, Duration::from_secs(60)));
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_classified_identifier(
+    ///             |req| match req.headers().get("x-api-key") {
+    ///                 Some(key) => {
+    ///                     let key = key.to_str().map_err(|_| ARError::IdentificationError)?;
+    ///                     Ok((format!("key:{}", key), KeyKind::ApiKey))
+    ///                 }
+    ///                 None => {
+    ///                     let info = req.connection_info();
+    ///                     let ip = info.remote_addr().ok_or(ARError::IdentificationError)?;
+    ///                     Ok((format!("ip:{}", ip), KeyKind::Ip))
+    ///                 }
+    ///             },
+    ///             limits,
+    ///         );
+    /// }
+    /// ```
+    pub fn with_classified_identifier<F, K>(
+        mut self,
+        identifier: F,
+        limits: HashMap<K, (usize, Duration)>,
+    ) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Result<(String, K), ARError> + Send + Sync + 'static,
+        K: Eq + Hash + Send + Sync + 'static,
+    {
+        let classify = Arc::new(move |req: &ServiceRequest| {
+            let (key, kind) = identifier(req)?;
+            Ok((key, limits.get(&kind).copied()))
+        });
+        let key = classify.clone();
+        self.identifier = Arc::new(Box::new(move |req| key(req).map(|(key, _)| Some(key))));
+        self.body_limit = None;
+        let limit = classify.clone();
+        self.limit = Some(Arc::new(Box::new(move |req| {
+            let (_, limits) = limit(req).ok()?;
+            limits.map(|(max_requests, _)| max_requests)
+        })));
+        self.interval_fn = Some(Arc::new(Box::new(move |req| {
+            let (_, limits) = classify(req).ok()?;
+            limits.map(|(_, interval)| interval)
+        })));
+        self
+    }
+
+    /// Apply the limit `limits` gives, as `(max_requests, interval)`, for the region of the
+    /// client's address, e.g. per country for compliance. `region` maps an address to its region,
+    /// typically through a geolocation database of your own, as none is bundled. Clients are
+    /// still identified by their address, and regions missing from `limits` get the
+    /// `with_max_requests` and `with_interval` limit. Like `with_classified_identifier`, which
+    /// this builds on, it replaces the identifier and the other per request limits, and
+    /// `region` is called more than once per request.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::collections::HashMap;
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    /// use std::net::IpAddr;
+    ///
+    /// #[derive(PartialEq, Eq, Hash)]
+    /// enum Region {
+    ///     Eu,
+    ///     Other,
+    /// }
+    ///
+    /// fn lookup(ip: IpAddr) -> Region {
+    ///     // Look the address up in a geolocation database
+    ///     # let _ = ip;
+    ///     Region::Other
+    /// }
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let mut limits = HashMap::new();
+    ///     limits.insert(Region::Eu, (50, Duration::from_secs(60)));
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_region_limits(lookup, limits);
+    /// }
+    /// ```
+    pub fn with_region_limits<F, R>(self, region: F, limits: HashMap<R, (usize, Duration)>) -> Self
+    where
+        F: Fn(IpAddr) -> R + Send + Sync + 'static,
+        R: Eq + Hash + Send + Sync + 'static,
+    {
+        self.with_classified_identifier(
+            move |req| {
+                let connection_info = req.connection_info();
+                let addr = connection_info
+                    .remote_addr()
+                    .ok_or(ARError::IdentificationError)?;
+                let ip = match addr.parse::<SocketAddr>() {
+                    Ok(addr) => addr.ip(),
+                    Err(_) => addr
+                        .parse::<IpAddr>()
+                        .map_err(|_| ARError::IdentificationError)?,
+                };
+                Ok((String::from(addr), region(ip)))
+            },
+            limits,
+        )
+    }
+
+    /// Derive the maximum number of requests from the average time the service took to handle
+    /// recent requests, e.g. to tighten the limit while a backend is struggling. The average is
+    /// kept by the limiter and shared by its clones, so a limiter built inside the `HttpServer`
+    /// factory keeps one per worker. The limit is picked whenever a client opens a new
+    /// window. This replaces the `with_max_requests` and `with_auth_tiers` limits; limits set
+    /// with `with_policies` or `with_rules` are left as they are.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_adaptive(|latency| {
+    ///             if latency > Duration::from_millis(500) {
+    ///                 20
+    ///             } else {
+    ///                 100
+    ///             }
+    ///         });
+    /// }
+    /// ```
+    pub fn with_adaptive<F>(mut self, limit: F) -> Self
+    where
+        F: Fn(Duration) -> usize + Send + Sync + 'static,
+    {
+        let latency = Arc::new(Latency::default());
+        let average = latency.clone();
+        self.limit = Some(Arc::new(Box::new(move |_| Some(limit(average.average())))));
+        self.latency = Some(latency);
+        self
+    }
+
+    /// Read the limit and interval from `limits` for every request, so that they can be changed
+    /// while the server runs, e.g. from an admin endpoint, without building the limiter again.
+    /// Like the limit of `with_adaptive`, they are picked whenever a client opens a new window,
+    /// so a window already open keeps its interval. This replaces `with_max_requests`,
+    /// `with_interval` and `with_interval_fn`, as well as the limits of `with_adaptive` and
+    /// `with_auth_tiers`; limits set with `with_policies` or `with_rules` are left as they are.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter, SharedLimits};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let limits = SharedLimits::new(100, Duration::from_secs(60));
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_shared_limits(limits.clone());
+    ///     // Later on, e.g. while under attack
+    ///     limits.set(10, Duration::from_secs(60));
+    /// }
+    /// ```
+    pub fn with_shared_limits(mut self, limits: SharedLimits) -> Self {
+        let (max_requests, interval) = limits.get();
+        self.max_requests = max_requests;
+        self.interval = interval;
+        let interval_limits = limits.clone();
+        self.limit = Some(Arc::new(Box::new(move |_| Some(limits.get().0))));
+        self.interval_fn = Some(Arc::new(Box::new(move |_| Some(interval_limits.get().1))));
+        self
+    }
+
+    /// Function transforming the client identifier before it is used as a key, so that the store
+    /// never sees raw personal data such as IP addresses. Use a keyed hash, e.g. HMAC-SHA256 with
+    /// a server secret, as plain hashes of IP addresses are easily reversed.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// # fn hmac_sha256_hex(secret: &[u8], data: &str) -> String { unimplemented!() }
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_key_hasher(|key| hmac_sha256_hex(b"server secret", key));
+    /// }
+    /// ```
+    pub fn with_key_hasher<F: Fn(&str) -> String + Send + Sync + 'static>(
+        mut self,
+        hasher: F,
+    ) -> Self {
+        self.key_hasher = Some(Arc::new(Box::new(hasher)));
+        self
+    }
+
+    /// Bound the identifiers used in keys to `max_key_length` bytes, so that clients identified by
+    /// what they send, e.g. an API key header, can't fill the store with huge keys.
+    /// Longer identifiers are cut short and end with a 16 digit hash of the part cut off, which
+    /// keeps them apart. Applies after `with_key_hasher`, and to the identifiers of rules as well.
+    ///
+    /// # Panics
+    /// Panics if `max_key_length` is under 16, leaving no room for the hash.
+    pub fn with_max_key_length(mut self, max_key_length: usize) -> Self {
+        assert!(
+            max_key_length >= 16,
+            "max_key_length must leave room for a 16 digit hash"
+        );
+        self.max_key_length = Some(max_key_length);
+        self
+    }
+
+    /// Function called with the headers of every response the limiter let through or rejected,
+    /// after the standard headers have been set, e.g. to add vendor headers computed from the
+    /// [Decision](struct.Decision.html). It is also called when
+    /// `with_success_headers` or `with_reject_headers` turned the standard headers off, but not
+    /// for requests the limits were not applied to, such as with `with_fail_open` or for
+    /// clients on an access list.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    /// use actix_web::http::{HeaderName, HeaderValue};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_header_hook(|headers, decision| {
+    ///             let policy = format!("{};w={}", decision.max_requests, decision.interval.as_secs());
+    ///             headers.insert(
+    ///                 HeaderName::from_static("x-ratelimit-policy"),
+    ///                 HeaderValue::from_str(&policy).unwrap(),
+    ///             );
+    ///         });
+    /// }
+    /// ```
+    pub fn with_header_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&mut HeaderMap, &Decision) + Send + Sync + 'static,
+    {
+        self.header_hook = Some(Arc::new(Box::new(hook)));
+        self
+    }
+
+    /// Function called for every request counted against the limits, once per policy, telling
+    /// whether the client was first seen, i.e. had no entry in the store, or drew from its
+    /// existing budget. Useful for monitoring, e.g. to spot a sudden influx of new identifiers.
+    /// The same distinction is logged, first seen clients at the `info` level and others at
+    /// `debug`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    /// use std::sync::Arc;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let new_clients = Arc::new(AtomicUsize::new(0));
+    ///     let counter = new_clients.clone();
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100)
+    ///         .with_count_hook(move |event| {
+    ///             if event.new_client {
+    ///                 counter.fetch_add(1, Ordering::Relaxed);
+    ///             }
+    ///         });
+    /// }
+    /// ```
+    pub fn with_count_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&CountEvent) + Send + Sync + 'static,
+    {
+        self.count_hook = Some(Arc::new(Box::new(hook)));
+        self
+    }
+
+    /// Function computing how much of the client's budget a request consumes, `1` by default.
+    /// A request is rejected when its cost exceeds what is left, and the limit headers are
+    /// expressed in the same unit as the cost. A request the function panics for costs `1`.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    /// use actix_web::http::header::CONTENT_LENGTH;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     // Allow 10 MB of uploads per minute
+    ///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(10 * 1024 * 1024)
+    ///         .with_cost(|req| {
+    ///             req.headers()
+    ///                 .get(CONTENT_LENGTH)
+    ///                 .and_then(|len| len.to_str().ok())
+    ///                 .and_then(|len| len.parse().ok())
+    ///                 .unwrap_or(0)
+    ///         });
+    /// }
+    /// ```
+    pub fn with_cost<F: Fn(&ServiceRequest) -> usize + Send + Sync + 'static>(
+        mut self,
+        cost: F,
+    ) -> Self {
+        self.cost = Arc::new(Box::new(cost));
+        self
+    }
+
+    /// The configured interval
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// The configured maximum number of requests per interval
+    pub fn max_requests(&self) -> usize {
+        self.max_requests
+    }
+
+    /// Query the health and key count of the store behind the provided address.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let addr = MemoryStoreActor::from(store.clone()).start();
+    ///     let stats = RateLimiter::stats(&addr).await.unwrap();
+    ///     assert_eq!(stats.key_count, Some(0));
+    /// }
+    /// ```
+    pub async fn stats(store: &Addr<T>) -> Result<StoreStats, ARError> {
+        let res = store
+            .send(ActorMessage::Stats)
+            .await
+            .map_err(|_| ARError::Disconnected)?;
+        res.expect_stats().await
+    }
+
+    /// Moving average of the requests per second counted against `key` over roughly the last
+    /// ten seconds, without counting a request. `None` if the key has not been seen or the
+    /// store does not track request rates, which only the memory store does.
+    pub async fn rate(store: &Addr<T>, key: &str) -> Result<Option<f64>, ARError> {
+        let res = store
+            .send(ActorMessage::Rate(key.to_string()))
+            .await
+            .map_err(|_| ARError::Disconnected)?;
+        res.expect_rate().await
+    }
+
+    /// Gives the client identified by `key` back `n` requests of its current window, the
+    /// opposite of a request being counted. The remaining count is never raised above `max`,
+    /// usually the limit the client is held to, and a count already above it is kept. Returns
+    /// the new remaining count, or `None` if the client has no window running, in which case
+    /// its next request starts a full one anyway.
+    ///
+    /// Only meant for algorithms which count down the remaining requests, like the default
+    /// fixed window.
+    pub async fn grant(
+        store: &Addr<T>,
+        key: &str,
+        n: usize,
+        max: usize,
+    ) -> Result<Option<usize>, ARError> {
+        let res = store
+            .send(ActorMessage::Grant {
+                key: key.to_string(),
+                value: n,
+                max,
+            })
+            .await
+            .map_err(|_| ARError::Disconnected)?;
+        res.expect_grant().await
+    }
+
+    /// Shuts the store down once the operations queued before this call have completed.
+    /// Afterwards the store turns down every message with `ARError::ShutDown`. Meant to be
+    /// called once the server has stopped, e.g. after `HttpServer::run` returns.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let addr = MemoryStoreActor::from(store.clone()).start();
+    ///     RateLimiter::shutdown(&addr).await.unwrap();
+    ///     assert!(RateLimiter::stats(&addr).await.is_err());
+    /// }
+    /// ```
+    pub async fn shutdown(store: &Addr<T>) -> Result<(), ARError> {
+        let res = store
+            .send(ActorMessage::Shutdown)
+            .await
+            .map_err(|_| ARError::Disconnected)?;
+        res.expect_shutdown().await
+    }
+
+    /// Removes the state of every client whose key starts with `prefix`, as set with
+    /// [with_prefix](#method.with_prefix), e.g. every key of a tenant, and returns how many keys
+    /// were removed. An empty prefix, which would remove the state of every limiter sharing the
+    /// store, is refused with `ARError::OperationError`.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let addr = MemoryStoreActor::from(store.clone()).start();
+    ///     let removed = RateLimiter::clear_all(&addr, "api:").await.unwrap();
+    ///     assert_eq!(removed, 0);
+    /// }
+    /// ```
+    pub async fn clear_all(store: &Addr<T>, prefix: &str) -> Result<usize, ARError> {
+        if prefix.is_empty() {
+            return Err(ARError::operation(
+                "clear",
+                prefix,
+                "refusing to clear without a prefix",
+            ));
+        }
+        let res = store
+            .send(ActorMessage::Clear(prefix.to_string()))
+            .await
+            .map_err(|_| ARError::Disconnected)?;
+        res.expect_clear().await
+    }
+}
+
+impl<B: KvBackend + Sync> RateLimiter<KvStore<B>> {
+    /// Creates a new instance of `RateLimiter` which uses `store` in place rather than through
+    /// an actor. The middleware itself still runs on actix-web, but the store can be shared with
+    /// code running without an actix `System`, e.g. on a plain tokio runtime.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, RateLimiter, Store};
+    ///
+    /// let store = Store::new(MemoryStore::new());
+    /// let ratelimiter = RateLimiter::direct(store.clone())
+    ///     .with_interval(Duration::from_secs(60))
+    ///     .with_max_requests(100);
+    /// ```
+    pub fn direct(store: Store<B>) -> Self {
+        Self::with_stores(vec![StoreHandle::Direct(Arc::new(move |msg| {
+            store.handle(msg)
+        }))])
+    }
+}
+
+impl<T, S, B> Transform<S> for RateLimiter<T>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = S::Error;
+    type InitError = ();
+    type Transform = RateLimitMiddleware<S, T>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        let policies = if !self.rules.is_empty() {
+            self.rules
+                .iter()
+                .map(|rule| (rule.max_requests, rule.interval))
+                .collect()
+        } else if self.policies.is_empty() {
+            vec![(self.max_requests, self.interval)]
+        } else {
+            self.policies.clone()
+        };
+        ok(RateLimitMiddleware {
+            service: Rc::new(RefCell::new(service)),
+            stores: Rc::new(match self.store_retries {
+                Some((retries, backoff)) => self
+                    .stores
+                    .iter()
+                    .map(|store| StoreHandle::Retrying {
+                        store: Arc::new(store.clone()),
+                        retries,
+                        backoff,
+                    })
+                    .collect(),
+                None => self.stores.clone(),
+            }),
+            policies: Rc::new(policies),
+            suffix_keys: !self.policies.is_empty() || !self.rules.is_empty(),
+            rules: Rc::new(self.rules.clone()),
+            algorithm: self.algorithm,
+            counting_mode: self.counting_mode,
+            registration_mode: self.registration_mode,
+            window_anchor: self.window_anchor,
+            warmup: self.warmup,
+            prefix: Rc::from(self.prefix.as_str()),
+            fallback_key: self.fallback_key.as_deref().map(Rc::from),
+            skipped_methods: Rc::new(self.skipped_methods.clone()),
+            method_limits: Rc::new(self.method_limits.clone()),
+            upgrade_limit: self.upgrade_limit,
+            path_filter: self.path_filter.clone().map(Rc::new),
+            excluded_paths: Rc::new(self.excluded_paths.clone()),
+            bypass: self.bypass.clone(),
+            header_names: Rc::new(self.header_names.clone()),
+            success_headers: self.success_headers,
+            reject_headers: self.reject_headers,
+            json_errors: self.json_errors,
+            problem_json: self.problem_json,
+            rejection_body: self.rejection_body.clone(),
+            fail_open: self.fail_open,
+            fail_open_headers: self.fail_open_headers,
+            dry_run: self.dry_run,
+            options_report: self.options_report,
+            store_timeout: self.store_timeout,
+            readiness: self.readiness.clone(),
+            max_concurrent: self.max_concurrent,
+            access_lists: self.access_lists,
+            upstream_refund: self.upstream_refund,
+            refund_on_error: self.refund_on_error,
+            tiers: self.tiers.clone().map(|pool| (pool, self.interval)),
+            circuit_breaker: self
+                .circuit_breaker
+                .map(|(failures, cooldown)| Rc::new(CircuitBreaker::new(failures, cooldown))),
+            identifier: self.identifier.clone(),
+            body_limit: self.body_limit,
+            limit: self.limit.clone(),
+            interval_fn: self.interval_fn.clone(),
+            latency: self.latency.clone(),
+            key_hasher: self.key_hasher.clone(),
+            max_key_length: self.max_key_length,
+            header_hook: self.header_hook.clone(),
+            count_hook: self.count_hook.clone(),
+            cost: self.cost.clone(),
+        })
+    }
+}
+
+/// Service factory for RateLimiter
+pub struct RateLimitMiddleware<S, T>
+where
+    S: 'static,
+    T: Handler<ActorMessage> + 'static,
+{
+    service: Rc<RefCell<S>>,
+    stores: Rc<Vec<StoreHandle<T>>>,
+    // Exists here for the sole purpose of knowing the max_requests and interval from RateLimiter
+    policies: Rc<Vec<(usize, Duration)>>,
+    suffix_keys: bool,
+    rules: Rc<Vec<Rule>>,
+    algorithm: Algorithm,
+    counting_mode: CountingMode,
+    registration_mode: RegistrationMode,
+    window_anchor: WindowAnchor,
+    warmup: Option<(usize, usize)>,
+    prefix: Rc<str>,
+    fallback_key: Option<Rc<str>>,
+    skipped_methods: Rc<Vec<Method>>,
+    method_limits: Rc<HashMap<Method, (usize, Duration)>>,
+    upgrade_limit: Option<(usize, Duration)>,
+    path_filter: Option<Rc<PathFilter>>,
+    excluded_paths: Rc<Vec<String>>,
+    bypass: Option<Bypass>,
+    header_names: Rc<HeaderNames>,
+    success_headers: bool,
+    reject_headers: bool,
+    json_errors: bool,
+    problem_json: bool,
+    rejection_body: Option<(Bytes, HeaderValue)>,
+    fail_open: bool,
+    fail_open_headers: bool,
+    dry_run: bool,
+    options_report: bool,
+    store_timeout: Option<Duration>,
+    readiness: Option<Readiness>,
+    circuit_breaker: Option<Rc<CircuitBreaker>>,
+    max_concurrent: Option<usize>,
+    access_lists: bool,
+    upstream_refund: bool,
+    refund_on_error: bool,
+    tiers: Option<(Arc<TierPool>, Duration)>,
+    identifier: Identifier,
+    /// Size up to which bodies are read ahead, see `RateLimiter::with_body_identifier`
+    body_limit: Option<usize>,
+    limit: Option<Limit>,
+    interval_fn: Option<IntervalFn>,
+    latency: Option<Arc<Latency>>,
+    key_hasher: Option<KeyHasher>,
+    max_key_length: Option<usize>,
+    header_hook: Option<HeaderHook>,
+    count_hook: Option<CountHook>,
+    cost: Cost,
+}
+
+/// Response extension telling whether the limiter rejected the request, so that middleware
+/// wrapping it, e.g. for logging, can tell throttled responses apart without looking at the
+/// status. Responses to requests the limiter let through carry `Throttled(false)`, and those
+/// it never looked at, such as skipped methods, carry none. With `RateLimiter::with_dry_run`,
+/// requests over the limit carry `Throttled(true)` although they were served.
+///
+/// # Example
+/// ```rust
+/// use actix_ratelimit::middleware::Throttled;
+/// use actix_web::dev::ServiceResponse;
+///
+/// fn throttled<B>(res: &ServiceResponse<B>) -> bool {
+///     res.response().extensions().get::<Throttled>() == Some(&Throttled(true))
+/// }
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throttled(pub bool);
+
+/// Request extension holding the key to count the request under, for middleware running
+/// before the limiter which can compute keys with more context than the identifier function
+/// has. When present, it is used in place of the identifier and of the function set with
+/// `RateLimiter::with_key_hasher`; the prefix is still prepended.
+///
+/// # Example
+/// ```rust
+/// use actix_ratelimit::middleware::RateLimitKey;
+/// use actix_web::dev::ServiceRequest;
+/// use actix_web::HttpMessage;
+///
+/// fn tag_tenant(req: &ServiceRequest, tenant: &str) {
+///     req.extensions_mut().insert(RateLimitKey(format!("tenant:{}", tenant)));
+/// }
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitKey(pub String);
+
+/// Calls `f`, one of the functions set on the limiter, for `req`. A panic is turned into
+/// `fallback`, so that it doesn't take the worker down with it.
+fn guarded<R>(name: &str, req: &ServiceRequest, f: impl FnOnce() -> R, fallback: R) -> R {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|_| {
+        error!("{} panicked for {}", name, req.path());
+        fallback
+    })
+}
+
+/// Request extension holding the body read ahead for `RateLimiter::with_body_identifier`, or
+/// `None` if it was longer than the limit
+struct BufferedBody(Option<Bytes>);
+
+/// Reads the body of `req`, up to `limit` bytes, and puts it back for the service to read
+async fn buffer_body(req: &mut ServiceRequest, limit: usize) -> Result<Option<Bytes>, AWError> {
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        body.extend_from_slice(&chunk?);
+        if body.len() > limit {
+            // Hand on what was read so far, followed by the rest
+            let read = stream::once(future::ready(Ok(body.freeze())));
+            req.set_payload(Payload::Stream(Box::pin(read.chain(payload))));
+            return Ok(None);
+        }
+    }
+    let body = body.freeze();
+    let read = stream::once(future::ready(Ok(body.clone())));
+    req.set_payload(Payload::Stream(Box::pin(read)));
+    Ok(Some(body))
+}
+
+/// TLS client certificate of the connection a request came on, see
+/// `RateLimiter::with_client_cert_identifier`. It is meant to be set in `HttpServer::on_connect`,
+/// e.g. from the peer certificate of an openssl or rustls stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// Distinguished name of the subject, as in RFC 4514, e.g. `CN=alice,O=Acme`
+    pub subject: String,
+    /// Serial number, in whatever notation the server picked
+    pub serial: String,
+}
+
+impl ClientCertificate {
+    /// Value of the attribute `name` of the subject, such as `CN`, with escapes resolved.
+    /// Attribute names are compared case-insensitively, and the first match is returned.
+    pub fn subject_attribute(&self, name: &str) -> Option<String> {
+        let mut attributes = vec![String::new()];
+        let mut chars = self.subject.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                ',' | '+' | ';' => attributes.push(String::new()),
+                c => {
+                    let current = attributes.last_mut().unwrap();
+                    current.push(c);
+                    if c == '\\' {
+                        current.extend(chars.next());
+                    }
+                }
+            }
+        }
+        attributes.iter().find_map(|attribute| {
+            let mut parts = attribute.splitn(2, '=');
+            let (key, value) = (parts.next()?, parts.next()?);
+            if key.trim().eq_ignore_ascii_case(name) {
+                Some(unescape_dn(value.trim_start()))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Field of a client certificate to identify clients by
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertField {
+    /// The serial number of the certificate
+    Serial,
+    /// An attribute of the subject, such as `CN`
+    Attribute(String),
+}
+
+/// Resolves the `\,` and `\2C` escapes of a distinguished name value
+fn unescape_dn(value: &str) -> String {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        rest = tail;
+        if byte != b'\\' {
+            bytes.push(byte);
+            continue;
+        }
+        let hex = rest
+            .get(..2)
+            .and_then(|pair| std::str::from_utf8(pair).ok())
+            .and_then(|pair| u8::from_str_radix(pair, 16).ok());
+        match hex {
+            Some(byte) => {
+                bytes.push(byte);
+                rest = &rest[2..];
+            }
+            None => {
+                if let Some((&byte, tail)) = rest.split_first() {
+                    bytes.push(byte);
+                    rest = tail;
+                }
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Outcome of the limit the headers of a response report on, handed to the function set with
+/// `RateLimiter::with_header_hook`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    /// Whether the request was rejected
+    pub throttled: bool,
+    pub max_requests: usize,
+    pub remaining: usize,
+    /// Time until the limit resets
+    pub reset: Duration,
+    pub interval: Duration,
+}
+
+/// Request counted against a limit, handed to the function set with
+/// `RateLimiter::with_count_hook`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CountEvent {
+    /// Key of the client in the store, including the prefix
+    pub key: String,
+    /// Whether the client had no entry in the store before this request
+    pub new_client: bool,
+    pub max_requests: usize,
+    /// Budget left after this request
+    pub remaining: usize,
+    /// Whether this request used up the last of the budget, i.e. the one which crossed the
+    /// limit. It is `true` once per window, unlike the rejections which follow.
+    pub exhausted: bool,
+}
+
+/// Algorithm used to count the requests of a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Allows `max_requests` per `interval`, the count starting over once the interval elapsed
+    FixedWindow,
+    /// Every request adds to a bucket holding up to `capacity` requests, which drains at a steady
+    /// `max_requests` per `interval`. Requests are rejected while the bucket is full, so bursts
+    /// are capped at `capacity` and sustained traffic at the drain rate.
+    ///
+    /// The level of the bucket is kept as the time it takes to drain, which is the expiry of
+    /// the client's key, so every store applies the same formula. The memcached store only
+    /// tracks expiries to the second.
+    LeakyBucket { capacity: usize },
+    /// Approximates a window sliding along with the requests: the requests of the previous
+    /// window count in proportion to how much of it the sliding window still covers. Unlike a
+    /// fixed window, a client can't fit twice the limit around the end of a window, while only
+    /// two counts are kept per client.
+    ///
+    /// The current window is kept under the client's key, which expires an interval after the
+    /// window ends so that it can still be weighed in, and the count of the previous window
+    /// under a second key, suffixed by `:previous`, which expires along with the current window.
+    /// The memory store and the redis store with plain counts check and count a request in one
+    /// step, see `ActorMessage::Slide`. Other stores are sent separate messages, so concurrent
+    /// requests of a client may overrun its limit there.
+    SlidingWindowCounter,
+}
+
+impl Default for Algorithm {
+    fn default() -> Self {
+        Algorithm::FixedWindow
+    }
+}
+
+/// What the remaining count reported for an allowed request is relative to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CountingMode {
+    /// The count after the request was counted, so the first request of a client reports one
+    /// less than the limit
+    Preemptive,
+    /// The count before the request was counted, so the first request of a client reports the
+    /// full limit. Rejected requests report the same in both modes, as they are not counted.
+    PostHoc,
+}
+
+impl Default for CountingMode {
+    fn default() -> Self {
+        CountingMode::Preemptive
+    }
+}
+
+/// When the windows of `Algorithm::FixedWindow` start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowAnchor {
+    /// A client's window starts with its first request and lasts the interval
+    FirstRequest,
+    /// Windows start at multiples of the interval since the unix epoch, e.g. at the top of every
+    /// minute for an interval of a minute or at midnight UTC for a day, and a client's first
+    /// window only lasts until the next of them
+    ClockAligned,
+}
+
+impl Default for WindowAnchor {
+    fn default() -> Self {
+        WindowAnchor::FirstRequest
+    }
+}
+
+/// What happens to clients the store has no entry for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegistrationMode {
+    /// An entry with the full limit is created on their first request
+    AutoCreate,
+    /// They are rejected with `ARError::Denied`, which renders as 403 Forbidden, and nothing is
+    /// written to the store. Entries have to be seeded by another process, under the key the
+    /// limiter would use, with the budget of the client and the time until it resets as expiry,
+    /// and seeded again once they expire. With `with_policies`, every policy's entry has to be
+    /// present. Since leaky buckets and sliding windows drop the entries of idle clients, this
+    /// mode is mostly useful with `Algorithm::FixedWindow`.
+    DenyUnknown,
+}
+
+impl Default for RegistrationMode {
+    fn default() -> Self {
+        RegistrationMode::AutoCreate
+    }
+}
+
+/// Health check and metrics endpoints never limited with `RateLimiter::with_default_exclusions`
+pub const DEFAULT_EXCLUSIONS: &[&str] = &["/health", "/healthz", "/livez", "/readyz", "/metrics"];
+
+/// Paths a limiter applies to, see `RateLimiter::with_path_filter`.
+///
+/// A pattern without `*` matches its path and everything below it, so `/api` matches `/api` and
+/// `/api/users` but not `/apis`. Otherwise it is a glob matched against the whole path, where `*`
+/// stands for any part of a segment and `**` for any number of segments, as in `/assets/*.css`
+/// or `/users/*/avatar` and `/static/**`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathFilter {
+    /// Only requests whose path matches one of the patterns are limited
+    Include(Vec<String>),
+    /// Requests whose path matches one of the patterns are not limited
+    Exclude(Vec<String>),
+}
+
+impl PathFilter {
+    /// Limit only the requests whose path matches one of `patterns`
+    pub fn include<P: Into<String>>(patterns: Vec<P>) -> Self {
+        PathFilter::Include(patterns.into_iter().map(Into::into).collect())
+    }
+
+    /// Limit every request except those whose path matches one of `patterns`
+    pub fn exclude<P: Into<String>>(patterns: Vec<P>) -> Self {
+        PathFilter::Exclude(patterns.into_iter().map(Into::into).collect())
+    }
+
+    /// Whether requests for `path` are limited
+    pub fn limits(&self, path: &str) -> bool {
+        let matches = |patterns: &[String]| patterns.iter().any(|p| path_matches(p, path));
+        match self {
+            PathFilter::Include(patterns) => matches(patterns),
+            PathFilter::Exclude(patterns) => !matches(patterns),
+        }
+    }
+}
+
+/// Limit counting requests under a key of its own, see `RateLimiter::with_rules`
+#[derive(Clone)]
+pub struct Rule {
+    identifier: RuleIdentifier,
+    max_requests: usize,
+    interval: Duration,
+}
+
+impl Rule {
+    /// Allows `max_requests` per `interval` for every key `identifier` returns, the rule not
+    /// applying to requests it returns `None` or panics for
+    pub fn new<F>(identifier: F, max_requests: usize, interval: Duration) -> Self
+    where
+        F: Fn(&ServiceRequest) -> Option<String> + Send + Sync + 'static,
+    {
+        Rule {
+            identifier: Arc::new(Box::new(identifier)),
+            max_requests,
+            interval,
+        }
+    }
+}
+
+fn path_matches(pattern: &str, path: &str) -> bool {
+    if pattern.contains('*') {
+        return glob_matches(pattern.as_bytes(), path.as_bytes());
+    }
+    match path.strip_prefix(pattern) {
+        Some(rest) => rest.is_empty() || pattern.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+fn glob_matches(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((b'*', rest)) => match rest.split_first() {
+            Some((b'*', rest)) => (0..=path.len()).any(|i| glob_matches(rest, &path[i..])),
+            // A single star stops at the end of the segment
+            _ => (0..=path.len())
+                .take_while(|&i| i == 0 || path[i - 1] != b'/')
+                .any(|i| glob_matches(rest, &path[i..])),
+        },
+        Some((c, rest)) => path.first() == Some(c) && glob_matches(rest, &path[1..]),
+    }
+}
+
+/// What `RateLimiter::with_forwarded_identifier` does with requests whose `X-Forwarded-For`
+/// chain does not hold one address per trusted proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HopMismatch {
+    /// Take the address added by the outermost trusted proxy anyway, as long as there is one.
+    /// Addresses the client made up itself precede it and are skipped.
+    Ignore,
+    /// Fail the request with `ARError::ForwardedMismatch`, which renders as 400 Bad Request
+    Reject,
+    /// Identify the client by the address of the socket instead, which is the innermost proxy
+    /// for requests that went through them
+    UsePeer,
+}
+
+/// Builder of a [RateLimiter](struct.RateLimiter.html) which has to be given the limit and the
+/// interval before it builds one, so that forgetting either is caught by the compiler rather
+/// than by the first client. The other settings are made on the built limiter as usual.
+///
+/// # Example
+/// ```rust
+/// # use std::time::Duration;
+/// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::builder(MemoryStoreActor::from(store.clone()).start())
+///         .max_requests(100)
+///         .interval(Duration::from_secs(60))
+///         .build()
+///         .with_prefix("api:");
+/// }
+/// ```
+///
+/// Without the interval there is nothing to build yet:
+/// ```rust,compile_fail,E0599
+/// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = MemoryStore::new();
+///     let ratelimiter = RateLimiter::builder(MemoryStoreActor::from(store.clone()).start())
+///         .max_requests(100)
+///         .build();
+/// }
+/// ```
+pub struct RateLimiterBuilder<T, M = (), I = ()>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    <T as Actor>::Context: ToEnvelope<T, ActorMessage>,
+{
+    limiter: RateLimiter<T>,
+    max_requests: M,
+    interval: I,
+}
+
+impl<T, I> RateLimiterBuilder<T, (), I>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    <T as Actor>::Context: ToEnvelope<T, ActorMessage>,
+{
+    /// Specify the maximum number of requests allowed in the interval
+    pub fn max_requests(self, max_requests: usize) -> RateLimiterBuilder<T, usize, I> {
+        RateLimiterBuilder {
+            limiter: self.limiter,
+            max_requests,
+            interval: self.interval,
+        }
+    }
+}
+
+impl<T, M> RateLimiterBuilder<T, M, ()>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    <T as Actor>::Context: ToEnvelope<T, ActorMessage>,
+{
+    /// Specify the interval. The counter for a client is reset after this interval
+    pub fn interval(self, interval: Duration) -> RateLimiterBuilder<T, M, Duration> {
+        RateLimiterBuilder {
+            limiter: self.limiter,
+            max_requests: self.max_requests,
+            interval,
+        }
+    }
+}
+
+impl<T> RateLimiterBuilder<T, usize, Duration>
+where
+    T: Handler<ActorMessage> + Send + Sync + 'static,
+    <T as Actor>::Context: ToEnvelope<T, ActorMessage>,
+{
+    /// Builds the limiter
+    pub fn build(self) -> RateLimiter<T> {
+        self.limiter
+            .with_max_requests(self.max_requests)
+            .with_interval(self.interval)
+    }
+}
+
+/// Limit and interval which can be changed while the server runs, see
+/// `RateLimiter::with_shared_limits`. Clones share the same values, also across the workers.
+#[derive(Debug, Clone)]
+pub struct SharedLimits {
+    inner: Arc<RwLock<(usize, Duration)>>,
+}
+
+impl SharedLimits {
+    /// Allows `max_requests` for every `interval`
+    pub fn new(max_requests: usize, interval: Duration) -> Self {
+        SharedLimits {
+            inner: Arc::new(RwLock::new((max_requests, interval))),
+        }
+    }
+
+    /// The limit and the interval currently in effect
+    pub fn get(&self) -> (usize, Duration) {
+        *self.inner.read().unwrap()
+    }
+
+    /// Allows `max_requests` for every `interval` from now on
+    pub fn set(&self, max_requests: usize, interval: Duration) {
+        *self.inner.write().unwrap() = (max_requests, interval);
+    }
+}
+
+/// How the limit, remaining count and reset are laid out in the response headers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderStyle {
+    /// One header each, named as set with `with_limit_header`, `with_remaining_header` and
+    /// `with_reset_header`
+    Separate,
+    /// A single structured field dictionary (RFC 8941) such as
+    /// `RateLimit: limit=100, remaining=99, reset=52`, with the reset in seconds. The names set
+    /// for the separate headers are not used.
+    Structured,
+}
+
+impl Default for HeaderStyle {
+    fn default() -> Self {
+        HeaderStyle::Separate
+    }
+}
+
+/// How the `Retry-After` header of rejected requests is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAfterFormat {
+    /// Number of seconds to wait, rounded up, e.g. `Retry-After: 52`
+    Seconds,
+    /// Date after which to retry (RFC 7231), e.g. `Retry-After: Sun, 06 Nov 1994 08:49:37 GMT`
+    HttpDate,
+}
+
+impl Default for RetryAfterFormat {
+    fn default() -> Self {
+        RetryAfterFormat::Seconds
+    }
+}
+
+/// Settings of a [RateLimiter](struct.RateLimiter.html) which take no functions, for building
+/// one with `RateLimiter::from_config`, e.g. from a configuration file. Each field is described
+/// by the `with_*` method of the same name, and defaults to the value a new `RateLimiter` has.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RateLimiterConfig {
+    pub interval: Duration,
+    pub max_requests: usize,
+    pub algorithm: Algorithm,
+    pub counting_mode: CountingMode,
+    pub registration_mode: RegistrationMode,
+    pub window_anchor: WindowAnchor,
+    /// Extra requests and the number of windows to spread them over, see `with_warmup`
+    pub warmup: Option<(usize, usize)>,
+    pub policies: Vec<(usize, Duration)>,
+    pub skipped_methods: Vec<Method>,
+    pub method_limits: HashMap<Method, (usize, Duration)>,
+    /// Limit of connection upgrades as `(max_requests, interval)`, see `with_upgrade_limit`
+    pub upgrade_limit: Option<(usize, Duration)>,
+    pub path_filter: Option<PathFilter>,
+    pub excluded_paths: Vec<String>,
+    pub limit_header: String,
+    pub remaining_header: String,
+    pub reset_header: String,
+    pub header_style: HeaderStyle,
+    pub retry_after_format: RetryAfterFormat,
+    pub success_headers: bool,
+    pub reject_headers: bool,
+    pub prefix: String,
+    /// Key of the requests which can't be identified, see `with_fallback_key`
+    pub fallback_key: Option<String>,
+    pub json_errors: bool,
+    pub problem_json: bool,
+    pub fail_open: bool,
+    pub fail_open_headers: bool,
+    pub dry_run: bool,
+    pub options_report: bool,
+    pub access_lists: bool,
+    pub upstream_refund: bool,
+    pub refund_on_error: bool,
+    pub store_timeout: Option<Duration>,
+    /// Retries and initial backoff, see `with_store_retries`
+    pub store_retries: Option<(usize, Duration)>,
+    pub max_concurrent: Option<usize>,
+    /// Longest identifier used as it is, see `with_max_key_length`
+    pub max_key_length: Option<usize>,
+    /// Failures and cooldown, see `with_circuit_breaker`
+    pub circuit_breaker: Option<(usize, Duration)>,
+}
+
+impl Default for RateLimiterConfig {
+    fn default() -> Self {
+        let headers = HeaderNames::default();
+        RateLimiterConfig {
+            interval: Duration::from_secs(0),
+            max_requests: 0,
+            algorithm: Algorithm::default(),
+            counting_mode: CountingMode::default(),
+            registration_mode: RegistrationMode::default(),
+            window_anchor: WindowAnchor::default(),
+            warmup: None,
+            policies: Vec::new(),
+            skipped_methods: Vec::new(),
+            method_limits: HashMap::new(),
+            upgrade_limit: None,
+            path_filter: None,
+            excluded_paths: Vec::new(),
+            limit_header: headers.limit.to_string(),
+            remaining_header: headers.remaining.to_string(),
+            reset_header: headers.reset.to_string(),
+            header_style: headers.style,
+            retry_after_format: headers.retry_after,
+            success_headers: true,
+            reject_headers: true,
+            prefix: String::new(),
+            fallback_key: None,
+            json_errors: false,
+            problem_json: false,
+            fail_open: false,
+            fail_open_headers: false,
+            dry_run: false,
+            options_report: false,
+            access_lists: false,
+            upstream_refund: false,
+            refund_on_error: false,
+            store_timeout: None,
+            store_retries: None,
+            max_concurrent: None,
+            max_key_length: None,
+            circuit_breaker: None,
+        }
+    }
+}
+
+/// Function identifying the client, `None` meaning the request is not rate limited
+type Identifier =
+    Arc<Box<dyn Fn(&ServiceRequest) -> Result<Option<String>, ARError> + Send + Sync>>;
+
+/// Function keying a request under a rule, `None` meaning the rule does not apply to it
+type RuleIdentifier = Arc<Box<dyn Fn(&ServiceRequest) -> Option<String> + Send + Sync>>;
+
+/// Function returning the maximum number of requests for a request, overriding the configured one
+/// unless it returns `None` or panics
+type Limit = Arc<Box<dyn Fn(&ServiceRequest) -> Option<usize> + Send + Sync>>;
+
+/// Function returning the interval for a request, overriding the configured one
+type IntervalFn = Arc<Box<dyn Fn(&ServiceRequest) -> Option<Duration> + Send + Sync>>;
+
+/// Function turning the client identifier into the key stored
+type KeyHasher = Arc<Box<dyn Fn(&str) -> String + Send + Sync>>;
+
+/// Function returning the share of the budget consumed by a request
+type Cost = Arc<Box<dyn Fn(&ServiceRequest) -> usize + Send + Sync>>;
+
+/// Function telling whether a request is exempt from rate limiting
+type Bypass = Arc<Box<dyn Fn(&ServiceRequest) -> bool + Send + Sync>>;
+
+/// Function adjusting the headers of a response the limiter decided on
+type HeaderHook = Arc<Box<dyn Fn(&mut HeaderMap, &Decision) + Send + Sync>>;
+
+/// Function told about every counted request, see `RateLimiter::with_count_hook`
+type CountHook = Arc<Box<dyn Fn(&CountEvent) + Send + Sync>>;
+
+/// Names of the headers describing the state of the limit
+#[derive(Clone)]
+struct HeaderNames {
+    limit: HeaderName,
+    remaining: HeaderName,
+    reset: HeaderName,
+    /// Reset in milliseconds, only sent for intervals under a second
+    reset_ms: HeaderName,
+    style: HeaderStyle,
+    retry_after: RetryAfterFormat,
+}
+
+impl Default for HeaderNames {
+    fn default() -> Self {
+        HeaderNames {
+            limit: HeaderName::from_static("x-ratelimit-limit"),
+            remaining: HeaderName::from_static("x-ratelimit-remaining"),
+            reset: HeaderName::from_static("x-ratelimit-reset"),
+            reset_ms: HeaderName::from_static("x-ratelimit-reset-ms"),
+            style: HeaderStyle::Separate,
+            retry_after: RetryAfterFormat::Seconds,
+        }
+    }
+}
+
+fn header_name(name: &str) -> HeaderName {
+    HeaderName::from_bytes(name.as_bytes())
+        .unwrap_or_else(|_| panic!("invalid header name: {}", name))
+}
+
+/// Usage of a single policy by a client
+#[derive(Clone)]
+struct PolicyState {
+    key: String,
+    /// Index of the store holding the entry
+    shard: usize,
+    max_requests: usize,
+    interval: Duration,
+    /// `None` if the client has no entry in the store yet
+    remaining: Option<usize>,
+    /// Whether the client had no entry in the store when the request was looked up
+    new_client: bool,
+    reset: Duration,
+    /// Time for a single request to drain, for leaky buckets
+    drain: Option<Duration>,
+    /// Index of the window opened by this request, when warming up
+    window: Option<usize>,
+    /// Counts of both windows, for sliding window counters
+    sliding: Option<SlidingWindow>,
+}
+
+impl PolicyState {
+    /// Time until there is room for a request of `cost`
+    fn retry_after(&self, cost: usize) -> Duration {
+        match (self.drain, &self.sliding) {
+            (Some(drain), _) => (self.reset + drain_for(drain, cost))
+                .checked_sub(drain_for(drain, self.max_requests))
+                .unwrap_or_default(),
+            (None, Some(sliding)) => sliding.retry_after(self.max_requests, cost, self.interval),
+            (None, None) => self.reset,
+        }
+    }
+}
+
+/// Counts of a client under `Algorithm::SlidingWindowCounter`
+#[derive(Clone)]
+struct SlidingWindow {
+    /// Requests counted in the current window
+    current: usize,
+    /// Requests counted in the previous window
+    previous: usize,
+    /// Time left in the current window, which is also how much of the previous window the
+    /// sliding window covers
+    left: Duration,
+    /// Whether the current window has yet to be opened, the one of the entry being over
+    opening: bool,
+}
+
+impl SlidingWindow {
+    /// Requests counted over the sliding window, rounded up
+    fn estimate(&self, interval: Duration) -> usize {
+        let interval = interval.as_nanos().max(1);
+        let weighted = (self.previous as u128 * self.left.as_nanos() + interval - 1) / interval;
+        self.current + weighted as usize
+    }
+
+    /// Time until a request of `cost` fits in `max_requests`
+    fn retry_after(&self, max_requests: usize, cost: usize, interval: Duration) -> Duration {
+        let room = max_requests.saturating_sub(cost);
+        // Share of an interval the sliding window may cover of a window with `count` requests
+        let covered = |room: usize, count: usize| {
+            Duration::from_nanos((interval.as_nanos() * room as u128 / count as u128) as u64)
+        };
+        if self.current <= room {
+            if self.previous == 0 {
+                return Duration::from_secs(0);
+            }
+            // Wait for the previous window to weigh in little enough
+            let covered = covered(room - self.current, self.previous).min(self.left);
+            self.left - covered
+        } else {
+            // Only once the current window is over and slides out in turn
+            self.left + interval - covered(room, self.current)
+        }
+    }
+}
+
+/// Counts consecutive store failures of a worker, see `RateLimiter::with_circuit_breaker`
+struct CircuitBreaker {
+    threshold: usize,
+    cooldown: Duration,
+    failures: Cell<usize>,
+    open_until: Cell<Option<Instant>>,
+}
+
+impl CircuitBreaker {
+    fn new(threshold: usize, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            failures: Cell::new(0),
+            open_until: Cell::new(None),
+        }
+    }
+
+    /// Whether the store should be contacted for this request
+    fn attempt(&self) -> bool {
+        match self.open_until.get() {
+            Some(until) if Instant::now() < until => false,
+            Some(_) => {
+                // Half open, hold off other requests while this one probes the store
+                self.open_until.set(Some(Instant::now() + self.cooldown));
+                true
+            }
+            None => true,
+        }
+    }
+
+    fn record(&self, success: bool) {
+        if success {
+            self.failures.set(0);
+            self.open_until.set(None);
+        } else {
+            let failures = self.failures.get() + 1;
+            self.failures.set(failures);
+            if failures >= self.threshold {
+                warn!(
+                    "Store failed {} times in a row, opening the circuit",
+                    failures
+                );
+                self.open_until.set(Some(Instant::now() + self.cooldown));
+            }
+        }
+    }
+}
+
+/// Moving average of the time the service takes to respond, see `RateLimiter::with_adaptive`
+#[derive(Default)]
+struct Latency(Mutex<Option<Duration>>);
+
+impl Latency {
+    fn average(&self) -> Duration {
+        self.0.lock().unwrap().unwrap_or_default()
+    }
+
+    /// Every response weighs a fifth of the average, older ones fading out
+    fn record(&self, elapsed: Duration) {
+        let mut average = self.0.lock().unwrap();
+        *average = Some(match *average {
+            Some(average) => (average * 4 + elapsed) / 5,
+            None => elapsed,
+        });
+    }
+}
+
+/// How long the in-flight count of a client is kept after its last request started, in case
+/// slots are never given back, e.g. because the process was killed
+const CONCURRENCY_EXPIRY: Duration = Duration::from_secs(3600);
+
+/// Store the middleware sends its messages to, either a store actor or a
+/// [Store](../stores/kv/struct.Store.html) answering them in place, see `RateLimiter::direct`
+enum StoreHandle<T: Actor> {
+    Actor(Addr<T>),
+    Direct(Arc<dyn Fn(ActorMessage) -> ActorResponse + Send + Sync>),
+    /// Retries transient failures of `store`, see `RateLimiter::with_store_retries`
+    Retrying {
+        store: Arc<StoreHandle<T>>,
+        retries: usize,
+        backoff: Duration,
+    },
+}
+
+impl<T: Actor> Clone for StoreHandle<T> {
+    fn clone(&self) -> Self {
+        match self {
+            StoreHandle::Actor(addr) => StoreHandle::Actor(addr.clone()),
+            StoreHandle::Direct(store) => StoreHandle::Direct(store.clone()),
+            StoreHandle::Retrying {
+                store,
+                retries,
+                backoff,
+            } => StoreHandle::Retrying {
+                store: store.clone(),
+                retries: *retries,
+                backoff: *backoff,
+            },
+        }
+    }
+}
+
+impl<T> StoreHandle<T>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    /// Sends `msg` to the store, like `Addr::send`
+    fn send(&self, msg: ActorMessage) -> impl Future<Output = Result<ActorResponse, MailboxError>> {
+        match self {
+            StoreHandle::Retrying {
+                store,
+                retries,
+                backoff,
+            } => {
+                let (store, retries, backoff) = (store.clone(), *retries, *backoff);
+                // Counting a request twice is worse than failing it
+                let idempotent = !matches!(
+                    msg,
+                    ActorMessage::Update { .. }
+                        | ActorMessage::Increment { .. }
+                        | ActorMessage::Grant { .. }
+                        | ActorMessage::Slide { .. }
+                        | ActorMessage::Draw { .. }
+                );
+                let fut: Pin<Box<dyn Future<Output = _>>> = Box::pin(async move {
+                    let res = store.send_once(msg.clone()).await?;
+                    // Sends the message once more, answering it as a disconnected store would
+                    // if the mailbox is closed by now
+                    let resend = move || -> Output<ActorResponse> {
+                        let store = store.clone();
+                        let msg = msg.clone();
+                        Box::pin(async move {
+                            store
+                                .send_once(msg)
+                                .await
+                                .map_err(|_| ARError::Disconnected)
+                        })
+                    };
+                    Ok(match res {
+                        ActorResponse::Get(c) => ActorResponse::Get(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_get,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Set(c) => ActorResponse::Set(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_set,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Update(c) => ActorResponse::Update(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_update,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Increment(c) => ActorResponse::Increment(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_increment,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Expire(c) => ActorResponse::Expire(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_expire,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Remove(c) => ActorResponse::Remove(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_remove,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Stats(c) => ActorResponse::Stats(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_stats,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Shutdown(c) => ActorResponse::Shutdown(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_shutdown,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Clear(c) => ActorResponse::Clear(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_clear,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::CheckList(c) => ActorResponse::CheckList(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_check_list,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Rate(c) => ActorResponse::Rate(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_rate,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Grant(c) => ActorResponse::Grant(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_grant,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Slide(c) => ActorResponse::Slide(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_slide,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                        ActorResponse::Draw(c) => ActorResponse::Draw(retried(
+                            c,
+                            resend,
+                            ActorResponse::expect_draw,
+                            retries,
+                            backoff,
+                            idempotent,
+                        )),
+                    })
+                });
+                Either::Right(fut)
+            }
+            _ => Either::Left(self.send_once(msg)),
+        }
+    }
+
+    /// Sends `msg` to the store without retrying it
+    fn send_once(
+        &self,
+        msg: ActorMessage,
+    ) -> Either<Request<T, ActorMessage>, Ready<Result<ActorResponse, MailboxError>>> {
+        match self {
+            StoreHandle::Actor(addr) => Either::Left(addr.send(msg)),
+            StoreHandle::Direct(store) => Either::Right(future::ready(Ok(store(msg)))),
+            StoreHandle::Retrying { store, .. } => store.send_once(msg),
+        }
+    }
+}
+
+/// Awaits `output`, and as long as it fails with a transient error and there are retries left,
+/// waits for `backoff`, doubled after every retry, and awaits the answer `expect` takes from
+/// `resend` instead. Unless the message is `idempotent`, only errors of stores which report
+/// they did not carry it out are retried.
+fn retried<R, F>(
+    output: Output<R>,
+    resend: F,
+    expect: fn(ActorResponse) -> Output<R>,
+    retries: usize,
+    backoff: Duration,
+    idempotent: bool,
+) -> Output<R>
+where
+    R: Send + 'static,
+    F: Fn() -> Output<ActorResponse> + Send + 'static,
+{
+    Box::pin(async move {
+        let mut result = output.await;
+        let mut backoff = backoff;
+        for attempt in 1..=retries {
+            match &result {
+                Err(e) if e.is_transient() && (idempotent || e.is_unapplied()) => {
+                    warn!("Store failed, retry {} of {}: {}", attempt, retries, e);
+                    actix::clock::delay_for(backoff).await;
+                    backoff *= 2;
+                    result = match resend().await {
+                        Ok(res) => expect(res).await,
+                        Err(e) => Err(e),
+                    };
+                }
+                _ => break,
+            }
+        }
+        result
+    })
+}
+
+/// Gives the in-flight slot of a request back when dropped, see `RateLimiter::with_max_concurrent`
+struct ConcurrencyGuard<T>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    store: StoreHandle<T>,
+    key: String,
+}
+
+impl<T> Drop for ConcurrencyGuard<T>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    fn drop(&mut self) {
+        let store = self.store.clone();
+        let key = std::mem::take(&mut self.key);
+        // Stores may only act once their response is awaited. `Update` neither takes the count
+        // below 0 nor brings back a key which expired in the meantime.
+        actix::spawn(async move {
+            let msg = ActorMessage::Update {
+                key: String::from(&key),
+                value: 1,
+            };
+            let released = match store.send(msg).await {
+                Ok(res) => res.expect_update().await.map(|_| ()),
+                Err(_) => Err(ARError::Disconnected),
+            };
+            if let Err(e) = released {
+                warn!("Could not release the slot of {}: {}", &key, e);
+            }
+        });
+    }
+}
+
+/// Budget shared by every client, see `RateLimiter::with_tier_reservations`
+struct TierPool {
+    max_requests: usize,
+    tier: Box<dyn Fn(&ServiceRequest) -> String + Send + Sync>,
+    reserved: Vec<(String, usize)>,
+}
+
+/// What a request drew on the tier pool, see `TierPool::acquire`
+struct Drawn {
+    shard: usize,
+    key: String,
+    max_requests: usize,
+    tier: String,
+    reserved: usize,
+    max_reserved: usize,
+}
+
+/// Gives `cost` back to each of `states` after the request was counted against them, and to the
+/// tier pool if it was `drawn` on. Entries which expired in the meantime are left alone, the
+/// client has its full budget again anyway. Counts are given back with a single `Grant`, so
+/// requests counted concurrently are not lost.
+async fn refund<T>(
+    stores: &[StoreHandle<T>],
+    states: &[PolicyState],
+    drawn: Option<&Drawn>,
+    cost: usize,
+) -> Result<(), AWError>
+where
+    T: Handler<ActorMessage> + 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    if let Some(drawn) = drawn {
+        let store = &stores[drawn.shard];
+        let res = store
+            .send(ActorMessage::Grant {
+                key: String::from(&drawn.key),
+                value: cost,
+                max: drawn.max_requests,
+            })
+            .await?;
+        res.expect_grant().await?;
+        if drawn.reserved > 0 {
+            // Only the tier itself may draw on its reservation again
+            let res = store
+                .send(ActorMessage::Grant {
+                    key: format!("{}:{}", &drawn.key, &drawn.tier),
+                    value: drawn.reserved,
+                    max: drawn.max_reserved,
+                })
+                .await?;
+            res.expect_grant().await?;
+        }
+    }
+    for state in states {
+        let store = &stores[state.shard];
+        let drain = match state.drain {
+            Some(drain) => drain,
+            None => {
+                let res = store
+                    .send(ActorMessage::Grant {
+                        key: String::from(&state.key),
+                        value: cost,
+                        max: state.max_requests,
+                    })
+                    .await?;
+                res.expect_grant().await?;
+                continue;
+            }
+        };
+        // The level of a bucket is its expiry, which is poured into with a `Set` as well
+        let res = store
+            .send(ActorMessage::Get(String::from(&state.key)))
+            .await?;
+        let remaining = match res.expect_get().await? {
+            Some(remaining) => remaining,
+            None => continue,
+        };
+        let res = store
+            .send(ActorMessage::Expire(String::from(&state.key)))
+            .await?;
+        // Take the request back out of the bucket
+        let expiry = res
+            .expect_expire()
+            .await?
+            .checked_sub(drain_for(drain, cost))
+            .unwrap_or_default();
+        if expiry == Duration::from_secs(0) {
+            let res = store
+                .send(ActorMessage::Remove(String::from(&state.key)))
+                .await?;
+            res.expect_remove().await?;
+            continue;
+        }
+        let res = store
+            .send(ActorMessage::Set {
+                key: String::from(&state.key),
+                value: (remaining + cost).min(state.max_requests),
+                expiry,
+            })
+            .await?;
+        res.expect_set().await?;
+    }
+    Ok(())
+}
+
+/// Awaits `fut`, failing with `ARError::StoreTimeout` once `timeout` elapsed
+async fn within<F, R>(timeout: Option<Duration>, fut: F) -> Result<R, AWError>
+where
+    F: Future<Output = Result<R, AWError>>,
+{
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return fut.await,
+    };
+    match future::select(Box::pin(fut), actix::clock::delay_for(timeout)).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => Err(ARError::StoreTimeout.into()),
+    }
+}
+
+impl TierPool {
+    /// Counts a request of `tier` against the pool at `key`, whose reservations are kept under
+    /// `key` suffixed by the tier, and returns what it drew. Returns the state of the pool
+    /// instead if the request does not fit, in which case nothing is counted.
+    async fn acquire<T>(
+        &self,
+        stores: &[StoreHandle<T>],
+        key: String,
+        tier: &str,
+        interval: Duration,
+        cost: usize,
+    ) -> Result<Result<Drawn, PolicyState>, AWError>
+    where
+        T: Handler<ActorMessage> + 'static,
+        T::Context: ToEnvelope<T, ActorMessage>,
+    {
+        // The pool is shared by every client, so it lives in a single store
+        let shard = shard_index(&key, stores.len());
+        let store = &stores[shard];
+        let res = store
+            .send(ActorMessage::Draw {
+                key: String::from(&key),
+                tier: tier.to_string(),
+                cost,
+                max: self.max_requests,
+                interval,
+                reserved: self.reserved.clone(),
+            })
+            .await?;
+        let draw = match res.expect_draw().await {
+            Ok(draw) => draw,
+            Err(ARError::Unsupported(_)) => self.draw(store, &key, tier, interval, cost).await?,
+            Err(e) => return Err(e.into()),
+        };
+        if draw.counted {
+            let max_reserved = self
+                .reserved
+                .iter()
+                .find(|(name, _)| name == tier)
+                .map_or(0, |(_, reserved)| *reserved);
+            return Ok(Ok(Drawn {
+                shard,
+                key,
+                max_requests: self.max_requests,
+                tier: tier.to_string(),
+                reserved: draw.reserved,
+                max_reserved,
+            }));
+        }
+        Ok(Err(PolicyState {
+            key,
+            shard,
+            max_requests: self.max_requests,
+            interval,
+            remaining: Some(draw.available),
+            new_client: false,
+            reset: draw.left,
+            drain: None,
+            window: None,
+            sliding: None,
+        }))
+    }
+
+    /// Draws on the pool with separate messages, for stores which turn down `Draw`. Requests
+    /// counted concurrently may overrun the pool, and two of them may both open a window.
+    async fn draw<T>(
+        &self,
+        store: &StoreHandle<T>,
+        key: &str,
+        tier: &str,
+        interval: Duration,
+        cost: usize,
+    ) -> Result<PoolDraw, AWError>
+    where
+        T: Handler<ActorMessage> + 'static,
+        T::Context: ToEnvelope<T, ActorMessage>,
+    {
+        let tier_key = |tier: &str| format!("{}:{}", key, tier);
+        let res = store.send(ActorMessage::Get(key.to_string())).await?;
+        let (remaining, left) = match res.expect_get().await? {
+            Some(remaining) => {
+                let res = store.send(ActorMessage::Expire(key.to_string())).await?;
+                (remaining, res.expect_expire().await?)
+            }
+            None => {
+                // Open a new window, restoring every reservation
+                let res = store
+                    .send(ActorMessage::Set {
+                        key: key.to_string(),
+                        value: self.max_requests,
+                        expiry: interval,
+                    })
+                    .await?;
+                res.expect_set().await?;
+                for (tier, reserved) in &self.reserved {
+                    let res = store
+                        .send(ActorMessage::Set {
+                            key: tier_key(tier),
+                            value: *reserved,
+                            expiry: interval,
+                        })
+                        .await?;
+                    res.expect_set().await?;
+                }
+                (self.max_requests, interval)
+            }
+        };
+        // What is left of the reservations, which the pool has to keep room for
+        let mut own = 0;
+        let mut unused = 0;
+        for (name, _) in &self.reserved {
+            let res = store.send(ActorMessage::Get(tier_key(name))).await?;
+            let left = res.expect_get().await?.unwrap_or(0);
+            if name == tier {
+                own = left;
+            }
+            unused += left;
+        }
+        let available = own + remaining.saturating_sub(unused);
+        if available < cost {
+            return Ok(PoolDraw {
+                counted: false,
+                available,
+                reserved: 0,
+                left,
+            });
+        }
+        // Draw on the reservation first, then on the unreserved part of the pool
+        let reserved = own.min(cost);
+        if reserved > 0 {
+            let res = store
+                .send(ActorMessage::Update {
+                    key: tier_key(tier),
+                    value: reserved,
+                })
+                .await?;
+            res.expect_update().await?;
+        }
+        let res = store
+            .send(ActorMessage::Update {
+                key: key.to_string(),
+                value: cost,
+            })
+            .await?;
+        res.expect_update().await?;
+        Ok(PoolDraw {
+            counted: true,
+            available: available - cost,
+            reserved,
+            left,
+        })
+    }
+}
+
+/// Result of checking a request against the store
+enum Outcome {
+    /// The request was counted against every policy, and against the tier pool if there is one
+    Allowed(Vec<PolicyState>, Option<Drawn>),
+    /// The policy which the request would exceed, nothing was counted
+    Exhausted(PolicyState),
+    /// The store has no entry for the client, which is not allowed to create one
+    Unknown,
+    /// The policies were looked up for an `OPTIONS` request, nothing was counted
+    Reported(Vec<PolicyState>),
+}
+
+/// Requests granted on top of the limit in the window at `index`
+fn warmup_allowance(extra_requests: usize, windows: usize, index: usize) -> usize {
+    if index >= windows {
+        0
+    } else {
+        extra_requests * (windows - index) / windows
+    }
+}
+
+/// Number of requests still in a leaky bucket, rounded up
+fn bucket_level(level: Duration, drain: Duration) -> usize {
+    let drain = drain.as_nanos().max(1);
+    let level = (level.as_nanos() + drain - 1) / drain;
+    usize::try_from(level).unwrap_or(usize::MAX)
+}
+
+/// Time it takes a leaky bucket to drain a single request, when `max_requests` drain per
+/// `interval`
+fn drain_time(interval: Duration, max_requests: usize) -> Duration {
+    saturating_nanos(interval.as_nanos() / max_requests.max(1) as u128)
+}
+
+/// Time it takes a leaky bucket to drain `n` requests
+fn drain_for(drain: Duration, n: usize) -> Duration {
+    saturating_nanos(drain.as_nanos().saturating_mul(n as u128))
+}
+
+/// Duration of `nanos` nanoseconds, capped at `u64::MAX` of them rather than wrapping around
+fn saturating_nanos(nanos: u128) -> Duration {
+    Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+}
+
+/// Time from `now` until the next multiple of `interval` since the unix epoch, a whole interval
+/// when `now` is on one
+fn until_boundary(now: SystemTime, interval: Duration) -> Duration {
+    let since_epoch = now
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let into_window = since_epoch % interval.as_nanos().max(1);
+    interval - Duration::from_nanos(into_window as u64)
+}
+
+/// Token letting requests to `path` bypass rate limiting when signed with the same `secret` as
+/// given to `RateLimiter::with_signed_bypass`. It is made of the unix time of `timestamp`, a dot,
+/// and the hex encoded HMAC-SHA1 of the path and that time.
+pub fn bypass_token<S: AsRef<[u8]>>(secret: S, path: &str, timestamp: SystemTime) -> String {
+    let secs = timestamp
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!("{}.{}", secs, bypass_signature(secret.as_ref(), path, secs))
+}
+
+/// HMAC-SHA1 of `path` and `secs`, keyed with `secret`
+fn bypass_mac(secret: &[u8], path: &str, secs: u64) -> Hmac<Sha1> {
+    let mut mac = Hmac::<Sha1>::new_varkey(secret).expect("HMAC takes keys of any length");
+    mac.update(format!("{}\n{}", path, secs).as_bytes());
+    mac
+}
+
+/// Hex encoded HMAC-SHA1 of `path` and `secs`, keyed with `secret`
+fn bypass_signature(secret: &[u8], path: &str, secs: u64) -> String {
+    bypass_mac(secret, path, secs)
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Checks `token` was made by `bypass_token` for `path` within `max_age` of `now`. The signature
+/// is compared in constant time, so that it can't be guessed byte by byte.
+fn valid_bypass_token(
+    secret: &[u8],
+    path: &str,
+    token: &[u8],
+    max_age: Duration,
+    now: SystemTime,
+) -> bool {
+    let token = match std::str::from_utf8(token) {
+        Ok(token) => token,
+        Err(_) => return false,
+    };
+    let mut parts = token.splitn(2, '.');
+    let (secs, signature) = match (parts.next().map(|secs| secs.parse()), parts.next()) {
+        (Some(Ok(secs)), Some(signature)) => (secs, signature),
+        _ => return false,
+    };
+    // The time is up to the client, and may lie beyond what `SystemTime` can hold
+    let signed_at = match UNIX_EPOCH.checked_add(Duration::from_secs(secs)) {
+        Some(signed_at) => signed_at,
+        None => return false,
+    };
+    // Tokens from clocks running a little ahead are fine as well
+    let age = now
+        .duration_since(signed_at)
+        .unwrap_or_else(|e| e.duration());
+    if age > max_age {
+        return false;
+    }
+    let signature: Option<Vec<u8>> = (0..signature.len())
+        .step_by(2)
+        .map(|i| {
+            let pair = signature.get(i..i + 2)?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect();
+    match signature {
+        Some(signature) => bypass_mac(secret, path, secs).verify(&signature).is_ok(),
+        None => false,
+    }
+}
+
+/// Seconds for the `Retry-After` header, rounded up so that clients don't retry too early
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+/// `Retry-After` header telling a client rejected at `now` to wait `retry_after`
+fn retry_after_value(
+    format: RetryAfterFormat,
+    retry_after: Duration,
+    now: SystemTime,
+) -> Result<HeaderValue, InvalidHeaderValue> {
+    let secs = retry_after_secs(retry_after);
+    match format {
+        RetryAfterFormat::Seconds => HeaderValue::from_str(secs.to_string().as_str()),
+        RetryAfterFormat::HttpDate => {
+            let date = HttpDate::from(now + Duration::from_secs(secs));
+            HeaderValue::from_str(date.to_string().as_str())
+        }
+    }
+}
+
+/// Strips the port off `addr` and masks IPv6 addresses to their /64 network. Addresses which
+/// can't be parsed, e.g. from a malformed `Forwarded` header, are returned as they are.
+fn masked_ip(addr: &str) -> String {
+    let ip = match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip(),
+        Err(_) => match addr.parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return addr.to_string(),
+        },
+    };
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => {
+            let s = ip.segments();
+            format!("{}/64", Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+        }
+    }
+}
+
+/// Whether `req` asks to upgrade the connection, e.g. to a websocket: it names a protocol in
+/// `Upgrade` and lists `upgrade` among the options in `Connection`
+fn is_upgrade(req: &ServiceRequest) -> bool {
+    let headers = req.headers();
+    headers.contains_key(UPGRADE)
+        && headers
+            .get_all(CONNECTION)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|option| option.trim().eq_ignore_ascii_case("upgrade"))
+}
+
+/// Returns the index of the store responsible for the client
+fn shard_index(identifier: &str, shards: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    identifier.hash(&mut hasher);
+    (hasher.finish() % shards as u64) as usize
+}
+
+/// Cuts `key` short to at most `max_length` bytes, replacing what is cut off by its hash
+fn bounded_key(mut key: String, max_length: usize) -> String {
+    if key.len() <= max_length {
+        return key;
+    }
+    let mut cut = max_length.saturating_sub(16);
+    while !key.is_char_boundary(cut) {
+        cut -= 1;
+    }
+    let mut hasher = DefaultHasher::new();
+    key[cut..].hash(&mut hasher);
+    key.truncate(cut);
+    key.push_str(&format!("{:016x}", hasher.finish()));
+    key
+}
+
+/// Renders the configured policies as `10;w=1, 1000;w=3600`
+fn policy_header(policies: &[(usize, Duration)]) -> String {
+    policies
+        .iter()
+        .map(|(max_requests, interval)| format!("{};w={}", max_requests, interval.as_secs()))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Adds the rate limit headers to `headers`. `remaining` is capped at `max_requests`, so that a
+/// corrupted count in the store never reaches the client.
+fn insert_headers(
+    headers: &mut HeaderMap,
+    names: &HeaderNames,
+    max_requests: usize,
+    remaining: usize,
+    reset: Duration,
+    interval: Duration,
+    policy: Option<&str>,
+) {
+    match names.style {
+        HeaderStyle::Separate => {
+            insert_header(headers, &names.limit, &max_requests.to_string());
+            insert_header(
+                headers,
+                &names.remaining,
+                &remaining.min(max_requests).to_string(),
+            );
+            insert_header(headers, &names.reset, &reset.as_secs().to_string());
+            if interval < Duration::from_secs(1) {
+                // Whole seconds would always read 0
+                insert_header(headers, &names.reset_ms, &reset.as_millis().to_string());
+            }
+        }
+        HeaderStyle::Structured => {
+            // Structured field integers have at most 15 digits
+            let integer = |value: u64| value.min(999_999_999_999_999);
+            let value = format!(
+                "limit={}, remaining={}, reset={}",
+                integer(max_requests as u64),
+                integer(remaining.min(max_requests) as u64),
+                integer(reset.as_secs())
+            );
+            insert_header(headers, &HeaderName::from_static("ratelimit"), &value);
+        }
+    }
+    if let Some(policy) = policy {
+        insert_header(
+            headers,
+            &HeaderName::from_static("ratelimit-policy"),
+            policy,
+        );
+    }
+}
+
+/// Sets the header `name` to `value`, leaving it out if `value` is not a valid header value. The
+/// request has been counted, and possibly served, by then, so that a missing header is better
+/// than failing it.
+fn insert_header(headers: &mut HeaderMap, name: &HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.insert(name.clone(), value);
+        }
+        Err(e) => warn!(
+            "Leaving out the {} header, {:?} is invalid: {}",
+            name, value, e
+        ),
+    }
+}
+
+impl<T, S, B> Service for RateLimitMiddleware<S, T>
+where
+    T: Handler<ActorMessage> + 'static,
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = AWError> + 'static,
+    S::Future: 'static,
+    B: 'static,
+    T::Context: ToEnvelope<T, ActorMessage>,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Some(readiness) = &self.readiness {
+            if readiness.poll_ready(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+        self.service.borrow_mut().poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: ServiceRequest) -> Self::Future {
+        let path = req.path();
+        if self.excluded_paths.iter().any(|p| path_matches(p, path)) {
+            return Box::pin(self.service.borrow_mut().call(req));
+        }
+        let report = self.options_report && req.method() == Method::OPTIONS;
+        if self.skipped_methods.contains(req.method()) && !report {
+            return Box::pin(self.service.borrow_mut().call(req));
+        }
+        if let Some(path_filter) = &self.path_filter {
+            if !path_filter.limits(req.path()) {
+                return Box::pin(self.service.borrow_mut().call(req));
+            }
+        }
+        let bypassed = self.bypass.as_ref().map_or(false, |bypass| {
+            guarded("Bypass", &req, || bypass(&req), false)
+        });
+        if bypassed {
+            return Box::pin(self.service.borrow_mut().call(req));
+        }
+        let stores = self.stores.clone();
+        let mut srv = self.service.clone();
+        let policies = self.policies.clone();
+        let suffix_keys = self.suffix_keys;
+        let algorithm = self.algorithm;
+        let counting_mode = self.counting_mode;
+        let registration_mode = self.registration_mode;
+        let window_anchor = self.window_anchor;
+        let warmup = self.warmup;
+        let prefix = self.prefix.clone();
+        let header_names = self.header_names.clone();
+        let success_headers = self.success_headers;
+        let reject_headers = self.reject_headers;
+        let json_errors = self.json_errors;
+        let problem_json = self.problem_json;
+        let rejection_body = self.rejection_body.clone();
+        let fail_open = self.fail_open;
+        let fail_open_headers = self.fail_open_headers;
+        let dry_run = self.dry_run;
+        let store_timeout = self.store_timeout;
+        let circuit_breaker = self.circuit_breaker.clone();
+        let max_concurrent = self.max_concurrent;
+        let access_lists = self.access_lists;
+        let upstream_refund = self.upstream_refund;
+        let refund_on_error = self.refund_on_error;
+        let tiers = self.tiers.clone().map(|(pool, interval)| {
+            let tier = guarded("Tier", &req, || (pool.tier)(&req), String::new());
+            (pool.clone(), interval, tier)
+        });
+        let identifier = self.identifier.clone();
+        let body_limit = self.body_limit;
+        let fallback_key = self.fallback_key.clone();
+        let key_hasher = self.key_hasher.clone();
+        let max_key_length = self.max_key_length;
+        let header_hook = self.header_hook.clone();
+        let count_hook = self.count_hook.clone();
+        let latency = self.latency.clone();
+        // Only override the limit and interval when no policies are configured. Upgrade requests
+        // and listed methods draw from buckets of their own, named after the kind of request.
+        let own_limit = match (self.upgrade_limit, self.method_limits.get(req.method())) {
+            _ if suffix_keys => None,
+            (Some(limits), _) if is_upgrade(&req) => Some(("upgrade".to_string(), limits)),
+            (_, Some(&limits)) => Some((req.method().to_string(), limits)),
+            _ => None,
+        };
+        let limit = match (&self.limit, &own_limit) {
+            (_, Some((_, (max_requests, _)))) => Some(*max_requests),
+            (Some(limit), None) if !suffix_keys => guarded("Limit", &req, || limit(&req), None),
+            _ => None,
+        };
+        let interval_override = match (&self.interval_fn, &own_limit) {
+            (_, Some((_, (_, interval)))) => Some(*interval),
+            (Some(interval), None) if !suffix_keys => {
+                guarded("Interval", &req, || interval(&req), None)
+            }
+            _ => None,
+        };
+        let cost = guarded("Cost", &req, || (self.cost)(&req), 1);
+        // Keys of the rules, in the order of the policies they make up
+        let rule_keys: Option<Vec<Option<String>>> = if self.rules.is_empty() {
+            None
+        } else {
+            let keys = self.rules.iter().map(|rule| {
+                let key = guarded("Rule identifier", &req, || (rule.identifier)(&req), None)?;
+                let key = match &key_hasher {
+                    Some(hasher) => (hasher)(&key),
+                    None => key,
+                };
+                Some(match max_key_length {
+                    Some(max_key_length) => bounded_key(key, max_key_length),
+                    None => key,
+                })
+            });
+            Some(keys.collect())
+        };
+        Box::pin(async move {
+            let precomputed = req
+                .extensions()
+                .get::<RateLimitKey>()
+                .map(|key| key.0.clone());
+            if let (Some(limit), None) = (body_limit, &precomputed) {
+                let body = buffer_body(&mut req, limit).await?;
+                req.extensions_mut().insert(BufferedBody(body));
+            }
+            let identifier = match precomputed {
+                Some(key) => key,
+                None => {
+                    let identifier = guarded(
+                        "Identifier",
+                        &req,
+                        || (identifier)(&req),
+                        Err(ARError::IdentificationError),
+                    );
+                    let identifier = match (identifier, fallback_key) {
+                        (Err(ARError::IdentificationError), Some(key))
+                        | (Err(ARError::Unidentifiable(_)), Some(key)) => Ok(Some(key.to_string())),
+                        (identifier, _) => identifier,
+                    };
+                    let identifier: String = match identifier? {
+                        Some(identifier) => identifier,
+                        None => return srv.call(req).await,
+                    };
+                    let identifier = match key_hasher {
+                        Some(hasher) => (hasher)(&identifier),
+                        None => identifier,
+                    };
+                    match max_key_length {
+                        Some(max_key_length) => bounded_key(identifier, max_key_length),
+                        None => identifier,
+                    }
+                }
+            };
+            let identifier = match own_limit {
+                Some((kind, _)) => format!("{}:{}", identifier, kind),
+                None => identifier,
+            };
+            let shard = shard_index(&identifier, stores.len());
+            let store = &stores[shard];
+            let policy = if suffix_keys {
+                Some(policy_header(&policies))
+            } else {
+                None
+            };
+            let attempted = circuit_breaker
+                .as_ref()
+                .map_or(true, |breaker| breaker.attempt());
+            // Whether the store failed before the policies were looked up, see `record`
+            let mut failed = false;
+            // Tells the circuit breaker how the store calls of this request went
+            let record = |success: bool| {
+                if let (true, Some(breaker)) = (attempted, &circuit_breaker) {
+                    breaker.record(success);
+                }
+            };
+            if access_lists {
+                let status = async {
+                    if !attempted {
+                        return Err(ARError::CircuitOpen.into());
+                    }
+                    let res = store
+                        .send(ActorMessage::CheckList(String::from(&identifier)))
+                        .await?;
+                    Ok::<_, AWError>(res.expect_check_list().await?)
+                };
+                match within(store_timeout, status).await {
+                    Ok(ListStatus::Allow) => {
+                        record(true);
+                        let mut res = srv.call(req).await?;
+                        res.response_mut().extensions_mut().insert(Throttled(false));
+                        return Ok(res);
+                    }
+                    Ok(ListStatus::Deny) => {
+                        record(true);
+                        info!("Denied client: {}", &identifier);
+                        let mut res = req.error_response(ARError::Denied);
+                        res.response_mut().extensions_mut().insert(Throttled(true));
+                        return Ok(res);
+                    }
+                    Ok(ListStatus::Normal) => {}
+                    // The limits below consult the store as well, and fail open or not
+                    Err(e) if fail_open => {
+                        warn!("Could not check the lists: {}", e);
+                        failed = true;
+                    }
+                    Err(e) => {
+                        record(false);
+                        return Err(e);
+                    }
+                }
+            }
+            // Take an in-flight slot first, so that a request turned away for it does not count
+            // against the rate limit
+            let _slot = match max_concurrent {
+                Some(max_concurrent) if !report => {
+                    let key = format!("{}{}:active", &prefix, &identifier);
+                    let acquired = async {
+                        if !attempted {
+                            return Err(ARError::CircuitOpen.into());
+                        }
+                        let res = store
+                            .send(ActorMessage::Increment {
+                                key: String::from(&key),
+                                value: 1,
+                                expiry: CONCURRENCY_EXPIRY,
+                            })
+                            .await?;
+                        Ok::<_, AWError>(res.expect_increment().await?)
+                    };
+                    match within(store_timeout, acquired).await {
+                        Ok(active) => {
+                            let slot = ConcurrencyGuard {
+                                store: store.clone(),
+                                key,
+                            };
+                            if active > max_concurrent {
+                                record(!failed);
+                                info!("Concurrency limit exceeded for client: {}", &identifier);
+                                drop(slot);
+                                let mut res = req.error_response(ARError::ConcurrencyLimitError {
+                                    max_concurrent,
+                                });
+                                res.response_mut().extensions_mut().insert(Throttled(true));
+                                return Ok(res);
+                            }
+                            Some(slot)
+                        }
+                        Err(e) if fail_open => {
+                            warn!("Store failed, letting the request through: {}", e);
+                            failed = true;
+                            None
+                        }
+                        Err(e) => {
+                            record(false);
+                            return Err(e);
+                        }
+                    }
+                }
+                _ => None,
+            };
+            let outcome = async {
+                if !attempted {
+                    return Err(ARError::CircuitOpen.into());
+                }
+                // Look up every policy before consuming anything, so that a rejected request does
+                // not count against the other limits
+                let mut states = Vec::with_capacity(policies.len());
+                for (index, &(max_requests, interval)) in policies.iter().enumerate() {
+                    // Overrides are only computed without policies or rules, which keep their own
+                    let max_requests = limit.unwrap_or(max_requests);
+                    let interval = interval_override.unwrap_or(interval);
+                    let (key, shard) = match rule_keys.as_ref().map(|keys| &keys[index]) {
+                        // The rule does not apply to this request
+                        Some(None) => continue,
+                        Some(Some(rule_key)) => (
+                            format!("{}{}:{}", &prefix, rule_key, interval.as_millis()),
+                            shard_index(rule_key, stores.len()),
+                        ),
+                        None if suffix_keys => (
+                            format!("{}{}:{}", &prefix, &identifier, interval.as_millis()),
+                            shard,
+                        ),
+                        None => (format!("{}{}", &prefix, &identifier), shard),
+                    };
+                    let store = &stores[shard];
+                    let remaining: ActorResponse =
+                        store.send(ActorMessage::Get(String::from(&key))).await?;
+                    let remaining = remaining.expect_get().await?;
+                    if remaining.is_none()
+                        && registration_mode == RegistrationMode::DenyUnknown
+                        && !report
+                    {
+                        return Ok(Outcome::Unknown);
+                    }
+                    let mut expiry = if remaining.is_some() {
+                        // Existing entry in store
+                        let expiry = store.send(ActorMessage::Expire(String::from(&key))).await?;
+                        Some(expiry.expect_expire().await?)
+                    } else {
+                        None
+                    };
+                    if let (Algorithm::FixedWindow, Some(ttl), Some(value)) =
+                        (algorithm, expiry, remaining)
+                    {
+                        if ttl > interval {
+                            // The window was opened under a longer interval, e.g. before the
+                            // configuration changed. Cut it down so that it matches the interval
+                            // and the reset reported to the client.
+                            let res = store
+                                .send(ActorMessage::Set {
+                                    key: String::from(&key),
+                                    value,
+                                    expiry: interval,
+                                })
+                                .await?;
+                            res.expect_set().await?;
+                            expiry = Some(interval);
+                        }
+                    }
+                    let mut window = None;
+                    let mut max_requests = max_requests;
+                    if let (Algorithm::FixedWindow, Some((extra_requests, windows))) =
+                        (algorithm, warmup)
+                    {
+                        let res = store
+                            .send(ActorMessage::Get(format!("{}:windows", &key)))
+                            .await?;
+                        let opened = res.expect_get().await?;
+                        let index = match (remaining, opened) {
+                            (Some(_), Some(opened)) => opened.saturating_sub(1),
+                            (Some(_), None) => windows,
+                            (None, opened) => {
+                                let index = opened.unwrap_or(0);
+                                window = Some(index);
+                                index
+                            }
+                        };
+                        max_requests += warmup_allowance(extra_requests, windows, index);
+                    }
+                    states.push(match algorithm {
+                        Algorithm::FixedWindow => PolicyState {
+                            key,
+                            shard,
+                            max_requests,
+                            interval,
+                            remaining,
+                            new_client: remaining.is_none(),
+                            reset: expiry.unwrap_or_else(|| match window_anchor {
+                                WindowAnchor::FirstRequest => interval,
+                                WindowAnchor::ClockAligned => {
+                                    until_boundary(SystemTime::now(), interval)
+                                }
+                            }),
+                            drain: None,
+                            window,
+                            sliding: None,
+                        },
+                        Algorithm::LeakyBucket { capacity } => {
+                            let drain = drain_time(interval, max_requests);
+                            let level = expiry.unwrap_or_default();
+                            PolicyState {
+                                key,
+                                shard,
+                                max_requests: capacity,
+                                interval,
+                                remaining: Some(
+                                    capacity.saturating_sub(bucket_level(level, drain)),
+                                ),
+                                new_client: remaining.is_none(),
+                                reset: level,
+                                drain: Some(drain),
+                                window: None,
+                                sliding: None,
+                            }
+                        }
+                        Algorithm::SlidingWindowCounter => {
+                            let sliding = match (remaining, expiry) {
+                                (Some(remaining), Some(ttl)) if ttl > interval => {
+                                    // Still within the window the entry was opened for
+                                    let res = store
+                                        .send(ActorMessage::Get(format!("{}:previous", &key)))
+                                        .await?;
+                                    SlidingWindow {
+                                        current: max_requests.saturating_sub(remaining),
+                                        previous: res.expect_get().await?.unwrap_or(0),
+                                        left: ttl - interval,
+                                        opening: false,
+                                    }
+                                }
+                                // The window of the entry is over and now is the previous one
+                                (Some(remaining), Some(ttl)) => SlidingWindow {
+                                    current: 0,
+                                    previous: max_requests.saturating_sub(remaining),
+                                    left: ttl,
+                                    opening: true,
+                                },
+                                _ => SlidingWindow {
+                                    current: 0,
+                                    previous: 0,
+                                    left: interval,
+                                    opening: true,
+                                },
+                            };
+                            PolicyState {
+                                key,
+                                shard,
+                                max_requests,
+                                interval,
+                                remaining: Some(
+                                    max_requests.saturating_sub(sliding.estimate(interval)),
+                                ),
+                                new_client: remaining.is_none(),
+                                reset: sliding.left,
+                                drain: None,
+                                window: None,
+                                sliding: Some(sliding),
+                            }
+                        }
+                    });
+                }
+
+                if report {
+                    return Ok(Outcome::Reported(states));
+                }
+                // Of the policies without enough budget left, report the one which takes the
+                // longest to reset
+                let exhausted = states
+                    .iter()
+                    .filter(|state| state.remaining.unwrap_or(state.max_requests) < cost)
+                    .max_by_key(|state| state.reset);
+                if let Some(state) = exhausted {
+                    return Ok(Outcome::Exhausted(state.clone()));
+                }
+                let drawn = match &tiers {
+                    Some((pool, interval, tier)) => {
+                        let key = format!("{}tiers", &prefix);
+                        match pool.acquire(&stores, key, tier, *interval, cost).await? {
+                            Ok(drawn) => Some(drawn),
+                            Err(state) => return Ok(Outcome::Exhausted(state)),
+                        }
+                    }
+                    None => None,
+                };
+
+                for state in states.iter_mut() {
+                    let store = &stores[state.shard];
+                    if let Some(sliding) = state.sliding.as_mut() {
+                        // Check and count in one step where the store can, as other requests
+                        // may have been counted since the lookup
+                        let res = store
+                            .send(ActorMessage::Slide {
+                                key: String::from(&state.key),
+                                cost,
+                                max: state.max_requests,
+                                interval: state.interval,
+                            })
+                            .await?;
+                        let counted = match res.expect_slide().await {
+                            Ok(count) => {
+                                *sliding = SlidingWindow {
+                                    current: count.current,
+                                    previous: count.previous,
+                                    left: count.left,
+                                    opening: false,
+                                };
+                                state.reset = count.left;
+                                Some(count.counted)
+                            }
+                            Err(ARError::Unsupported(_)) => None,
+                            Err(e) => return Err(e.into()),
+                        };
+                        match counted {
+                            Some(true) => {}
+                            Some(false) => {
+                                // Other requests took the room left, while the policies counted
+                                // before this one stay counted
+                                let estimate = sliding.estimate(state.interval);
+                                state.remaining = Some(state.max_requests.saturating_sub(estimate));
+                                return Ok(Outcome::Exhausted(state.clone()));
+                            }
+                            None if sliding.opening => {
+                                // The window outlives itself by an interval, to be weighed in later
+                                let res = store
+                                    .send(ActorMessage::Set {
+                                        key: String::from(&state.key),
+                                        value: state.max_requests.saturating_sub(cost),
+                                        expiry: sliding.left + state.interval,
+                                    })
+                                    .await?;
+                                res.expect_set().await?;
+                                if sliding.previous > 0 && sliding.left > Duration::from_secs(0) {
+                                    let res = store
+                                        .send(ActorMessage::Set {
+                                            key: format!("{}:previous", &state.key),
+                                            value: sliding.previous,
+                                            expiry: sliding.left,
+                                        })
+                                        .await?;
+                                    res.expect_set().await?;
+                                }
+                                sliding.current = cost;
+                                sliding.opening = false;
+                            }
+                            None => {
+                                let res = store
+                                    .send(ActorMessage::Update {
+                                        key: String::from(&state.key),
+                                        value: cost,
+                                    })
+                                    .await?;
+                                let remaining = res.expect_update().await?;
+                                sliding.current = state.max_requests.saturating_sub(remaining);
+                            }
+                        }
+                        let estimate = sliding.estimate(state.interval);
+                        state.remaining = Some(state.max_requests.saturating_sub(estimate));
+                    } else if let Some(drain) = state.drain {
+                        // Pour the request into the bucket, which now takes longer to drain
+                        let level = state.reset + drain_for(drain, cost);
+                        let remaining = state.remaining.unwrap_or(0).saturating_sub(cost);
+                        if level > Duration::from_secs(0) {
+                            let res = store
+                                .send(ActorMessage::Set {
+                                    key: String::from(&state.key),
+                                    value: remaining,
+                                    expiry: level,
+                                })
+                                .await?;
+                            res.expect_set().await?;
+                        }
+                        state.remaining = Some(remaining);
+                        state.reset = level;
+                    } else if state.remaining.is_some() {
+                        // Decrement value
+                        let res: ActorResponse = store
+                            .send(ActorMessage::Update {
+                                key: String::from(&state.key),
+                                value: cost,
+                            })
+                            .await?;
+                        let updated_value: usize = res.expect_update().await?;
+                        state.remaining = Some(updated_value);
+                    } else {
+                        // New client, create entry in store
+                        let current_value = state.max_requests - cost;
+                        let res = store
+                            .send(ActorMessage::Set {
+                                key: String::from(&state.key),
+                                value: current_value,
+                                expiry: state.reset,
+                            })
+                            .await?;
+                        res.expect_set().await?;
+                        state.remaining = Some(current_value);
+                        if let Some(index) = state.window {
+                            // Count the window, forgotten if no other one opens within an interval
+                            let res = store
+                                .send(ActorMessage::Set {
+                                    key: format!("{}:windows", &state.key),
+                                    value: index + 1,
+                                    expiry: state.interval * 2,
+                                })
+                                .await?;
+                            res.expect_set().await?;
+                        }
+                    }
+                }
+                Ok::<_, AWError>(Outcome::Allowed(states, drawn))
+            };
+            let outcome = within(store_timeout, outcome).await;
+            record(!failed && outcome.is_ok());
+            let (states, drawn) = match outcome {
+                Ok(Outcome::Allowed(states, drawn)) => {
+                    for state in &states {
+                        let remaining = state.remaining.unwrap_or(0);
+                        let exhausted = remaining == 0;
+                        if exhausted {
+                            warn!(
+                                "Client {} used up its limit of {} requests",
+                                &state.key, state.max_requests
+                            );
+                        } else if state.new_client {
+                            info!(
+                                "New client: {}, {} of {} requests left",
+                                &state.key, remaining, state.max_requests
+                            );
+                        } else {
+                            debug!(
+                                "Counted request for client: {}, {} of {} requests left",
+                                &state.key, remaining, state.max_requests
+                            );
+                        }
+                        if let Some(hook) = &count_hook {
+                            hook(&CountEvent {
+                                key: String::from(&state.key),
+                                new_client: state.new_client,
+                                max_requests: state.max_requests,
+                                remaining,
+                                exhausted,
+                            });
+                        }
+                    }
+                    (states, drawn)
+                }
+                Ok(Outcome::Unknown) => {
+                    info!("Unknown client: {}", &identifier);
+                    let mut res = req.error_response(ARError::Denied);
+                    res.response_mut().extensions_mut().insert(Throttled(true));
+                    return Ok(res);
+                }
+                Ok(Outcome::Reported(states)) => {
+                    let mut res = srv.call(req).await?;
+                    res.response_mut().extensions_mut().insert(Throttled(false));
+                    let remaining =
+                        |state: &PolicyState| state.remaining.unwrap_or(state.max_requests);
+                    let state = match states
+                        .iter()
+                        .min_by_key(|state| (remaining(state), Reverse(state.reset)))
+                    {
+                        Some(state) => state,
+                        None => return Ok(res),
+                    };
+                    let applicable: Vec<_> = states
+                        .iter()
+                        .map(|state| (state.max_requests, state.interval))
+                        .collect();
+                    insert_headers(
+                        res.headers_mut(),
+                        &header_names,
+                        state.max_requests,
+                        remaining(state),
+                        state.reset,
+                        state.interval,
+                        Some(&policy_header(&applicable)),
+                    );
+                    if let Some(hook) = &header_hook {
+                        let decision = Decision {
+                            throttled: false,
+                            max_requests: state.max_requests,
+                            remaining: remaining(state).min(state.max_requests),
+                            reset: state.reset,
+                            interval: state.interval,
+                        };
+                        hook(res.headers_mut(), &decision);
+                    }
+                    return Ok(res);
+                }
+                Ok(Outcome::Exhausted(state)) if dry_run => {
+                    info!(
+                        "Limit exceeded for client: {}, serving it in dry run",
+                        &identifier
+                    );
+                    let mut res = srv.call(req).await?;
+                    res.response_mut().extensions_mut().insert(Throttled(true));
+                    if success_headers {
+                        insert_headers(
+                            res.headers_mut(),
+                            &header_names,
+                            state.max_requests,
+                            0,
+                            state.reset,
+                            state.interval,
+                            policy.as_deref(),
+                        );
+                    }
+                    if let Some(hook) = &header_hook {
+                        let decision = Decision {
+                            throttled: true,
+                            max_requests: state.max_requests,
+                            remaining: 0,
+                            reset: state.reset,
+                            interval: state.interval,
+                        };
+                        hook(res.headers_mut(), &decision);
+                    }
+                    return Ok(res);
+                }
+                Ok(Outcome::Exhausted(state)) => {
+                    info!("Limit exceeded for client: {}", &identifier);
+                    let remaining = state
+                        .remaining
+                        .unwrap_or(state.max_requests)
+                        .min(state.max_requests);
+                    // Respond with the typed error so that error handlers further up can tell
+                    // throttled requests apart
+                    let mut res = req.error_response(ARError::RateLimitError {
+                        max_requests: state.max_requests,
+                        c: remaining,
+                        reset: state.reset.as_secs(),
+                    });
+                    // The error renders the default header names, replace them with the
+                    // configured ones
+                    let headers = res.headers_mut();
+                    let defaults = HeaderNames::default();
+                    for name in &[defaults.limit, defaults.remaining, defaults.reset] {
+                        headers.remove(name);
+                    }
+                    if reject_headers {
+                        insert_headers(
+                            headers,
+                            &header_names,
+                            state.max_requests,
+                            remaining,
+                            state.reset,
+                            state.interval,
+                            policy.as_deref(),
+                        );
+                    }
+                    let retry_after = retry_after_secs(state.retry_after(cost));
+                    headers.insert(
+                        RETRY_AFTER,
+                        retry_after_value(
+                            header_names.retry_after,
+                            state.retry_after(cost),
+                            SystemTime::now(),
+                        )?,
+                    );
+                    if let Some(hook) = &header_hook {
+                        let decision = Decision {
+                            throttled: true,
+                            max_requests: state.max_requests,
+                            remaining,
+                            reset: state.reset,
+                            interval: state.interval,
+                        };
+                        hook(headers, &decision);
+                    }
+                    if let Some((body, content_type)) = rejection_body {
+                        headers.insert(CONTENT_TYPE, content_type);
+                        res = res.map_body(|_, _| ResponseBody::Other(Body::from(body)));
+                    } else if problem_json {
+                        headers.insert(
+                            CONTENT_TYPE,
+                            HeaderValue::from_static("application/problem+json"),
+                        );
+                        let body = format!(
+                            r#"{{"type":"about:blank","title":"Too Many Requests","status":429,"retryAfter":{}}}"#,
+                            retry_after
+                        );
+                        res = res.map_body(|_, _| ResponseBody::Other(Body::from(body)));
+                    } else if json_errors {
+                        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                        let body = format!(
+                            r#"{{"error":"too_many_requests","limit":{},"remaining":{},"retry_after":{}}}"#,
+                            state.max_requests, remaining, retry_after
+                        );
+                        res = res.map_body(|_, _| ResponseBody::Other(Body::from(body)));
+                    }
+                    res.response_mut().extensions_mut().insert(Throttled(true));
+                    return Ok(res);
+                }
+                Err(e) if fail_open => {
+                    warn!("Store failed, letting the request through: {}", e);
+                    let mut res = srv.call(req).await?;
+                    res.response_mut().extensions_mut().insert(Throttled(false));
+                    if fail_open_headers && success_headers {
+                        // Nothing was counted, report the first policy as untouched
+                        let (max_requests, interval) = policies[0];
+                        let max_requests = limit.unwrap_or(max_requests);
+                        let interval = interval_override.unwrap_or(interval);
+                        insert_headers(
+                            res.headers_mut(),
+                            &header_names,
+                            max_requests,
+                            max_requests,
+                            interval,
+                            interval,
+                            policy.as_deref(),
+                        );
+                    }
+                    return Ok(res);
+                }
+                Err(e) => return Err(e),
+            };
+
+            // Execute the request
+            let started = Instant::now();
+            let fut = srv.call(req);
+            let mut res = match fut.await {
+                Ok(res) => res,
+                Err(e) => {
+                    if refund_on_error {
+                        let refunded = refund(&stores, &states, drawn.as_ref(), cost);
+                        if let Err(e) = within(store_timeout, refunded).await {
+                            warn!("Could not refund client {}: {}", &identifier, e);
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+            res.response_mut().extensions_mut().insert(Throttled(false));
+            if let Some(latency) = &latency {
+                latency.record(started.elapsed());
+            }
+            if res.status() == StatusCode::TOO_MANY_REQUESTS {
+                // The service limited the request itself, leave its headers alone
+                if upstream_refund {
+                    let refunded = refund(&stores, &states, drawn.as_ref(), cost);
+                    if let Err(e) = within(store_timeout, refunded).await {
+                        warn!("Could not refund client {}: {}", &identifier, e);
+                    }
+                }
+                return Ok(res);
+            }
+            // Report the policy with the fewest requests left
+            let state = match states
+                .iter()
+                .min_by_key(|state| (state.remaining, Reverse(state.reset)))
+            {
+                Some(state) => state,
+                // None of the rules applied to the request
+                None => return Ok(res),
+            };
+            let remaining = match counting_mode {
+                CountingMode::Preemptive => state.remaining.unwrap_or(0),
+                CountingMode::PostHoc => state.remaining.unwrap_or(0) + cost,
+            };
+            if success_headers {
+                insert_headers(
+                    res.headers_mut(),
+                    &header_names,
+                    state.max_requests,
+                    remaining,
+                    state.reset,
+                    state.interval,
+                    policy.as_deref(),
+                );
+            }
+            if let Some(hook) = &header_hook {
+                let decision = Decision {
+                    throttled: false,
+                    max_requests: state.max_requests,
+                    remaining: remaining.min(state.max_requests),
+                    reset: state.reset,
+                    interval: state.interval,
+                };
+                hook(res.headers_mut(), &decision);
+            }
+            Ok(res)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::{MemoryStore, MemoryStoreActor, ShardedMemoryStore, SlidingCount};
+    use actix_http::Request;
+    use actix_web::middleware::errhandlers::{ErrorHandlerResponse, ErrorHandlers};
+    use actix_web::{http::header::CONTENT_LENGTH, test, web, App, HttpResponse};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    fn request() -> Request {
+        test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request()
+    }
+
+    /// Sends a request and returns the status and headers, whether it was accepted or rejected
+    async fn send<S, B>(app: &mut S, req: Request) -> (StatusCode, HeaderMap)
+    where
+        S: Service<Request = Request, Response = ServiceResponse<B>, Error = AWError>,
+    {
+        match app.call(req).await {
+            Ok(res) => (res.status(), res.headers().clone()),
+            Err(e) => {
+                let res = HttpResponse::from_error(e);
+                (res.status(), res.headers().clone())
+            }
+        }
+    }
+
+    fn header<'a>(headers: &'a HeaderMap, name: &str) -> &'a str {
+        headers.get(name).unwrap().to_str().unwrap()
+    }
+
+    #[actix_rt::test]
+    async fn test_policies() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start()).with_policies(
+                        vec![(2, Duration::from_secs(1)), (3, Duration::from_secs(86400))],
+                    ),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "2");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        assert_eq!(header(&headers, "ratelimit-policy"), "2;w=1, 3;w=86400");
+
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        // Burst limit exhausted
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "2");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+
+        actix_rt::time::delay_for(Duration::from_millis(1100)).await;
+        // Burst window has passed, the daily limit is now the most restrictive
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "3");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "3");
+    }
+
+    #[actix_rt::test]
+    async fn test_skipped_methods() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_skipped_methods(vec![Method::OPTIONS, Method::HEAD]),
+                )
+                .route("/", web::route().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (_, headers) = send(&mut app, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+        let req = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let (status, headers) = send(&mut app, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.get("x-ratelimit-remaining").is_none());
+        let (_, headers) = send(&mut app, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "3");
+    }
+
+    #[actix_rt::test]
+    async fn test_header_names() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_limit_header("X-Rate-Limit-Limit")
+                        .with_remaining_header("x-rate-limit-remaining")
+                        .with_reset_header("vendor-ratelimit-reset"),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for &status in &[StatusCode::OK, StatusCode::TOO_MANY_REQUESTS] {
+            let (res_status, headers) = send(&mut app, request()).await;
+            assert_eq!(res_status, status);
+            assert_eq!(header(&headers, "x-rate-limit-limit"), "1");
+            assert_eq!(header(&headers, "x-rate-limit-remaining"), "0");
+            assert!(headers.contains_key("vendor-ratelimit-reset"));
+            assert!(!headers.contains_key("x-ratelimit-limit"));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid header name")]
+    fn test_invalid_header_name() {
+        header_name("x-ratelimit limit");
+    }
+
+    #[actix_rt::test]
+    async fn test_sharded() {
+        let shards = ShardedMemoryStore::new(4).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::sharded(shards.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for remaining in &["4", "3", "2"] {
+            let (_, headers) = send(&mut app, request()).await;
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+        }
+        // Every request of the client went to the same shard
+        let index = shard_index("127.0.0.1:8080", 4);
+        for (i, shard) in shards.iter().enumerate() {
+            let stats = RateLimiter::stats(shard).await.unwrap();
+            let expected = if i == index { 1 } else { 0 };
+            assert_eq!(stats.key_count, Some(expected));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_optional_identifier() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_optional_identifier(|req| match req.headers().get("x-api-key") {
+                            Some(key) if key == "invalid" => Err(ARError::IdentificationError),
+                            Some(key) => Ok(Some(key.to_str().unwrap().to_string())),
+                            None => Ok(None),
+                        }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // No key, not limited
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains_key("x-ratelimit-remaining"));
+
+        let req = test::TestRequest::default()
+            .header("x-api-key", "secret")
+            .to_request();
+        let (status, headers) = send(&mut app, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+
+        let req = test::TestRequest::default()
+            .header("x-api-key", "invalid")
+            .to_request();
+        let (status, _) = send(&mut app, req).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    fn tag_throttled<B>(mut res: ServiceResponse<B>) -> actix_web::Result<ErrorHandlerResponse<B>> {
+        let throttled = match res.response().error() {
+            Some(e) => matches!(
+                e.as_error::<ARError>(),
+                Some(ARError::RateLimitError {
+                    max_requests: 1,
+                    ..
+                })
+            ),
+            None => false,
+        };
+        res.headers_mut().insert(
+            HeaderName::from_static("x-throttled"),
+            HeaderValue::from_static(if throttled { "true" } else { "false" }),
+        );
+        Ok(ErrorHandlerResponse::Response(res))
+    }
+
+    #[actix_rt::test]
+    async fn test_rate_limit_error() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1),
+                )
+                .wrap(ErrorHandlers::new().handler(StatusCode::TOO_MANY_REQUESTS, tag_throttled))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-throttled"), "true");
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "1");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+    }
+
+    #[actix_rt::test]
+    async fn test_cost() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1000)
+                        .with_cost(|req| {
+                            req.headers()
+                                .get(CONTENT_LENGTH)
+                                .and_then(|len| len.to_str().ok())
+                                .and_then(|len| len.parse().ok())
+                                .unwrap_or(0)
+                        }),
+                )
+                .route("/", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+        let upload = |len: &str| {
+            test::TestRequest::post()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .header(CONTENT_LENGTH, len)
+                .to_request()
+        };
+
+        let (status, headers) = send(&mut app, upload("600")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "1000");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "400");
+        let (status, headers) = send(&mut app, upload("100")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "300");
+        // Too large for what is left, and does not consume anything
+        let (status, headers) = send(&mut app, upload("500")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "300");
+        let (status, headers) = send(&mut app, upload("300")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+    }
+
+    #[actix_rt::test]
+    async fn test_accessors() {
+        let store = MemoryStore::new();
+        let limiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(100);
+        assert_eq!(limiter.interval(), Duration::from_secs(60));
+        assert_eq!(limiter.max_requests(), 100);
+    }
+
+    #[actix_rt::test]
+    async fn test_prefix() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .service(
+                    web::scope("/api")
+                        .wrap(
+                            RateLimiter::new(addr.clone())
+                                .with_interval(Duration::from_secs(60))
+                                .with_max_requests(5)
+                                .with_prefix("api:"),
+                        )
+                        .route("", web::get().to(HttpResponse::Ok)),
+                )
+                .service(
+                    web::scope("/login")
+                        .wrap(
+                            RateLimiter::new(addr.clone())
+                                .with_interval(Duration::from_secs(60))
+                                .with_max_requests(2)
+                                .with_prefix("login:"),
+                        )
+                        .route("", web::get().to(HttpResponse::Ok)),
+                ),
+        )
+        .await;
+        let get = |path: &str| {
+            test::TestRequest::with_uri(path)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+
+        let (_, headers) = send(&mut app, get("/api")).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+        let (_, headers) = send(&mut app, get("/login")).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        let (_, headers) = send(&mut app, get("/login")).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+        let (status, _) = send(&mut app, get("/login")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // The exhausted login limit leaves the api limit alone
+        let (status, headers) = send(&mut app, get("/api")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "3");
+        let stats = RateLimiter::stats(&addr).await.unwrap();
+        assert_eq!(stats.key_count, Some(2));
+    }
+
+    #[actix_rt::test]
+    async fn test_leaky_bucket() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(1))
+                        .with_max_requests(10)
+                        .with_algorithm(Algorithm::LeakyBucket { capacity: 3 }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // A burst fills the bucket, then overflows it
+        for remaining in &["2", "1", "0"] {
+            let (status, headers) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "x-ratelimit-limit"), "3");
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+        }
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // Room for another request within the 100ms it takes one to drain
+        assert_eq!(header(&headers, "retry-after"), "1");
+
+        // Requests arriving at the drain rate keep being accepted
+        for _ in 0..5 {
+            actix::clock::delay_for(Duration::from_millis(120)).await;
+            let (status, _) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        // The bucket never got to drain completely, another burst overflows it sooner
+        let mut accepted = 0;
+        while send(&mut app, request()).await.0 == StatusCode::OK {
+            accepted += 1;
+        }
+        assert!(accepted < 3);
+    }
+
+    #[test]
+    fn test_bucket_level() {
+        let drain = Duration::from_millis(100);
+        assert_eq!(bucket_level(Duration::from_secs(0), drain), 0);
+        assert_eq!(bucket_level(Duration::from_millis(100), drain), 1);
+        assert_eq!(bucket_level(Duration::from_millis(101), drain), 2);
+        assert_eq!(retry_after_secs(Duration::from_millis(100)), 1);
+        assert_eq!(retry_after_secs(Duration::from_secs(2)), 2);
+
+        // Limits and costs beyond `u32` neither divide by zero nor wrap around
+        let second = Duration::from_secs(1);
+        assert_eq!(drain_time(second, 0), second);
+        assert_eq!(drain_time(second, 10), drain);
+        assert_eq!(drain_time(second, 1 << 32), Duration::from_nanos(0));
+        assert_eq!(drain_for(drain, 3), Duration::from_millis(300));
+        assert_eq!(drain_for(drain, 1 << 32), drain * 4096 * 1024 * 1024);
+        assert_eq!(drain_for(drain, usize::MAX), Duration::from_nanos(u64::MAX));
+        assert_eq!(bucket_level(second, Duration::from_nanos(0)), 1_000_000_000);
+    }
+
+    #[actix_rt::test]
+    async fn test_key_hasher() {
+        fn hash(key: &str) -> String {
+            let mut hasher = DefaultHasher::new();
+            key.hash(&mut hasher);
+            format!("{:x}", hasher.finish())
+        }
+        assert_eq!(hash("127.0.0.1:8080"), hash("127.0.0.1:8080"));
+
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_key_hasher(hash),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for remaining in &["4", "3"] {
+            let (_, headers) = send(&mut app, request()).await;
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+        }
+        for (key, expected) in &[
+            (hash("127.0.0.1:8080"), Some(3)),
+            ("127.0.0.1:8080".to_string(), None),
+        ] {
+            let res = addr.send(ActorMessage::Get(key.clone())).await.unwrap();
+            match res {
+                ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), *expected),
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_json_errors() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_json_errors(true),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = test::call_service(&mut app, request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&mut app, request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(res.headers(), "content-type"), "application/json");
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        assert_eq!(body["error"], "too_many_requests");
+        assert_eq!(body["limit"], 1);
+        assert_eq!(body["remaining"], 0);
+        let retry_after = body["retry_after"].as_u64().unwrap();
+        assert!(retry_after > 58 && retry_after <= 60);
+    }
+
+    #[actix_rt::test]
+    async fn test_problem_json() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_json_errors(true)
+                        .with_problem_json(true),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = test::call_service(&mut app, request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = test::call_service(&mut app, request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            header(res.headers(), "content-type"),
+            "application/problem+json"
+        );
+        assert_eq!(header(res.headers(), "x-ratelimit-limit"), "1");
+        assert_eq!(header(res.headers(), "x-ratelimit-remaining"), "0");
+        let retry_after: u64 = header(res.headers(), "retry-after").parse().unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&test::read_body(res).await).unwrap();
+        assert_eq!(body["type"], "about:blank");
+        assert_eq!(body["title"], "Too Many Requests");
+        assert_eq!(body["status"], 429);
+        assert_eq!(body["retryAfter"], retry_after);
+        assert!(retry_after > 58 && retry_after <= 60);
+    }
+
+    #[actix_rt::test]
+    async fn test_interval_change() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let limiter = |interval| {
+            RateLimiter::new(addr.clone())
+                .with_interval(Duration::from_secs(interval))
+                .with_max_requests(5)
+        };
+        let mut before = test::init_service(
+            App::new()
+                .wrap(limiter(60))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut after = test::init_service(
+            App::new()
+                .wrap(limiter(10))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (_, headers) = send(&mut before, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "60");
+        // The window opened under the longer interval is shortened to the new one
+        let (_, headers) = send(&mut after, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "3");
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "10");
+        let res = addr
+            .send(ActorMessage::Expire("127.0.0.1:8080".to_string()))
+            .await
+            .unwrap();
+        match res {
+            ActorResponse::Expire(c) => assert!(c.await.unwrap() <= Duration::from_secs(10)),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let (_, headers) = send(&mut after, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "2");
+        let reset: u64 = header(&headers, "x-ratelimit-reset").parse().unwrap();
+        assert!(reset <= 10);
+    }
+
+    #[actix_rt::test]
+    async fn test_warmup() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_millis(200))
+                        .with_max_requests(2)
+                        .with_warmup(4, 2),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // The allowance decays over two windows, then the steady limit applies
+        for limit in &[6, 4, 2, 2] {
+            let mut accepted = 0;
+            loop {
+                let (status, headers) = send(&mut app, request()).await;
+                if status != StatusCode::OK {
+                    break;
+                }
+                assert_eq!(header(&headers, "x-ratelimit-limit"), limit.to_string());
+                accepted += 1;
+            }
+            assert_eq!(accepted, *limit);
+            actix::clock::delay_for(Duration::from_millis(250)).await;
+        }
+    }
+
+    #[test]
+    fn test_warmup_allowance() {
+        assert_eq!(warmup_allowance(50, 2, 0), 50);
+        assert_eq!(warmup_allowance(50, 2, 1), 25);
+        assert_eq!(warmup_allowance(50, 2, 2), 0);
+        assert_eq!(warmup_allowance(50, 0, 0), 0);
+    }
+
+    #[actix_rt::test]
+    async fn test_auth_tiers() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_auth_tiers(
+                            |req| req.headers().contains_key("x-user-id"),
+                            (
+                                |req| {
+                                    let user = req.headers().get("x-user-id").unwrap();
+                                    Ok(format!("user:{}", user.to_str().unwrap()))
+                                },
+                                5,
+                            ),
+                            (
+                                |req| {
+                                    let info = req.connection_info();
+                                    let ip =
+                                        info.remote_addr().ok_or(ARError::IdentificationError)?;
+                                    Ok(format!("ip:{}", ip))
+                                },
+                                2,
+                            ),
+                        ),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let authenticated = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .header("x-user-id", "42")
+                .to_request()
+        };
+
+        let (status, headers) = send(&mut app, authenticated()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "5");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "2");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        send(&mut app, request()).await;
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        // The same client is still let through once authenticated
+        let (status, headers) = send(&mut app, authenticated()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "3");
+        for (key, expected) in &[("user:42", Some(3)), ("ip:127.0.0.1:8080", Some(0))] {
+            let res = addr.send(ActorMessage::Get(key.to_string())).await.unwrap();
+            match res {
+                ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), *expected),
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_fail_open_headers() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let limiter = || {
+            RateLimiter::new(addr.clone())
+                .with_interval(Duration::from_secs(60))
+                .with_max_requests(5)
+        };
+        let mut strict = test::init_service(
+            App::new()
+                .wrap(limiter())
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut open = test::init_service(
+            App::new()
+                .wrap(limiter().with_fail_open(true))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut uniform = test::init_service(
+            App::new()
+                .wrap(limiter().with_fail_open(true).with_fail_open_headers(true))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        RateLimiter::shutdown(&addr).await.unwrap();
+
+        let (status, _) = send(&mut strict, request()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        let (status, headers) = send(&mut open, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(headers.get("x-ratelimit-remaining").is_none());
+        let (status, headers) = send(&mut uniform, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "5");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "5");
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "60");
+    }
+
+    #[actix_rt::test]
+    async fn test_route_identifier() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(2)
+                        .with_route_identifier(),
+                )
+                .route("/users/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |path: &str, client: &str| {
+            test::TestRequest::with_uri(path)
+                .peer_addr(client.parse().unwrap())
+                .to_request()
+        };
+
+        // Concrete paths and clients share the bucket of the route
+        let (status, _) = send(&mut app, request("/users/123", "127.0.0.1:8080")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("/users/456", "127.0.0.2:8080")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("/users/789", "127.0.0.3:8080")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let res = addr
+            .send(ActorMessage::Get("route:/users/{id}".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(res.expect_get().await.unwrap(), Some(0));
+        // Unmatched requests fall back to their path
+        let (status, _) = send(&mut app, request("/missing", "127.0.0.1:8080")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let res = addr
+            .send(ActorMessage::Get("route:/missing".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(res.expect_get().await.unwrap(), Some(1));
+    }
+
+    #[actix_rt::test]
+    async fn test_ip_and_path_identifier() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_ip_and_path_identifier(),
+                )
+                .route("/a", web::get().to(HttpResponse::Ok))
+                .route("/b/{id}", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |path: &str| {
+            test::TestRequest::with_uri(path)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+
+        let (status, _) = send(&mut app, request("/a")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("/a")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // Another route has its own bucket
+        let (status, _) = send(&mut app, request("/b/1")).await;
+        assert_eq!(status, StatusCode::OK);
+        // Which is shared by every value of the path parameter
+        let (status, _) = send(&mut app, request("/b/2")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let res = addr
+            .send(ActorMessage::Get("127.0.0.1:8080|/b/{id}".to_string()))
+            .await
+            .unwrap();
+        match res {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), Some(0)),
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_reset_ms() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_millis(500))
+                        .with_max_requests(2),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (_, headers) = send(&mut app, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "0");
+        assert_eq!(header(&headers, "x-ratelimit-reset-ms"), "500");
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        let (_, headers) = send(&mut app, request()).await;
+        let reset: u64 = header(&headers, "x-ratelimit-reset-ms").parse().unwrap();
+        assert!(reset > 0 && reset <= 400, "reset: {}", reset);
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(
+            header(&headers, "x-ratelimit-reset-ms")
+                .parse::<u64>()
+                .unwrap()
+                > 0
+        );
+    }
+
+    /// Store which fails every message, counting how many it received
+    struct FailingStore(Arc<AtomicUsize>);
+    impl Actor for FailingStore {
+        type Context = actix::Context<Self>;
+    }
+    impl Handler<ActorMessage> for FailingStore {
+        type Result = ActorResponse;
+        fn handle(&mut self, msg: ActorMessage, _: &mut Self::Context) -> Self::Result {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            ActorResponse::failed(&msg, ARError::Disconnected)
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_circuit_breaker() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = FailingStore(hits.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_fail_open(true)
+                        .with_circuit_breaker(2, Duration::from_millis(200)),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for _ in 0..5 {
+            let (status, _) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        // The circuit opened after two failures
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        actix_rt::time::delay_for(Duration::from_millis(250)).await;
+        // A single request probes the store, which still fails
+        send(&mut app, request()).await;
+        send(&mut app, request()).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_circuit_breaker_lists() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = FailingStore(hits.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_access_lists(true)
+                        .with_circuit_breaker(2, Duration::from_secs(60)),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for _ in 0..3 {
+            let (status, _) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        // Checking the lists opened the circuit, which then kept them from being checked
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_circuit_breaker_concurrency() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let addr = FailingStore(hits.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_max_concurrent(2)
+                        .with_circuit_breaker(2, Duration::from_secs(60)),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for _ in 0..3 {
+            let (status, _) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        // Taking a slot opened the circuit, which then kept the store from being contacted
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_header_or_ip() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_header_or_ip("x-api-key"),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let with_key = |key: &str| {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .header("x-api-key", key)
+                .to_request()
+        };
+
+        send(&mut app, with_key("secret")).await;
+        send(&mut app, request()).await;
+        // An empty value is no key at all
+        send(&mut app, with_key(" ")).await;
+        for (key, expected) in &[("key:secret", Some(4)), ("ip:127.0.0.1", Some(3))] {
+            let res = addr.send(ActorMessage::Get(key.to_string())).await.unwrap();
+            match res {
+                ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), *expected),
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_forwarded_identifier() {
+        let forwarded = |chain: &str| {
+            test::TestRequest::default()
+                .peer_addr("10.0.0.1:4000".parse().unwrap())
+                .header("x-forwarded-for", chain)
+                .to_request()
+        };
+        // Through a single proxy, once as it should be and once with an address made up by the
+        // client in front of the one the proxy added
+        let legitimate = "203.0.113.7";
+        let spoofed = "198.51.100.1, 203.0.113.7";
+        let cases = [
+            (HopMismatch::Ignore, StatusCode::OK, Some(3), None),
+            (HopMismatch::Reject, StatusCode::BAD_REQUEST, Some(4), None),
+            (HopMismatch::UsePeer, StatusCode::OK, Some(4), Some(4)),
+        ];
+        for (mode, spoofed_status, client, peer) in &cases {
+            let store = MemoryStore::new();
+            let addr = MemoryStoreActor::from(store.clone()).start();
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(
+                        RateLimiter::new(addr.clone())
+                            .with_interval(Duration::from_secs(60))
+                            .with_max_requests(5)
+                            .with_forwarded_identifier(1, *mode),
+                    )
+                    .route("/", web::get().to(HttpResponse::Ok)),
+            )
+            .await;
+
+            let (status, _) = send(&mut app, forwarded(legitimate)).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, _) = send(&mut app, forwarded(spoofed)).await;
+            assert_eq!(status, *spoofed_status);
+            for (key, expected) in &[("ip:203.0.113.7", client), ("ip:10.0.0.1", peer)] {
+                let res = addr.send(ActorMessage::Get(key.to_string())).await.unwrap();
+                assert_eq!(res.expect_get().await.unwrap(), **expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_masked_ip() {
+        assert_eq!(masked_ip("127.0.0.1:8080"), "127.0.0.1");
+        assert_eq!(masked_ip("127.0.0.1"), "127.0.0.1");
+        assert_eq!(masked_ip("[2001:db8:1:2:3:4:5:6]:443"), "2001:db8:1:2::/64");
+        assert_eq!(masked_ip("2001:db8:1:2:3:4:5:6"), "2001:db8:1:2::/64");
+        assert_eq!(masked_ip("unknown"), "unknown");
+    }
+
+    #[actix_rt::test]
+    async fn test_adaptive() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_adaptive(|latency| {
+                            if latency > Duration::from_millis(50) {
+                                2
+                            } else {
+                                10
+                            }
+                        }),
+                )
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+                        ""
+                    }),
+                ),
+        )
+        .await;
+        let client = |addr: &str| {
+            test::TestRequest::default()
+                .peer_addr(addr.parse().unwrap())
+                .to_request()
+        };
+
+        let (_, headers) = send(&mut app, client("127.0.0.1:8080")).await;
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "10");
+        // The slow response tightened the limit for the next client
+        let (_, headers) = send(&mut app, client("127.0.0.2:8080")).await;
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "2");
+        send(&mut app, client("127.0.0.2:8080")).await;
+        let (status, _) = send(&mut app, client("127.0.0.2:8080")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn test_latency() {
+        let latency = Latency::default();
+        assert_eq!(latency.average(), Duration::from_millis(0));
+        latency.record(Duration::from_millis(100));
+        assert_eq!(latency.average(), Duration::from_millis(100));
+        latency.record(Duration::from_millis(600));
+        assert_eq!(latency.average(), Duration::from_millis(200));
+    }
+
+    #[actix_rt::test]
+    async fn test_interval_fn() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_max_requests(5)
+                        .with_interval_fn(|req| {
+                            if req.headers().contains_key("x-trial") {
+                                Duration::from_secs(3600)
+                            } else {
+                                Duration::from_secs(60)
+                            }
+                        }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let trial = || {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.2:8080".parse().unwrap())
+                .header("x-trial", "1")
+                .to_request()
+        };
+
+        let (_, headers) = send(&mut app, trial()).await;
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "3600");
+        let (_, headers) = send(&mut app, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "60");
+        // The reset follows the window of the client
+        let (_, headers) = send(&mut app, trial()).await;
+        let reset: u64 = header(&headers, "x-ratelimit-reset").parse().unwrap();
+        assert!(reset > 3590 && reset <= 3600);
+        for (key, max) in &[("127.0.0.2:8080", 3600), ("127.0.0.1:8080", 60)] {
+            let res = addr
+                .send(ActorMessage::Expire(key.to_string()))
+                .await
+                .unwrap();
+            match res {
+                ActorResponse::Expire(c) => {
+                    let ttl = c.await.unwrap();
+                    assert!(ttl <= Duration::from_secs(*max));
+                    assert!(ttl > Duration::from_secs(*max - 10));
+                }
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+    }
+
+    /// Store which answers every message as if it were a `Set`
+    struct MismatchedStore;
+    impl Actor for MismatchedStore {
+        type Context = actix::Context<Self>;
+    }
+    impl Handler<ActorMessage> for MismatchedStore {
+        type Result = ActorResponse;
+        fn handle(&mut self, _: ActorMessage, _: &mut Self::Context) -> Self::Result {
+            ActorResponse::Set(Box::pin(async { Ok(()) }))
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_mismatched_response() {
+        let addr = MismatchedStore.start();
+        match RateLimiter::stats(&addr).await {
+            Err(ARError::UnexpectedResponse(expected)) => assert_eq!(expected, "Stats"),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_rt::test]
+    async fn test_path_identifier() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new().service(
+                web::resource("/users/{id}")
+                    .wrap(
+                        RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                            .with_interval(Duration::from_secs(60))
+                            .with_max_requests(1)
+                            .with_path_identifier("id"),
+                    )
+                    .route(web::get().to(HttpResponse::Ok)),
+            ),
+        )
+        .await;
+        let request = |path: &str, addr: &str| {
+            test::TestRequest::with_uri(path)
+                .peer_addr(addr.parse().unwrap())
+                .to_request()
+        };
+
+        let (status, _) = send(&mut app, request("/users/1", "127.0.0.1:8080")).await;
+        assert_eq!(status, StatusCode::OK);
+        // Another address doesn't get around the limit of the resource
+        let (status, _) = send(&mut app, request("/users/1", "127.0.0.2:8080")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = send(&mut app, request("/users/2", "127.0.0.1:8080")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_query_identifier() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_query_identifier("user_id"),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |path: &str| {
+            test::TestRequest::with_uri(path)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+
+        let (status, _) = send(&mut app, request("/?user_id=1")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("/?page=2&user_id=1")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = send(&mut app, request("/?user_id=2")).await;
+        assert_eq!(status, StatusCode::OK);
+        // The client left the parameter out
+        let (status, _) = send(&mut app, request("/")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_rt::test]
+    async fn test_query_identifier_fallback() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_query_identifier("user_id")
+                        .with_fallback_key("anonymous"),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let res = addr.send(ActorMessage::Get("anonymous".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(0));
+    }
+
+    #[actix_rt::test]
+    async fn test_body_identifier() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_body_identifier(16, |_, body| {
+                            Ok(format!("account:{}", String::from_utf8_lossy(body)))
+                        }),
+                )
+                .route(
+                    "/",
+                    web::post().to(|body: Bytes| HttpResponse::Ok().body(body)),
+                ),
+        )
+        .await;
+        let request = |body: &'static str| {
+            test::TestRequest::post()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .set_payload(body)
+                .to_request()
+        };
+
+        // The service still gets the body read by the limiter
+        let res = app.call(request("1")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, Bytes::from("1"));
+        let (status, _) = send(&mut app, request("1")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = send(&mut app, request("2")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("a body over the limit")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let res = addr.send(ActorMessage::Get("account:2".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(0));
+    }
+
+    #[actix_rt::test]
+    async fn test_identifier_panic() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_identifier(|req| {
+                            let key = req.headers().get("x-api-key").unwrap();
+                            Ok(key.to_str().unwrap().to_string())
+                        }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        // The service is still up for the next request
+        let req = test::TestRequest::default()
+            .header("x-api-key", "secret")
+            .to_request();
+        let (status, headers) = send(&mut app, req).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+    }
+
+    #[actix_rt::test]
+    async fn test_function_panics() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_bypass(|_| panic!("bypass"))
+                        .with_cost(|_| panic!("cost"))
+                        .with_interval_fn(|_| panic!("interval"))
+                        .with_adaptive(|_| panic!("limit")),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // Counted with the defaults, and the service is still up for the next request
+        for remaining in &["4", "3"] {
+            let (status, headers) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "x-ratelimit-limit"), "5");
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_max_concurrent() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_max_requests(10)
+                        .with_max_concurrent(1),
+                )
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        actix_rt::time::delay_for(Duration::from_millis(200)).await;
+                        ""
+                    }),
+                ),
+        )
+        .await;
+
+        let first = app.call(request());
+        let second = app.call(request());
+        let (first, second) = futures::join!(first, second);
+        let mut statuses = vec![
+            first
+                .map(|res| res.status())
+                .unwrap_or_else(|e| HttpResponse::from_error(e).status()),
+            second
+                .map(|res| res.status())
+                .unwrap_or_else(|e| HttpResponse::from_error(e).status()),
+        ];
+        statuses.sort();
+        assert_eq!(
+            statuses,
+            vec![StatusCode::OK, StatusCode::TOO_MANY_REQUESTS]
+        );
+        // The slot is given back once the request is done
+        actix_rt::time::delay_for(Duration::from_millis(50)).await;
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        actix_rt::time::delay_for(Duration::from_millis(50)).await;
+        let res = addr
+            .send(ActorMessage::Get("127.0.0.1:8080:active".to_string()))
+            .await
+            .unwrap();
+        match res {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), Some(0)),
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let store = MemoryStore::new().with_clock(clock.clone());
+        let addr = MemoryStoreActor::from(store).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        clock.advance(Duration::from_secs(20));
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "40");
+        // A new window starts once the clock passes the end of the current one
+        clock.advance(Duration::from_secs(40));
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "60");
+    }
+
+    #[actix_rt::test]
+    async fn test_success_and_reject_headers() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_success_headers(false),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains_key("x-ratelimit-limit"));
+        assert!(!headers.contains_key("x-ratelimit-remaining"));
+        assert!(!headers.contains_key("x-ratelimit-reset"));
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "retry-after"), "60");
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "1");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+
+        // Rejections can leave out everything but Retry-After
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_reject_headers(false),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert!(headers.contains_key("retry-after"));
+        assert!(!headers.contains_key("x-ratelimit-limit"));
+        assert!(!headers.contains_key("x-ratelimit-remaining"));
+        assert!(!headers.contains_key("x-ratelimit-reset"));
+    }
+
+    #[actix_rt::test]
+    async fn test_tier_reservations() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let limiter = |prefix: &str| {
+            RateLimiter::new(addr.clone())
+                .with_interval(Duration::from_secs(60))
+                .with_max_requests(100)
+                .with_prefix(prefix)
+                .with_tier_reservations(
+                    10,
+                    |req| {
+                        let premium = req.headers().contains_key("x-premium");
+                        String::from(if premium { "premium" } else { "free" })
+                    },
+                    vec![("premium", 3)],
+                )
+        };
+        let free = |client: u8| {
+            test::TestRequest::default()
+                .peer_addr(format!("127.0.0.{}:8080", client).parse().unwrap())
+                .to_request()
+        };
+        let premium = |client: u8| {
+            test::TestRequest::default()
+                .peer_addr(format!("127.0.1.{}:8080", client).parse().unwrap())
+                .header("x-premium", "1")
+                .to_request()
+        };
+
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter("a:"))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        // Free clients saturate everything but the premium reservation
+        for client in 0..7 {
+            let (status, _) = send(&mut app, free(client)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, headers) = send(&mut app, free(7)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "10");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+        assert_eq!(header(&headers, "retry-after"), "60");
+        for client in 0..3 {
+            let (status, _) = send(&mut app, premium(client)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = send(&mut app, premium(3)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        // Premium clients may use the whole pool while free ones are idle
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter("b:"))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        for client in 0..10 {
+            let (status, _) = send(&mut app, premium(client)).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = send(&mut app, free(0)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_tier_reservations_concurrent() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(100)
+                        .with_tier_reservations(3, |_| "free".to_string(), vec![("premium", 1)]),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // Each request checks and draws on the pool in one step, so only two get through
+        let requests: Vec<_> = (0..6)
+            .map(|client| {
+                app.call(
+                    test::TestRequest::default()
+                        .peer_addr(format!("127.0.0.{}:8080", client).parse().unwrap())
+                        .to_request(),
+                )
+            })
+            .collect();
+        let allowed = futures::future::join_all(requests)
+            .await
+            .into_iter()
+            .filter(|res| matches!(res, Ok(res) if res.status() == StatusCode::OK))
+            .count();
+        assert_eq!(allowed, 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_throttled_extension() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = app.call(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.response().extensions().get::<Throttled>(),
+            Some(&Throttled(false))
+        );
+        let res = app.call(request()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            res.response().extensions().get::<Throttled>(),
+            Some(&Throttled(true))
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_from_config() {
+        let store = MemoryStore::new();
+        store.deny("127.0.0.3:8080");
+        let addr = MemoryStoreActor::from(store).start();
+        let config = RateLimiterConfig {
+            interval: Duration::from_secs(60),
+            max_requests: 2,
+            prefix: "config:".to_string(),
+            remaining_header: "x-left".to_string(),
+            skipped_methods: vec![Method::HEAD],
+            json_errors: true,
+            counting_mode: CountingMode::PostHoc,
+            access_lists: true,
+            upstream_refund: true,
+            refund_on_error: true,
+            ..RateLimiterConfig::default()
+        };
+        let builder = RateLimiter::new(addr.clone())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_prefix("builder:")
+            .with_remaining_header("x-left")
+            .with_skipped_methods(vec![Method::HEAD])
+            .with_json_errors(true)
+            .with_counting_mode(CountingMode::PostHoc)
+            .with_access_lists(true)
+            .with_upstream_refund(true)
+            .with_refund_on_error(true);
+        let mut configured = test::init_service(
+            App::new()
+                .wrap_fn(|req, srv| {
+                    let fail = req.path() == "/fail";
+                    let fut = srv.call(req);
+                    async move {
+                        if fail {
+                            return Err(actix_web::error::ErrorServiceUnavailable("unavailable"));
+                        }
+                        fut.await
+                    }
+                })
+                .wrap(RateLimiter::from_config(addr.clone(), config))
+                .route("/", web::to(HttpResponse::Ok))
+                .route("/limited", web::to(HttpResponse::TooManyRequests))
+                .route("/fail", web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut built = test::init_service(
+            App::new()
+                .wrap(builder)
+                .route("/", web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let head = || {
+            test::TestRequest::default()
+                .method(Method::HEAD)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+
+        let describe = |(status, headers): (StatusCode, HeaderMap)| {
+            let mut headers: Vec<_> = headers
+                .iter()
+                .map(|(name, value)| format!("{}: {:?}", name, value))
+                .collect();
+            headers.sort();
+            (status, headers)
+        };
+
+        for req in &[request as fn() -> Request, head, request, request] {
+            let configured = describe(send(&mut configured, req()).await);
+            let built = describe(send(&mut built, req()).await);
+            assert_eq!(configured, built);
+        }
+        let (status, headers) = send(&mut configured, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-left"), "0");
+        assert_eq!(header(&headers, "content-type"), "application/json");
+        let denied = test::TestRequest::default()
+            .peer_addr("127.0.0.3:8080".parse().unwrap())
+            .to_request();
+        let (status, _) = send(&mut configured, denied).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        // Requests the service limited itself or failed are given back
+        for (path, client) in &[("/limited", "127.0.0.4:8080"), ("/fail", "127.0.0.5:8080")] {
+            let req = test::TestRequest::get()
+                .uri(path)
+                .peer_addr(client.parse().unwrap())
+                .to_request();
+            send(&mut configured, req).await;
+            let res = addr
+                .send(ActorMessage::Get(format!("config:{}", client)))
+                .await
+                .unwrap();
+            assert_eq!(res.expect_get().await.unwrap(), Some(2));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_access_lists() {
+        let store = MemoryStore::new();
+        store.allow("127.0.0.2:8080");
+        store.deny("127.0.0.3:8080");
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_access_lists(true),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |client: &str| {
+            test::TestRequest::default()
+                .peer_addr(client.parse().unwrap())
+                .to_request()
+        };
+        let count = |key: &str| {
+            let res = addr.send(ActorMessage::Get(key.to_string()));
+            async move { res.await.unwrap().expect_get().await.unwrap() }
+        };
+
+        // Normal clients are limited as usual
+        let (status, _) = send(&mut app, request("127.0.0.1:8080")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("127.0.0.1:8080")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // Allowed clients are never limited
+        for _ in 0..3 {
+            let (status, headers) = send(&mut app, request("127.0.0.2:8080")).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(!headers.contains_key("x-ratelimit-remaining"));
+        }
+        assert_eq!(count("127.0.0.2:8080").await, None);
+        // Denied clients are rejected without consuming anything
+        let (status, _) = send(&mut app, request("127.0.0.3:8080")).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(count("127.0.0.3:8080").await, None);
+        // Lists can change at any time
+        store.unlist("127.0.0.3:8080");
+        let (status, _) = send(&mut app, request("127.0.0.3:8080")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_corrupted_count() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let set = ActorMessage::Set {
+            key: "127.0.0.1:8080".to_string(),
+            value: usize::MAX,
+            expiry: Duration::from_secs(60),
+        };
+        addr.send(set).await.unwrap().expect_set().await.unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "5");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "5");
+    }
+
+    #[actix_rt::test]
+    async fn test_upstream_refund() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let upstream = || {
+            HttpResponse::TooManyRequests()
+                .header("x-ratelimit-limit", "10")
+                .header("x-ratelimit-remaining", "0")
+                .finish()
+        };
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(2)
+                        .with_upstream_refund(true),
+                )
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/upstream", web::get().to(upstream)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        for _ in 0..3 {
+            let req = test::TestRequest::get()
+                .uri("/upstream")
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let (status, headers) = send(&mut app, req).await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(header(&headers, "x-ratelimit-limit"), "10");
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+            assert!(!headers.contains_key("x-ratelimit-reset"));
+        }
+        let res = addr
+            .send(ActorMessage::Get("127.0.0.1:8080".to_string()))
+            .await
+            .unwrap();
+        assert_eq!(res.expect_get().await.unwrap(), Some(1));
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+    }
+
+    #[actix_rt::test]
+    async fn test_upstream_refund_tiers() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(100)
+                        .with_upstream_refund(true)
+                        .with_tier_reservations(3, |_| "premium".to_string(), vec![("premium", 2)]),
+                )
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/upstream", web::get().to(HttpResponse::TooManyRequests)),
+        )
+        .await;
+
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        // Neither the pool nor the reservation are used up by refunded requests
+        for _ in 0..4 {
+            let req = test::TestRequest::get()
+                .uri("/upstream")
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let (status, _) = send(&mut app, req).await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        }
+        let res = addr.send(ActorMessage::Get("tiers".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(2));
+        let res = addr
+            .send(ActorMessage::Get("tiers:premium".to_string()))
+            .await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(1));
+        for _ in 0..2 {
+            let (status, _) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_refund_on_error() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(|req, srv| {
+                    let fail = req.path() == "/fail";
+                    let fut = srv.call(req);
+                    async move {
+                        if fail {
+                            return Err(actix_web::error::ErrorServiceUnavailable("unavailable"));
+                        }
+                        fut.await
+                    }
+                })
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(2)
+                        .with_refund_on_error(true),
+                )
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/fail", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        for _ in 0..3 {
+            let req = test::TestRequest::get()
+                .uri("/fail")
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request();
+            let (status, _) = send(&mut app, req).await;
+            assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+            let res = addr
+                .send(ActorMessage::Get("127.0.0.1:8080".to_string()))
+                .await
+                .unwrap();
+            assert_eq!(res.expect_get().await.unwrap(), Some(1));
+        }
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+    }
+
+    #[actix_rt::test]
+    async fn test_refund_concurrent() {
+        // Counts another request of the client right before the refund reaches the store
+        let store = Store::new(MemoryStore::new());
+        let inner = store.clone();
+        let limiter: RateLimiter<KvStore<MemoryStore>> =
+            RateLimiter::with_stores(vec![StoreHandle::Direct(Arc::new(move |msg| {
+                let update = match &msg {
+                    ActorMessage::Grant { key, .. } => inner.handle(ActorMessage::Update {
+                        key: key.clone(),
+                        value: 1,
+                    }),
+                    _ => return inner.handle(msg),
+                };
+                let grant = inner.handle(msg);
+                ActorResponse::Grant(Box::pin(async move {
+                    update.expect_update().await?;
+                    grant.expect_grant().await
+                }))
+            }))]);
+        let mut app = test::init_service(
+            App::new()
+                .wrap_fn(|req, srv| {
+                    let fail = req.path() == "/fail";
+                    let fut = srv.call(req);
+                    async move {
+                        if fail {
+                            return Err(actix_web::error::ErrorServiceUnavailable("unavailable"));
+                        }
+                        fut.await
+                    }
+                })
+                .wrap(
+                    limiter
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(3)
+                        .with_refund_on_error(true),
+                )
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/fail", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        let req = test::TestRequest::get()
+            .uri("/fail")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let (status, _) = send(&mut app, req).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        // Neither the refund nor the concurrent request is lost
+        let res = store.handle(ActorMessage::Get("127.0.0.1:8080".to_string()));
+        assert_eq!(res.expect_get().await.unwrap(), Some(1));
+    }
+
+    #[actix_rt::test]
+    async fn test_sliding_window_counter() {
+        let clock = ManualClock::new();
+        let limiter = |algorithm| {
+            let store = MemoryStore::new().with_clock(clock.clone());
+            RateLimiter::new(MemoryStoreActor::from(store).start())
+                .with_interval(Duration::from_secs(60))
+                .with_max_requests(10)
+                .with_algorithm(algorithm)
+        };
+        let mut fixed = test::init_service(
+            App::new()
+                .wrap(limiter(Algorithm::FixedWindow))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let mut sliding = test::init_service(
+            App::new()
+                .wrap(limiter(Algorithm::SlidingWindowCounter))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for _ in 0..10 {
+            assert_eq!(send(&mut fixed, request()).await.0, StatusCode::OK);
+            assert_eq!(send(&mut sliding, request()).await.0, StatusCode::OK);
+        }
+        let (status, _) = send(&mut fixed, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, headers) = send(&mut sliding, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+
+        // Right after the boundary the fixed window starts over, while the sliding window still
+        // covers nearly all of the previous one
+        clock.advance(Duration::from_secs(61));
+        let (status, headers) = send(&mut fixed, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "9");
+        let (status, headers) = send(&mut sliding, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "59");
+        assert_eq!(header(&headers, "retry-after"), "5");
+
+        // Halfway through, half of the previous window is left
+        clock.advance(Duration::from_secs(29));
+        let (status, headers) = send(&mut sliding, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "30");
+        for remaining in &["3", "2", "1", "0"] {
+            let (status, headers) = send(&mut sliding, request()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+        }
+        let (status, _) = send(&mut sliding, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_sliding_window_concurrent() {
+        // Other requests of the client take the room left between the lookup and the count
+        let store = Store::new(MemoryStore::new());
+        let limiter: RateLimiter<KvStore<MemoryStore>> =
+            RateLimiter::with_stores(vec![StoreHandle::Direct(Arc::new(move |msg| match msg {
+                ActorMessage::Slide { max, interval, .. } => {
+                    ActorResponse::Slide(Box::pin(async move {
+                        Ok(SlidingCount {
+                            counted: false,
+                            current: max,
+                            previous: 0,
+                            left: interval,
+                        })
+                    }))
+                }
+                msg => store.handle(msg),
+            }))]);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    limiter
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(3)
+                        .with_algorithm(Algorithm::SlidingWindowCounter),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+        // Until two thirds of the current window slid out
+        assert_eq!(header(&headers, "retry-after"), "80");
+    }
+
+    #[actix_rt::test]
+    async fn test_sliding_window_separate_messages() {
+        // The key-value store turns down `Slide`, so windows are counted with `Set` and `Update`
+        let store = Store::new(MemoryStore::new());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::direct(store.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(3)
+                        .with_algorithm(Algorithm::SlidingWindowCounter),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for remaining in &["2", "1", "0"] {
+            let (status, headers) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+        }
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let res = store.handle(ActorMessage::Get("127.0.0.1:8080".to_string()));
+        assert_eq!(res.expect_get().await.unwrap(), Some(0));
+    }
+
+    #[test]
+    fn test_sliding_window_retry_after() {
+        let interval = Duration::from_secs(60);
+        let window = SlidingWindow {
+            current: 10,
+            previous: 0,
+            left: Duration::from_secs(30),
+            opening: false,
+        };
+        assert_eq!(window.estimate(interval), 10);
+        // 6s into the next window, the full one covers 54s worth of the sliding window
+        assert_eq!(window.retry_after(10, 1, interval), Duration::from_secs(36));
+        let window = SlidingWindow {
+            current: 2,
+            previous: 4,
+            left: Duration::from_secs(30),
+            opening: false,
+        };
+        assert_eq!(window.estimate(interval), 4);
+        assert_eq!(window.retry_after(10, 1, interval), Duration::from_secs(0));
+    }
+
+    #[actix_rt::test]
+    async fn test_header_hook() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_header_hook(|headers, decision| {
+                            let value = format!("{}:{}", decision.throttled, decision.remaining);
+                            headers.insert(
+                                HeaderName::from_static("x-decision"),
+                                HeaderValue::from_str(&value).unwrap(),
+                            );
+                            headers.remove("x-ratelimit-reset");
+                        }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-decision"), "false:0");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+        assert!(!headers.contains_key("x-ratelimit-reset"));
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-decision"), "true:0");
+        assert!(!headers.contains_key("x-ratelimit-reset"));
+    }
+
+    #[actix_rt::test]
+    async fn test_bypass() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_bypass(|req| {
+                            req.headers()
+                                .get("x-bypass-token")
+                                .map_or(false, |token| token == "secret")
+                        }),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let with_token = |token| {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .header("x-bypass-token", token)
+                .to_request()
+        };
+
+        for _ in 0..3 {
+            let (status, headers) = send(&mut app, with_token("secret")).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(!headers.contains_key("x-ratelimit-remaining"));
+        }
+        let (status, headers) = send(&mut app, with_token("guess")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+        let (status, _) = send(&mut app, with_token("guess")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = send(&mut app, with_token("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_counting_mode() {
+        for &(mode, first, second) in &[
+            (CountingMode::Preemptive, "2", "1"),
+            (CountingMode::PostHoc, "3", "2"),
+        ] {
+            let store = MemoryStore::new();
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(
+                        RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                            .with_interval(Duration::from_secs(60))
+                            .with_max_requests(3)
+                            .with_counting_mode(mode),
+                    )
+                    .route("/", web::get().to(HttpResponse::Ok)),
+            )
+            .await;
+
+            for remaining in &[first, second] {
+                let (status, headers) = send(&mut app, request()).await;
+                assert_eq!(status, StatusCode::OK);
+                assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+            }
+            let (status, _) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+            let (status, headers) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_precomputed_key() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_prefix("api:"),
+                )
+                .wrap_fn(|req, srv| {
+                    if let Some(tenant) = req.headers().get("x-tenant") {
+                        let key = format!("tenant:{}", tenant.to_str().unwrap());
+                        req.extensions_mut().insert(RateLimitKey(key));
+                    }
+                    srv.call(req)
+                })
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let with_tenant = |tenant| {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .header("x-tenant", tenant)
+                .to_request()
+        };
+
+        // Tenants are counted apart even though they share an address
+        let (status, _) = send(&mut app, with_tenant("a")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, with_tenant("b")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, with_tenant("a")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // Without the extension the identifier is used
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        for key in &["api:tenant:a", "api:tenant:b", "api:127.0.0.1:8080"] {
+            let res = addr.send(ActorMessage::Get(key.to_string())).await.unwrap();
+            assert_eq!(res.expect_get().await.unwrap(), Some(0));
+        }
+    }
+
+    #[test]
+    fn test_subject_attribute() {
+        let cert = ClientCertificate {
+            subject: r"CN=alice\, admin,OU=ops+O=Acme\2C Inc.,C=US".to_string(),
+            serial: "01:a4".to_string(),
+        };
+        assert_eq!(cert.subject_attribute("CN").unwrap(), "alice, admin");
+        assert_eq!(cert.subject_attribute("cn").unwrap(), "alice, admin");
+        assert_eq!(cert.subject_attribute("O").unwrap(), "Acme, Inc.");
+        assert_eq!(cert.subject_attribute("C").unwrap(), "US");
+        assert_eq!(cert.subject_attribute("L"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_client_cert_identifier() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_client_cert_identifier(CertField::Attribute("CN".to_string())),
+                )
+                // Stands in for the server setting the certificate on connect
+                .wrap_fn(|req, srv| {
+                    if let Some(cn) = req.headers().get("x-test-cn") {
+                        req.extensions_mut().insert(ClientCertificate {
+                            subject: format!("CN={},O=Acme", cn.to_str().unwrap()),
+                            serial: "01".to_string(),
+                        });
+                    }
+                    srv.call(req)
+                })
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let with_cn = |cn| {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .header("x-test-cn", cn)
+                .to_request()
+        };
+
+        let (status, _) = send(&mut app, with_cn("alice")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, with_cn("bob")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, with_cn("alice")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let res = addr.send(ActorMessage::Get("cert:alice".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(0));
+        // Without a certificate the client can't be identified
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[actix_rt::test]
+    async fn test_structured_header() {
+        let clock = ManualClock::new();
+        let store = MemoryStore::new().with_clock(clock.clone());
+        let addr = MemoryStoreActor::from(store).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(2)
+                        .with_header_style(HeaderStyle::Structured),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            header(&headers, "ratelimit"),
+            "limit=2, remaining=1, reset=60"
+        );
+        assert!(!headers.contains_key("x-ratelimit-limit"));
+        assert!(!headers.contains_key("x-ratelimit-remaining"));
+        assert!(!headers.contains_key("x-ratelimit-reset"));
+        clock.advance(Duration::from_secs(8));
+        send(&mut app, request()).await;
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            header(&headers, "ratelimit"),
+            "limit=2, remaining=0, reset=52"
+        );
+        assert!(!headers.contains_key("x-ratelimit-limit"));
+        assert_eq!(header(&headers, "retry-after"), "52");
+    }
+
+    #[test]
+    fn test_path_matches() {
+        assert!(path_matches("/api", "/api"));
+        assert!(path_matches("/api", "/api/users"));
+        assert!(!path_matches("/api", "/apis"));
+        assert!(path_matches("/api/", "/api/users"));
+        assert!(path_matches("/assets/*.css", "/assets/site.css"));
+        assert!(!path_matches("/assets/*.css", "/assets/css/site.css"));
+        assert!(path_matches("/users/*/avatar", "/users/42/avatar"));
+        assert!(!path_matches("/users/*/avatar", "/users/42"));
+        assert!(path_matches("/static/**", "/static/js/app.js"));
+        assert!(path_matches("**.png", "/img/logo.png"));
+    }
+
+    #[actix_rt::test]
+    async fn test_path_filter() {
+        for (filter, limited, passed) in [
+            (
+                PathFilter::include(vec!["/api"]),
+                "/api/users",
+                "/static/app.js",
+            ),
+            (
+                PathFilter::exclude(vec!["/static/**"]),
+                "/api/users",
+                "/static/app.js",
+            ),
+        ] {
+            let store = MemoryStore::new();
+            let mut app = test::init_service(
+                App::new()
+                    .wrap(
+                        RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                            .with_interval(Duration::from_secs(60))
+                            .with_max_requests(1)
+                            .with_path_filter(filter),
+                    )
+                    .default_service(web::to(HttpResponse::Ok)),
+            )
+            .await;
+            let request = |path| {
+                test::TestRequest::with_uri(path)
+                    .peer_addr("127.0.0.1:8080".parse().unwrap())
+                    .to_request()
+            };
+
+            let (status, headers) = send(&mut app, request(limited)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+            let (status, _) = send(&mut app, request(limited)).await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+            // Paths the filter does not select skip the store
+            for _ in 0..3 {
+                let (status, headers) = send(&mut app, request(passed)).await;
+                assert_eq!(status, StatusCode::OK);
+                assert!(!headers.contains_key("x-ratelimit-remaining"));
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_without_path_filter() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1),
+                )
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        // Every path counts against the same limit by default
+        let request = |path| {
+            test::TestRequest::with_uri(path)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+        let (status, _) = send(&mut app, request("/static/app.js")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("/api/users")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    /// Store which takes the given time to answer every lookup
+    struct SlowStore(Duration);
+    impl Actor for SlowStore {
+        type Context = actix::Context<Self>;
+    }
+    impl Handler<ActorMessage> for SlowStore {
+        type Result = ActorResponse;
+        fn handle(&mut self, msg: ActorMessage, _: &mut Self::Context) -> Self::Result {
+            let delay = self.0;
+            match msg {
+                ActorMessage::Get(_) => ActorResponse::Get(Box::pin(async move {
+                    actix_rt::time::delay_for(delay).await;
+                    Ok(None)
+                })),
+                ActorMessage::Increment { .. } => ActorResponse::Increment(Box::pin(async move {
+                    actix_rt::time::delay_for(delay).await;
+                    Ok(1)
+                })),
+                msg => ActorResponse::failed(&msg, ARError::Disconnected),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_store_timeout() {
+        let addr = SlowStore(Duration::from_millis(500)).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_store_timeout(Duration::from_millis(50)),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let started = Instant::now();
+        let (status, headers) = send(&mut app, request()).await;
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(header(&headers, "retry-after"), "1");
+    }
+
+    #[actix_rt::test]
+    async fn test_store_timeout_concurrency() {
+        // Taking the in-flight slot is bound by the timeout as well
+        let addr = SlowStore(Duration::from_secs(3600)).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr)
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_max_concurrent(2)
+                        .with_store_timeout(Duration::from_millis(50)),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[actix_rt::test]
+    async fn test_deny_unknown() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        // The seeded client has two requests left in its window
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "127.0.0.1:8080".to_string(),
+                value: 2,
+                expiry: Duration::from_secs(60),
+            })
+            .await
+            .unwrap();
+        res.expect_set().await.unwrap();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_registration_mode(RegistrationMode::DenyUnknown),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        let unknown = test::TestRequest::default()
+            .peer_addr("127.0.0.2:8080".parse().unwrap())
+            .to_request();
+        let (status, headers) = send(&mut app, unknown).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert!(!headers.contains_key("x-ratelimit-remaining"));
+        // Nothing was provisioned for the unknown client
+        let res = addr
+            .send(ActorMessage::Get("127.0.0.2:8080".to_string()))
+            .await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_clear_all_tenant() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let keys = [
+            "tenant123:127.0.0.1:8080",
+            "tenant123:127.0.0.2:8080",
+            "tenant456:127.0.0.1:8080",
+        ];
+        for key in &keys {
+            let res = addr
+                .send(ActorMessage::Set {
+                    key: key.to_string(),
+                    value: 5,
+                    expiry: Duration::from_secs(60),
+                })
+                .await
+                .unwrap();
+            res.expect_set().await.unwrap();
+        }
+
+        assert!(RateLimiter::clear_all(&addr, "").await.is_err());
+        assert_eq!(store.len(), 3);
+        let removed = RateLimiter::clear_all(&addr, "tenant123:").await.unwrap();
+        assert_eq!(removed, 2);
+        for (key, expected) in keys.iter().zip(&[None, None, Some(5)]) {
+            let res = addr.send(ActorMessage::Get(key.to_string())).await.unwrap();
+            assert_eq!(res.expect_get().await.unwrap(), *expected);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_classified_identifier() {
+        #[derive(PartialEq, Eq, Hash)]
+        enum KeyKind {
+            Ip,
+            ApiKey,
+            Internal,
+        }
+        let mut limits = HashMap::new();
+        limits.insert(KeyKind::Ip, (1, Duration::from_secs(60)));
+        limits.insert(KeyKind::ApiKey, (3, Duration::from_secs(3600)));
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(10))
+                        .with_max_requests(2)
+                        .with_classified_identifier(
+                            |req| match req.headers().get("x-api-key") {
+                                Some(key) if key == "internal" => {
+                                    Ok(("internal".to_string(), KeyKind::Internal))
+                                }
+                                Some(key) => {
+                                    let key = key.to_str().unwrap();
+                                    Ok((format!("key:{}", key), KeyKind::ApiKey))
+                                }
+                                None => Ok(("ip".to_string(), KeyKind::Ip)),
+                            },
+                            limits,
+                        ),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let with_key = |key| {
+            test::TestRequest::default()
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .header("x-api-key", key)
+                .to_request()
+        };
+
+        let (status, headers) = send(&mut app, with_key("secret")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "3");
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "3600");
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "1");
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "60");
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // Kinds without an entry get the default limit
+        let (status, headers) = send(&mut app, with_key("internal")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "2");
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "10");
+    }
+
+    #[actix_rt::test]
+    async fn test_streaming_response() {
+        use actix_web::web::Bytes;
+        use futures::channel::mpsc;
+
+        let (events, stream) = mpsc::unbounded::<Result<Bytes, AWError>>();
+        let stream = Rc::new(RefCell::new(Some(stream)));
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5),
+                )
+                .route(
+                    "/events",
+                    web::get().to(move || {
+                        let stream = stream.borrow_mut().take().unwrap();
+                        HttpResponse::Ok()
+                            .content_type("text/event-stream")
+                            .streaming(stream)
+                    }),
+                ),
+        )
+        .await;
+
+        // The response comes back while the stream is still open
+        let req = test::TestRequest::with_uri("/events")
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request();
+        let deadline = actix_rt::time::delay_for(Duration::from_secs(1));
+        let res = match future::select(Box::pin(app.call(req)), deadline).await {
+            Either::Left((res, _)) => res.unwrap(),
+            Either::Right(_) => panic!("the limiter waited for the stream to end"),
+        };
+        assert_eq!(header(res.headers(), "x-ratelimit-remaining"), "4");
+
+        for event in &["data: 1\n\n", "data: 2\n\n"] {
+            events
+                .unbounded_send(Ok(Bytes::from_static(event.as_bytes())))
+                .unwrap();
+        }
+        drop(events);
+        let body = test::read_body(res).await;
+        assert_eq!(body, Bytes::from_static(b"data: 1\n\ndata: 2\n\n"));
+        // The events were not charged
+        let res = addr
+            .send(ActorMessage::Get("127.0.0.1:8080".to_string()))
+            .await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(4));
+    }
+
+    #[actix_rt::test]
+    async fn test_readiness() {
+        let store = MemoryStore::new();
+        let readiness = Readiness::new(false);
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_readiness(readiness.clone()),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let pending = future::poll_fn(|cx| Poll::Ready(app.poll_ready(cx).is_pending()));
+        assert!(pending.await);
+        let started = Instant::now();
+        let handle = readiness.clone();
+        actix_rt::spawn(async move {
+            actix::clock::delay_for(Duration::from_millis(50)).await;
+            handle.set_ready(true);
+        });
+        // Waits for the store rather than failing the request
+        future::poll_fn(|cx| app.poll_ready(cx)).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+
+        readiness.set_ready(false);
+        let pending = future::poll_fn(|cx| Poll::Ready(app.poll_ready(cx).is_pending()));
+        assert!(pending.await);
+    }
+
+    #[actix_rt::test]
+    async fn test_method_limits() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let mut limits = HashMap::new();
+        limits.insert(Method::GET, (3, Duration::from_secs(60)));
+        limits.insert(Method::POST, (1, Duration::from_secs(10)));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_method_limits(limits),
+                )
+                .route("/", web::route().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |method: Method| {
+            test::TestRequest::default()
+                .method(method)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+
+        let (status, headers) = send(&mut app, request(Method::POST)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "1");
+        let (status, _) = send(&mut app, request(Method::POST)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // GET requests are counted apart, against a larger limit
+        for remaining in (0..3).rev() {
+            let (status, headers) = send(&mut app, request(Method::GET)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "x-ratelimit-limit"), "3");
+            assert_eq!(
+                header(&headers, "x-ratelimit-remaining"),
+                remaining.to_string()
+            );
+        }
+        let (status, _) = send(&mut app, request(Method::GET)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // Unlisted methods fall back to the default limit
+        let (status, headers) = send(&mut app, request(Method::PUT)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "5");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+        for (key, remaining) in [
+            ("127.0.0.1:8080:POST", 0),
+            ("127.0.0.1:8080:GET", 0),
+            ("127.0.0.1:8080", 4),
+        ] {
+            let res = addr.send(ActorMessage::Get(key.to_string())).await;
+            assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(remaining));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_direct_store() {
+        let store = Store::new(MemoryStore::new());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::direct(store.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(2),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        // The count is visible to code using the store outside of the middleware
+        assert_eq!(store.get("127.0.0.1:8080").await.unwrap(), Some(1));
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_count_hook() {
+        let store = MemoryStore::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_count_hook(move |event| recorded.lock().unwrap().push(event.clone())),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let event = |key: &str, new_client, remaining| CountEvent {
+            key: key.to_string(),
+            new_client,
+            max_requests: 5,
+            remaining,
+            exhausted: false,
+        };
+
+        send(&mut app, request()).await;
+        assert_eq!(*events.lock().unwrap(), [event("127.0.0.1:8080", true, 4)]);
+        send(&mut app, request()).await;
+        assert_eq!(events.lock().unwrap()[1], event("127.0.0.1:8080", false, 3));
+        let other = test::TestRequest::default()
+            .peer_addr("127.0.0.2:8080".parse().unwrap())
+            .to_request();
+        send(&mut app, other).await;
+        assert_eq!(events.lock().unwrap()[2], event("127.0.0.2:8080", true, 4));
+        assert_eq!(events.lock().unwrap().len(), 3);
+    }
+
+    #[actix_rt::test]
+    async fn test_upgrade_limit() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(5)
+                        .with_upgrade_limit(1, Duration::from_secs(60)),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let upgrade = |connection: &str| {
+            test::TestRequest::default()
+                .header("upgrade", "websocket")
+                .header("connection", connection)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+
+        let (status, headers) = send(&mut app, upgrade("keep-alive, Upgrade")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "1");
+        let (status, _) = send(&mut app, upgrade("Upgrade")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // A plain GET from the same client is counted apart, against the default limit
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "5");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+        // So is one which names a protocol without asking to upgrade
+        let (status, headers) = send(&mut app, upgrade("keep-alive")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "3");
+    }
+
+    #[actix_rt::test]
+    async fn test_grant() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(2),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for _ in 0..2 {
+            let (status, _) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+        }
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        // Capped at the limit, however much is granted
+        let granted = RateLimiter::grant(&addr, "127.0.0.1:8080", 5, 2).await;
+        assert_eq!(granted.unwrap(), Some(2));
+        for remaining in &["1", "0"] {
+            let (status, headers) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+        }
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        let granted = RateLimiter::grant(&addr, "127.0.0.2:8080", 1, 2).await;
+        assert_eq!(granted.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_fingerprint_identifier() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_fingerprint_identifier(&["user-agent", "accept-language"]),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |peer: &str, headers: &[(&str, &str)]| {
+            let mut req = test::TestRequest::default().peer_addr(peer.parse().unwrap());
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            req.to_request()
+        };
+        let device = [("user-agent", "curl/7.68"), ("accept-language", "en")];
+
+        let (status, _) = send(&mut app, request("127.0.0.1:8080", &device)).await;
+        assert_eq!(status, StatusCode::OK);
+        // The same device on another connection shares the bucket
+        let (status, _) = send(&mut app, request("127.0.0.1:9090", &device)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        // Another device, or the same one from elsewhere, has a bucket of its own
+        let other = [("user-agent", "curl/7.68"), ("accept-language", "de")];
+        let (status, _) = send(&mut app, request("127.0.0.1:8080", &other)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("127.0.0.2:8080", &device)).await;
+        assert_eq!(status, StatusCode::OK);
+        // A missing header is told apart from an empty one, each time the same way
+        let missing = [("user-agent", "curl/7.68")];
+        let (status, _) = send(&mut app, request("127.0.0.1:8080", &missing)).await;
+        assert_eq!(status, StatusCode::OK);
+        let empty = [("user-agent", "curl/7.68"), ("accept-language", "")];
+        let (status, _) = send(&mut app, request("127.0.0.1:8080", &empty)).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("127.0.0.1:8080", &missing)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_rejection_body() {
+        let page = "<html><body>Slow down</body></html>";
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_json_errors(true)
+                        .with_rejection_body(page, ContentType::html()),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let res = test::call_service(&mut app, request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, Bytes::new());
+        let res = test::call_service(&mut app, request()).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(res.headers(), "content-type"), "text/html");
+        assert_eq!(header(res.headers(), "x-ratelimit-limit"), "1");
+        assert_eq!(header(res.headers(), "x-ratelimit-remaining"), "0");
+        assert!(res.headers().contains_key(RETRY_AFTER));
+        assert_eq!(
+            test::read_body(res).await,
+            Bytes::from_static(page.as_bytes())
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_shared_limits() {
+        let limits = SharedLimits::new(1, Duration::from_secs(60));
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_shared_limits(limits.clone()),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let client = |peer: &str| {
+            test::TestRequest::default()
+                .peer_addr(peer.parse().unwrap())
+                .to_request()
+        };
+
+        let (status, headers) = send(&mut app, client("127.0.0.1:8080")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "1");
+        let (status, _) = send(&mut app, client("127.0.0.1:8080")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        // Raised while the app runs, windows opened from now on get the new limit
+        limits.set(3, Duration::from_secs(60));
+        for remaining in &["2", "1", "0"] {
+            let (status, headers) = send(&mut app, client("127.0.0.2:8080")).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "x-ratelimit-limit"), "3");
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+        }
+        let (status, _) = send(&mut app, client("127.0.0.2:8080")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_builder() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::builder(MemoryStoreActor::from(store.clone()).start())
+                        .interval(Duration::from_secs(60))
+                        .max_requests(2)
+                        .build(),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for remaining in &["1", "0"] {
+            let (status, headers) = send(&mut app, request()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "x-ratelimit-limit"), "2");
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), *remaining);
+        }
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let reset: u64 = header(&headers, "x-ratelimit-reset").parse().unwrap();
+        assert!(reset > 58 && reset <= 60);
+    }
+
+    #[actix_rt::test]
+    async fn test_retry_after_format() {
+        // RFC 7231's example date, less the 90 seconds to wait
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_687);
+        let retry_after = Duration::from_millis(89_500);
+        let value = retry_after_value(RetryAfterFormat::HttpDate, retry_after, now).unwrap();
+        assert_eq!(value, "Sun, 06 Nov 1994 08:49:37 GMT");
+        let value = retry_after_value(RetryAfterFormat::Seconds, retry_after, now).unwrap();
+        assert_eq!(value, "90");
+
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_retry_after_format(RetryAfterFormat::HttpDate),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains_key(RETRY_AFTER));
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let date: HttpDate = header(&headers, "retry-after").parse().unwrap();
+        let retry_at = SystemTime::from(date);
+        assert!(retry_at > SystemTime::now());
+        assert!(retry_at <= SystemTime::now() + Duration::from_secs(61));
+    }
+
+    #[actix_rt::test]
+    async fn test_exhausted_event() {
+        let store = MemoryStore::new();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(2)
+                        .with_count_hook(move |event| recorded.lock().unwrap().push(event.clone())),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let mut statuses = Vec::new();
+        for _ in 0..5 {
+            statuses.push(send(&mut app, request()).await.0);
+        }
+        assert_eq!(statuses[1], StatusCode::OK);
+        assert_eq!(statuses[2], StatusCode::TOO_MANY_REQUESTS);
+        // Only the accepted requests are counted, and only the second one crossed the limit
+        let exhausted: Vec<_> = events.lock().unwrap().iter().map(|e| e.exhausted).collect();
+        assert_eq!(exhausted, [false, true]);
+    }
+
+    #[actix_rt::test]
+    async fn test_region_limits() {
+        #[derive(PartialEq, Eq, Hash)]
+        enum Region {
+            Eu,
+            Us,
+            Other,
+        }
+        let store = MemoryStore::new();
+        let mut limits = HashMap::new();
+        limits.insert(Region::Eu, (1, Duration::from_secs(60)));
+        limits.insert(Region::Us, (2, Duration::from_secs(60)));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(3)
+                        .with_region_limits(
+                            |ip: IpAddr| match ip.to_string().as_str() {
+                                "127.0.0.1" => Region::Eu,
+                                "127.0.0.2" => Region::Us,
+                                _ => Region::Other,
+                            },
+                            limits,
+                        ),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |peer: &str| {
+            test::TestRequest::default()
+                .peer_addr(peer.parse().unwrap())
+                .to_request()
+        };
+
+        for (peer, max_requests) in &[
+            ("127.0.0.1:8080", 1),
+            ("127.0.0.2:8080", 2),
+            ("127.0.0.3:8080", 3),
+        ] {
+            let (status, headers) = send(&mut app, request(peer)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(
+                header(&headers, "x-ratelimit-limit"),
+                max_requests.to_string()
+            );
+            for _ in 1..*max_requests {
+                let (status, _) = send(&mut app, request(peer)).await;
+                assert_eq!(status, StatusCode::OK);
+            }
+            let (status, _) = send(&mut app, request(peer)).await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_clone_into_factories() {
+        fn assert_send<S: Send + Clone>(_: &S) {}
+        let store = MemoryStore::new();
+        let limiter = RateLimiter::new(MemoryStoreActor::from(store).start())
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(3)
+            .with_count_hook(|_| ());
+        assert_send(&limiter);
+        assert!(format!("{:?}", limiter).contains("max_requests: 3"));
+
+        let factory = move || {
+            App::new()
+                .wrap(limiter.clone())
+                .route("/", web::get().to(HttpResponse::Ok))
+        };
+        let mut first = test::init_service(factory()).await;
+        let mut second = test::init_service(factory()).await;
+        // Every app counts against the same store
+        let (status, headers) = send(&mut first, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "2");
+        let (_, headers) = send(&mut second, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        send(&mut first, request()).await;
+        let (status, _) = send(&mut second, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_dry_run() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(2)
+                        .with_dry_run(true),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        for remaining in &["1", "0"] {
+            let res = app.call(request()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(header(res.headers(), "x-ratelimit-remaining"), *remaining);
+            assert_eq!(
+                res.response().extensions().get::<Throttled>(),
+                Some(&Throttled(false))
+            );
+        }
+        // Over the limit, but served and flagged
+        for _ in 0..2 {
+            let res = app.call(request()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(header(res.headers(), "x-ratelimit-remaining"), "0");
+            assert!(!res.headers().contains_key(RETRY_AFTER));
+            assert_eq!(
+                res.response().extensions().get::<Throttled>(),
+                Some(&Throttled(true))
+            );
+        }
+        let res = addr
+            .send(ActorMessage::Get("127.0.0.1:8080".to_string()))
+            .await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(0));
+    }
+
+    #[actix_rt::test]
+    async fn test_rules() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store).start()).with_rules(vec![
+                        Rule::new(
+                            |req| Some(format!("ip:{}", req.peer_addr()?.ip())),
+                            3,
+                            Duration::from_secs(3600),
+                        ),
+                        Rule::new(
+                            |req| {
+                                let key = req.headers().get("x-api-key")?.to_str().ok()?;
+                                Some(format!("key:{}", key))
+                            },
+                            2,
+                            Duration::from_secs(60),
+                        ),
+                    ]),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |peer: &str, key: Option<&str>| {
+            let mut req = test::TestRequest::default().peer_addr(peer.parse().unwrap());
+            if let Some(key) = key {
+                req = req.header("x-api-key", key);
+            }
+            req.to_request()
+        };
+
+        // The api key runs out first, whichever address it is used from
+        let (status, headers) = send(&mut app, request("127.0.0.1:8080", Some("a"))).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "2");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        assert_eq!(header(&headers, "ratelimit-policy"), "3;w=3600, 2;w=60");
+        let (status, _) = send(&mut app, request("127.0.0.2:8080", Some("a"))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, headers) = send(&mut app, request("127.0.0.3:8080", Some("a"))).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "2");
+
+        // The address runs out with fresh keys, or none at all
+        let (status, headers) = send(&mut app, request("127.0.0.1:8080", None)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "3");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        let (status, _) = send(&mut app, request("127.0.0.1:8080", Some("b"))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, headers) = send(&mut app, request("127.0.0.1:8080", Some("c"))).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "3");
+        // Neither of the keys was charged for the rejected request
+        let (status, _) = send(&mut app, request("127.0.0.4:8080", Some("c"))).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("127.0.0.5:8080", Some("c"))).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_rules_overrides() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store).start())
+                        .with_adaptive(|_| 100)
+                        .with_interval_fn(|_| Duration::from_secs(1))
+                        .with_rules(vec![Rule::new(
+                            |req| Some(format!("ip:{}", req.peer_addr()?.ip())),
+                            1,
+                            Duration::from_secs(3600),
+                        )]),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // The rule keeps its own limit and interval
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "1");
+        assert_eq!(header(&headers, "ratelimit-policy"), "1;w=3600");
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_default_exclusions() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_default_exclusions()
+                        .with_exclusions(&["/status"]),
+                )
+                .route("/health", web::get().to(HttpResponse::Ok))
+                .route("/status", web::get().to(HttpResponse::Ok))
+                .route("/api", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |path: &str| {
+            test::TestRequest::with_uri(path)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+
+        for _ in 0..3 {
+            for path in &["/health", "/status"] {
+                let (status, headers) = send(&mut app, request(path)).await;
+                assert_eq!(status, StatusCode::OK);
+                assert!(!headers.contains_key("x-ratelimit-remaining"));
+            }
+        }
+        // The store was never consulted for the excluded paths
+        let res = addr
+            .send(ActorMessage::Get("127.0.0.1:8080".to_string()))
+            .await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), None);
+        let (status, _) = send(&mut app, request("/api")).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(&mut app, request("/api")).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        let (status, _) = send(&mut app, request("/health")).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[actix_rt::test]
+    async fn test_window_anchor() {
+        // 50 seconds into a minute window
+        let now = UNIX_EPOCH + Duration::from_secs(1_599_999_960 + 50);
+        assert_eq!(1_599_999_960 % 60, 0);
+        let minute = Duration::from_secs(60);
+        assert_eq!(until_boundary(now, minute), Duration::from_secs(10));
+        let now = now + Duration::from_millis(500);
+        assert_eq!(until_boundary(now, minute), Duration::from_millis(9500));
+        // On the boundary a whole window is left
+        assert_eq!(until_boundary(UNIX_EPOCH + minute * 100, minute), minute);
+
+        let limiter = |anchor| {
+            RateLimiter::new(MemoryStoreActor::from(MemoryStore::new()).start())
+                .with_interval(Duration::from_secs(3600))
+                .with_max_requests(5)
+                .with_window_anchor(anchor)
+        };
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter(WindowAnchor::ClockAligned))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let expected = until_boundary(SystemTime::now(), Duration::from_secs(3600)).as_secs();
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        let reset: u64 = header(&headers, "x-ratelimit-reset").parse().unwrap();
+        assert!(reset <= expected && reset + 2 >= expected);
+
+        let mut app = test::init_service(
+            App::new()
+                .wrap(limiter(WindowAnchor::FirstRequest))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let (_, headers) = send(&mut app, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "3600");
+    }
+
+    #[test]
+    fn test_bypass_token() {
+        let at = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let token = bypass_token("secret", "/search", at);
+        assert_eq!(token, "1600000000.6e08a896e71cc5f44c2b6ec0b81e54597e945b33");
+        let max_age = Duration::from_secs(300);
+        assert!(valid_bypass_token(
+            b"secret",
+            "/search",
+            token.as_bytes(),
+            max_age,
+            at
+        ));
+        assert!(!valid_bypass_token(
+            b"secret",
+            "/",
+            token.as_bytes(),
+            max_age,
+            at
+        ));
+        // Times past what `SystemTime` holds are turned down rather than overflowing
+        for token in &[
+            "18446744073709551615.6e08a896e71cc5f44c2b6ec0b81e54597e945b33",
+            "18446744073709551615.",
+            "1600000000.6e08a896e71cc5f44c2b6ec0b81e54597e945b3",
+            "1600000000.zz",
+        ] {
+            assert!(!valid_bypass_token(
+                b"secret",
+                "/search",
+                token.as_bytes(),
+                max_age,
+                at
+            ));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_signed_bypass() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(1)
+                        .with_signed_bypass("secret", Duration::from_secs(300)),
+                )
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route("/other", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let with_token = |path, token: String| {
+            test::TestRequest::with_uri(path)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .header("x-ratelimit-bypass", token)
+                .to_request()
+        };
+        let now = SystemTime::now();
+
+        // Valid tokens never touch the store
+        for _ in 0..3 {
+            let token = bypass_token("secret", "/", now);
+            let (status, headers) = send(&mut app, with_token("/", token)).await;
+            assert_eq!(status, StatusCode::OK);
+            assert!(!headers.contains_key("x-ratelimit-remaining"));
+        }
+        let token = bypass_token("secret", "/", now - Duration::from_secs(299));
+        let (status, headers) = send(&mut app, with_token("/", token)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!headers.contains_key("x-ratelimit-remaining"));
+
+        // Expired tokens are limited as usual
+        let token = bypass_token("secret", "/", now - Duration::from_secs(301));
+        let (status, headers) = send(&mut app, with_token("/", token)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+        let token = bypass_token("secret", "/", now - Duration::from_secs(301));
+        let (status, _) = send(&mut app, with_token("/", token)).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+
+        // So are forged ones, signed with another secret, for another path or tampered with
+        let forged = vec![
+            bypass_token("guess", "/", now),
+            bypass_token("secret", "/other", now),
+            bypass_token("secret", "/", now).replacen('.', "0.", 1),
+            format!("{}.", now.duration_since(UNIX_EPOCH).unwrap().as_secs()),
+            "garbage".to_string(),
+            // Would overflow the time it was signed at
+            format!(
+                "{}.{}",
+                u64::MAX,
+                bypass_signature(b"secret", "/", u64::MAX)
+            ),
+        ];
+        for token in forged {
+            let (status, _) = send(&mut app, with_token("/", token)).await;
+            assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+        }
+
+        let token = bypass_token("secret", "/", now);
+        let (status, _) = send(&mut app, with_token("/", token)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_invalid_header_left_out() {
+        let mut headers = HeaderMap::new();
+        insert_headers(
+            &mut headers,
+            &HeaderNames::default(),
+            10,
+            4,
+            Duration::from_secs(30),
+            Duration::from_secs(60),
+            Some("10;w=60\n"),
+        );
+        // The other headers are still set
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "10");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "4");
+        assert_eq!(header(&headers, "x-ratelimit-reset"), "30");
+        assert!(!headers.contains_key("ratelimit-policy"));
+
+        insert_header(
+            &mut headers,
+            &HeaderName::from_static("x-ratelimit-limit"),
+            "\0",
+        );
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "10");
+    }
+
+    #[actix_rt::test]
+    async fn test_max_key_length() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(addr.clone())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(2)
+                        .with_identifier(|req| {
+                            let key = req
+                                .headers()
+                                .get("x-api-key")
+                                .ok_or(ARError::IdentificationError)?;
+                            Ok(key.to_str().unwrap().to_string())
+                        })
+                        .with_max_key_length(32),
+                )
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let with_key = |key: &str| {
+            test::TestRequest::default()
+                .header("x-api-key", key)
+                .to_request()
+        };
+        let long = "a".repeat(10_000);
+        let other = format!("{}b", "a".repeat(9_999));
+
+        let (_, headers) = send(&mut app, with_key(&long)).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        // Keys which only differ past the bound are still told apart
+        let (_, headers) = send(&mut app, with_key(&other)).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        let (_, headers) = send(&mut app, with_key(&long)).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+
+        let key = bounded_key(long.clone(), 32);
+        assert_eq!(key.len(), 32);
+        assert!(key.starts_with(&"a".repeat(16)));
+        assert_ne!(key, bounded_key(other, 32));
+        let res = addr.send(ActorMessage::Get(key)).await.unwrap();
+        assert_eq!(res.expect_get().await.unwrap(), Some(0));
+        let res = addr.send(ActorMessage::Get(long)).await.unwrap();
+        assert_eq!(res.expect_get().await.unwrap(), None);
+
+        // Short keys are left alone, and multibyte characters are not split
+        assert_eq!(bounded_key("short".to_string(), 32), "short");
+        let key = bounded_key("é".repeat(20), 33);
+        assert_eq!(key.len(), 32);
+        assert!(key.starts_with(&"é".repeat(8)));
+    }
+
+    #[actix_rt::test]
+    async fn test_options_report() {
+        let store = MemoryStore::new();
+        let mut app = test::init_service(
+            App::new()
+                .wrap(
+                    RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                        .with_interval(Duration::from_secs(60))
+                        .with_max_requests(3)
+                        .with_skipped_methods(vec![Method::OPTIONS])
+                        .with_options_report(true),
+                )
+                .route("/", web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let options = || {
+            test::TestRequest::with_uri("/")
+                .method(Method::OPTIONS)
+                .peer_addr("127.0.0.1:8080".parse().unwrap())
+                .to_request()
+        };
+
+        // Unknown clients are told about the whole limit
+        let (status, headers) = send(&mut app, options()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "ratelimit-policy"), "3;w=60");
+        assert_eq!(header(&headers, "x-ratelimit-limit"), "3");
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "3");
+        let (_, headers) = send(&mut app, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "2");
+        assert!(!headers.contains_key("ratelimit-policy"));
+
+        for _ in 0..3 {
+            let (status, headers) = send(&mut app, options()).await;
+            assert_eq!(status, StatusCode::OK);
+            assert_eq!(header(&headers, "ratelimit-policy"), "3;w=60");
+            assert_eq!(header(&headers, "x-ratelimit-remaining"), "2");
+            let reset: u64 = header(&headers, "x-ratelimit-reset").parse().unwrap();
+            assert!(reset <= 60);
+        }
+        let (_, headers) = send(&mut app, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        let (_, headers) = send(&mut app, request()).await;
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+
+        // Exhausted clients are still answered
+        let (status, headers) = send(&mut app, options()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "0");
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_rt::test]
+    async fn test_store_retries() {
+        // Fails the first `failures` messages with `err`, then answers them from a memory store
+        fn flaky(
+            failures: Arc<AtomicUsize>,
+            err: fn() -> ARError,
+        ) -> RateLimiter<KvStore<MemoryStore>> {
+            let store = Store::new(MemoryStore::new());
+            RateLimiter::with_stores(vec![StoreHandle::Direct(Arc::new(move |msg| {
+                let failing = failures
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                    .is_ok();
+                if failing {
+                    ActorResponse::failed(&msg, err())
+                } else {
+                    store.handle(msg)
+                }
+            }))])
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(2)
+            .with_store_retries(2, Duration::from_millis(10))
+        }
+
+        // A store failing once is retried, and the request passes
+        let failures = Arc::new(AtomicUsize::new(1));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(flaky(failures.clone(), || ARError::Disconnected))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "1");
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        // Retries run out
+        let failures = Arc::new(AtomicUsize::new(4));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(flaky(failures.clone(), || {
+                    ARError::StoreUnavailable("LOADING".to_string())
+                }))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Errors which retrying won't fix are not retried
+        let failures = Arc::new(AtomicUsize::new(2));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(flaky(failures.clone(), || ARError::Unsupported("Get")))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_store_retries_counting() {
+        // Fails the first `failures` updates with `err`, then answers them from a memory store
+        fn flaky(
+            failures: Arc<AtomicUsize>,
+            err: fn() -> ARError,
+        ) -> RateLimiter<KvStore<MemoryStore>> {
+            let store = Store::new(MemoryStore::new());
+            RateLimiter::with_stores(vec![StoreHandle::Direct(Arc::new(move |msg| {
+                let failing = matches!(msg, ActorMessage::Update { .. })
+                    && failures
+                        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                        .is_ok();
+                if failing {
+                    ActorResponse::failed(&msg, err())
+                } else {
+                    store.handle(msg)
+                }
+            }))])
+            .with_interval(Duration::from_secs(60))
+            .with_max_requests(5)
+            .with_store_retries(2, Duration::from_millis(10))
+        }
+
+        // The store reports it did not count the request, so it is counted again
+        let failures = Arc::new(AtomicUsize::new(1));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(flaky(failures.clone(), || {
+                    ARError::StoreUnavailable("LOADING".to_string())
+                }))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        assert_eq!(send(&mut app, request()).await.0, StatusCode::OK);
+        let (status, headers) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(header(&headers, "x-ratelimit-remaining"), "3");
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        // A connection lost halfway may have counted the request already
+        let failures = Arc::new(AtomicUsize::new(1));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(flaky(failures.clone(), || {
+                    ARError::ReadWriteError("connection reset".to_string())
+                }))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        assert_eq!(send(&mut app, request()).await.0, StatusCode::OK);
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(failures.load(Ordering::SeqCst), 0);
+
+        // A missing key stays missing
+        let failures = Arc::new(AtomicUsize::new(2));
+        let mut app = test::init_service(
+            App::new()
+                .wrap(flaky(failures.clone(), || {
+                    ARError::operation("update", "127.0.0.1:8080", "key not found")
+                }))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        assert_eq!(send(&mut app, request()).await.0, StatusCode::OK);
+        let (status, _) = send(&mut app, request()).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(failures.load(Ordering::SeqCst), 1);
+    }
 }
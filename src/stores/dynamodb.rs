@@ -0,0 +1,602 @@
+//! DynamoDB store for rate limiting
+//!
+//! Every client is an item of a dedicated table, whose partition key is the string attribute
+//! `key`. The table has to exist beforehand, e.g.
+//!
+//! ```text
+//! aws dynamodb create-table --table-name ratelimit \
+//!     --attribute-definitions AttributeName=key,AttributeType=S \
+//!     --key-schema AttributeName=key,KeyType=HASH \
+//!     --billing-mode PAY_PER_REQUEST
+//! aws dynamodb update-time-to-live --table-name ratelimit \
+//!     --time-to-live-specification Enabled=true,AttributeName=expires_at
+//! ```
+//!
+//! Enabling the time to live on `expires_at` lets DynamoDB delete expired windows. As it may
+//! take a while to do so, the store also ignores expired items itself.
+use crate::clock::{Clock, SystemClock};
+use crate::errors::ARError;
+use crate::stores::start_supervised;
+use crate::{ActorMessage, ActorResponse, ListStatus, StoreStats};
+use actix::prelude::*;
+use log::*;
+use rusoto_core::RusotoError;
+use rusoto_dynamodb::{
+    AttributeValue, DeleteItemError, DeleteItemInput, DescribeTableInput, DynamoDb, DynamoDbClient,
+    GetItemError, GetItemInput, PutItemError, PutItemInput, ScanError, ScanInput, UpdateItemError,
+    UpdateItemInput,
+};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use rusoto_core::Region;
+
+/// Partition key of the table
+const KEY: &str = "key";
+const REMAINING: &str = "remaining";
+/// Expiry in seconds since the epoch, for DynamoDB's time to live
+const EXPIRES_AT: &str = "expires_at";
+/// Expiry in milliseconds since the epoch, checked by the store itself
+const EXPIRES_AT_MS: &str = "expires_at_ms";
+
+type Item = HashMap<String, AttributeValue>;
+
+/// Maps a failed request to `ARError`, calling out throttling by DynamoDB so that it can be told
+/// apart from an outage. Throttled requests were not carried out and requests which failed to
+/// get through may not have been, so both are worth retrying, see `ARError::is_transient`.
+macro_rules! request_error {
+    ($op:expr, $key:expr, $err:expr, $kind:ident) => {
+        match $err {
+            err @ RusotoError::Service($kind::ProvisionedThroughputExceeded(_))
+            | err @ RusotoError::Service($kind::RequestLimitExceeded(_)) => {
+                ARError::unavailable($op, $key, format!("throughput exceeded: {}", err))
+            }
+            err @ RusotoError::HttpDispatch(_) => ARError::ReadWriteError(format!(
+                "{} failed for key '{}': {}",
+                $op, $key, err
+            )),
+            err => ARError::operation($op, $key, err),
+        }
+    };
+}
+
+/// Type used to connect to a DynamoDB table
+#[derive(Clone)]
+pub struct DynamoStore {
+    client: DynamoDbClient,
+    table: String,
+    clock: Arc<dyn Clock>,
+}
+
+impl DynamoStore {
+    /// Uses the table `table` in `region`, with credentials from the environment
+    ///
+    /// # Example
+    /// ```rust
+    /// # #[cfg(feature = "dynamodb-store")] {
+    /// use actix_ratelimit::stores::dynamodb::Region;
+    /// use actix_ratelimit::DynamoStore;
+    ///
+    /// let store = DynamoStore::new(Region::EuWest1, "ratelimit");
+    /// # }
+    /// ```
+    pub fn new<S: Into<String>>(region: Region, table: S) -> Self {
+        Self::with_client(DynamoDbClient::new(region), table)
+    }
+
+    /// Uses the table `table` through an existing client, e.g. one with custom credentials
+    pub fn with_client<S: Into<String>>(client: DynamoDbClient, table: S) -> Self {
+        let table = table.into();
+        debug!("Creating new DynamoStore for table {}", &table);
+        DynamoStore {
+            client,
+            table,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Compute expiries with `clock` instead of the system time. DynamoDB still deletes expired
+    /// items by its own clock.
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+/// Actor for DynamoDB store
+pub struct DynamoStoreActor {
+    client: DynamoDbClient,
+    table: String,
+    clock: Arc<dyn Clock>,
+    shutdown: bool,
+}
+
+impl From<DynamoStore> for DynamoStoreActor {
+    fn from(store: DynamoStore) -> Self {
+        DynamoStoreActor {
+            client: store.client,
+            table: store.table,
+            clock: store.clock,
+            shutdown: false,
+        }
+    }
+}
+
+impl DynamoStoreActor {
+    /// Starts the DynamoDB store actor and returns it's address
+    pub fn start(self) -> Addr<Self> {
+        debug!("Started dynamodb store");
+        start_supervised(|_| self)
+    }
+}
+
+impl Actor for DynamoStoreActor {
+    type Context = Context<Self>;
+}
+
+impl Supervised for DynamoStoreActor {
+    fn restarting(&mut self, _: &mut Self::Context) {
+        debug!("restarting dynamodb store");
+    }
+}
+
+fn string(value: &str) -> AttributeValue {
+    AttributeValue {
+        s: Some(value.to_string()),
+        ..Default::default()
+    }
+}
+
+fn number<N: ToString>(value: N) -> AttributeValue {
+    AttributeValue {
+        n: Some(value.to_string()),
+        ..Default::default()
+    }
+}
+
+fn key_of(key: &str) -> Item {
+    let mut item = HashMap::new();
+    item.insert(KEY.to_string(), string(key));
+    item
+}
+
+fn number_of(item: &Item, name: &str) -> Option<i64> {
+    item.get(name)?.n.as_ref()?.parse().ok()
+}
+
+/// Attribute names are passed as placeholders, as `key` is a reserved word
+fn names(names: &[(&str, &str)]) -> Option<HashMap<String, String>> {
+    Some(
+        names
+            .iter()
+            .map(|(placeholder, name)| (placeholder.to_string(), name.to_string()))
+            .collect(),
+    )
+}
+
+fn values(values: Vec<(&str, AttributeValue)>) -> Option<Item> {
+    Some(
+        values
+            .into_iter()
+            .map(|(placeholder, value)| (placeholder.to_string(), value))
+            .collect(),
+    )
+}
+
+/// Fetches an item, treating expired ones which DynamoDB has not deleted yet as missing
+async fn get_item(
+    client: &DynamoDbClient,
+    table: String,
+    key: &str,
+    now: Duration,
+) -> Result<Option<Item>, RusotoError<GetItemError>> {
+    let input = GetItemInput {
+        table_name: table,
+        key: key_of(key),
+        consistent_read: Some(true),
+        ..Default::default()
+    };
+    let now = now.as_millis() as i64;
+    let output = client.get_item(input).await?;
+    Ok(output
+        .item
+        .filter(|item| number_of(item, EXPIRES_AT_MS).map_or(false, |expires_at| expires_at > now)))
+}
+
+impl Handler<ActorMessage> for DynamoStoreActor {
+    type Result = ActorResponse;
+    fn handle(&mut self, msg: ActorMessage, _: &mut Self::Context) -> Self::Result {
+        if self.shutdown {
+            return ActorResponse::failed(&msg, ARError::ShutDown);
+        }
+        let client = self.client.clone();
+        let table = self.table.clone();
+        let now = self.clock.now();
+        match msg {
+            ActorMessage::Set { key, value, expiry } => ActorResponse::Set(Box::pin(async move {
+                let expires_at = now + expiry;
+                let mut item = key_of(&key);
+                item.insert(REMAINING.to_string(), number(value));
+                item.insert(EXPIRES_AT.to_string(), number(expires_at.as_secs()));
+                item.insert(EXPIRES_AT_MS.to_string(), number(expires_at.as_millis()));
+                let input = PutItemInput {
+                    table_name: table,
+                    item,
+                    ..Default::default()
+                };
+                match client.put_item(input).await {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(request_error!("set", &key, e, PutItemError)),
+                }
+            })),
+            ActorMessage::Update { key, value } => ActorResponse::Update(Box::pin(async move {
+                // Atomic decrement, as long as the window has not expired
+                let input = UpdateItemInput {
+                    table_name: table,
+                    key: key_of(&key),
+                    update_expression: Some("ADD #r :delta".to_string()),
+                    condition_expression: Some("#e > :now".to_string()),
+                    expression_attribute_names: names(&[("#r", REMAINING), ("#e", EXPIRES_AT_MS)]),
+                    expression_attribute_values: values(vec![
+                        (":delta", number(-(value as i64))),
+                        (":now", number(now.as_millis())),
+                    ]),
+                    return_values: Some("UPDATED_NEW".to_string()),
+                    ..Default::default()
+                };
+                match client.update_item(input).await {
+                    Ok(output) => {
+                        let remaining = output
+                            .attributes
+                            .as_ref()
+                            .and_then(|item| number_of(item, REMAINING));
+                        Ok(remaining.unwrap_or(0).max(0) as usize)
+                    }
+                    Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                        Err(ARError::operation("update", &key, "key not found"))
+                    }
+                    Err(e) => Err(request_error!("update", &key, e, UpdateItemError)),
+                }
+            })),
+            ActorMessage::Increment { key, value, expiry } => {
+                ActorResponse::Increment(Box::pin(async move {
+                    let expires_at = now + expiry;
+                    // Counts on from the current value, unless it has expired already
+                    let input = UpdateItemInput {
+                        table_name: table.clone(),
+                        key: key_of(&key),
+                        update_expression: Some("ADD #r :delta SET #e = :s, #es = :ms".to_string()),
+                        condition_expression: Some(
+                            "attribute_not_exists(#k) OR #es > :now".to_string(),
+                        ),
+                        expression_attribute_names: names(&[
+                            ("#k", KEY),
+                            ("#r", REMAINING),
+                            ("#e", EXPIRES_AT),
+                            ("#es", EXPIRES_AT_MS),
+                        ]),
+                        expression_attribute_values: values(vec![
+                            (":delta", number(value)),
+                            (":s", number(expires_at.as_secs())),
+                            (":ms", number(expires_at.as_millis())),
+                            (":now", number(now.as_millis())),
+                        ]),
+                        return_values: Some("UPDATED_NEW".to_string()),
+                        ..Default::default()
+                    };
+                    match client.update_item(input).await {
+                        Ok(output) => {
+                            let count = output
+                                .attributes
+                                .as_ref()
+                                .and_then(|item| number_of(item, REMAINING));
+                            return Ok(count.unwrap_or(0).max(0) as usize);
+                        }
+                        Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {}
+                        Err(e) => {
+                            return Err(request_error!("increment", &key, e, UpdateItemError))
+                        }
+                    }
+                    let mut item = key_of(&key);
+                    item.insert(REMAINING.to_string(), number(value));
+                    item.insert(EXPIRES_AT.to_string(), number(expires_at.as_secs()));
+                    item.insert(EXPIRES_AT_MS.to_string(), number(expires_at.as_millis()));
+                    let input = PutItemInput {
+                        table_name: table,
+                        item,
+                        ..Default::default()
+                    };
+                    match client.put_item(input).await {
+                        Ok(_) => Ok(value),
+                        Err(e) => Err(request_error!("increment", &key, e, PutItemError)),
+                    }
+                }))
+            }
+            ActorMessage::Get(key) => ActorResponse::Get(Box::pin(async move {
+                match get_item(&client, table, &key, now).await {
+                    Ok(item) => Ok(item
+                        .and_then(|item| number_of(&item, REMAINING))
+                        .map(|remaining| remaining.max(0) as usize)),
+                    Err(e) => Err(request_error!("get", &key, e, GetItemError)),
+                }
+            })),
+            ActorMessage::Expire(key) => ActorResponse::Expire(Box::pin(async move {
+                let item = match get_item(&client, table, &key, now).await {
+                    Ok(item) => item,
+                    Err(e) => return Err(request_error!("expire", &key, e, GetItemError)),
+                };
+                match item.and_then(|item| number_of(&item, EXPIRES_AT_MS)) {
+                    Some(expires_at) => Ok(Duration::from_millis(expires_at as u64)
+                        .checked_sub(now)
+                        .unwrap_or_default()),
+                    None => Err(ARError::operation("expire", &key, "key not found")),
+                }
+            })),
+            ActorMessage::Remove(key) => ActorResponse::Remove(Box::pin(async move {
+                let input = DeleteItemInput {
+                    table_name: table,
+                    key: key_of(&key),
+                    return_values: Some("ALL_OLD".to_string()),
+                    ..Default::default()
+                };
+                match client.delete_item(input).await {
+                    Ok(output) => match output
+                        .attributes
+                        .as_ref()
+                        .and_then(|item| number_of(item, REMAINING))
+                    {
+                        Some(remaining) => Ok(remaining.max(0) as usize),
+                        None => Err(ARError::operation("remove", &key, "key not found")),
+                    },
+                    Err(e) => Err(request_error!("remove", &key, e, DeleteItemError)),
+                }
+            })),
+            ActorMessage::Stats => ActorResponse::Stats(Box::pin(async move {
+                let input = DescribeTableInput { table_name: table };
+                // DynamoDB only refreshes the item count of a table every few hours
+                Ok(StoreStats {
+                    connected: client.describe_table(input).await.is_ok(),
+                    key_count: None,
+                })
+            })),
+            ActorMessage::CheckList(_) => {
+                // Lists are not kept in the table
+                ActorResponse::CheckList(Box::pin(async move { Ok(ListStatus::Normal) }))
+            }
+            // Request rates are only tracked in memory
+            ActorMessage::Rate(_) => ActorResponse::Rate(Box::pin(async move { Ok(None) })),
+            ActorMessage::Grant { key, value, max } => ActorResponse::Grant(Box::pin(async move {
+                // Expressions can't cap a number, so the count is read first and only written
+                // back if no request changed it in between
+                for _ in 0..3 {
+                    let item = match get_item(&client, table.clone(), &key, now).await {
+                        Ok(item) => item,
+                        Err(e) => return Err(request_error!("grant", &key, e, GetItemError)),
+                    };
+                    let remaining = match item.and_then(|item| number_of(&item, REMAINING)) {
+                        Some(remaining) => remaining.max(0) as usize,
+                        None => return Ok(None),
+                    };
+                    if remaining >= max {
+                        return Ok(Some(remaining));
+                    }
+                    let granted = remaining.saturating_add(value).min(max);
+                    let input = UpdateItemInput {
+                        table_name: table.clone(),
+                        key: key_of(&key),
+                        update_expression: Some("SET #r = :granted".to_string()),
+                        condition_expression: Some("#r = :remaining AND #e > :now".to_string()),
+                        expression_attribute_names: names(&[
+                            ("#r", REMAINING),
+                            ("#e", EXPIRES_AT_MS),
+                        ]),
+                        expression_attribute_values: values(vec![
+                            (":granted", number(granted)),
+                            (":remaining", number(remaining)),
+                            (":now", number(now.as_millis())),
+                        ]),
+                        ..Default::default()
+                    };
+                    match client.update_item(input).await {
+                        Ok(_) => return Ok(Some(granted)),
+                        Err(RusotoError::Service(UpdateItemError::ConditionalCheckFailed(_))) => {
+                            continue
+                        }
+                        Err(e) => return Err(request_error!("grant", &key, e, UpdateItemError)),
+                    }
+                }
+                Err(ARError::operation("grant", &key, "count kept changing"))
+            })),
+            ActorMessage::Clear(prefix) => ActorResponse::Clear(Box::pin(async move {
+                let mut removed = 0;
+                let mut start = None;
+                loop {
+                    let input = ScanInput {
+                        table_name: table.clone(),
+                        filter_expression: Some("begins_with(#k, :prefix)".to_string()),
+                        projection_expression: Some("#k".to_string()),
+                        expression_attribute_names: names(&[("#k", KEY)]),
+                        expression_attribute_values: values(vec![(":prefix", string(&prefix))]),
+                        exclusive_start_key: start.take(),
+                        ..Default::default()
+                    };
+                    let output = client
+                        .scan(input)
+                        .await
+                        .map_err(|e| request_error!("clear", &prefix, e, ScanError))?;
+                    for key in output.items.unwrap_or_default() {
+                        let input = DeleteItemInput {
+                            table_name: table.clone(),
+                            key,
+                            ..Default::default()
+                        };
+                        client
+                            .delete_item(input)
+                            .await
+                            .map_err(|e| request_error!("clear", &prefix, e, DeleteItemError))?;
+                        removed += 1;
+                    }
+                    start = output.last_evaluated_key;
+                    if start.is_none() {
+                        break;
+                    }
+                }
+                Ok(removed)
+            })),
+            ActorMessage::Shutdown => {
+                // Requests are run to completion by the futures handed out earlier, so there is
+                // nothing left to wait for here
+                debug!("shutting down dynamodb store");
+                self.shutdown = true;
+                ActorResponse::Shutdown(Box::pin(async move { Ok(()) }))
+            }
+            // Sliding windows are counted with the separate messages instead
+            msg @ ActorMessage::Slide { .. } => ActorResponse::unsupported(&msg),
+            msg @ ActorMessage::Draw { .. } => ActorResponse::unsupported(&msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::credential::StaticProvider;
+    use rusoto_core::HttpClient;
+    use rusoto_dynamodb::{AttributeDefinition, CreateTableInput, KeySchemaElement};
+
+    /// Connects to DynamoDB local, at `DYNAMODB_ENDPOINT` or on its default port, and creates
+    /// the table if needed
+    async fn store() -> DynamoStore {
+        let endpoint = std::env::var("DYNAMODB_ENDPOINT")
+            .unwrap_or_else(|_| "http://127.0.0.1:8000".to_string());
+        let region = Region::Custom {
+            name: "local".to_string(),
+            endpoint,
+        };
+        let credentials = StaticProvider::new_minimal("local".to_string(), "local".to_string());
+        let client =
+            DynamoDbClient::new_with(HttpClient::new().unwrap(), credentials, region.clone());
+        let input = CreateTableInput {
+            table_name: "ratelimit".to_string(),
+            attribute_definitions: vec![AttributeDefinition {
+                attribute_name: KEY.to_string(),
+                attribute_type: "S".to_string(),
+            }],
+            key_schema: vec![KeySchemaElement {
+                attribute_name: KEY.to_string(),
+                key_type: "HASH".to_string(),
+            }],
+            billing_mode: Some("PAY_PER_REQUEST".to_string()),
+            ..Default::default()
+        };
+        // Fails if the table exists already
+        let _ = client.create_table(input).await;
+        DynamoStore::with_client(client, "ratelimit")
+    }
+
+    #[actix_rt::test]
+    async fn test_set() {
+        let store = store().await;
+        let addr = DynamoStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello".to_string(),
+                value: 30usize,
+                expiry: Duration::from_secs(5),
+            })
+            .await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Set(c) => match c.await {
+                Ok(()) => {}
+                Err(e) => panic!("Shouldn't happen: {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_get() {
+        let store = store().await;
+        let addr = DynamoStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello_get".to_string(),
+                value: 30usize,
+                expiry: Duration::from_secs(5),
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Set(c) => c.await.unwrap(),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "hello_get".to_string(),
+                value: 2,
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Update(c) => assert_eq!(c.await.unwrap(), 28),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr.send(ActorMessage::Get("hello_get".to_string())).await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), Some(28)),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr.send(ActorMessage::Get("missing".to_string())).await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), None),
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_expiry() {
+        let store = store().await;
+        let addr = DynamoStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello_expiry".to_string(),
+                value: 30usize,
+                expiry: Duration::from_millis(500),
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Set(c) => c.await.unwrap(),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr
+            .send(ActorMessage::Expire("hello_expiry".to_string()))
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Expire(c) => {
+                let dur = c.await.unwrap();
+                assert!(dur > Duration::from_millis(0) && dur <= Duration::from_millis(500));
+            }
+            _ => panic!("Shouldn't happen!"),
+        }
+
+        // Expired, even if DynamoDB has not deleted the item yet
+        actix_rt::time::delay_for(Duration::from_millis(600)).await;
+        let res = addr
+            .send(ActorMessage::Get("hello_expiry".to_string()))
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), None),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "hello_expiry".to_string(),
+                value: 1,
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Update(c) => assert!(c.await.is_err()),
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
+}
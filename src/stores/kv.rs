@@ -0,0 +1,591 @@
+//! Store built on top of a plain key-value backend
+//!
+//! Rather than handling every [ActorMessage](../../enum.ActorMessage.html) in an actor of its
+//! own, a store can implement [KvBackend](trait.KvBackend.html), which only reads, writes and
+//! deletes counts along with their expiry. [KvStore](struct.KvStore.html) turns it into a store
+//! actor, taking care of expired keys and of the messages the backend has no counterpart for.
+//!
+//! # Example
+//! ```rust
+//! use actix_ratelimit::{stores::kv::KvBackend, KvStore, Output};
+//! use std::collections::HashMap;
+//! use std::sync::{Arc, Mutex};
+//! use std::time::Duration;
+//!
+//! #[derive(Clone, Default)]
+//! struct MapBackend(Arc<Mutex<HashMap<String, (usize, Duration)>>>);
+//!
+//! impl KvBackend for MapBackend {
+//!     fn get(&self, key: &str) -> Output<Option<(usize, Duration)>> {
+//!         let entry = self.0.lock().unwrap().get(key).copied();
+//!         Box::pin(async move { Ok(entry) })
+//!     }
+//!     fn set(&self, key: &str, value: usize, deadline: Duration, _: Duration) -> Output<()> {
+//!         self.0.lock().unwrap().insert(key.to_string(), (value, deadline));
+//!         Box::pin(async { Ok(()) })
+//!     }
+//!     fn del(&self, key: &str) -> Output<Option<usize>> {
+//!         let value = self.0.lock().unwrap().remove(key).map(|(value, _)| value);
+//!         Box::pin(async move { Ok(value) })
+//!     }
+//! }
+//!
+//! #[actix_rt::main]
+//! async fn main() {
+//!     let store = KvStore::new(MapBackend::default()).start();
+//! }
+//! ```
+use actix::prelude::*;
+use log::*;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::clock::{Clock, SystemClock};
+use crate::errors::ARError;
+use crate::stores::start_supervised;
+use crate::{ActorMessage, ActorResponse, ListStatus, Output, StoreStats};
+
+/// Key-value storage [KvStore](struct.KvStore.html) keeps the counts in. Every entry is a count
+/// along with its deadline, the time since the unix epoch at which it expires. Only `get`, `set`
+/// and `del` have to be implemented; the other methods fall back to them, and backends able to
+/// do better, e.g. atomically, can override them.
+pub trait KvBackend: Clone + Send + Unpin + 'static {
+    /// Count and deadline of `key`, expired or not, `None` if it does not exist
+    fn get(&self, key: &str) -> Output<Option<(usize, Duration)>>;
+
+    /// Store `value` under `key` until `deadline`. `ttl` is the time left until then, for
+    /// backends which expire keys on their own.
+    fn set(&self, key: &str, value: usize, deadline: Duration, ttl: Duration) -> Output<()>;
+
+    /// Delete `key`, returning its count if it existed
+    fn del(&self, key: &str) -> Output<Option<usize>>;
+
+    /// Increase the count of `key` by `value`, starting over from 0 if it does not exist or
+    /// expired before `now`, and have it expire at `deadline`. Returns the new count.
+    fn incr(&self, key: &str, value: usize, now: Duration, deadline: Duration) -> Output<usize> {
+        let backend = self.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            let count = match backend.get(&key).await? {
+                Some((count, expiry)) if expiry > now => count + value,
+                _ => value,
+            };
+            backend.set(&key, count, deadline, deadline - now).await?;
+            Ok(count)
+        })
+    }
+
+    /// Decrease the count of `key` by `value`, stopping at 0, unless it does not exist or
+    /// expired before `now`. Returns the new count.
+    fn decr(&self, key: &str, value: usize, now: Duration) -> Output<Option<usize>> {
+        let backend = self.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            let (count, deadline) = match live(backend.clone(), key.clone(), now).await? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            let count = count.saturating_sub(value);
+            backend.set(&key, count, deadline, deadline - now).await?;
+            Ok(Some(count))
+        })
+    }
+
+    /// Increase the count of `key` by `value`, but not above `max`, unless it does not exist or
+    /// expired before `now`. A count already above `max` is kept. Returns the new count.
+    fn grant(&self, key: &str, value: usize, max: usize, now: Duration) -> Output<Option<usize>> {
+        let backend = self.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            let (count, deadline) = match live(backend.clone(), key.clone(), now).await? {
+                Some(entry) => entry,
+                None => return Ok(None),
+            };
+            if count >= max {
+                return Ok(Some(count));
+            }
+            let count = count.saturating_add(value).min(max);
+            backend.set(&key, count, deadline, deadline - now).await?;
+            Ok(Some(count))
+        })
+    }
+
+    /// Delete `key` if it expired before `now`, returning its deadline if it is still there.
+    /// The default looks the key up before deleting it, so it may delete a key set again in
+    /// between.
+    fn remove_expired(&self, key: &str, now: Duration) -> Output<Option<Duration>> {
+        let backend = self.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            match backend.get(&key).await? {
+                Some((_, deadline)) if deadline > now => Ok(Some(deadline)),
+                Some(_) => backend.del(&key).await.map(|_| None),
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Remove every key starting with `prefix`, returning how many were removed
+    fn clear(&self, _prefix: &str) -> Output<usize> {
+        Box::pin(async move { Err(ARError::Unsupported("Clear")) })
+    }
+
+    /// Number of keys, if the backend can tell
+    fn count(&self) -> Output<Option<usize>> {
+        Box::pin(async { Ok(None) })
+    }
+
+    /// Whether the backend drops keys by itself once their `ttl` has passed. Otherwise the
+    /// store deletes every key it sets once it expires.
+    fn expires_keys(&self) -> bool {
+        false
+    }
+}
+
+/// Store actor on top of a [KvBackend](trait.KvBackend.html). Keys past their deadline are
+/// treated as missing, whether the backend removed them yet or not. Stores built this way have
+/// no allow and deny lists.
+pub struct KvStore<B> {
+    backend: B,
+    clock: Arc<dyn Clock>,
+    shutdown: bool,
+}
+
+impl<B: KvBackend> KvStore<B> {
+    /// Creates a store keeping its counts in `backend`
+    pub fn new(backend: B) -> Self {
+        KvStore {
+            backend,
+            clock: Arc::new(SystemClock),
+            shutdown: false,
+        }
+    }
+
+    /// Compute deadlines with `clock` instead of the system time
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Starts the store actor and returns its address
+    pub fn start(self) -> Addr<Self> {
+        debug!("Started key-value store");
+        start_supervised(|_| self)
+    }
+
+    /// Deletes `key` once it expires after `ttl`, unless it has been set again with a later
+    /// deadline by then
+    fn expire_later(&self, ctx: &mut Context<Self>, key: String, ttl: Duration) {
+        if self.backend.expires_keys() {
+            return;
+        }
+        ctx.run_later(ttl, move |act, ctx| {
+            let now = act.clock.now();
+            act.backend
+                .remove_expired(&key, now)
+                .into_actor(act)
+                .map(move |res, act, ctx| match res {
+                    Ok(Some(deadline)) => act.expire_later(ctx, key, deadline - now),
+                    Ok(None) => {}
+                    Err(e) => warn!("key-value store: could not expire {}: {}", &key, e),
+                })
+                .spawn(ctx);
+        });
+    }
+}
+
+/// Looks `key` up, treating it as missing once it expired before `now`. The backend is asked
+/// right away rather than once the result is awaited.
+fn live<B: KvBackend>(
+    backend: B,
+    key: String,
+    now: Duration,
+) -> impl Future<Output = Result<Option<(usize, Duration)>, ARError>> {
+    let entry = backend.get(&key);
+    async move {
+        match entry.await? {
+            Some((value, deadline)) if deadline > now => Ok(Some((value, deadline))),
+            Some(_) => backend.remove_expired(&key, now).await.map(|_| None),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<B: KvBackend> Actor for KvStore<B> {
+    type Context = Context<Self>;
+}
+
+impl<B: KvBackend> Supervised for KvStore<B> {
+    fn restarting(&mut self, _: &mut Self::Context) {
+        debug!("Restarting key-value store");
+    }
+}
+
+impl<B: KvBackend> Handler<ActorMessage> for KvStore<B> {
+    type Result = ActorResponse;
+    fn handle(&mut self, msg: ActorMessage, ctx: &mut Self::Context) -> Self::Result {
+        if self.shutdown {
+            return ActorResponse::failed(&msg, ARError::ShutDown);
+        }
+        match &msg {
+            ActorMessage::Set { key, expiry, .. } | ActorMessage::Increment { key, expiry, .. } => {
+                self.expire_later(ctx, key.clone(), *expiry);
+            }
+            ActorMessage::Shutdown => {
+                // The futures handed out earlier run to completion on their own
+                debug!("Shutting down key-value store");
+                self.shutdown = true;
+            }
+            _ => {}
+        }
+        respond(self.backend.clone(), self.clock.now(), msg)
+    }
+}
+
+/// Deadline `expiry` from `now`, the latest one there is for expiries too long to add up
+fn deadline_after(now: Duration, expiry: Duration) -> Duration {
+    now.checked_add(expiry)
+        .unwrap_or_else(|| Duration::new(u64::MAX, 999_999_999))
+}
+
+/// Answers `msg` from `backend`, shared by [KvStore](struct.KvStore.html),
+/// [Store](struct.Store.html) and the store actors built on a backend, which take care of
+/// expiring keys and shutting down themselves. Every message calls the backend before returning,
+/// so backends applying their changes right away do so in the order the messages came in.
+pub(crate) fn respond<B: KvBackend>(backend: B, now: Duration, msg: ActorMessage) -> ActorResponse {
+    match msg {
+        ActorMessage::Get(key) => {
+            let entry = live(backend, key, now);
+            ActorResponse::Get(Box::pin(
+                async move { Ok(entry.await?.map(|(value, _)| value)) },
+            ))
+        }
+        ActorMessage::Set { key, value, expiry } => {
+            ActorResponse::Set(backend.set(&key, value, deadline_after(now, expiry), expiry))
+        }
+        ActorMessage::Update { key, value } => {
+            let count = backend.decr(&key, value, now);
+            ActorResponse::Update(Box::pin(async move {
+                match count.await? {
+                    Some(count) => Ok(count),
+                    None => Err(ARError::operation("update", &key, "key not found")),
+                }
+            }))
+        }
+        ActorMessage::Increment { key, value, expiry } => {
+            let deadline = deadline_after(now, expiry);
+            ActorResponse::Increment(backend.incr(&key, value, now, deadline))
+        }
+        ActorMessage::Expire(key) => {
+            let entry = live(backend, key.clone(), now);
+            ActorResponse::Expire(Box::pin(async move {
+                match entry.await? {
+                    Some((_, deadline)) => Ok(deadline - now),
+                    None => Err(ARError::operation("expire", &key, "key not found")),
+                }
+            }))
+        }
+        ActorMessage::Remove(key) => {
+            let value = backend.del(&key);
+            ActorResponse::Remove(Box::pin(async move {
+                match value.await? {
+                    Some(value) => Ok(value),
+                    None => Err(ARError::operation("remove", &key, "key not found")),
+                }
+            }))
+        }
+        ActorMessage::Stats => {
+            let count = backend.count();
+            ActorResponse::Stats(Box::pin(async move {
+                Ok(StoreStats {
+                    connected: true,
+                    key_count: count.await?,
+                })
+            }))
+        }
+        ActorMessage::Clear(prefix) => ActorResponse::Clear(backend.clear(&prefix)),
+        ActorMessage::CheckList(_) => {
+            ActorResponse::CheckList(Box::pin(async { Ok(ListStatus::Normal) }))
+        }
+        ActorMessage::Rate(_) => ActorResponse::Rate(Box::pin(async { Ok(None) })),
+        ActorMessage::Grant { key, value, max } => {
+            ActorResponse::Grant(backend.grant(&key, value, max, now))
+        }
+        // Sliding windows are counted with the separate messages instead
+        msg @ ActorMessage::Slide { .. } => ActorResponse::unsupported(&msg),
+        msg @ ActorMessage::Draw { .. } => ActorResponse::unsupported(&msg),
+        ActorMessage::Shutdown => ActorResponse::Shutdown(Box::pin(async { Ok(()) })),
+    }
+}
+
+/// Store on top of a [KvBackend](trait.KvBackend.html) without an actor, for services which do
+/// not run an actix `System`. It works like [KvStore](struct.KvStore.html), but the operations
+/// are plain futures, which can be awaited from any runtime, or from several threads at once
+/// through clones of the store. Pass it to `RateLimiter::direct` to have the middleware use it.
+///
+/// Without an actor to run timers, keys past their deadline are only deleted once they are
+/// looked up again, unless the backend expires keys by itself.
+///
+/// # Example
+/// ```rust
+/// use actix_ratelimit::{MemoryStore, Store};
+/// use std::time::Duration;
+///
+/// #[tokio::main]
+/// async fn main() {
+///     let store = Store::new(MemoryStore::new());
+///     store.set("client", 10, Duration::from_secs(60)).await.unwrap();
+///     assert_eq!(store.update("client", 1).await.unwrap(), 9);
+/// }
+/// ```
+#[derive(Clone)]
+pub struct Store<B> {
+    backend: B,
+    clock: Arc<dyn Clock>,
+    shutdown: Arc<AtomicBool>,
+}
+
+impl<B: KvBackend> Store<B> {
+    /// Creates a store keeping its counts in `backend`
+    pub fn new(backend: B) -> Self {
+        Store {
+            backend,
+            clock: Arc::new(SystemClock),
+            shutdown: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Compute deadlines with `clock` instead of the system time
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Answers `msg` as a store actor would, see [ActorMessage](../../enum.ActorMessage.html)
+    pub fn handle(&self, msg: ActorMessage) -> ActorResponse {
+        if self.shutdown.load(Ordering::Acquire) {
+            return ActorResponse::failed(&msg, ARError::ShutDown);
+        }
+        if let ActorMessage::Shutdown = msg {
+            debug!("Shutting down key-value store");
+            self.shutdown.store(true, Ordering::Release);
+        }
+        respond(self.backend.clone(), self.clock.now(), msg)
+    }
+
+    /// Remaining count of `key`, `None` if it does not exist or expired
+    pub fn get(&self, key: &str) -> Output<Option<usize>> {
+        self.handle(ActorMessage::Get(key.to_string())).expect_get()
+    }
+
+    /// Set the count of `key` to `value`, expiring after `expiry`
+    pub fn set(&self, key: &str, value: usize, expiry: Duration) -> Output<()> {
+        let msg = ActorMessage::Set {
+            key: key.to_string(),
+            value,
+            expiry,
+        };
+        self.handle(msg).expect_set()
+    }
+
+    /// Decrease the count of `key` by `value`, stopping at 0. Returns the new count.
+    pub fn update(&self, key: &str, value: usize) -> Output<usize> {
+        let msg = ActorMessage::Update {
+            key: key.to_string(),
+            value,
+        };
+        self.handle(msg).expect_update()
+    }
+
+    /// Increase the count of `key` by `value`, but not above `max`. Returns the new count, or
+    /// `None` if `key` does not exist.
+    pub fn grant(&self, key: &str, value: usize, max: usize) -> Output<Option<usize>> {
+        let msg = ActorMessage::Grant {
+            key: key.to_string(),
+            value,
+            max,
+        };
+        self.handle(msg).expect_grant()
+    }
+
+    /// Increase the count of `key` by `value`, starting from 0 if it does not exist, and have it
+    /// expire after `expiry` from now. Returns the new count.
+    pub fn increment(&self, key: &str, value: usize, expiry: Duration) -> Output<usize> {
+        let msg = ActorMessage::Increment {
+            key: key.to_string(),
+            value,
+            expiry,
+        };
+        self.handle(msg).expect_increment()
+    }
+
+    /// Time left until `key` expires
+    pub fn expire(&self, key: &str) -> Output<Duration> {
+        self.handle(ActorMessage::Expire(key.to_string()))
+            .expect_expire()
+    }
+
+    /// Remove `key`, returning its count
+    pub fn remove(&self, key: &str) -> Output<usize> {
+        self.handle(ActorMessage::Remove(key.to_string()))
+            .expect_remove()
+    }
+}
+
+#[cfg(all(test, feature = "memory"))]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::MemoryStore;
+
+    /// Only implements what a backend has to, leaving the rest to the defaults
+    #[derive(Clone)]
+    struct Plain(MemoryStore);
+
+    impl KvBackend for Plain {
+        fn get(&self, key: &str) -> Output<Option<(usize, Duration)>> {
+            self.0.get(key)
+        }
+        fn set(&self, key: &str, value: usize, deadline: Duration, ttl: Duration) -> Output<()> {
+            self.0.set(key, value, deadline, ttl)
+        }
+        fn del(&self, key: &str) -> Output<Option<usize>> {
+            self.0.del(key)
+        }
+    }
+
+    async fn exercise<B: KvBackend>(backend: B) {
+        let clock = ManualClock::new();
+        let addr = KvStore::new(backend).with_clock(clock.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello".to_string(),
+                value: 30,
+                expiry: Duration::from_secs(5),
+            })
+            .await
+            .unwrap();
+        res.expect_set().await.unwrap();
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "hello".to_string(),
+                value: 5,
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.expect_update().await.unwrap(), 25);
+        let res = addr.send(ActorMessage::Get("hello".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(25));
+        let res = addr
+            .send(ActorMessage::Grant {
+                key: "hello".to_string(),
+                value: 10,
+                max: 28,
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.expect_grant().await.unwrap(), Some(28));
+        clock.advance(Duration::from_secs(2));
+        let res = addr.send(ActorMessage::Expire("hello".to_string())).await;
+        let expiry = res.unwrap().expect_expire().await.unwrap();
+        assert_eq!(expiry, Duration::from_secs(3));
+
+        for expected in &[2, 4] {
+            let res = addr
+                .send(ActorMessage::Increment {
+                    key: "active".to_string(),
+                    value: 2,
+                    expiry: Duration::from_secs(5),
+                })
+                .await
+                .unwrap();
+            assert_eq!(res.expect_increment().await.unwrap(), *expected);
+        }
+
+        // Both keys are gone once the clock passes their deadline
+        clock.advance(Duration::from_secs(5));
+        let res = addr.send(ActorMessage::Get("hello".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), None);
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "hello".to_string(),
+                value: 5,
+            })
+            .await
+            .unwrap();
+        assert!(res.expect_update().await.is_err());
+        let res = addr
+            .send(ActorMessage::Increment {
+                key: "active".to_string(),
+                value: 1,
+                expiry: Duration::from_secs(5),
+            })
+            .await
+            .unwrap();
+        assert_eq!(res.expect_increment().await.unwrap(), 1);
+    }
+
+    #[actix_rt::test]
+    async fn test_memory_backend() {
+        exercise(MemoryStore::new()).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_default_methods() {
+        exercise(Plain(MemoryStore::new())).await;
+    }
+
+    #[actix_rt::test]
+    async fn test_expired_keys_removed() {
+        let store = MemoryStore::new();
+        let addr = KvStore::new(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello".to_string(),
+                value: 30,
+                expiry: Duration::from_millis(50),
+            })
+            .await
+            .unwrap();
+        res.expect_set().await.unwrap();
+        let res = addr.send(ActorMessage::Stats).await.unwrap();
+        assert_eq!(res.expect_stats().await.unwrap().key_count, Some(1));
+        actix_rt::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(store.count().await.unwrap(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_store_without_system() {
+        // Neither an actix System nor an actor is involved
+        assert!(!System::is_set());
+        let clock = ManualClock::new();
+        let store = Store::new(MemoryStore::new()).with_clock(clock.clone());
+        let task = tokio::spawn({
+            let store = store.clone();
+            async move {
+                store.set("hello", 30, Duration::from_secs(5)).await?;
+                store.update("hello", 5).await
+            }
+        });
+        assert_eq!(task.await.unwrap().unwrap(), 25);
+        assert_eq!(store.get("hello").await.unwrap(), Some(25));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(store.expire("hello").await.unwrap(), Duration::from_secs(3));
+        assert_eq!(
+            store
+                .increment("active", 2, Duration::from_secs(5))
+                .await
+                .unwrap(),
+            2
+        );
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(store.get("hello").await.unwrap(), None);
+        assert!(store.update("hello", 5).await.is_err());
+
+        let res = store.handle(ActorMessage::Shutdown);
+        res.expect_shutdown().await.unwrap();
+        assert!(store.get("active").await.is_err());
+    }
+}
@@ -1,14 +1,22 @@
 //! Memcached store for rate limiting
+use crate::clock::{Clock, SystemClock};
 use crate::errors::ARError;
-use crate::{ActorMessage, ActorResponse};
+use crate::stores::{
+    kv::{self, KvBackend},
+    start_supervised, ConnectionEvent, ConnectionListener, Outage, ReconnectBackoff,
+};
+use crate::{ActorMessage, ActorResponse, ListStatus, Output, StoreStats};
 use actix::prelude::*;
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
 use log::*;
-use r2d2_memcache::r2d2::Pool;
+use r2d2_memcache::memcache::MemcacheError;
+use r2d2_memcache::r2d2::{Pool, PooledConnection};
 use r2d2_memcache::MemcacheConnectionManager;
-use std::convert::TryInto;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+use std::time::Duration;
 
 struct GetAddr;
 impl Message for GetAddr {
@@ -20,6 +28,7 @@ pub struct MemcacheStore {
     addr: String,
     backoff: ExponentialBackoff,
     client: Option<Pool<MemcacheConnectionManager>>,
+    outage: Outage,
 }
 
 impl MemcacheStore {
@@ -35,15 +44,75 @@ impl MemcacheStore {
     /// }
     /// ```
     pub fn connect<S: Into<String>>(addr: S) -> Addr<Self> {
-        let addr = addr.into();
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_elapsed_time = None;
+        Self::connect_with_backoff(addr, ReconnectBackoff::new())
+    }
+
+    /// Like `connect`, but retries connecting to memcached according to `backoff` instead of
+    /// the defaults of `ReconnectBackoff`
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{stores::ReconnectBackoff, MemcacheStore};
+    /// use std::time::Duration;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() -> std::io::Result<()>{
+    ///     let backoff = ReconnectBackoff::new().with_max_interval(Duration::from_secs(5));
+    ///     let store = MemcacheStore::connect_with_backoff("memcache://127.0.0.1:11211", backoff);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn connect_with_backoff<S: Into<String>>(addr: S, backoff: ReconnectBackoff) -> Addr<Self> {
+        Self::start(addr.into(), backoff, None)
+    }
+
+    /// Like `connect_with_backoff`, also calling `listener` whenever the store restarts to
+    /// reconnect and once it is connected again, along with how long it was down
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::stores::{ConnectionEvent, ReconnectBackoff};
+    /// use actix_ratelimit::MemcacheStore;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() -> std::io::Result<()>{
+    ///     let store = MemcacheStore::connect_with_listener(
+    ///         "memcache://127.0.0.1:11211",
+    ///         ReconnectBackoff::new(),
+    ///         |event| {
+    ///             if let ConnectionEvent::Reconnected { downtime } = event {
+    ///                 eprintln!("memcached is back after {:?}", downtime);
+    ///             }
+    ///         },
+    ///     );
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn connect_with_listener<S, F>(
+        addr: S,
+        backoff: ReconnectBackoff,
+        listener: F,
+    ) -> Addr<Self>
+    where
+        S: Into<String>,
+        F: Fn(ConnectionEvent) + Send + Sync + 'static,
+    {
+        Self::start(addr.into(), backoff, Some(Arc::new(listener)))
+    }
+
+    fn start(
+        addr: String,
+        backoff: ReconnectBackoff,
+        listener: Option<ConnectionListener>,
+    ) -> Addr<Self> {
+        let backoff = backoff.build();
         let manager = MemcacheConnectionManager::new(addr.clone());
         let pool = Pool::builder().max_size(15).build(manager).unwrap();
-        Supervisor::start(|_| MemcacheStore {
+        start_supervised(|_| MemcacheStore {
             addr,
             backoff,
             client: Some(pool),
+            outage: Outage::new(listener),
         })
     }
 }
@@ -62,9 +131,13 @@ impl Actor for MemcacheStore {
                 match con {
                     Ok(c) => {
                         act.client = Some(c);
+                        if let Some(downtime) = act.outage.connected() {
+                            info!("Reconnected to memcached after {:?}", downtime);
+                        }
                     }
                     Err(e) => {
                         error!("Error connecting to memcached: {}", &e);
+                        act.outage.down();
                         if let Some(timeout) = act.backoff.next_backoff() {
                             context.run_later(timeout, |_, ctx| ctx.stop());
                         }
@@ -81,6 +154,7 @@ impl Supervised for MemcacheStore {
     fn restarting(&mut self, _: &mut Self::Context) {
         debug!("restarting memcache store");
         self.client.take();
+        self.outage.restarting();
     }
 }
 
@@ -103,6 +177,8 @@ pub struct MemcacheStoreActor {
     addr: Addr<MemcacheStore>,
     backoff: ExponentialBackoff,
     inner: Option<Pool<MemcacheConnectionManager>>,
+    clock: Arc<dyn Clock>,
+    shutdown: bool,
 }
 
 impl Actor for MemcacheStoreActor {
@@ -142,15 +218,31 @@ impl From<Addr<MemcacheStore>> for MemcacheStoreActor {
             addr,
             backoff,
             inner: None,
+            clock: Arc::new(SystemClock),
+            shutdown: false,
         }
     }
 }
 
 impl MemcacheStoreActor {
+    /// Retry reaching the main store according to `backoff`. By default the actor retries at
+    /// least every three seconds and gives up after 15 minutes.
+    pub fn with_backoff(mut self, backoff: ReconnectBackoff) -> Self {
+        self.backoff = backoff.build();
+        self
+    }
+
+    /// Compute the expiries kept alongside the keys with `clock` instead of the system time.
+    /// Memcached still drops the keys by its own clock.
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
     /// Starts the memcached store actor and returns it's address
     pub fn start(self) -> Addr<Self> {
         debug!("Started memcache actor");
-        Supervisor::start(|_| self)
+        start_supervised(|_| self)
     }
 }
 
@@ -164,92 +256,257 @@ impl Supervised for MemcacheStoreActor {
 impl Handler<ActorMessage> for MemcacheStoreActor {
     type Result = ActorResponse;
     fn handle(&mut self, msg: ActorMessage, ctx: &mut Self::Context) -> Self::Result {
-        let pool = self.inner.clone();
-        if let Some(p) = pool {
-            if let Ok(client) = p.get() {
-                match msg {
-                    ActorMessage::Set { key, value, expiry } => {
-                        ActorResponse::Set(Box::pin(async move {
-                            let ex_key = format!("{}:expire", key);
-                            let now = SystemTime::now();
-                            let now = now.duration_since(UNIX_EPOCH).unwrap();
-                            let result = client.set(
-                                &key,
-                                value as u64,
-                                expiry.as_secs().try_into().unwrap(),
-                            );
-                            let val = now + expiry;
-                            let val: u64 = val.as_secs().try_into().unwrap();
-                            client
-                                .set(&ex_key, val, expiry.as_secs().try_into().unwrap())
-                                .unwrap();
-                            match result {
-                                Ok(_) => Ok(()),
-                                Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
-                            }
-                        }))
-                    }
-                    ActorMessage::Update { key, value } => {
-                        ActorResponse::Update(Box::pin(async move {
-                            let result = client.decrement(&key, value as u64);
-                            match result {
-                                Ok(c) => Ok(c as usize),
-                                Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
-                            }
-                        }))
-                    }
-                    ActorMessage::Get(key) => ActorResponse::Get(Box::pin(async move {
-                        let result: Result<Option<u64>, _> = client.get(&key);
-                        match result {
-                            Ok(c) => match c {
-                                Some(v) => Ok(Some(v as usize)),
-                                None => Ok(None),
-                            }
-                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
-                        }
-                    })),
-                    ActorMessage::Expire(key) => ActorResponse::Expire(Box::pin(async move {
-                        let result: Result<Option<u64>, _> =
-                            client.get(&format!("{}:expire", &key));
-                        match result {
-                            Ok(c) => {
-                                if let Some(d) = c {
-                                    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                                    let now = now.as_secs().try_into().unwrap();
-                                    let res = d.checked_sub(now).unwrap_or_else(|| 0);
-                                    Ok(Duration::from_secs(res))
-                                } else {
-                                    Err(ARError::ReadWriteError(
-                                        "error: expiration data not found".to_owned(),
-                                    ))
-                                }
-                            }
-                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
-                        }
-                    })),
-                    ActorMessage::Remove(key) => ActorResponse::Remove(Box::pin(async move {
-                        let result = client.delete(&key);
-                        let _ = client.delete(&format!("{}:expire", &key));
-                        match result {
-                            Ok(_) => Ok(1),
-                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
-                        }
-                    })),
-                }
-            } else {
-                ctx.stop();
-                ActorResponse::Set(Box::pin(async move { Err(ARError::Disconnected) }))
-            }
+        if self.shutdown {
+            return ActorResponse::failed(&msg, ARError::ShutDown);
+        }
+        if let ActorMessage::Shutdown = msg {
+            // Commands are run to completion by the futures handed out earlier, so there is
+            // nothing left to wait for here
+            debug!("shutting down memcache actor");
+            self.shutdown = true;
+            self.inner.take();
+            return ActorResponse::Shutdown(Box::pin(async move { Ok(()) }));
+        }
+        if let ActorMessage::CheckList(_) = msg {
+            // memcached has no sets to keep the lists in
+            return ActorResponse::CheckList(Box::pin(async move { Ok(ListStatus::Normal) }));
+        }
+        if let ActorMessage::Rate(_) = msg {
+            // Request rates are only tracked in memory
+            return ActorResponse::Rate(Box::pin(async move { Ok(None) }));
+        }
+        if let ActorMessage::Clear(_) = msg {
+            // memcached offers no way to enumerate keys
+            return ActorResponse::unsupported(&msg);
+        }
+        if matches!(msg, ActorMessage::Slide { .. } | ActorMessage::Draw { .. }) {
+            // Sliding windows and pools are counted with the separate messages instead
+            return ActorResponse::unsupported(&msg);
+        }
+        // Only go on with a pool which hands out connections, so that the actor restarts to
+        // reconnect once memcached went away
+        let pool = self.inner.clone().filter(|pool| pool.get().is_ok());
+        if let (ActorMessage::Stats, None) = (&msg, &pool) {
+            return ActorResponse::Stats(Box::pin(async move {
+                Ok(StoreStats {
+                    connected: false,
+                    key_count: None,
+                })
+            }));
+        }
+        if let Some(pool) = pool {
+            // The pool keeps the counts as the backend of a KvStore would
+            kv::respond(pool, self.clock.now(), msg)
         } else {
             ctx.stop();
-            ActorResponse::Set(Box::pin(async move { Err(ARError::Disconnected) }))
+            ActorResponse::failed(&msg, ARError::Disconnected)
         }
     }
 }
 
+/// Longest expiry memcached takes relative to now, larger ones are read as unix timestamps
+const MAX_TTL_SECS: u32 = 30 * 24 * 60 * 60;
 
+/// Latest deadline `encode` writes so that `decode` can read it back
+const MAX_DEADLINE: Duration = Duration::from_millis(u64::MAX);
+
+/// Memcached counts in whole seconds, where 0 never expires, so round up. Longer expiries are
+/// cut down to 30 days, after which memcached drops the key before its deadline.
+fn ttl_secs(ttl: Duration) -> u32 {
+    u32::try_from((ttl.as_millis() + 999) / 1000)
+        .unwrap_or(MAX_TTL_SECS)
+        .max(1)
+        .min(MAX_TTL_SECS)
+}
 
+type Connection = PooledConnection<MemcacheConnectionManager>;
 
+/// Attempts at changing an entry before giving up, when other clients keep changing it
+const CAS_ATTEMPTS: usize = 10;
+
+/// Encodes a count along with its deadline in milliseconds since the unix epoch, so that both
+/// are kept under a single key. Deadlines are cut down to `MAX_DEADLINE`.
+fn encode(value: usize, deadline: Duration) -> String {
+    format!("{}:{}", value, deadline.min(MAX_DEADLINE).as_millis())
+}
+
+/// Decodes an entry written by `encode`. Values without a deadline, e.g. written by earlier
+/// versions which kept it under a second key, are treated as missing.
+fn decode(value: &str) -> Option<(usize, Duration)> {
+    let mut fields = value.splitn(2, ':');
+    let value = fields.next()?.parse().ok()?;
+    let deadline = fields.next()?.parse().ok()?;
+    Some((value, Duration::from_millis(deadline)))
+}
+
+/// Maps a failed memcached command to `ARError`. Commands which never got a connection may
+/// succeed when retried, and so may those which lost theirs, see `ARError::is_transient`; errors
+/// of the command itself won't.
+fn memcache_error(op: &'static str, key: &str, e: &MemcacheError) -> ARError {
+    match e {
+        MemcacheError::PoolError(_) => ARError::unavailable(op, key, e),
+        MemcacheError::IOError(_) => {
+            ARError::ReadWriteError(format!("{} failed for key '{}': {}", op, key, e))
+        }
+        _ => ARError::operation(op, key, e),
+    }
+}
+
+/// Count and deadline of `key`, whether it expired or not
+fn fetch(
+    client: &Connection,
+    key: &str,
+    op: &'static str,
+) -> Result<Option<(usize, Duration)>, ARError> {
+    let value: Option<String> = client.get(key).map_err(|e| memcache_error(op, key, &e))?;
+    Ok(value.as_deref().and_then(decode))
+}
+
+/// Replaces the entry of `key` by what `f` makes of it, passing `None` for keys missing or
+/// expired by `now`. The entry is written with compare-and-swap, so that changes made by other
+/// clients in between are not lost. Returns the entry written, or `None` if `f` returned `None`.
+fn modify<F>(
+    client: &Connection,
+    key: &str,
+    now: Duration,
+    op: &'static str,
+    f: F,
+) -> Result<Option<(usize, Duration)>, ARError>
+where
+    F: Fn(Option<(usize, Duration)>) -> Option<(usize, Duration)>,
+{
+    for _ in 0..CAS_ATTEMPTS {
+        let mut found: HashMap<String, (Vec<u8>, u32, Option<u64>)> =
+            client.gets(&[key]).map_err(|e| memcache_error(op, key, &e))?;
+        let stored = found.remove(key);
+        let current = stored
+            .as_ref()
+            .and_then(|(value, _, _)| std::str::from_utf8(value).ok())
+            .and_then(decode)
+            .filter(|(_, deadline)| *deadline > now);
+        let (value, deadline) = match f(current) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let encoded = encode(value, deadline);
+        let ttl = ttl_secs(deadline.checked_sub(now).unwrap_or_default());
+        let written = match stored {
+            // Fails if another client created the key in between
+            None => client.add(key, encoded.as_str(), ttl).is_ok(),
+            Some((_, _, Some(cas))) => client
+                .cas(key, encoded.as_str(), ttl, cas)
+                .map_err(|e| memcache_error(op, key, &e))?,
+            Some((_, _, None)) => {
+                client
+                    .set(key, encoded.as_str(), ttl)
+                    .map_err(|e| memcache_error(op, key, &e))?;
+                true
+            }
+        };
+        if written {
+            return Ok(Some((value, deadline)));
+        }
+    }
+    Err(ARError::operation(op, key, "entry kept changing"))
+}
+
+/// Keeps the counts of a [KvStore](../kv/struct.KvStore.html) in memcached, encoded along
+/// with their deadline. `MemcacheStoreActor` keeps its counts the same way, on top of handling
+/// reconnects. Memcached drops the keys by its own clock.
+///
+/// # Example
+/// ```rust
+/// use actix_ratelimit::KvStore;
+/// use r2d2_memcache::{r2d2::Pool, MemcacheConnectionManager};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let manager = MemcacheConnectionManager::new("memcache://127.0.0.1:11211");
+///     let pool = Pool::builder().max_size(15).build(manager).unwrap();
+///     let store = KvStore::new(pool).start();
+/// }
+/// ```
+impl KvBackend for Pool<MemcacheConnectionManager> {
+    fn get(&self, key: &str) -> Output<Option<(usize, Duration)>> {
+        let pool = self.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            let client = pool.get().map_err(|e| ARError::unavailable("get", &key, e))?;
+            fetch(&client, &key, "get")
+        })
+    }
+
+    fn set(&self, key: &str, value: usize, deadline: Duration, ttl: Duration) -> Output<()> {
+        let pool = self.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            let client = pool.get().map_err(|e| ARError::unavailable("set", &key, e))?;
+            let value = encode(value, deadline);
+            client
+                .set(&key, value.as_str(), ttl_secs(ttl))
+                .map_err(|e| memcache_error("set", &key, &e))
+        })
+    }
+
+    fn del(&self, key: &str) -> Output<Option<usize>> {
+        let pool = self.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            let client = pool.get().map_err(|e| ARError::unavailable("remove", &key, e))?;
+            let entry = fetch(&client, &key, "remove")?;
+            client
+                .delete(&key)
+                .map_err(|e| memcache_error("remove", &key, &e))?;
+            Ok(entry.map(|(value, _)| value))
+        })
+    }
+
+    fn incr(&self, key: &str, value: usize, now: Duration, deadline: Duration) -> Output<usize> {
+        let pool = self.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            let client = pool
+                .get()
+                .map_err(|e| ARError::unavailable("increment", &key, e))?;
+            let entry = modify(&client, &key, now, "increment", |entry| {
+                Some((entry.map_or(0, |(count, _)| count).saturating_add(value), deadline))
+            })?;
+            Ok(entry.map_or(value, |(count, _)| count))
+        })
+    }
+
+    fn decr(&self, key: &str, value: usize, now: Duration) -> Output<Option<usize>> {
+        let pool = self.clone();
+        let key = key.to_string();
+        Box::pin(async move {
+            let client = pool.get().map_err(|e| ARError::unavailable("update", &key, e))?;
+            let entry = modify(&client, &key, now, "update", |entry| {
+                entry.map(|(count, deadline)| (count.saturating_sub(value), deadline))
+            })?;
+            Ok(entry.map(|(count, _)| count))
+        })
+    }
+
+    fn count(&self) -> Output<Option<usize>> {
+        let pool = self.clone();
+        Box::pin(async move {
+            // Best effort: memcached only reports the item count per server, which also
+            // includes unrelated data
+            let key_count = pool.get().ok().and_then(|client| client.stats().ok()).map(|servers| {
+                servers
+                    .iter()
+                    .filter_map(|(_, stats)| stats.get("curr_items"))
+                    .filter_map(|c| c.parse::<usize>().ok())
+                    .sum()
+            });
+            Ok(key_count)
+        })
+    }
+
+    fn expires_keys(&self) -> bool {
+        true
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -259,6 +516,23 @@ mod tests {
         let _ = env_logger::builder().is_test(true).try_init();
     }
 
+    #[actix_rt::test]
+    async fn test_disconnected() {
+        init();
+        // A store which can't be reached, so the actor never gets a connection
+        let (tx, rx) = actix::dev::channel::channel::<MemcacheStore>(16);
+        drop(rx);
+        let addr = MemcacheStoreActor::from(Addr::new(tx)).start();
+        let res = addr
+            .send(ActorMessage::Get("hello".to_string()))
+            .await
+            .expect("Failed to send msg");
+        match res.expect_get().await {
+            Err(ARError::Disconnected) => {}
+            res => panic!("Shouldn't happen: {:?}", res.map_err(|e| e.to_string())),
+        }
+    }
+
     #[actix_rt::test]
     async fn test_set() {
         init();
@@ -359,4 +633,110 @@ mod tests {
         };
 
     }
+
+    #[test]
+    fn test_encoding() {
+        let deadline = Duration::from_millis(1_600_000_000_123);
+        assert_eq!(decode(&encode(30, deadline)), Some((30, deadline)));
+        // Written by earlier versions, which kept the deadline under a second key
+        assert_eq!(decode("30"), None);
+        assert_eq!(decode("30:soon"), None);
+        // Neither the largest count nor the latest deadline is lost on the way
+        let entry = decode(&encode(usize::MAX, Duration::MAX));
+        assert_eq!(entry, Some((usize::MAX, MAX_DEADLINE)));
+    }
+
+    #[test]
+    fn test_ttl_secs() {
+        assert_eq!(ttl_secs(Duration::from_millis(1)), 1);
+        assert_eq!(ttl_secs(Duration::from_millis(1500)), 2);
+        assert_eq!(ttl_secs(Duration::from_secs(u64::from(MAX_TTL_SECS))), MAX_TTL_SECS);
+        // Would be read as a timestamp in 1970, expiring the key right away
+        assert_eq!(ttl_secs(Duration::from_secs(31 * 24 * 60 * 60)), MAX_TTL_SECS);
+        assert_eq!(ttl_secs(Duration::MAX), MAX_TTL_SECS);
+    }
+
+    #[actix_rt::test]
+    async fn test_single_key() {
+        init();
+        let store = MemcacheStore::connect("memcache://127.0.0.1:11211");
+        let addr = MemcacheStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello_single".to_string(),
+                value: 30usize,
+                expiry: Duration::from_secs(5),
+            })
+            .await
+            .expect("Failed to send msg");
+        res.expect_set().await.unwrap();
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "hello_single".to_string(),
+                value: 5,
+            })
+            .await
+            .expect("Failed to send msg");
+        assert_eq!(res.expect_update().await.unwrap(), 25);
+        let res = addr
+            .send(ActorMessage::Expire("hello_single".to_string()))
+            .await
+            .expect("Failed to send msg");
+        let expiry = res.expect_expire().await.unwrap();
+        assert!(expiry > Duration::from_secs(4) && expiry <= Duration::from_secs(5));
+
+        // The deadline lives in the value, no other key is written
+        let manager = MemcacheConnectionManager::new("memcache://127.0.0.1:11211");
+        let pool = Pool::builder().max_size(1).build(manager).unwrap();
+        let client = pool.get().unwrap();
+        let companion: Option<String> = client.get("hello_single:expire").unwrap();
+        assert_eq!(companion, None);
+        let value: Option<String> = client.get("hello_single").unwrap();
+        assert!(value.unwrap().starts_with("25:"));
+    }
+
+    /// Drops the pool of the main store and restarts it, as a lost connection would
+    struct DropConnection;
+    impl Message for DropConnection {
+        type Result = ();
+    }
+    impl Handler<DropConnection> for MemcacheStore {
+        type Result = ();
+        fn handle(&mut self, _: DropConnection, ctx: &mut Self::Context) {
+            self.client.take();
+            ctx.stop();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_connection_events() {
+        init();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let store = MemcacheStore::connect_with_listener(
+            "memcache://127.0.0.1:11211",
+            ReconnectBackoff::new(),
+            move |event| recorded.lock().unwrap().push(event),
+        );
+        let addr = MemcacheStoreActor::from(store.clone()).start();
+        addr.send(ActorMessage::Get("hello".to_string()))
+            .await
+            .unwrap()
+            .expect_get()
+            .await
+            .unwrap();
+        assert!(events.lock().unwrap().is_empty());
+
+        store.send(DropConnection).await.unwrap();
+        for _ in 0..50 {
+            if events.lock().unwrap().len() == 2 {
+                break;
+            }
+            actix::clock::delay_for(Duration::from_millis(20)).await;
+        }
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], ConnectionEvent::Restarting);
+        assert!(matches!(events[1], ConnectionEvent::Reconnected { .. }));
+        assert_eq!(events.len(), 2);
+    }
 }
@@ -3,16 +3,32 @@ use actix::prelude::*;
 use dashmap::DashMap;
 use futures::future::{self};
 use log::*;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::Duration;
 
+use crate::clock::{Clock, SystemClock};
 use crate::errors::ARError;
-use crate::{ActorMessage, ActorResponse};
+use crate::stores::kv::{self, KvBackend};
+use crate::stores::start_supervised;
+use crate::{ActorMessage, ActorResponse, ListStatus, Output, PoolDraw, SlidingCount};
+
+/// How often a persistent store writes its snapshot to disk
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(10);
+/// Time constant of the moving average answering `ActorMessage::Rate`
+const RATE_WINDOW: Duration = Duration::from_secs(10);
 
 /// Type used to create a concurrent hashmap store
 #[derive(Clone)]
 pub struct MemoryStore {
     inner: Arc<DashMap<String, (usize, Duration)>>,
+    persistence: Option<PathBuf>,
+    clock: Arc<dyn Clock>,
+    lists: Arc<DashMap<String, ListStatus>>,
+    rates: Arc<DashMap<String, (f64, Duration)>>,
 }
 
 impl MemoryStore {
@@ -28,6 +44,10 @@ impl MemoryStore {
         debug!("Creating new MemoryStore");
         MemoryStore {
             inner: Arc::new(DashMap::<String, (usize, Duration)>::new()),
+            persistence: None,
+            clock: Arc::new(SystemClock),
+            lists: Arc::new(DashMap::new()),
+            rates: Arc::new(DashMap::new()),
         }
     }
 
@@ -38,31 +58,540 @@ impl MemoryStore {
             inner: Arc::new(DashMap::<String, (usize, Duration)>::with_capacity(
                 capacity,
             )),
+            persistence: None,
+            clock: Arc::new(SystemClock),
+            lists: Arc::new(DashMap::new()),
+            rates: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Create a new hashmap which survives restarts. Entries are restored from the snapshot at
+    /// `path` if one exists, skipping those that have already expired. The store actor then
+    /// rewrites the snapshot every few seconds and once more on
+    /// [Shutdown](../../enum.ActorMessage.html).
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::MemoryStore;
+    ///
+    /// let store = MemoryStore::with_persistence("/tmp/ratelimit.snapshot");
+    /// ```
+    pub fn with_persistence<P: Into<PathBuf>>(path: P) -> Self {
+        let path = path.into();
+        debug!("Creating new MemoryStore persisted to {}", path.display());
+        let inner = DashMap::<String, (usize, Duration)>::new();
+        match load_snapshot(&path, &inner) {
+            Ok(count) => debug!("Restored {} keys from {}", count, path.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Could not restore snapshot {}: {}", path.display(), e),
         }
+        MemoryStore {
+            inner: Arc::new(inner),
+            persistence: Some(path),
+            clock: Arc::new(SystemClock),
+            lists: Arc::new(DashMap::new()),
+            rates: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Compute expiries with `clock` instead of the system time. Keys are considered gone as
+    /// soon as the clock passes their expiry.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{clock::ManualClock, MemoryStore};
+    ///
+    /// let clock = ManualClock::new();
+    /// let store = MemoryStore::new().with_clock(clock.clone());
+    /// ```
+    pub fn with_clock<C: Clock>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Put the client identified by `key` on the allowlist, so that it is never rate limited by
+    /// limiters using `RateLimiter::with_access_lists`. Takes effect immediately, also for
+    /// actors started earlier. The lists are not persisted.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::MemoryStore;
+    ///
+    /// let store = MemoryStore::new();
+    /// store.allow("10.0.0.1");
+    /// store.deny("203.0.113.7");
+    /// ```
+    pub fn allow(&self, key: &str) {
+        self.lists.insert(key.to_string(), ListStatus::Allow);
+    }
+
+    /// Put the client identified by `key` on the denylist, see [allow](#method.allow)
+    pub fn deny(&self, key: &str) {
+        self.lists.insert(key.to_string(), ListStatus::Deny);
+    }
+
+    /// Take the client identified by `key` off either list
+    pub fn unlist(&self, key: &str) {
+        self.lists.remove(key);
+    }
+
+    /// Number of keys in the store, read straight from the map without going through an actor.
+    /// Keys are removed shortly after they expire, so this may include a few which already have.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::MemoryStore;
+    ///
+    /// let store = MemoryStore::new();
+    /// assert_eq!(store.len(), 0);
+    /// assert!(store.capacity() >= store.len());
+    /// ```
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Whether the store holds no keys, see [len](#method.len)
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Number of keys the map can hold without reallocating. Each slot takes about
+    /// `size_of::<String>() + size_of::<(usize, Duration)>()` bytes, 48 on 64-bit targets, on top
+    /// of which every key stores its own bytes.
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
     }
 }
 
+/// Memory store for tests, whose entries can be seeded and inspected in place instead of through
+/// the actor. Values are the requests a client has left, as counted down by the middleware, and
+/// keys are the ones it uses, e.g. `127.0.0.1:8080` for clients identified by their address and
+/// no prefix. Actors started from it with `MemoryStoreActor::from` work on the same entries.
+///
+/// # Example
+/// ```rust
+/// use std::time::Duration;
+/// use actix_ratelimit::{MemoryStoreActor, RateLimiter, TestMemoryStore};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = TestMemoryStore::new();
+///     // The client has a single request left for the next 30 seconds
+///     store.set_raw("127.0.0.1:8080", 1, Duration::from_secs(30));
+///     let ratelimiter = RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+///         .with_interval(Duration::from_secs(60))
+///         .with_max_requests(100);
+///     assert_eq!(store.get_raw("127.0.0.1:8080").map(|(value, _)| value), Some(1));
+/// }
+/// ```
+#[derive(Clone)]
+pub struct TestMemoryStore {
+    store: MemoryStore,
+}
+
+impl TestMemoryStore {
+    /// Create an empty store following the system time. Use `From<MemoryStore>` for stores with
+    /// a clock of their own, see `MemoryStore::with_clock`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Value of `key` and the time until it expires, or `None` if it does not exist or has
+    /// expired
+    pub fn get_raw(&self, key: &str) -> Option<(usize, Duration)> {
+        let (value, deadline) = *self.store.inner.get(key)?.value();
+        let ttl = deadline.checked_sub(self.store.clock.now())?;
+        if ttl == Duration::from_secs(0) {
+            return None;
+        }
+        Some((value, ttl))
+    }
+
+    /// Set `key` to `value`, expiring after `ttl`. Expired keys are removed by the actor when it
+    /// looks them up, so that they don't have to be removed by hand.
+    pub fn set_raw(&self, key: &str, value: usize, ttl: Duration) {
+        let deadline = self.store.clock.now() + ttl;
+        self.store.inner.insert(key.to_string(), (value, deadline));
+    }
+
+    /// Every key which has not expired yet, with its value and the time until it expires
+    pub fn inner_snapshot(&self) -> HashMap<String, (usize, Duration)> {
+        self.store
+            .inner
+            .iter()
+            .filter_map(|entry| {
+                let key = entry.key();
+                self.get_raw(key).map(|raw| (key.clone(), raw))
+            })
+            .collect()
+    }
+}
+
+impl Default for TestMemoryStore {
+    fn default() -> Self {
+        MemoryStore::new().into()
+    }
+}
+
+impl From<MemoryStore> for TestMemoryStore {
+    fn from(store: MemoryStore) -> Self {
+        TestMemoryStore { store }
+    }
+}
+
+/// Keeps the counts of a [KvStore](../kv/struct.KvStore.html) in the map, which then follows
+/// the clock of the `KvStore` rather than its own. `MemoryStoreActor` keeps its counts the same
+/// way, adding the allow and deny lists, request rates and persistence.
+///
+/// # Example
+/// ```rust
+/// use actix_ratelimit::{KvStore, MemoryStore};
+///
+/// #[actix_rt::main]
+/// async fn main() {
+///     let store = KvStore::new(MemoryStore::new()).start();
+/// }
+/// ```
+impl KvBackend for MemoryStore {
+    fn get(&self, key: &str) -> Output<Option<(usize, Duration)>> {
+        let entry = self.inner.get(key).map(|c| *c.value());
+        Box::pin(future::ready(Ok(entry)))
+    }
+
+    fn set(&self, key: &str, value: usize, deadline: Duration, _: Duration) -> Output<()> {
+        self.inner.insert(key.to_string(), (value, deadline));
+        Box::pin(future::ready(Ok(())))
+    }
+
+    fn del(&self, key: &str) -> Output<Option<usize>> {
+        let value = self.inner.remove(key).map(|(_, (value, _))| value);
+        Box::pin(future::ready(Ok(value)))
+    }
+
+    fn incr(&self, key: &str, value: usize, now: Duration, deadline: Duration) -> Output<usize> {
+        let mut entry = self.inner.entry(key.to_string()).or_insert((0, deadline));
+        if entry.1 <= now {
+            entry.0 = 0;
+        }
+        entry.0 += value;
+        entry.1 = deadline;
+        let count = entry.0;
+        Box::pin(future::ready(Ok(count)))
+    }
+
+    fn decr(&self, key: &str, value: usize, now: Duration) -> Output<Option<usize>> {
+        let count = match self.inner.get_mut(key) {
+            Some(mut c) if c.value().1 > now => {
+                let entry = c.value_mut();
+                entry.0 = entry.0.saturating_sub(value);
+                Some(entry.0)
+            }
+            _ => None,
+        };
+        Box::pin(future::ready(Ok(count)))
+    }
+
+    fn grant(&self, key: &str, value: usize, max: usize, now: Duration) -> Output<Option<usize>> {
+        let count = self
+            .inner
+            .get_mut(key)
+            .filter(|c| c.value().1 > now)
+            .map(|mut c| {
+                let entry = c.value_mut();
+                // A count already above the ceiling is left alone, never lowered
+                entry.0 = entry.0.max(entry.0.saturating_add(value).min(max));
+                entry.0
+            });
+        Box::pin(future::ready(Ok(count)))
+    }
+
+    fn remove_expired(&self, key: &str, now: Duration) -> Output<Option<Duration>> {
+        self.inner
+            .remove_if(key, |_, (_, deadline)| *deadline <= now);
+        let deadline = self.inner.get(key).map(|c| c.value().1);
+        Box::pin(future::ready(Ok(deadline)))
+    }
+
+    fn clear(&self, prefix: &str) -> Output<usize> {
+        let before = self.inner.len();
+        self.inner.retain(|key, _| !key.starts_with(prefix));
+        let removed = before.saturating_sub(self.inner.len());
+        Box::pin(future::ready(Ok(removed)))
+    }
+
+    fn count(&self) -> Output<Option<usize>> {
+        Box::pin(future::ready(Ok(Some(self.inner.len()))))
+    }
+}
+
+/// The moving average `rate` after `elapsed` without requests
+fn decay(rate: f64, elapsed: Duration) -> f64 {
+    rate * (-elapsed.as_secs_f64() / RATE_WINDOW.as_secs_f64()).exp()
+}
+
+/// Escapes the line breaks and backslashes of `key`, so that every entry of a snapshot takes up
+/// exactly one line
+fn escape_key(key: &str) -> String {
+    let mut escaped = String::with_capacity(key.len());
+    for c in key.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverses `escape_key`, `None` if `escaped` holds an escape sequence it never writes
+fn unescape_key(escaped: &str) -> Option<String> {
+    let mut key = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            key.push(c);
+            continue;
+        }
+        match chars.next()? {
+            '\\' => key.push('\\'),
+            'n' => key.push('\n'),
+            'r' => key.push('\r'),
+            _ => return None,
+        }
+    }
+    Some(key)
+}
+
+/// Parses a line of a snapshot into its key, value and expiry
+fn parse_snapshot_line(line: &[u8]) -> Option<(String, usize, Duration)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let mut fields = line.splitn(3, '\t');
+    let value = fields.next()?.parse::<usize>().ok()?;
+    let expiry = fields.next()?.parse::<u64>().ok()?;
+    let key = unescape_key(fields.next()?)?;
+    Some((key, value, Duration::from_millis(expiry)))
+}
+
+/// Reads the entries of a snapshot which have not expired yet into `map`. Malformed lines are
+/// skipped, so that a damaged line doesn't take the entries after it down too.
+fn load_snapshot(path: &Path, map: &DashMap<String, (usize, Duration)>) -> io::Result<usize> {
+    let now = SystemClock.now();
+    let reader = BufReader::new(File::open(path)?);
+    let mut count = 0;
+    for (number, line) in reader.split(b'\n').enumerate() {
+        let line = line?;
+        let (key, value, expiry) = match parse_snapshot_line(&line) {
+            Some(entry) => entry,
+            None => {
+                warn!(
+                    "Skipping malformed line {} of snapshot {}",
+                    number + 1,
+                    path.display()
+                );
+                continue;
+            }
+        };
+        if expiry > now {
+            map.insert(key, (value, expiry));
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Writes every entry of `map` to a temporary file which then replaces the snapshot at `path`,
+/// so a crash mid-write never leaves a truncated snapshot behind
+fn write_snapshot(path: &Path, map: &DashMap<String, (usize, Duration)>) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = BufWriter::new(File::create(&tmp)?);
+    for entry in map.iter() {
+        let (value, expiry) = entry.value();
+        let key = escape_key(entry.key());
+        writeln!(file, "{}\t{}\t{}", value, expiry.as_millis(), key)?;
+    }
+    file.flush()?;
+    file.get_ref().sync_all()?;
+    fs::rename(&tmp, path)
+}
+
 /// Actor for memory store
 pub struct MemoryStoreActor {
     inner: Arc<DashMap<String, (usize, Duration)>>,
+    persistence: Option<PathBuf>,
+    clock: Arc<dyn Clock>,
+    lists: Arc<DashMap<String, ListStatus>>,
+    rates: Arc<DashMap<String, (f64, Duration)>>,
+    mailbox_capacity: Option<usize>,
+    shutdown: bool,
 }
 
 impl From<MemoryStore> for MemoryStoreActor {
     fn from(store: MemoryStore) -> Self {
-        MemoryStoreActor { inner: store.inner }
+        MemoryStoreActor {
+            inner: store.inner,
+            persistence: store.persistence,
+            clock: store.clock,
+            lists: store.lists,
+            rates: store.rates,
+            mailbox_capacity: None,
+            shutdown: false,
+        }
+    }
+}
+
+impl From<TestMemoryStore> for MemoryStoreActor {
+    fn from(store: TestMemoryStore) -> Self {
+        MemoryStoreActor::from(store.store)
     }
 }
 
 impl MemoryStoreActor {
+    /// Number of messages the actor queues before senders have to wait, 16 by default. A larger
+    /// mailbox absorbs bursts at the cost of memory and of requests waiting longer on a busy
+    /// actor.
+    pub fn with_mailbox_capacity(mut self, capacity: usize) -> Self {
+        self.mailbox_capacity = Some(capacity);
+        self
+    }
+
     /// Starts the memory actor and returns it's address
     pub fn start(self) -> Addr<Self> {
         debug!("Started memory store");
-        Supervisor::start(|_| self)
+        start_supervised(|_| self)
+    }
+
+    /// Starts `instances` actors acting on the same store, each in its own arbiter when an actix
+    /// `System` is running, and returns their addresses. Pass them to
+    /// [RateLimiter::sharded](../../middleware/struct.RateLimiter.html#method.sharded) so that
+    /// clients are spread over the actors instead of all queueing up behind a single mailbox.
+    /// Only the first actor writes the snapshots of a persistent store.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use std::time::Duration;
+    /// use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = MemoryStore::new();
+    ///     let actors = MemoryStoreActor::from(store)
+    ///         .with_mailbox_capacity(256)
+    ///         .start_many(4);
+    ///     let ratelimiter = RateLimiter::sharded(actors)
+    ///         .with_interval(Duration::from_secs(60))
+    ///         .with_max_requests(100);
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if `instances` is zero.
+    pub fn start_many(self, instances: usize) -> Vec<Addr<Self>> {
+        assert!(instances > 0, "at least one store actor is required");
+        debug!("Started memory store with {} actors", instances);
+        (0..instances)
+            .map(|instance| {
+                let actor = MemoryStoreActor {
+                    inner: self.inner.clone(),
+                    persistence: self.persistence.clone().filter(|_| instance == 0),
+                    clock: self.clock.clone(),
+                    lists: self.lists.clone(),
+                    rates: self.rates.clone(),
+                    mailbox_capacity: self.mailbox_capacity,
+                    shutdown: false,
+                };
+                if System::is_set() {
+                    Supervisor::start_in_arbiter(&Arbiter::new(), |_| actor)
+                } else {
+                    start_supervised(|_| actor)
+                }
+            })
+            .collect()
+    }
+
+    /// The map of the actor as a [KvBackend](../kv/trait.KvBackend.html), which answers the
+    /// messages on plain counts
+    fn backend(&self) -> MemoryStore {
+        MemoryStore {
+            inner: self.inner.clone(),
+            persistence: None,
+            clock: self.clock.clone(),
+            lists: self.lists.clone(),
+            rates: self.rates.clone(),
+        }
+    }
+
+    /// Counts `value` requests against the moving average of `key`. The average decays by
+    /// `e^(-t / RATE_WINDOW)` over time `t`, so each request adds `1 / RATE_WINDOW` to it.
+    fn record_rate(&self, key: &str, value: usize) {
+        let now = self.clock.now();
+        let mut entry = self.rates.entry(key.to_string()).or_insert((0.0, now));
+        let (rate, last) = entry.value_mut();
+        *rate = decay(*rate, now.checked_sub(*last).unwrap_or_default())
+            + value as f64 / RATE_WINDOW.as_secs_f64();
+        *last = now;
+    }
+
+    /// Writes the snapshot, if the store is persistent
+    fn snapshot(&self) -> io::Result<()> {
+        match &self.persistence {
+            Some(path) => write_snapshot(path, &self.inner),
+            None => Ok(()),
+        }
+    }
+
+    /// Sets `key` to `value`, expiring after `expiry`
+    fn insert(&self, ctx: &mut Context<Self>, key: String, value: usize, expiry: Duration) {
+        let now = self.clock.now();
+        let previous = self.inner.insert(String::from(&key), (value, now + expiry));
+        // A key set again keeps its pending removal, which follows a later expiry
+        if previous.map_or(true, |(_, previous)| previous > now + expiry) {
+            Self::expire_later(ctx, key, expiry);
+        }
+    }
+
+    /// Removes `key` after `ttl`, unless it has been set again with a later expiry by then
+    fn expire_later(ctx: &mut Context<Self>, key: String, ttl: Duration) {
+        ctx.run_later(ttl, move |act, ctx| {
+            let now = act.clock.now();
+            let expiry = act.inner.get(&key).map(|entry| entry.value().1);
+            match expiry {
+                Some(expiry) if expiry > now => Self::expire_later(ctx, key, expiry - now),
+                Some(_) => {
+                    act.inner.remove(&key);
+                    act.rates.remove(&key);
+                }
+                None => {}
+            }
+        });
     }
 }
 
 impl Actor for MemoryStoreActor {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        if let Some(capacity) = self.mailbox_capacity {
+            ctx.set_mailbox_capacity(capacity);
+        }
+        // Entries restored from a snapshot still have to expire, and so do those whose pending
+        // removals were dropped along with the context when the actor restarted
+        let now = self.clock.now();
+        for entry in self.inner.iter() {
+            let ttl = entry.value().1.checked_sub(now).unwrap_or_default();
+            Self::expire_later(ctx, entry.key().clone(), ttl);
+        }
+        if self.persistence.is_none() {
+            return;
+        }
+        ctx.run_interval(SNAPSHOT_INTERVAL, |act, _| {
+            if let Err(e) = act.snapshot() {
+                error!("memory store: snapshot failed: {}", e);
+            }
+        });
+    }
 }
 
 impl Supervised for MemoryStoreActor {
@@ -74,76 +603,206 @@ impl Supervised for MemoryStoreActor {
 impl Handler<ActorMessage> for MemoryStoreActor {
     type Result = ActorResponse;
     fn handle(&mut self, msg: ActorMessage, ctx: &mut Self::Context) -> Self::Result {
+        if self.shutdown {
+            return ActorResponse::failed(&msg, ARError::ShutDown);
+        }
+        let now = self.clock.now();
+        // Plain counts are kept by the backend, the actor only adds expiry and request rates
+        match &msg {
+            ActorMessage::Set { key, expiry, .. } => {
+                debug!("Inserting key {} with expiry {}", key, &expiry.as_secs());
+                // A key set again keeps its pending removal, which follows a later expiry
+                let previous = self.inner.get(key).map(|c| c.value().1);
+                if previous.map_or(true, |previous| previous > now + *expiry) {
+                    Self::expire_later(ctx, key.clone(), *expiry);
+                }
+            }
+            // An existing key has its pending removal pushed back along with the expiry
+            ActorMessage::Increment { key, expiry, .. } if !self.inner.contains_key(key) => {
+                Self::expire_later(ctx, key.clone(), *expiry);
+            }
+            ActorMessage::Update { key, value }
+                if self.inner.get(key).map_or(false, |c| c.value().1 > now) =>
+            {
+                self.record_rate(key, *value);
+            }
+            ActorMessage::Remove(key) => {
+                debug!("Removing key: {}", key);
+                self.rates.remove(key);
+            }
+            ActorMessage::Clear(prefix) => {
+                debug!("Clearing keys starting with {}", prefix);
+                self.rates.retain(|key, _| !key.starts_with(prefix));
+            }
+            _ => {}
+        }
         match msg {
-            ActorMessage::Set { key, value, expiry } => {
-                debug!("Inserting key {} with expiry {}", &key, &expiry.as_secs());
-                let future_key = String::from(&key);
-                let now = SystemTime::now();
-                let now = now.duration_since(UNIX_EPOCH).unwrap();
-                self.inner.insert(key, (value, now + expiry));
-                ctx.notify_later(ActorMessage::Remove(future_key), expiry);
-                ActorResponse::Set(Box::pin(future::ready(Ok(()))))
-            }
-            ActorMessage::Update { key, value } => match self.inner.get_mut(&key) {
-                Some(mut c) => {
-                    let val_mut: &mut (usize, Duration) = c.value_mut();
-                    if val_mut.0 > value {
-                        val_mut.0 -= value;
+            ActorMessage::CheckList(key) => {
+                let status = self
+                    .lists
+                    .get(&key)
+                    .map_or(ListStatus::Normal, |c| *c.value());
+                ActorResponse::CheckList(Box::pin(future::ready(Ok(status))))
+            }
+            ActorMessage::Rate(key) => {
+                let now = self.clock.now();
+                let rate = self.rates.get(&key).map(|c| {
+                    let (rate, last) = *c.value();
+                    decay(rate, now.checked_sub(last).unwrap_or_default())
+                });
+                ActorResponse::Rate(Box::pin(future::ready(Ok(rate))))
+            }
+            ActorMessage::Grant { key, value, max } => {
+                let now = self.clock.now();
+                let new_val =
+                    self.inner
+                        .get_mut(&key)
+                        .filter(|c| c.value().1 > now)
+                        .map(|mut c| {
+                            let val_mut: &mut (usize, Duration) = c.value_mut();
+                            // A count already above the ceiling is left alone, never lowered
+                            val_mut.0 = val_mut.0.max(val_mut.0.saturating_add(value).min(max));
+                            val_mut.0
+                        });
+                ActorResponse::Grant(Box::pin(future::ready(Ok(new_val))))
+            }
+            ActorMessage::Slide {
+                key,
+                cost,
+                max,
+                interval,
+            } => {
+                let now = self.clock.now();
+                let previous_key = format!("{}:previous", &key);
+                // Read ahead of locking the entry of the client, as both may share a shard.
+                // Actors started with `start_many` may open a window in between, which at worst
+                // leaves the previous window out for this one request.
+                let previous = self
+                    .inner
+                    .get(&previous_key)
+                    .map(|c| *c.value())
+                    .filter(|(_, expiry)| *expiry > now)
+                    .map_or(0, |(value, _)| value);
+                let mut entry = self.inner.entry(String::from(&key)).or_insert((max, now));
+                let (remaining, expiry) = *entry.value();
+                let ttl = expiry.checked_sub(now).unwrap_or_default();
+                let (current, previous, left, opening) = if ttl > interval {
+                    // Still within the window the entry was opened for
+                    (
+                        max.saturating_sub(remaining),
+                        previous,
+                        ttl - interval,
+                        false,
+                    )
+                } else if ttl > Duration::from_secs(0) {
+                    // The window of the entry is over and now is the previous one
+                    (0, max.saturating_sub(remaining), ttl, true)
+                } else {
+                    (0, 0, interval, true)
+                };
+                let nanos = interval.as_nanos().max(1);
+                let weighted = (previous as u128 * left.as_nanos() + nanos - 1) / nanos;
+                let mut count = SlidingCount {
+                    counted: current as u128 + weighted + cost as u128 <= max as u128,
+                    current,
+                    previous,
+                    left,
+                };
+                if count.counted {
+                    // The window outlives itself by an interval, to be weighed in later
+                    *entry.value_mut() = if opening {
+                        (max.saturating_sub(cost), now + left + interval)
                     } else {
-                        val_mut.0 = 0;
-                    }
-                    let new_val = val_mut.0;
-                    ActorResponse::Update(Box::pin(future::ready(Ok(new_val))))
-                }
-                None => {
-                    return ActorResponse::Update(Box::pin(future::ready(Err(
-                        ARError::ReadWriteError("memory store: read failed!".to_string()),
-                    ))))
+                        (remaining.saturating_sub(cost), expiry)
+                    };
+                    count.current = max.saturating_sub(entry.value().0);
                 }
-            },
-            ActorMessage::Get(key) => {
-                if self.inner.contains_key(&key) {
-                    let val = match self.inner.get(&key) {
-                        Some(c) => c,
-                        None => {
-                            return ActorResponse::Get(Box::pin(future::ready(Err(
-                                ARError::ReadWriteError("memory store: read failed!".to_string()),
-                            ))))
+                drop(entry);
+                match (count.counted, opening) {
+                    (true, true) => {
+                        if ttl == Duration::from_secs(0) {
+                            Self::expire_later(ctx, String::from(&key), left + interval);
                         }
-                    };
-                    let val = val.value().0;
-                    ActorResponse::Get(Box::pin(future::ready(Ok(Some(val)))))
-                } else {
-                    ActorResponse::Get(Box::pin(future::ready(Ok(None))))
+                        if previous > 0 && left > Duration::from_secs(0) {
+                            self.insert(ctx, previous_key, previous, left);
+                        }
+                    }
+                    (true, false) => self.record_rate(&key, cost),
+                    // Drop the placeholder of a client turned down on its first request
+                    (false, _) => {
+                        self.inner.remove_if(&key, |_, (_, expiry)| *expiry <= now);
+                    }
                 }
+                ActorResponse::Slide(Box::pin(future::ready(Ok(count))))
             }
-            ActorMessage::Expire(key) => {
-                let c = match self.inner.get(&key) {
-                    Some(d) => d,
+            ActorMessage::Draw {
+                key,
+                tier,
+                cost,
+                max,
+                interval,
+                reserved,
+            } => {
+                let now = self.clock.now();
+                let tier_key = |tier: &str| format!("{}:{}", &key, tier);
+                let live = |key: &str| {
+                    self.inner
+                        .get(key)
+                        .map(|c| *c.value())
+                        .filter(|(_, expiry)| *expiry > now)
+                };
+                // Messages are handled one at a time, so nothing changes the pool in between.
+                // Actors started with `start_many` share the map, and may at worst both open
+                // a window or draw on the same requests.
+                let (remaining, expiry) = match live(&key) {
+                    Some(pool) => pool,
                     None => {
-                        return ActorResponse::Expire(Box::pin(future::ready(Err(
-                            ARError::ReadWriteError("memory store: read failed!".to_string()),
-                        ))))
+                        // Open a new window, restoring every reservation
+                        self.insert(ctx, String::from(&key), max, interval);
+                        for (name, value) in &reserved {
+                            self.insert(ctx, tier_key(name), *value, interval);
+                        }
+                        (max, now + interval)
                     }
                 };
-                let dur = c.value().1;
-                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                let res = dur.checked_sub(now).unwrap_or_else(|| Duration::new(0, 0));
-                ActorResponse::Expire(Box::pin(future::ready(Ok(res))))
-            }
-            ActorMessage::Remove(key) => {
-                debug!("Removing key: {}", &key);
-                let val = match self.inner.remove::<String>(&key) {
-                    Some(c) => c,
-                    None => {
-                        return ActorResponse::Remove(Box::pin(future::ready(Err(
-                            ARError::ReadWriteError("memory store: remove failed!".to_string()),
-                        ))))
+                // What is left of the reservations, which the pool has to keep room for
+                let mut own = 0;
+                let mut unused = 0;
+                for (name, _) in &reserved {
+                    let left = live(&tier_key(name)).map_or(0, |(value, _)| value);
+                    if *name == tier {
+                        own = left;
                     }
+                    unused += left;
+                }
+                let available = own + remaining.saturating_sub(unused);
+                let mut draw = PoolDraw {
+                    counted: available >= cost,
+                    available,
+                    reserved: 0,
+                    left: expiry - now,
                 };
-                let val = val.1;
-                ActorResponse::Remove(Box::pin(future::ready(Ok(val.0))))
+                if draw.counted {
+                    // Draw on the reservation first, then on the unreserved part of the pool
+                    draw.reserved = own.min(cost);
+                    draw.available -= cost;
+                    for (key, value) in vec![(tier_key(&tier), draw.reserved), (key, cost)] {
+                        if let Some(mut c) = self.inner.get_mut(&key) {
+                            let count = &mut c.value_mut().0;
+                            *count = count.saturating_sub(value);
+                        }
+                    }
+                }
+                ActorResponse::Draw(Box::pin(future::ready(Ok(draw))))
             }
+            ActorMessage::Shutdown => {
+                // Every earlier message has already been applied to the map
+                debug!("Shutting down memory store");
+                self.shutdown = true;
+                let res = self.snapshot().map_err(ARError::UnknownError);
+                ActorResponse::Shutdown(Box::pin(future::ready(res)))
+            }
+            msg => kv::respond(self.backend(), now, msg),
         }
     }
 }
@@ -151,6 +810,7 @@ impl Handler<ActorMessage> for MemoryStoreActor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::ManualClock;
 
     #[actix_rt::test]
     async fn test_set() {
@@ -248,4 +908,598 @@ mod tests {
             _ => panic!("Shouldn't happen!"),
         };
     }
+
+    #[actix_rt::test]
+    async fn test_subsecond_expiry() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello".to_string(),
+                value: 30usize,
+                expiry: Duration::from_millis(250),
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Set(c) => c.await.unwrap(),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr.send(ActorMessage::Expire("hello".to_string())).await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Expire(c) => {
+                let dur = c.await.unwrap();
+                assert!(dur > Duration::from_millis(0) && dur <= Duration::from_millis(250));
+            }
+            _ => panic!("Shouldn't happen!"),
+        }
+
+        for (wait, expected) in &[(150, Some(30)), (150, None)] {
+            actix_rt::time::delay_for(Duration::from_millis(*wait)).await;
+            let res = addr.send(ActorMessage::Get("hello".to_string())).await;
+            match res.expect("Failed to send msg") {
+                ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), *expected),
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_stats() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        for key in &["hello", "world"] {
+            let res = addr
+                .send(ActorMessage::Set {
+                    key: key.to_string(),
+                    value: 30usize,
+                    expiry: Duration::from_secs(5),
+                })
+                .await;
+            let res = res.expect("Failed to send msg");
+            match res {
+                ActorResponse::Set(c) => match c.await {
+                    Ok(()) => {}
+                    Err(e) => panic!("Shouldn't happen {}", &e),
+                },
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+        let res2 = addr.send(ActorMessage::Stats).await;
+        let res2 = res2.expect("Failed to send msg");
+        match res2 {
+            ActorResponse::Stats(c) => match c.await {
+                Ok(stats) => {
+                    assert_eq!(stats.connected, true);
+                    assert_eq!(stats.key_count, Some(2));
+                }
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+    }
+
+    #[actix_rt::test]
+    async fn test_shutdown() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello".to_string(),
+                value: 30usize,
+                expiry: Duration::from_secs(5),
+            })
+            .await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Set(c) => match c.await {
+                Ok(()) => {}
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        }
+        for _ in 0..10 {
+            addr.do_send(ActorMessage::Update {
+                key: "hello".to_string(),
+                value: 1,
+            });
+        }
+        let res2 = addr.send(ActorMessage::Shutdown).await;
+        let res2 = res2.expect("Failed to send msg");
+        match res2 {
+            ActorResponse::Shutdown(c) => match c.await {
+                Ok(()) => {}
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+        // Every update queued before the shutdown has been applied
+        assert_eq!(store.inner.get("hello").unwrap().value().0, 20usize);
+        let res3 = addr.send(ActorMessage::Get("hello".to_string())).await;
+        let res3 = res3.expect("Failed to send msg");
+        match res3 {
+            ActorResponse::Get(c) => match c.await {
+                Err(ARError::ShutDown) => {}
+                _ => panic!("Shouldn't happen!"),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+    }
+
+    #[actix_rt::test]
+    async fn test_persistence() {
+        let path =
+            std::env::temp_dir().join(format!("actix-ratelimit-{}.snapshot", std::process::id()));
+        let store = MemoryStore::with_persistence(&path);
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello".to_string(),
+                value: 30usize,
+                expiry: Duration::from_secs(10),
+            })
+            .await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Set(c) => match c.await {
+                Ok(()) => {}
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "hello".to_string(),
+                value: 5,
+            })
+            .await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Update(c) => match c.await {
+                Ok(c) => assert_eq!(c, 25usize),
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        }
+        // Shutting down writes the final snapshot
+        let res = addr.send(ActorMessage::Shutdown).await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Shutdown(c) => match c.await {
+                Ok(()) => {}
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        }
+
+        let store = MemoryStore::with_persistence(&path);
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let res2 = addr.send(ActorMessage::Get("hello".to_string())).await;
+        let res2 = res2.expect("Failed to send msg");
+        match res2 {
+            ActorResponse::Get(c) => match c.await {
+                Ok(d) => assert_eq!(d, Some(25usize)),
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+        let res3 = addr.send(ActorMessage::Expire("hello".to_string())).await;
+        let res3 = res3.expect("Failed to send msg");
+        match res3 {
+            ActorResponse::Expire(c) => match c.await {
+                Ok(dur) => {
+                    assert!(dur <= Duration::from_secs(10));
+                    assert!(dur > Duration::from_secs(8));
+                }
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+        let _ = fs::remove_file(&path);
+    }
+
+    #[actix_rt::test]
+    async fn test_clear() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        for key in &["api:1", "api:2", "api:3", "login:1"] {
+            let res = addr
+                .send(ActorMessage::Set {
+                    key: key.to_string(),
+                    value: 30usize,
+                    expiry: Duration::from_secs(5),
+                })
+                .await;
+            let res = res.expect("Failed to send msg");
+            match res {
+                ActorResponse::Set(c) => match c.await {
+                    Ok(()) => {}
+                    Err(e) => panic!("Shouldn't happen {}", &e),
+                },
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+        let res2 = addr.send(ActorMessage::Clear("api:".to_string())).await;
+        let res2 = res2.expect("Failed to send msg");
+        match res2 {
+            ActorResponse::Clear(c) => match c.await {
+                Ok(removed) => assert_eq!(removed, 3),
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+        assert_eq!(store.inner.len(), 1);
+        assert!(store.inner.contains_key("login:1"));
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[tokio::test]
+    async fn test_tokio_runtime() {
+        // No actix System is running here, the actor moves to a background one
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello".to_string(),
+                value: 30usize,
+                expiry: Duration::from_secs(5),
+            })
+            .await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Set(c) => match c.await {
+                Ok(()) => {}
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res2 = addr.send(ActorMessage::Get("hello".to_string())).await;
+        let res2 = res2.expect("Failed to send msg");
+        match res2 {
+            ActorResponse::Get(c) => match c.await {
+                Ok(d) => assert_eq!(d, Some(30usize)),
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+    }
+
+    #[actix_rt::test]
+    async fn test_operation_error() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "ip:1.2.3.4".to_string(),
+                value: 1,
+            })
+            .await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Update(c) => match c.await {
+                Ok(_) => panic!("Shouldn't happen!"),
+                Err(e) => assert_eq!(
+                    e.to_string(),
+                    "update failed for key 'ip:1.2.3.4': key not found"
+                ),
+            },
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_manual_clock() {
+        let clock = ManualClock::new();
+        let store = MemoryStore::new().with_clock(clock.clone());
+        let addr = MemoryStoreActor::from(store).start();
+        let set = ActorMessage::Set {
+            key: "hello".to_string(),
+            value: 30usize,
+            expiry: Duration::from_secs(60),
+        };
+        addr.send(set).await.unwrap().expect_set().await.unwrap();
+        let ttl = |addr: Addr<MemoryStoreActor>| async move {
+            let res = addr.send(ActorMessage::Expire("hello".to_string())).await;
+            res.unwrap().expect_expire().await.unwrap()
+        };
+        assert_eq!(ttl(addr.clone()).await, Duration::from_secs(60));
+        clock.advance(Duration::from_secs(45));
+        assert_eq!(ttl(addr.clone()).await, Duration::from_secs(15));
+        let res = addr.send(ActorMessage::Get("hello".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(30));
+        // Gone once the clock passes the expiry, without waiting for it
+        clock.advance(Duration::from_secs(15));
+        let res = addr.send(ActorMessage::Get("hello".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_slide() {
+        let clock = ManualClock::new();
+        let store = MemoryStore::new().with_clock(clock.clone());
+        let addr = MemoryStoreActor::from(store).start();
+        let slide = |addr: Addr<MemoryStoreActor>| async move {
+            let msg = ActorMessage::Slide {
+                key: "hello".to_string(),
+                cost: 1,
+                max: 3,
+                interval: Duration::from_secs(60),
+            };
+            addr.send(msg).await.unwrap().expect_slide().await.unwrap()
+        };
+        for current in 1..=3 {
+            let count = slide(addr.clone()).await;
+            assert!(count.counted);
+            assert_eq!(count.current, current);
+            assert_eq!(count.left, Duration::from_secs(60));
+        }
+        let count = slide(addr.clone()).await;
+        assert!(!count.counted);
+        assert_eq!(count.current, 3);
+
+        // The previous window still weighs in fully, then for a third of it
+        clock.advance(Duration::from_secs(61));
+        let count = slide(addr.clone()).await;
+        assert!(!count.counted);
+        assert_eq!((count.current, count.previous), (0, 3));
+        assert_eq!(count.left, Duration::from_secs(59));
+        clock.advance(Duration::from_secs(40));
+        let count = slide(addr.clone()).await;
+        assert!(count.counted);
+        assert_eq!((count.current, count.previous), (1, 3));
+        assert_eq!(count.left, Duration::from_secs(19));
+        let res = addr
+            .send(ActorMessage::Get("hello:previous".to_string()))
+            .await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(3));
+        assert!(slide(addr.clone()).await.counted);
+        let count = slide(addr.clone()).await;
+        assert!(!count.counted);
+        assert_eq!(count.current, 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_draw() {
+        let clock = ManualClock::new();
+        let store = MemoryStore::new().with_clock(clock.clone());
+        let addr = MemoryStoreActor::from(store).start();
+        let draw = |addr: Addr<MemoryStoreActor>, tier: &str| {
+            let msg = ActorMessage::Draw {
+                key: "pool".to_string(),
+                tier: tier.to_string(),
+                cost: 1,
+                max: 5,
+                interval: Duration::from_secs(60),
+                reserved: vec![("premium".to_string(), 2)],
+            };
+            async move { addr.send(msg).await.unwrap().expect_draw().await.unwrap() }
+        };
+        // Other tiers leave the reservation alone
+        for available in (0..3).rev() {
+            let draw = draw(addr.clone(), "free").await;
+            assert!(draw.counted);
+            assert_eq!((draw.available, draw.reserved), (available, 0));
+            assert_eq!(draw.left, Duration::from_secs(60));
+        }
+        let draw_free = draw(addr.clone(), "free").await;
+        assert!(!draw_free.counted);
+        assert_eq!(draw_free.available, 0);
+        let draw_premium = draw(addr.clone(), "premium").await;
+        assert!(draw_premium.counted);
+        assert_eq!((draw_premium.available, draw_premium.reserved), (1, 1));
+        let res = addr.send(ActorMessage::Get("pool".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(1));
+
+        // A missing pool opens a new window with the reservations restored
+        clock.advance(Duration::from_secs(61));
+        let draw_premium = draw(addr.clone(), "premium").await;
+        assert!(draw_premium.counted);
+        assert_eq!((draw_premium.available, draw_premium.reserved), (4, 1));
+        let res = addr
+            .send(ActorMessage::Get("pool:premium".to_string()))
+            .await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(1));
+    }
+
+    struct Restart;
+
+    impl Message for Restart {
+        type Result = ();
+    }
+
+    impl Handler<Restart> for MemoryStoreActor {
+        type Result = ();
+        fn handle(&mut self, _: Restart, ctx: &mut Self::Context) {
+            ctx.stop();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_expiry_survives_restart() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello".to_string(),
+                value: 30usize,
+                expiry: Duration::from_millis(200),
+            })
+            .await
+            .unwrap();
+        res.expect_set().await.unwrap();
+        // The supervisor restarts the actor, dropping the pending removal of the key
+        addr.send(Restart).await.unwrap();
+        actix_rt::time::delay_for(Duration::from_millis(400)).await;
+        assert!(!store.inner.contains_key("hello"));
+        // The restarted actor still serves requests
+        let res = addr.send(ActorMessage::Get("hello".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_start_many() {
+        let store = MemoryStore::new();
+        let actors = MemoryStoreActor::from(store.clone())
+            .with_mailbox_capacity(64)
+            .start_many(3);
+        assert_eq!(actors.len(), 3);
+        let res = actors[0]
+            .send(ActorMessage::Set {
+                key: "hello".to_string(),
+                value: 30usize,
+                expiry: Duration::from_secs(5),
+            })
+            .await
+            .unwrap();
+        res.expect_set().await.unwrap();
+        // Every actor acts on the same map
+        for addr in &actors[1..] {
+            let res = addr
+                .send(ActorMessage::Update {
+                    key: "hello".to_string(),
+                    value: 1,
+                })
+                .await
+                .unwrap();
+            res.expect_update().await.unwrap();
+        }
+        let res = actors[0].send(ActorMessage::Get("hello".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(28));
+    }
+
+    #[actix_rt::test]
+    async fn test_len() {
+        let store = MemoryStore::new();
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        assert!(store.is_empty());
+        for key in &["first", "second"] {
+            let res = addr
+                .send(ActorMessage::Set {
+                    key: key.to_string(),
+                    value: 30usize,
+                    expiry: Duration::from_millis(100),
+                })
+                .await
+                .unwrap();
+            res.expect_set().await.unwrap();
+        }
+        assert_eq!(store.len(), 2);
+        assert!(store.capacity() >= 2);
+        actix_rt::time::delay_for(Duration::from_millis(300)).await;
+        assert_eq!(store.len(), 0);
+        assert!(store.is_empty());
+    }
+
+    #[actix_rt::test]
+    async fn test_rate() {
+        let clock = ManualClock::new();
+        let store = MemoryStore::new().with_clock(clock.clone());
+        let addr = MemoryStoreActor::from(store).start();
+        let res = addr.send(ActorMessage::Rate("hello".to_string())).await;
+        assert_eq!(res.unwrap().expect_rate().await.unwrap(), None);
+        let set = ActorMessage::Set {
+            key: "hello".to_string(),
+            value: 10_000usize,
+            expiry: Duration::from_secs(3600),
+        };
+        addr.send(set).await.unwrap().expect_set().await.unwrap();
+        // Ten requests per second for a minute
+        for _ in 0..600 {
+            clock.advance(Duration::from_millis(100));
+            let update = ActorMessage::Update {
+                key: "hello".to_string(),
+                value: 1,
+            };
+            addr.send(update)
+                .await
+                .unwrap()
+                .expect_update()
+                .await
+                .unwrap();
+        }
+        let rate = |addr: Addr<MemoryStoreActor>| async move {
+            let res = addr.send(ActorMessage::Rate("hello".to_string())).await;
+            res.unwrap().expect_rate().await.unwrap().unwrap()
+        };
+        let estimate = rate(addr.clone()).await;
+        assert!(estimate > 9.0 && estimate < 11.0, "{}", estimate);
+        // Decays while the client is idle
+        clock.advance(Duration::from_secs(30));
+        let estimate = rate(addr.clone()).await;
+        assert!(estimate > 0.1 && estimate < 1.0, "{}", estimate);
+    }
+
+    #[actix_rt::test]
+    async fn test_raw_access() {
+        let clock = ManualClock::new();
+        let store = TestMemoryStore::from(MemoryStore::new().with_clock(clock.clone()));
+        let addr = MemoryStoreActor::from(store.clone()).start();
+        store.set_raw("seeded", 5, Duration::from_secs(60));
+        let res = addr.send(ActorMessage::Get("seeded".to_string())).await;
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(5));
+
+        let update = ActorMessage::Update {
+            key: "seeded".to_string(),
+            value: 2,
+        };
+        addr.send(update)
+            .await
+            .unwrap()
+            .expect_update()
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(store.get_raw("seeded"), Some((3, Duration::from_secs(40))));
+        assert_eq!(store.get_raw("missing"), None);
+        store.set_raw("short", 1, Duration::from_secs(10));
+        assert_eq!(store.inner_snapshot().len(), 2);
+
+        // Expired keys are gone, whether or not the actor removed them yet
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(store.get_raw("short"), None);
+        let snapshot = store.inner_snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot["seeded"], (3, Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn test_snapshot_keys() {
+        let path = std::env::temp_dir().join(format!(
+            "actix-ratelimit-keys-{}.snapshot",
+            std::process::id()
+        ));
+        // Snapshots keep expiries in milliseconds
+        let expiry = Duration::from_millis(SystemClock.now().as_millis() as u64 + 60_000);
+        let map = DashMap::new();
+        for key in &[
+            "multi\nline",
+            "carriage\r\n",
+            "back\\slash\\n",
+            "tab\tbed",
+            "plain",
+        ] {
+            map.insert(key.to_string(), (1, expiry));
+        }
+        write_snapshot(&path, &map).unwrap();
+        let restored = DashMap::new();
+        assert_eq!(load_snapshot(&path, &restored).unwrap(), 5);
+        for entry in map.iter() {
+            assert_eq!(
+                restored.get(entry.key()).map(|e| *e.value()),
+                Some((1, expiry))
+            );
+        }
+
+        // A malformed line doesn't stop the entries after it from being restored
+        let millis = expiry.as_millis();
+        let mut lines =
+            format!("1\t{0}\tfirst\n3\nbroken\n2\t{0}\tbad\\escape\n", millis).into_bytes();
+        lines.extend_from_slice(b"\xff\n");
+        lines.extend_from_slice(format!("4\t{}\tlast\n", millis).as_bytes());
+        fs::write(&path, lines).unwrap();
+        let restored = DashMap::new();
+        assert_eq!(load_snapshot(&path, &restored).unwrap(), 2);
+        assert_eq!(restored.get("first").map(|e| e.value().0), Some(1));
+        assert_eq!(restored.get("last").map(|e| e.value().0), Some(4));
+        fs::remove_file(&path).unwrap();
+    }
 }
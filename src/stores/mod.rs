@@ -18,7 +18,7 @@
 //! use std::collections::HashMap;
 //! use std::time::Duration;
 //! use actix::prelude::*;
-//! use actix_ratelimit::{ActorMessage, ActorResponse};
+//! use actix_ratelimit::{ActorMessage, ActorResponse, ListStatus, StoreStats};
 //! use futures::future::{ok, err};
 //!
 //! struct MyStore(HashMap<String, usize>);
@@ -55,6 +55,12 @@
 //!                 let new_val = *val;
 //!                 ActorResponse::Update(Box::pin(ok(new_val)))
 //!             },
+//!             // Handle Increment message
+//!             ActorMessage::Increment {key, value, expiry} => {
+//!                 let val = self.inner.entry(key).or_insert(0);
+//!                 *val += value;
+//!                 ActorResponse::Increment(Box::pin(ok(*val)))
+//!             },
 //!             // Handle get message
 //!             ActorMessage::Get(key) => {
 //!                 let val = *self.inner.get(&key).unwrap();
@@ -70,7 +76,42 @@
 //!                 let val = self.inner.remove(&key).unwrap();
 //!                 ActorResponse::Remove(Box::pin(ok(val)))
 //!             },
-//!
+//!             // Handle Stats message
+//!             ActorMessage::Stats => {
+//!                 let stats = StoreStats {
+//!                     connected: true,
+//!                     key_count: Some(self.inner.len()),
+//!                 };
+//!                 ActorResponse::Stats(Box::pin(ok(stats)))
+//!             },
+//!             // Handle Shutdown message, nothing is pending in this store
+//!             ActorMessage::Shutdown => {
+//!                 ActorResponse::Shutdown(Box::pin(ok(())))
+//!             },
+//!             // Handle Clear message
+//!             ActorMessage::Clear(prefix) => {
+//!                 let before = self.inner.len();
+//!                 self.inner.retain(|key, _| !key.starts_with(&prefix));
+//!                 ActorResponse::Clear(Box::pin(ok(before - self.inner.len())))
+//!             },
+//!             // Handle CheckList message, this store keeps no allow or deny lists
+//!             ActorMessage::CheckList(key) => {
+//!                 ActorResponse::CheckList(Box::pin(ok(ListStatus::Normal)))
+//!             },
+//!             // Handle Rate message, this store does not track request rates
+//!             ActorMessage::Rate(key) => {
+//!                 ActorResponse::Rate(Box::pin(ok(None)))
+//!             },
+//!             // Handle Grant message
+//!             ActorMessage::Grant {key, value, max} => {
+//!                 let val = self.inner.get_mut(&key).map(|val| {
+//!                     *val = (*val).max((*val + value).min(max));
+//!                     *val
+//!                 });
+//!                 ActorResponse::Grant(Box::pin(ok(val)))
+//!             },
+//!             // Turn down the kinds of messages added in later releases
+//!             msg => ActorResponse::unsupported(&msg),
 //!             }
 //!         }
 //! }
@@ -78,12 +119,300 @@
 //! # Note
 //!
 //! The above example is not thread-safe and does not implement key expiration! It's just for demonstration purposes.
+//!
+//! `ActorMessage` is `#[non_exhaustive]`, so a store outside of this crate needs a catch-all arm
+//! like the last one above. Messages it answers with `ActorResponse::unsupported` fail with
+//! `ARError::Unsupported`, which the middleware handles like any other store error, except for
+//! `ActorMessage::Slide` and `ActorMessage::Draw`, which it then replaces with separate messages.
+//!
+//! # Runtimes
+//!
+//! Store actors run on actix's `System`. With the `tokio-runtime` feature, enabled by default,
+//! stores started outside of a `System`, for example from a plain `#[tokio::main]`, are moved to
+//! a background thread running its own `System`, so their addresses can be used from any
+//! runtime.
+
+use actix::prelude::*;
+#[cfg(any(feature = "redis-store", feature = "memcached"))]
+use backoff::ExponentialBackoff;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{self, Poll, Waker};
+#[cfg(any(feature = "redis-store", feature = "memcached"))]
+use std::time::{Duration, Instant};
+
+pub mod kv;
 
 #[cfg(feature = "memory")]
 pub mod memory;
 
+#[cfg(feature = "memory")]
+pub mod sharded;
+
 #[cfg(feature = "redis-store")]
 pub mod redis;
 
 #[cfg(feature = "memcached")]
 pub mod memcached;
+
+#[cfg(feature = "dynamodb-store")]
+pub mod dynamodb;
+
+/// How often the redis and memcached stores retry connecting, see
+/// `RedisStore::connect_with_backoff` and `RedisStoreActor::with_backoff` and their memcached
+/// counterparts. Retries wait exponentially longer, up to a random delay around `max_interval`.
+///
+/// # Example
+/// ```rust
+/// use actix_ratelimit::stores::ReconnectBackoff;
+/// use std::time::Duration;
+///
+/// // Retry at least every five seconds, and give up after a minute
+/// let backoff = ReconnectBackoff::new()
+///     .with_max_interval(Duration::from_secs(5))
+///     .with_multiplier(2.0)
+///     .with_max_elapsed_time(Some(Duration::from_secs(60)));
+/// ```
+#[cfg(any(feature = "redis-store", feature = "memcached"))]
+#[derive(Debug, Clone)]
+pub struct ReconnectBackoff {
+    max_interval: Duration,
+    multiplier: f64,
+    max_elapsed_time: Option<Duration>,
+}
+
+#[cfg(any(feature = "redis-store", feature = "memcached"))]
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        let backoff = ExponentialBackoff::default();
+        ReconnectBackoff {
+            max_interval: backoff.max_interval,
+            multiplier: backoff.multiplier,
+            max_elapsed_time: None,
+        }
+    }
+}
+
+#[cfg(any(feature = "redis-store", feature = "memcached"))]
+impl ReconnectBackoff {
+    /// Retries every minute at most, growing by 1.5 each time, and never gives up. These are the
+    /// values used by `RedisStore::connect` and `MemcacheStore::connect`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the longest delay between two attempts
+    pub fn with_max_interval(mut self, max_interval: Duration) -> Self {
+        self.max_interval = max_interval;
+        self
+    }
+
+    /// Set the factor the delay grows by after each failed attempt
+    pub fn with_multiplier(mut self, multiplier: f64) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    /// Set how long to keep retrying before giving up, `None` to retry forever
+    pub fn with_max_elapsed_time(mut self, max_elapsed_time: Option<Duration>) -> Self {
+        self.max_elapsed_time = max_elapsed_time;
+        self
+    }
+
+    pub(crate) fn build(&self) -> ExponentialBackoff {
+        ExponentialBackoff {
+            max_interval: self.max_interval,
+            multiplier: self.multiplier,
+            max_elapsed_time: self.max_elapsed_time,
+            ..ExponentialBackoff::default()
+        }
+    }
+}
+
+/// Change in the connection of a redis or memcached store, handed to the function given to
+/// `RedisStore::connect_with_listener` or `MemcacheStore::connect_with_listener`, e.g. to raise
+/// an alert while the store is down
+#[cfg(any(feature = "redis-store", feature = "memcached"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    /// The store lost its connection, or couldn't open one, and restarts to connect again
+    Restarting,
+    /// The store is connected again, after being down for `downtime`
+    Reconnected { downtime: Duration },
+}
+
+#[cfg(any(feature = "redis-store", feature = "memcached"))]
+pub(crate) type ConnectionListener = Arc<dyn Fn(ConnectionEvent) + Send + Sync>;
+
+/// Time since the connection of a store went down, for reporting its restarts and recovery to
+/// a listener
+#[cfg(any(feature = "redis-store", feature = "memcached"))]
+#[derive(Default)]
+pub(crate) struct Outage {
+    listener: Option<ConnectionListener>,
+    since: Option<Instant>,
+}
+
+#[cfg(any(feature = "redis-store", feature = "memcached"))]
+impl Outage {
+    pub(crate) fn new(listener: Option<ConnectionListener>) -> Self {
+        Outage {
+            listener,
+            since: None,
+        }
+    }
+
+    /// Marks the connection as down, unless it already is
+    pub(crate) fn down(&mut self) {
+        self.since.get_or_insert_with(Instant::now);
+    }
+
+    pub(crate) fn restarting(&mut self) {
+        self.down();
+        self.emit(ConnectionEvent::Restarting);
+    }
+
+    /// Marks the connection as up, returning how long it was down if it was
+    pub(crate) fn connected(&mut self) -> Option<Duration> {
+        let downtime = self.since.take()?.elapsed();
+        self.emit(ConnectionEvent::Reconnected { downtime });
+        Some(downtime)
+    }
+
+    fn emit(&self, event: ConnectionEvent) {
+        if let Some(listener) = &self.listener {
+            listener(event);
+        }
+    }
+}
+
+/// Flag shared between a store and the middleware telling whether the store can serve requests,
+/// see `RedisStoreActor::readiness` and `RateLimiter::with_readiness`. Clones share the same flag.
+///
+/// # Example
+/// ```rust
+/// use actix_ratelimit::stores::Readiness;
+///
+/// let readiness = Readiness::new(false);
+/// // Hand a clone to the middleware, and mark the store ready once it is connected
+/// let handle = readiness.clone();
+/// handle.set_ready(true);
+/// assert!(readiness.is_ready());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Readiness {
+    inner: Arc<ReadinessState>,
+}
+
+#[derive(Debug, Default)]
+struct ReadinessState {
+    ready: AtomicBool,
+    /// Tasks to wake once the store becomes ready
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl Readiness {
+    /// Creates a flag, set to `ready`
+    pub fn new(ready: bool) -> Self {
+        let readiness = Self::default();
+        readiness.inner.ready.store(ready, Ordering::Release);
+        readiness
+    }
+
+    /// Whether the store can currently serve requests
+    pub fn is_ready(&self) -> bool {
+        self.inner.ready.load(Ordering::Acquire)
+    }
+
+    /// Marks the store as ready or not, waking the tasks waiting on it in the former case
+    pub fn set_ready(&self, ready: bool) {
+        self.inner.ready.store(ready, Ordering::Release);
+        if ready {
+            let wakers = std::mem::take(&mut *self.inner.wakers.lock().unwrap());
+            for waker in wakers {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Resolves once the store is ready, waking the task of `cx` when it becomes ready otherwise
+    pub fn poll_ready(&self, cx: &mut task::Context<'_>) -> Poll<()> {
+        if self.is_ready() {
+            return Poll::Ready(());
+        }
+        let mut wakers = self.inner.wakers.lock().unwrap();
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        // Checked again under the lock, the store may have become ready in between
+        if self.is_ready() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Starts a supervised store actor, on the background system if no actix `System` is running
+pub(crate) fn start_supervised<A, F>(f: F) -> Addr<A>
+where
+    A: Supervised + Actor<Context = Context<A>>,
+    F: FnOnce(&mut Context<A>) -> A + Send + 'static,
+{
+    #[cfg(feature = "tokio-runtime")]
+    {
+        if !System::is_set() {
+            return Supervisor::start_in_arbiter(&background_arbiter(), f);
+        }
+    }
+    Supervisor::start(f)
+}
+
+/// Arbiter of the system hosting the stores started outside of an actix `System`
+#[cfg(feature = "tokio-runtime")]
+fn background_arbiter() -> Arbiter {
+    lazy_static::lazy_static! {
+        static ref ARBITER: Arbiter = {
+            let (tx, rx) = std::sync::mpsc::channel();
+            std::thread::Builder::new()
+                .name("actix-ratelimit".to_string())
+                .spawn(move || {
+                    let system = System::new("actix-ratelimit");
+                    let _ = tx.send(Arbiter::current());
+                    system.run()
+                })
+                .expect("failed to spawn the store thread");
+            rx.recv().expect("store thread exited")
+        };
+    }
+    ARBITER.clone()
+}
+
+#[cfg(all(test, any(feature = "redis-store", feature = "memcached")))]
+mod tests {
+    use super::*;
+    use backoff::backoff::Backoff;
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let backoff = ReconnectBackoff::new().build();
+        assert_eq!(backoff.max_elapsed_time, None);
+
+        let mut backoff = ReconnectBackoff::new()
+            .with_max_interval(Duration::from_millis(200))
+            .with_multiplier(4.0)
+            .with_max_elapsed_time(Some(Duration::from_millis(100)))
+            .build();
+        assert_eq!(backoff.max_interval, Duration::from_millis(200));
+        assert_eq!(backoff.multiplier, 4.0);
+        // The first delay is drawn around the initial interval of 500ms, later ones around the
+        // maximum
+        backoff.next_backoff().unwrap();
+        for _ in 0..5 {
+            let delay = backoff.next_backoff().unwrap();
+            assert!(delay >= Duration::from_millis(100) && delay <= Duration::from_millis(300));
+        }
+        std::thread::sleep(Duration::from_millis(150));
+        assert_eq!(backoff.next_backoff(), None);
+    }
+}
@@ -2,12 +2,215 @@
 use actix::prelude::*;
 use backoff::backoff::Backoff;
 use backoff::ExponentialBackoff;
+use futures::channel::oneshot;
+use futures::future::{self, Either};
 use log::*;
 use redis_rs::{self as redis, aio::MultiplexedConnection};
-use std::time::Duration;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::errors::ARError;
-use crate::{ActorMessage, ActorResponse};
+use crate::stores::{
+    start_supervised, ConnectionEvent, ConnectionListener, Outage, Readiness, ReconnectBackoff,
+};
+use crate::{ActorMessage, ActorResponse, ListStatus, Output, PoolDraw, SlidingCount, StoreStats};
+
+/// Maximum time a request waits for the connection to be restored before giving up
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Maximum time `RedisStore::connect_verified` waits for redis to answer
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sets holding the identifiers of clients which are never or always rejected, see
+/// `RateLimiter::with_access_lists`
+const ALLOWLIST: &str = "allowlist";
+const DENYLIST: &str = "denylist";
+
+/// Number of keys requested per `SCAN` iteration when clearing or counting keys
+const SCAN_COUNT: usize = 500;
+
+/// Decrements `KEYS[1]` by `ARGV[1]`, stopping at 0 and keeping its expiry. Returns the count it
+/// would have been left with without stopping, or nil if the key does not exist.
+const DECREMENT: &str = r"
+local count = redis.call('GET', KEYS[1])
+if not count then
+    return false
+end
+count = tonumber(count)
+local decrement = math.min(count, tonumber(ARGV[1]))
+if decrement > 0 then
+    redis.call('DECRBY', KEYS[1], decrement)
+end
+return count - tonumber(ARGV[1])
+";
+
+/// Decrements field `ARGV[1]` of hash `KEYS[1]` by `ARGV[2]`, stopping at 0, and returns the new
+/// count, or -1 if the field does not exist
+const HASH_DECREMENT: &str = r"
+local count = redis.call('HGET', KEYS[1], ARGV[1])
+if not count then
+    return -1
+end
+count = math.max(tonumber(count) - tonumber(ARGV[2]), 0)
+redis.call('HSET', KEYS[1], ARGV[1], count)
+return count
+";
+
+/// Increments `KEYS[1]` by `ARGV[1]`, but not above `ARGV[2]`, keeping its expiry, and returns
+/// the new count, or -1 if the key does not exist
+const GRANT: &str = r"
+local count = redis.call('GET', KEYS[1])
+if not count then
+    return -1
+end
+count = tonumber(count)
+local granted = math.min(count + tonumber(ARGV[1]), tonumber(ARGV[2]))
+if granted > count then
+    count = redis.call('INCRBY', KEYS[1], granted - count)
+end
+return count
+";
+
+/// Increments field `ARGV[1]` of hash `KEYS[1]` by `ARGV[2]`, but not above `ARGV[3]`, and
+/// returns the new count, or -1 if the field does not exist
+const HASH_GRANT: &str = r"
+local count = redis.call('HGET', KEYS[1], ARGV[1])
+if not count then
+    return -1
+end
+count = tonumber(count)
+local granted = math.min(count + tonumber(ARGV[2]), tonumber(ARGV[3]))
+if granted > count then
+    count = redis.call('HINCRBY', KEYS[1], ARGV[1], granted - count)
+end
+return count
+";
+
+/// Counts `ARGV[1]` requests against the window of `KEYS[1]`, the count of the previous window
+/// being kept under `KEYS[2]`, if they fit in `ARGV[2]` requests per `ARGV[3]` milliseconds, see
+/// `Algorithm::SlidingWindowCounter`. Returns whether they were counted, the counts of the current
+/// and the previous window, and the milliseconds left in the current one.
+const SLIDE: &str = r"
+local cost = tonumber(ARGV[1])
+local max = tonumber(ARGV[2])
+local interval = tonumber(ARGV[3])
+local remaining = redis.call('GET', KEYS[1])
+local ttl = redis.call('PTTL', KEYS[1])
+local current, previous, left, opening = 0, 0, interval, true
+if remaining and ttl > interval then
+    current = math.max(max - tonumber(remaining), 0)
+    previous = tonumber(redis.call('GET', KEYS[2]) or 0)
+    left = ttl - interval
+    opening = false
+elseif remaining and ttl > 0 then
+    previous = math.max(max - tonumber(remaining), 0)
+    left = ttl
+end
+if current + math.ceil(previous * left / interval) + cost > max then
+    return {0, current, previous, left}
+end
+if opening then
+    redis.call('SET', KEYS[1], max - cost, 'PX', left + interval)
+    if previous > 0 and left > 0 then
+        redis.call('SET', KEYS[2], previous, 'PX', left)
+    end
+else
+    redis.call('DECRBY', KEYS[1], cost)
+end
+return {1, current + cost, previous, left}
+";
+
+/// Counts `ARGV[1]` requests against the pool of `ARGV[2]` requests per `ARGV[3]` milliseconds
+/// at `KEYS[1]`, see `ActorMessage::Draw`. The other keys hold what is left of each reservation,
+/// which the next arguments restore a new window to, and `ARGV[4]` is the position among them of
+/// the tier drawing, or 0 if it has no reservation. Returns whether the requests were counted,
+/// the requests available to the tier afterwards, those taken from its reservation, and the
+/// milliseconds left in the window.
+const DRAW: &str = r"
+local cost = tonumber(ARGV[1])
+local interval = tonumber(ARGV[3])
+local own_index = tonumber(ARGV[4]) + 1
+local remaining = redis.call('GET', KEYS[1])
+if not remaining then
+    remaining = ARGV[2]
+    redis.call('SET', KEYS[1], remaining, 'PX', interval)
+    for i = 2, #KEYS do
+        redis.call('SET', KEYS[i], ARGV[i + 3], 'PX', interval)
+    end
+end
+remaining = tonumber(remaining)
+local left = math.max(redis.call('PTTL', KEYS[1]), 0)
+local own, unused = 0, 0
+for i = 2, #KEYS do
+    local count = tonumber(redis.call('GET', KEYS[i]) or 0)
+    if i == own_index then
+        own = count
+    end
+    unused = unused + count
+end
+local available = own + math.max(remaining - unused, 0)
+if available < cost then
+    return {0, available, 0, left}
+end
+local reserved = math.min(own, cost)
+if reserved > 0 then
+    redis.call('DECRBY', KEYS[own_index], reserved)
+end
+if math.min(remaining, cost) > 0 then
+    redis.call('DECRBY', KEYS[1], math.min(remaining, cost))
+end
+return {1, available - cost, reserved, left}
+";
+
+/// Sets `KEYS[1]` to `ARGV[2]` if it still holds `ARGV[1]`, or does not exist if `ARGV[1]` is
+/// empty, and returns 1, or 0 if it changed in between. The key then expires after `ARGV[3]`
+/// milliseconds, or keeps its expiry if `ARGV[3]` is empty.
+const COMPARE_AND_SET: &str = r"
+local current = redis.call('GET', KEYS[1])
+if (current or '') ~= ARGV[1] then
+    return 0
+end
+if ARGV[3] ~= '' then
+    redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+else
+    local ttl = redis.call('PTTL', KEYS[1])
+    if ttl > 0 then
+        redis.call('SET', KEYS[1], ARGV[2], 'PX', ttl)
+    else
+        redis.call('SET', KEYS[1], ARGV[2])
+    end
+end
+return 1
+";
+
+/// Attempts at changing an encoded count before giving up, when other clients keep changing it
+const CAS_ATTEMPTS: usize = 10;
+
+/// Encoding of the counts kept in redis, see `RedisStoreActor::with_codec`
+pub trait ValueCodec: Send + Sync + 'static {
+    /// Encodes a count for storing
+    fn encode(&self, count: usize) -> String;
+
+    /// Decodes a stored count, `None` if it is malformed
+    fn decode(&self, raw: &str) -> Option<usize>;
+}
+
+/// Plain decimal integers, the format used by default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IntegerCodec;
+
+impl ValueCodec for IntegerCodec {
+    fn encode(&self, count: usize) -> String {
+        count.to_string()
+    }
+
+    fn decode(&self, raw: &str) -> Option<usize> {
+        raw.parse().ok()
+    }
+}
 
 struct GetAddr;
 impl Message for GetAddr {
@@ -19,6 +222,7 @@ pub struct RedisStore {
     addr: String,
     backoff: ExponentialBackoff,
     client: Option<MultiplexedConnection>,
+    outage: Outage,
 }
 
 impl RedisStore {
@@ -35,15 +239,118 @@ impl RedisStore {
     /// }
     /// ```
     pub fn connect<S: Into<String>>(addr: S) -> Addr<Self> {
-        let addr = addr.into();
-        let mut backoff = ExponentialBackoff::default();
-        backoff.max_elapsed_time = None;
-        Supervisor::start(|_| RedisStore {
+        Self::connect_with_backoff(addr, ReconnectBackoff::new())
+    }
+
+    /// Like `connect`, but retries connecting to redis according to `backoff` instead of
+    /// the defaults of `ReconnectBackoff`
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{stores::ReconnectBackoff, RedisStore};
+    /// use std::time::Duration;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() -> std::io::Result<()>{
+    ///     let backoff = ReconnectBackoff::new().with_max_interval(Duration::from_secs(5));
+    ///     let store = RedisStore::connect_with_backoff("redis://127.0.0.1", backoff);
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn connect_with_backoff<S: Into<String>>(addr: S, backoff: ReconnectBackoff) -> Addr<Self> {
+        Self::start(addr.into(), backoff, None)
+    }
+
+    /// Like `connect_with_backoff`, also calling `listener` whenever the store restarts to
+    /// reconnect and once it is connected again, along with how long it was down
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::stores::{ConnectionEvent, ReconnectBackoff};
+    /// use actix_ratelimit::RedisStore;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() -> std::io::Result<()>{
+    ///     let store = RedisStore::connect_with_listener(
+    ///         "redis://127.0.0.1",
+    ///         ReconnectBackoff::new(),
+    ///         |event| match event {
+    ///             ConnectionEvent::Restarting => eprintln!("redis is down"),
+    ///             ConnectionEvent::Reconnected { downtime } => {
+    ///                 eprintln!("redis is back after {:?}", downtime)
+    ///             }
+    ///         },
+    ///     );
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn connect_with_listener<S, F>(
+        addr: S,
+        backoff: ReconnectBackoff,
+        listener: F,
+    ) -> Addr<Self>
+    where
+        S: Into<String>,
+        F: Fn(ConnectionEvent) + Send + Sync + 'static,
+    {
+        Self::start(addr.into(), backoff, Some(Arc::new(listener)))
+    }
+
+    fn start(
+        addr: String,
+        backoff: ReconnectBackoff,
+        listener: Option<ConnectionListener>,
+    ) -> Addr<Self> {
+        let backoff = backoff.build();
+        start_supervised(|_| RedisStore {
             addr,
             backoff,
             client: None,
+            outage: Outage::new(listener),
         })
     }
+
+    /// Like `connect`, but first makes sure that redis answers a `PING`, so that a wrong address
+    /// or a server which is down is noticed on startup rather than by the first request. Fails
+    /// with `ARError::NotConnected` if redis can't be reached within five seconds.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::RedisStore;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     match RedisStore::connect_verified("redis://127.0.0.1").await {
+    ///         Ok(store) => {
+    ///             // Start the store actors and the server
+    ///         }
+    ///         Err(e) => eprintln!("redis is unreachable: {}", e),
+    ///     }
+    /// }
+    /// ```
+    pub async fn connect_verified<S: Into<String>>(addr: S) -> Result<Addr<Self>, ARError> {
+        let addr = addr.into();
+        let url = addr.clone();
+        let ping = async move {
+            let client = redis::Client::open(url.as_ref())?;
+            let mut con = client.get_async_connection().await?;
+            redis::cmd("PING")
+                .query_async::<redis::aio::Connection, ()>(&mut con)
+                .await
+        };
+        let deadline = actix::clock::delay_for(VERIFY_TIMEOUT);
+        match future::select(Box::pin(ping), deadline).await {
+            Either::Left((Ok(()), _)) => Ok(Self::connect(addr)),
+            Either::Left((Err(e), _)) => {
+                error!("Error connecting to redis: {}", &e);
+                Err(ARError::NotConnected)
+            }
+            Either::Right(_) => {
+                error!("Timed out connecting to redis at {}", &addr);
+                Err(ARError::NotConnected)
+            }
+        }
+    }
 }
 
 impl Actor for RedisStore {
@@ -63,9 +370,13 @@ impl Actor for RedisStore {
                     act.client = Some(c.0);
                     let fut = c.1;
                     fut.into_actor(act).spawn(context);
+                    if let Some(downtime) = act.outage.connected() {
+                        info!("Reconnected to redis after {:?}", downtime);
+                    }
                 }
                 Err(e) => {
                     error!("Error connecting to redis: {}", &e);
+                    act.outage.down();
                     if let Some(timeout) = act.backoff.next_backoff() {
                         context.run_later(timeout, |_, ctx| ctx.stop());
                     }
@@ -82,6 +393,7 @@ impl Supervised for RedisStore {
     fn restarting(&mut self, _: &mut Self::Context) {
         debug!("restarting redis store");
         self.client.take();
+        self.outage.restarting();
     }
 }
 
@@ -105,6 +417,14 @@ pub struct RedisStoreActor {
     addr: Addr<RedisStore>,
     backoff: ExponentialBackoff,
     inner: Option<MultiplexedConnection>,
+    coalescing: Option<Duration>,
+    codec: Option<Arc<dyn ValueCodec>>,
+    hash_window: Option<Duration>,
+    stats_prefix: Option<String>,
+    /// Decrements waiting to be flushed, by key and in the order they arrived
+    pending: HashMap<String, Vec<(usize, oneshot::Sender<Result<usize, ARError>>)>>,
+    readiness: Readiness,
+    shutdown: bool,
 }
 
 impl Actor for RedisStoreActor {
@@ -118,6 +438,7 @@ impl Actor for RedisStoreActor {
                 Ok(c) => {
                     if let Ok(conn) = c {
                         act.inner = Some(conn);
+                        act.readiness.set_ready(true);
                     } else {
                         error!("could not get redis store address");
                         if let Some(timeout) = act.backoff.next_backoff() {
@@ -144,15 +465,232 @@ impl From<Addr<RedisStore>> for RedisStoreActor {
             addr,
             backoff,
             inner: None,
+            coalescing: None,
+            codec: None,
+            hash_window: None,
+            stats_prefix: None,
+            pending: HashMap::new(),
+            readiness: Readiness::new(false),
+            shutdown: false,
         }
     }
 }
 
 impl RedisStoreActor {
+    /// Retry reaching the main store according to `backoff`. By default the actor retries at
+    /// least every three seconds and gives up after 15 minutes.
+    pub fn with_backoff(mut self, backoff: ReconnectBackoff) -> Self {
+        self.backoff = backoff.build();
+        self
+    }
+
+    /// Buffer the decrements of `ActorMessage::Update` for up to `window` and send them to redis
+    /// in a single pipeline, with one decrement per key. Under bursts this trades a few
+    /// milliseconds of latency for far fewer round trips. Each update still resolves to the value
+    /// it would have left had it been sent on its own, and reads account for the decrements
+    /// which are still buffered.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{RedisStore, RedisStoreActor};
+    /// use std::time::Duration;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = RedisStore::connect("redis://127.0.0.1");
+    ///     let addr = RedisStoreActor::from(store)
+    ///         .with_coalescing(Duration::from_millis(5))
+    ///         .start();
+    /// }
+    /// ```
+    pub fn with_coalescing(mut self, window: Duration) -> Self {
+        self.coalescing = Some(window);
+        self
+    }
+
+    /// Encode the counts kept in redis with `codec` instead of as plain integers, e.g. to share
+    /// the keys with another limiter which stores them in its own format. Expiries are still
+    /// kept as the TTL of the keys.
+    ///
+    /// Redis can't do arithmetic on encoded counts, so updates read the count and write it back,
+    /// and concurrent updates of the same key may be lost. `with_coalescing` has no effect along
+    /// with a codec.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::stores::redis::ValueCodec;
+    /// use actix_ratelimit::{RedisStore, RedisStoreActor};
+    ///
+    /// /// Counts stored as `{"count":5}`
+    /// struct JsonCodec;
+    ///
+    /// impl ValueCodec for JsonCodec {
+    ///     fn encode(&self, count: usize) -> String {
+    ///         format!(r#"{{"count":{}}}"#, count)
+    ///     }
+    ///
+    ///     fn decode(&self, raw: &str) -> Option<usize> {
+    ///         raw.strip_prefix(r#"{"count":"#)?.strip_suffix('}')?.parse().ok()
+    ///     }
+    /// }
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = RedisStore::connect("redis://127.0.0.1");
+    ///     let addr = RedisStoreActor::from(store).with_codec(JsonCodec).start();
+    /// }
+    /// ```
+    pub fn with_codec<C: ValueCodec>(mut self, codec: C) -> Self {
+        self.codec = Some(Arc::new(codec));
+        self
+    }
+
+    /// Keep the counts as fields of one hash per `window`, named `window:<start>` after the start
+    /// of the window in seconds since the unix epoch, rather than as a key each. The hash
+    /// expires as a whole once the window ends, so redis holds a few keys however many clients
+    /// there are, and old windows are dropped at once.
+    ///
+    /// Counts can't outlive their window this way: every entry expires at the end of the
+    /// window it was written in, whatever expiry it was given. The layout suits fixed windows
+    /// with an interval of `window`, all clients starting over at the same time; keys kept
+    /// across windows, as for `with_warmup` or the sliding window counter, are cut short. Stores
+    /// sharing a database should use the same window, and the windows follow the clock of the
+    /// application rather than that of redis. `with_codec` and `with_coalescing` have no effect
+    /// along with it.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{RedisStore, RedisStoreActor};
+    /// use std::time::Duration;
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = RedisStore::connect("redis://127.0.0.1");
+    ///     let addr = RedisStoreActor::from(store)
+    ///         .with_hash_layout(Duration::from_secs(60))
+    ///         .start();
+    /// }
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if `window` is shorter than a second.
+    pub fn with_hash_layout(mut self, window: Duration) -> Self {
+        assert!(
+            window >= Duration::from_secs(1),
+            "windows must last at least a second"
+        );
+        self.hash_window = Some(window);
+        self
+    }
+
+    /// Only count the keys starting with `prefix` in response to `ActorMessage::Stats`, e.g. the
+    /// prefix given to `RateLimiter::with_prefix`. By default every key of the database is
+    /// counted, including those which have nothing to do with rate limiting. Counting walks the
+    /// keys with `SCAN`, which takes longer the more keys the database holds. Under
+    /// `with_hash_layout`, the clients of the current window are counted either way.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{RedisStore, RedisStoreActor};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = RedisStore::connect("redis://127.0.0.1");
+    ///     let addr = RedisStoreActor::from(store)
+    ///         .with_stats_prefix("api:")
+    ///         .start();
+    /// }
+    /// ```
+    pub fn with_stats_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.stats_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Flag telling whether the actor holds a connection to redis. Pass it to
+    /// `RateLimiter::with_readiness` to hold requests back while the actor connects or
+    /// reconnects, instead of having them wait on the store or fail with `Disconnected`.
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::{RateLimiter, RedisStore, RedisStoreActor};
+    ///
+    /// #[actix_rt::main]
+    /// async fn main() {
+    ///     let store = RedisStore::connect("redis://127.0.0.1");
+    ///     let actor = RedisStoreActor::from(store);
+    ///     let readiness = actor.readiness();
+    ///     let ratelimiter = RateLimiter::new(actor.start()).with_readiness(readiness);
+    /// }
+    /// ```
+    pub fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
     /// Starts the redis actor and returns it's address
     pub fn start(self) -> Addr<Self> {
         debug!("started redis actor");
-        Supervisor::start(|_| self)
+        start_supervised(|_| self)
+    }
+
+    /// Sends the buffered decrements, resolving once every update waiting on them is answered
+    fn flush(&mut self) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let batch: Vec<_> = self.pending.drain().collect();
+        if batch.is_empty() {
+            return Box::pin(async {});
+        }
+        let connection: Output<MultiplexedConnection> = match self.inner.clone() {
+            Some(con) => Box::pin(async move { Ok(con) }),
+            None => wait_for_connection(self.addr.clone(), self.backoff.max_interval),
+        };
+        Box::pin(async move {
+            // `None` if there was no connection to send the batch on
+            let result = match connection.await {
+                Ok(mut con) => {
+                    let mut pipe = redis::pipe();
+                    pipe.atomic();
+                    for (key, updates) in &batch {
+                        let value: usize = updates.iter().map(|(value, _)| value).sum();
+                        pipe.cmd("EVAL").arg(DECREMENT).arg(1).arg(key).arg(value);
+                    }
+                    pipe.query_async::<MultiplexedConnection, Vec<Option<i64>>>(&mut con)
+                        .await
+                        .map_err(Some)
+                }
+                Err(_) => Err(None),
+            };
+            match result {
+                Ok(counts) => {
+                    for ((key, updates), count) in batch.into_iter().zip(counts) {
+                        let mut remaining = match count {
+                            Some(count) => count,
+                            None => {
+                                for (_, tx) in updates {
+                                    let err = ARError::operation("update", &key, "key not found");
+                                    let _ = tx.send(Err(err));
+                                }
+                                continue;
+                            }
+                        };
+                        // Walk back from the final count, as if the updates were applied in turn
+                        for (value, tx) in updates.into_iter().rev() {
+                            let _ = tx.send(Ok(remaining.max(0) as usize));
+                            remaining += value as i64;
+                        }
+                    }
+                }
+                Err(e) => {
+                    for (key, updates) in batch {
+                        for (_, tx) in updates {
+                            let err = match &e {
+                                Some(e) => redis_error("update", &key, e),
+                                None => ARError::Disconnected,
+                            };
+                            let _ = tx.send(Err(err));
+                        }
+                    }
+                }
+            }
+        })
     }
 }
 
@@ -160,87 +698,776 @@ impl Supervised for RedisStoreActor {
     fn restarting(&mut self, _: &mut Self::Context) {
         debug!("restarting redis actor!");
         self.inner.take();
+        self.readiness.set_ready(false);
+        // The timer flushing them is gone, fail the updates instead of leaving them hanging
+        self.pending.clear();
+    }
+}
+
+/// Maps a failed redis command to `ARError`. Commands the server turned down without running
+/// them, because it refused the connection or is still loading its data, and lost connections
+/// may succeed when retried, see `ARError::is_transient`; errors of the command itself won't.
+fn redis_error(op: &'static str, key: &str, e: &redis::RedisError) -> ARError {
+    let unavailable = matches!(
+        e.kind(),
+        redis::ErrorKind::BusyLoadingError
+            | redis::ErrorKind::TryAgain
+            | redis::ErrorKind::ClusterDown
+    );
+    if unavailable || e.is_connection_refusal() {
+        ARError::unavailable(op, key, e)
+    } else if e.is_io_error() {
+        ARError::ReadWriteError(format!("{} failed for key '{}': {}", op, key, e))
+    } else {
+        ARError::operation(op, key, e)
+    }
+}
+
+/// Escapes the glob characters of `prefix` for use in a `MATCH` pattern
+fn escape_pattern(prefix: &str) -> String {
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if let '*' | '?' | '[' | ']' | '\\' = c {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    pattern
+}
+
+/// Reads the count of `key`
+async fn read(
+    con: &mut MultiplexedConnection,
+    codec: &dyn ValueCodec,
+    key: &str,
+    op: &'static str,
+) -> Result<Option<usize>, ARError> {
+    let raw = redis::cmd("GET")
+        .arg(key)
+        .query_async::<MultiplexedConnection, Option<String>>(con)
+        .await
+        .map_err(|e| redis_error(op, key, &e))?;
+    match raw {
+        Some(raw) => codec
+            .decode(&raw)
+            .map(Some)
+            .ok_or_else(|| ARError::operation(op, key, format!("malformed value {:?}", raw))),
+        None => Ok(None),
+    }
+}
+
+/// Name of the hash holding the counts of the window `now` falls in, and the time the window
+/// ends at, both measured from the unix epoch
+fn window_hash(window: Duration, now: Duration) -> (String, Duration) {
+    let window = window.as_millis();
+    let start = now.as_millis() / window * window;
+    let end = Duration::from_millis((start + window) as u64);
+    (format!("window:{}", start / 1000), end)
+}
+
+/// Answers `msg` from the per-window hashes, see `RedisStoreActor::with_hash_layout`
+fn hashed(
+    connection: Output<MultiplexedConnection>,
+    window: Duration,
+    msg: ActorMessage,
+) -> ActorResponse {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let (hash, end) = window_hash(window, now);
+    let end_ms = end.as_millis() as u64;
+    match msg {
+        ActorMessage::Set { key, value, .. } => ActorResponse::Set(Box::pin(async move {
+            let mut con = connection.await?;
+            redis::pipe()
+                .atomic()
+                .cmd("HSET")
+                .arg(&hash)
+                .arg(&key)
+                .arg(value)
+                .ignore()
+                .cmd("PEXPIREAT")
+                .arg(&hash)
+                .arg(end_ms)
+                .ignore()
+                .query_async::<MultiplexedConnection, ()>(&mut con)
+                .await
+                .map_err(|e| redis_error("set", &key, &e))
+        })),
+        ActorMessage::Update { key, value } => ActorResponse::Update(Box::pin(async move {
+            let mut con = connection.await?;
+            let count = redis::cmd("EVAL")
+                .arg(HASH_DECREMENT)
+                .arg(1)
+                .arg(&hash)
+                .arg(&key)
+                .arg(value)
+                .query_async::<MultiplexedConnection, i64>(&mut con)
+                .await
+                .map_err(|e| redis_error("update", &key, &e))?;
+            if count < 0 {
+                return Err(ARError::operation("update", &key, "key not found"));
+            }
+            Ok(count as usize)
+        })),
+        ActorMessage::Grant { key, value, max } => ActorResponse::Grant(Box::pin(async move {
+            let mut con = connection.await?;
+            let count = redis::cmd("EVAL")
+                .arg(HASH_GRANT)
+                .arg(1)
+                .arg(&hash)
+                .arg(&key)
+                .arg(value)
+                .arg(max)
+                .query_async::<MultiplexedConnection, i64>(&mut con)
+                .await
+                .map_err(|e| redis_error("grant", &key, &e))?;
+            Ok(if count < 0 {
+                None
+            } else {
+                Some(count as usize)
+            })
+        })),
+        ActorMessage::Increment { key, value, .. } => {
+            ActorResponse::Increment(Box::pin(async move {
+                let mut con = connection.await?;
+                let (count,) = redis::pipe()
+                    .atomic()
+                    .cmd("HINCRBY")
+                    .arg(&hash)
+                    .arg(&key)
+                    .arg(value)
+                    .cmd("PEXPIREAT")
+                    .arg(&hash)
+                    .arg(end_ms)
+                    .ignore()
+                    .query_async::<MultiplexedConnection, (usize,)>(&mut con)
+                    .await
+                    .map_err(|e| redis_error("increment", &key, &e))?;
+                Ok(count)
+            }))
+        }
+        ActorMessage::Get(key) => ActorResponse::Get(Box::pin(async move {
+            let mut con = connection.await?;
+            redis::cmd("HGET")
+                .arg(&hash)
+                .arg(&key)
+                .query_async::<MultiplexedConnection, Option<usize>>(&mut con)
+                .await
+                .map_err(|e| redis_error("get", &key, &e))
+        })),
+        ActorMessage::Expire(key) => ActorResponse::Expire(Box::pin(async move {
+            let mut con = connection.await?;
+            let exists = redis::cmd("HEXISTS")
+                .arg(&hash)
+                .arg(&key)
+                .query_async::<MultiplexedConnection, bool>(&mut con)
+                .await
+                .map_err(|e| redis_error("expire", &key, &e))?;
+            if !exists {
+                return Err(ARError::operation("expire", &key, "key not found"));
+            }
+            Ok(end.checked_sub(now).unwrap_or_default())
+        })),
+        ActorMessage::Remove(key) => ActorResponse::Remove(Box::pin(async move {
+            let mut con = connection.await?;
+            let (count,) = redis::pipe()
+                .atomic()
+                .cmd("HGET")
+                .arg(&hash)
+                .arg(&key)
+                .cmd("HDEL")
+                .arg(&hash)
+                .arg(&key)
+                .ignore()
+                .query_async::<MultiplexedConnection, (Option<usize>,)>(&mut con)
+                .await
+                .map_err(|e| redis_error("remove", &key, &e))?;
+            count.ok_or_else(|| ARError::operation("remove", &key, "key not found"))
+        })),
+        ActorMessage::Stats => ActorResponse::Stats(Box::pin(async move {
+            let mut con = connection.await?;
+            // Only the clients counted in the current window
+            let count = redis::cmd("HLEN")
+                .arg(&hash)
+                .query_async::<MultiplexedConnection, usize>(&mut con)
+                .await
+                .map_err(|e| redis_error("stats", &hash, &e))?;
+            Ok(StoreStats {
+                connected: true,
+                key_count: Some(count),
+            })
+        })),
+        ActorMessage::Clear(prefix) => ActorResponse::Clear(Box::pin(async move {
+            let mut con = connection.await?;
+            let pattern = escape_pattern(&prefix);
+            let mut cursor: u64 = 0;
+            let mut removed = 0;
+            loop {
+                let (next, entries) = redis::cmd("HSCAN")
+                    .arg(&hash)
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_COUNT)
+                    .query_async::<MultiplexedConnection, (u64, Vec<String>)>(&mut con)
+                    .await
+                    .map_err(|e| redis_error("clear", &prefix, &e))?;
+                // Fields alternate with their values
+                let fields: Vec<String> = entries.into_iter().step_by(2).collect();
+                if !fields.is_empty() {
+                    removed += redis::cmd("HDEL")
+                        .arg(&hash)
+                        .arg(fields)
+                        .query_async::<MultiplexedConnection, usize>(&mut con)
+                        .await
+                        .map_err(|e| redis_error("clear", &prefix, &e))?;
+                }
+                if next == 0 {
+                    break;
+                }
+                cursor = next;
+            }
+            Ok(removed)
+        })),
+        ActorMessage::CheckList(_)
+        | ActorMessage::Shutdown
+        | ActorMessage::Rate(_)
+        | ActorMessage::Slide { .. }
+        | ActorMessage::Draw { .. } => unreachable!(),
+    }
+}
+
+/// Replaces the count of `key` by what `f` makes of it, passing `None` for missing keys. The
+/// count is written with compare-and-set, so that changes made by other clients in between are
+/// not lost. The key expires after `expiry`, or keeps its expiry if that is `None`. Returns the
+/// count written, or `None` if `f` returned `None`.
+async fn modify<F>(
+    con: &mut MultiplexedConnection,
+    codec: &dyn ValueCodec,
+    key: &str,
+    expiry: Option<Duration>,
+    op: &'static str,
+    f: F,
+) -> Result<Option<usize>, ARError>
+where
+    F: Fn(Option<usize>) -> Option<usize>,
+{
+    for _ in 0..CAS_ATTEMPTS {
+        let raw = redis::cmd("GET")
+            .arg(key)
+            .query_async::<MultiplexedConnection, Option<String>>(con)
+            .await
+            .map_err(|e| redis_error(op, key, &e))?;
+        let current = match &raw {
+            Some(raw) => Some(codec.decode(raw).ok_or_else(|| {
+                ARError::operation(op, key, format!("malformed value {:?}", raw))
+            })?),
+            None => None,
+        };
+        let count = match f(current) {
+            Some(count) => count,
+            None => return Ok(None),
+        };
+        let expiry = expiry.map_or_else(String::new, |expiry| expiry.as_millis().to_string());
+        let written = redis::cmd("EVAL")
+            .arg(COMPARE_AND_SET)
+            .arg(1)
+            .arg(key)
+            .arg(raw.unwrap_or_default())
+            .arg(codec.encode(count))
+            .arg(expiry)
+            .query_async::<MultiplexedConnection, i64>(con)
+            .await
+            .map_err(|e| redis_error(op, key, &e))?;
+        if written == 1 {
+            return Ok(Some(count));
+        }
+    }
+    Err(ARError::operation(op, key, "entry kept changing"))
+}
+
+/// Writes the count of `key`, expiring after `ttl` if there is one
+async fn write(
+    con: &mut MultiplexedConnection,
+    codec: &dyn ValueCodec,
+    key: &str,
+    count: usize,
+    ttl: Option<Duration>,
+    op: &'static str,
+) -> Result<(), ARError> {
+    let mut cmd = redis::Cmd::new();
+    cmd.arg("SET").arg(key).arg(codec.encode(count));
+    if let Some(ttl) = ttl {
+        cmd.arg("PX").arg(ttl.as_millis() as u64);
     }
+    cmd.query_async::<MultiplexedConnection, ()>(con)
+        .await
+        .map_err(|e| redis_error(op, key, &e))
+}
+
+/// Asks the main store for a connection, retrying with backoff until `RECONNECT_TIMEOUT`
+/// elapses. Used for requests that arrive while the actor is reconnecting.
+fn wait_for_connection(
+    addr: Addr<RedisStore>,
+    max_interval: Duration,
+) -> Output<MultiplexedConnection> {
+    Box::pin(async move {
+        let mut backoff = ExponentialBackoff::default();
+        backoff.max_interval = max_interval;
+        backoff.max_elapsed_time = Some(RECONNECT_TIMEOUT);
+        loop {
+            if let Ok(Ok(con)) = addr.send(GetAddr).await {
+                return Ok(con);
+            }
+            match backoff.next_backoff() {
+                Some(timeout) => actix::clock::delay_for(timeout).await,
+                None => return Err(ARError::Disconnected),
+            }
+        }
+    })
 }
 
 impl Handler<ActorMessage> for RedisStoreActor {
     type Result = ActorResponse;
     fn handle(&mut self, msg: ActorMessage, ctx: &mut Self::Context) -> Self::Result {
-        let connection = self.inner.clone();
-        if let Some(mut con) = connection {
-            match msg {
-                ActorMessage::Set { key, value, expiry } => {
-                    ActorResponse::Set(Box::pin(async move {
-                        let mut cmd = redis::Cmd::new();
-                        cmd.arg("SET")
-                            .arg(key)
-                            .arg(value)
-                            .arg("EX")
-                            .arg(expiry.as_secs());
-                        let result = cmd.query_async::<MultiplexedConnection, ()>(&mut con).await;
-                        match result {
-                            Ok(_) => Ok(()),
-                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+        if self.shutdown {
+            return ActorResponse::failed(&msg, ARError::ShutDown);
+        }
+        if let ActorMessage::Shutdown = msg {
+            debug!("shutting down redis actor");
+            self.shutdown = true;
+            let flushed = self.flush();
+            let connection = self.inner.take();
+            return ActorResponse::Shutdown(Box::pin(async move {
+                flushed.await;
+                // Replies on a multiplexed connection arrive in order, so once the PING is
+                // answered every command sent before it has completed as well.
+                if let Some(mut con) = connection {
+                    redis::cmd("PING")
+                        .query_async::<MultiplexedConnection, ()>(&mut con)
+                        .await
+                        .map_err(|e| redis_error("shutdown", "", &e))?;
+                }
+                Ok(())
+            }));
+        }
+        if let ActorMessage::Clear(prefix) = &msg {
+            if prefix.is_empty() {
+                // Would wipe the whole database, including data unrelated to rate limiting
+                return ActorResponse::failed(
+                    &msg,
+                    ARError::operation("clear", prefix, "refusing to clear without a prefix"),
+                );
+            }
+        }
+        if let ActorMessage::Rate(_) = msg {
+            // Request rates are only tracked in memory
+            return ActorResponse::Rate(Box::pin(async move { Ok(None) }));
+        }
+        if matches!(msg, ActorMessage::Slide { .. } | ActorMessage::Draw { .. })
+            && (self.codec.is_some() || self.hash_window.is_some())
+        {
+            // The scripts only read plain counts, so these layouts count sliding windows and
+            // pools with the separate messages instead
+            return ActorResponse::unsupported(&msg);
+        }
+        if let (ActorMessage::Stats, None) = (&msg, &self.inner) {
+            // Report the outage instead of waiting for the connection to come back
+            return ActorResponse::Stats(Box::pin(async move {
+                Ok(StoreStats {
+                    connected: false,
+                    key_count: None,
+                })
+            }));
+        }
+        if let (Some(window), None, None, ActorMessage::Update { key, value }) =
+            (self.coalescing, &self.codec, self.hash_window, &msg)
+        {
+            if self.pending.is_empty() {
+                ctx.run_later(window, |act, _| {
+                    actix::spawn(act.flush());
+                });
+            }
+            let (tx, rx) = oneshot::channel();
+            self.pending
+                .entry(key.clone())
+                .or_default()
+                .push((*value, tx));
+            let key = key.clone();
+            return ActorResponse::Update(Box::pin(async move {
+                rx.await.unwrap_or_else(|_| {
+                    Err(ARError::unavailable("update", &key, "store restarted"))
+                })
+            }));
+        }
+        // Buffered decrements must not be applied on top of a value set or removed after them
+        let flushed = match &msg {
+            ActorMessage::Set { key, .. }
+            | ActorMessage::Remove(key)
+            | ActorMessage::Grant { key, .. }
+            | ActorMessage::Slide { key, .. }
+            | ActorMessage::Draw { key, .. }
+                if self.pending.contains_key(key) =>
+            {
+                Some(self.flush())
+            }
+            _ => None,
+        };
+        let buffered: usize = match &msg {
+            ActorMessage::Get(key) => self
+                .pending
+                .get(key)
+                .map_or(0, |updates| updates.iter().map(|(value, _)| value).sum()),
+            _ => 0,
+        };
+        let connection: Output<MultiplexedConnection> = match self.inner.clone() {
+            Some(con) => Box::pin(async move { Ok(con) }),
+            None => {
+                // Restart to re-acquire the connection, while the request itself waits for
+                // the store to come back instead of failing right away.
+                warn!("redis actor disconnected, waiting for connection");
+                self.readiness.set_ready(false);
+                ctx.stop();
+                wait_for_connection(self.addr.clone(), self.backoff.max_interval)
+            }
+        };
+        if let Some(window) = self.hash_window {
+            // The allow and deny lists are kept the same way in either layout
+            if !matches!(msg, ActorMessage::CheckList(_)) {
+                return hashed(connection, window, msg);
+            }
+        }
+        let codec = self.codec.clone();
+        let stats_prefix = self.stats_prefix.clone();
+        match msg {
+            ActorMessage::Set { key, value, expiry } => ActorResponse::Set(Box::pin(async move {
+                if let Some(flushed) = flushed {
+                    flushed.await;
+                }
+                let mut con = connection.await?;
+                if let Some(codec) = codec {
+                    return write(&mut con, &*codec, &key, value, Some(expiry), "set").await;
+                }
+                let mut cmd = redis::Cmd::new();
+                cmd.arg("SET")
+                    .arg(&key)
+                    .arg(value)
+                    .arg("PX")
+                    .arg(expiry.as_millis() as u64);
+                let result = cmd.query_async::<MultiplexedConnection, ()>(&mut con).await;
+                match result {
+                    Ok(_) => Ok(()),
+                    Err(e) => Err(redis_error("set", &key, &e)),
+                }
+            })),
+            ActorMessage::Update { key, value } => ActorResponse::Update(Box::pin(async move {
+                let mut con = connection.await?;
+                if let Some(codec) = codec {
+                    let count = modify(&mut con, &*codec, &key, None, "update", |count| {
+                        count.map(|count| count.saturating_sub(value))
+                    })
+                    .await?;
+                    return count
+                        .ok_or_else(|| ARError::operation("update", &key, "key not found"));
+                }
+                let count = redis::cmd("EVAL")
+                    .arg(DECREMENT)
+                    .arg(1)
+                    .arg(&key)
+                    .arg(value)
+                    .query_async::<MultiplexedConnection, Option<i64>>(&mut con)
+                    .await
+                    .map_err(|e| redis_error("update", &key, &e))?;
+                match count {
+                    Some(count) => Ok(count.max(0) as usize),
+                    None => Err(ARError::operation("update", &key, "key not found")),
+                }
+            })),
+            ActorMessage::Grant { key, value, max } => ActorResponse::Grant(Box::pin(async move {
+                if let Some(flushed) = flushed {
+                    flushed.await;
+                }
+                let mut con = connection.await?;
+                if let Some(codec) = codec {
+                    return modify(&mut con, &*codec, &key, None, "grant", |count| {
+                        count.map(|count| count.max(count.saturating_add(value).min(max)))
+                    })
+                    .await;
+                }
+                let count = redis::cmd("EVAL")
+                    .arg(GRANT)
+                    .arg(1)
+                    .arg(&key)
+                    .arg(value)
+                    .arg(max)
+                    .query_async::<MultiplexedConnection, i64>(&mut con)
+                    .await
+                    .map_err(|e| redis_error("grant", &key, &e))?;
+                Ok(if count < 0 {
+                    None
+                } else {
+                    Some(count as usize)
+                })
+            })),
+            ActorMessage::Slide {
+                key,
+                cost,
+                max,
+                interval,
+            } => ActorResponse::Slide(Box::pin(async move {
+                if let Some(flushed) = flushed {
+                    flushed.await;
+                }
+                let mut con = connection.await?;
+                let reply = redis::cmd("EVAL")
+                    .arg(SLIDE)
+                    .arg(2)
+                    .arg(&key)
+                    .arg(format!("{}:previous", &key))
+                    .arg(cost)
+                    .arg(max)
+                    .arg(interval.as_millis().max(1) as u64)
+                    .query_async::<MultiplexedConnection, Vec<i64>>(&mut con)
+                    .await
+                    .map_err(|e| redis_error("slide", &key, &e))?;
+                match reply[..] {
+                    [counted, current, previous, left] => Ok(SlidingCount {
+                        counted: counted == 1,
+                        current: current.max(0) as usize,
+                        previous: previous.max(0) as usize,
+                        left: Duration::from_millis(left.max(0) as u64),
+                    }),
+                    _ => Err(ARError::operation("slide", &key, "unexpected reply")),
+                }
+            })),
+            ActorMessage::Draw {
+                key,
+                tier,
+                cost,
+                max,
+                interval,
+                reserved,
+            } => ActorResponse::Draw(Box::pin(async move {
+                if let Some(flushed) = flushed {
+                    flushed.await;
+                }
+                let mut con = connection.await?;
+                let own = reserved
+                    .iter()
+                    .position(|(name, _)| *name == tier)
+                    .map_or(0, |index| index + 1);
+                let mut cmd = redis::cmd("EVAL");
+                cmd.arg(DRAW).arg(1 + reserved.len()).arg(&key);
+                for (name, _) in &reserved {
+                    cmd.arg(format!("{}:{}", &key, name));
+                }
+                cmd.arg(cost)
+                    .arg(max)
+                    .arg(interval.as_millis().max(1) as u64)
+                    .arg(own);
+                for (_, value) in &reserved {
+                    cmd.arg(*value);
+                }
+                let reply = cmd
+                    .query_async::<MultiplexedConnection, Vec<i64>>(&mut con)
+                    .await
+                    .map_err(|e| redis_error("draw", &key, &e))?;
+                match reply[..] {
+                    [counted, available, reserved, left] => Ok(PoolDraw {
+                        counted: counted == 1,
+                        available: available.max(0) as usize,
+                        reserved: reserved.max(0) as usize,
+                        left: Duration::from_millis(left.max(0) as u64),
+                    }),
+                    _ => Err(ARError::operation("draw", &key, "unexpected reply")),
+                }
+            })),
+            ActorMessage::Increment { key, value, expiry } => {
+                ActorResponse::Increment(Box::pin(async move {
+                    let mut con = connection.await?;
+                    if let Some(codec) = codec {
+                        let count = modify(
+                            &mut con,
+                            &*codec,
+                            &key,
+                            Some(expiry),
+                            "increment",
+                            |count| Some(count.unwrap_or(0) + value),
+                        )
+                        .await?;
+                        return Ok(count.unwrap_or(value));
+                    }
+                    let result = redis::pipe()
+                        .atomic()
+                        .cmd("INCRBY")
+                        .arg(&key)
+                        .arg(value)
+                        .cmd("PEXPIRE")
+                        .arg(&key)
+                        .arg(expiry.as_millis() as u64)
+                        .ignore()
+                        .query_async::<MultiplexedConnection, (usize,)>(&mut con)
+                        .await;
+                    match result {
+                        Ok((c,)) => Ok(c),
+                        Err(e) => Err(redis_error("increment", &key, &e)),
+                    }
+                }))
+            }
+            ActorMessage::Get(key) => ActorResponse::Get(Box::pin(async move {
+                let mut con = connection.await?;
+                if let Some(codec) = codec {
+                    let count = read(&mut con, &*codec, &key, "get").await?;
+                    return Ok(count.map(|count| count.saturating_sub(buffered)));
+                }
+                let mut cmd = redis::Cmd::new();
+                cmd.arg("GET").arg(&key);
+                let result = cmd
+                    .query_async::<MultiplexedConnection, Option<usize>>(&mut con)
+                    .await;
+
+                match result {
+                    Ok(c) => Ok(c.map(|c| c.saturating_sub(buffered))),
+                    Err(e) => Err(redis_error("get", &key, &e)),
+                }
+            })),
+            ActorMessage::Expire(key) => ActorResponse::Expire(Box::pin(async move {
+                let mut con = connection.await?;
+                let mut cmd = redis::Cmd::new();
+                cmd.arg("PTTL").arg(&key);
+                let result = cmd
+                    .query_async::<MultiplexedConnection, isize>(&mut con)
+                    .await;
+                match result {
+                    Ok(c) => {
+                        if c > 0 {
+                            Ok(Duration::from_millis(c as u64))
+                        } else {
+                            Err(ARError::operation(
+                                "expire",
+                                &key,
+                                "key does not exist or has no associated ttl",
+                            ))
                         }
-                    }))
+                    }
+                    Err(e) => Err(redis_error("expire", &key, &e)),
                 }
-                ActorMessage::Update { key, value } => {
-                    ActorResponse::Update(Box::pin(async move {
+            })),
+            ActorMessage::Remove(key) => ActorResponse::Remove(Box::pin(async move {
+                if let Some(flushed) = flushed {
+                    flushed.await;
+                }
+                let mut con = connection.await?;
+                let mut cmd = redis::Cmd::new();
+                cmd.arg("DEL").arg(&key);
+                let result = cmd
+                    .query_async::<MultiplexedConnection, usize>(&mut con)
+                    .await;
+                match result {
+                    Ok(c) => Ok(c),
+                    Err(e) => Err(redis_error("remove", &key, &e)),
+                }
+            })),
+            ActorMessage::Stats => ActorResponse::Stats(Box::pin(async move {
+                let mut con = connection.await?;
+                let prefix = match stats_prefix {
+                    Some(prefix) => prefix,
+                    None => {
                         let mut cmd = redis::Cmd::new();
-                        cmd.arg("DECRBY").arg(key).arg(value);
+                        cmd.arg("DBSIZE");
                         let result = cmd
                             .query_async::<MultiplexedConnection, usize>(&mut con)
                             .await;
-                        match result {
-                            Ok(c) => Ok(c),
-                            Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
-                        }
-                    }))
-                }
-                ActorMessage::Get(key) => ActorResponse::Get(Box::pin(async move {
-                    let mut cmd = redis::Cmd::new();
-                    cmd.arg("GET").arg(key);
-                    let result = cmd
-                        .query_async::<MultiplexedConnection, Option<usize>>(&mut con)
-                        .await;
-
-                    match result {
-                        Ok(c) => Ok(c),
-                        Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                        return match result {
+                            Ok(c) => Ok(StoreStats {
+                                connected: true,
+                                key_count: Some(c),
+                            }),
+                            Err(e) => Err(redis_error("stats", "", &e)),
+                        };
                     }
-                })),
-                ActorMessage::Expire(key) => ActorResponse::Expire(Box::pin(async move {
+                };
+                let pattern = escape_pattern(&prefix);
+                let mut cursor: u64 = 0;
+                let mut count = 0;
+                loop {
                     let mut cmd = redis::Cmd::new();
-                    cmd.arg("TTL").arg(key);
-                    let result = cmd
-                        .query_async::<MultiplexedConnection, isize>(&mut con)
-                        .await;
-                    match result {
-                        Ok(c) => {
-                            if c > 0 {
-                                Ok(Duration::new(c as u64, 0))
-                            } else {
-                                Err(ARError::ReadWriteError("redis error: key does not exists or does not has a associated ttl.".to_string()))
-                            }
-                        }
-                        Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                    cmd.arg("SCAN")
+                        .arg(cursor)
+                        .arg("MATCH")
+                        .arg(&pattern)
+                        .arg("COUNT")
+                        .arg(SCAN_COUNT);
+                    let (next, keys) = cmd
+                        .query_async::<MultiplexedConnection, (u64, Vec<String>)>(&mut con)
+                        .await
+                        .map_err(|e| redis_error("stats", &prefix, &e))?;
+                    count += keys.len();
+                    if next == 0 {
+                        break;
                     }
-                })),
-                ActorMessage::Remove(key) => ActorResponse::Remove(Box::pin(async move {
+                    cursor = next;
+                }
+                Ok(StoreStats {
+                    connected: true,
+                    key_count: Some(count),
+                })
+            })),
+            ActorMessage::CheckList(key) => ActorResponse::CheckList(Box::pin(async move {
+                let mut con = connection.await?;
+                let result = redis::pipe()
+                    .cmd("SISMEMBER")
+                    .arg(DENYLIST)
+                    .arg(&key)
+                    .cmd("SISMEMBER")
+                    .arg(ALLOWLIST)
+                    .arg(&key)
+                    .query_async::<MultiplexedConnection, (bool, bool)>(&mut con)
+                    .await;
+                match result {
+                    // Being denied wins over being allowed
+                    Ok((true, _)) => Ok(ListStatus::Deny),
+                    Ok((false, true)) => Ok(ListStatus::Allow),
+                    Ok((false, false)) => Ok(ListStatus::Normal),
+                    Err(e) => Err(redis_error("check list", &key, &e)),
+                }
+            })),
+            ActorMessage::Clear(prefix) => ActorResponse::Clear(Box::pin(async move {
+                let mut con = connection.await?;
+                let pattern = escape_pattern(&prefix);
+                let mut cursor: u64 = 0;
+                let mut removed = 0;
+                loop {
                     let mut cmd = redis::Cmd::new();
-                    cmd.arg("DEL").arg(key);
-                    let result = cmd
-                        .query_async::<MultiplexedConnection, usize>(&mut con)
-                        .await;
-                    match result {
-                        Ok(c) => Ok(c),
-                        Err(e) => Err(ARError::ReadWriteError(format!("{:?}", &e))),
+                    cmd.arg("SCAN")
+                        .arg(cursor)
+                        .arg("MATCH")
+                        .arg(&pattern)
+                        .arg("COUNT")
+                        .arg(SCAN_COUNT);
+                    let (next, keys) = cmd
+                        .query_async::<MultiplexedConnection, (u64, Vec<String>)>(&mut con)
+                        .await
+                        .map_err(|e| redis_error("clear", &prefix, &e))?;
+                    if !keys.is_empty() {
+                        let mut cmd = redis::Cmd::new();
+                        cmd.arg("DEL").arg(keys);
+                        removed += cmd
+                            .query_async::<MultiplexedConnection, usize>(&mut con)
+                            .await
+                            .map_err(|e| redis_error("clear", &prefix, &e))?;
                     }
-                })),
-            }
-        } else {
-            ctx.stop();
-            ActorResponse::Set(Box::pin(async move { Err(ARError::Disconnected) }))
+                    if next == 0 {
+                        break;
+                    }
+                    cursor = next;
+                }
+                Ok(removed)
+            })),
+            ActorMessage::Shutdown | ActorMessage::Rate(_) => unreachable!(),
         }
     }
 }
@@ -275,6 +1502,16 @@ mod tests {
         }
     }
 
+    #[actix_rt::test]
+    async fn test_connect_verified() {
+        init();
+        // Nothing listens on port 1, the connection is refused right away
+        let started = std::time::Instant::now();
+        let res = RedisStore::connect_verified("redis://127.0.0.1:1/").await;
+        assert!(matches!(res, Err(ARError::NotConnected)));
+        assert!(started.elapsed() < VERIFY_TIMEOUT);
+    }
+
     #[actix_rt::test]
     async fn test_get() {
         init();
@@ -352,4 +1589,690 @@ mod tests {
             _ => panic!("Shouldn't happen!"),
         };
     }
+
+    #[actix_rt::test]
+    async fn test_subsecond_expiry() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store.clone()).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello_subsecond".to_string(),
+                value: 30usize,
+                expiry: Duration::from_millis(250),
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Set(c) => c.await.unwrap(),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr
+            .send(ActorMessage::Expire("hello_subsecond".to_string()))
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Expire(c) => {
+                let dur = c.await.unwrap();
+                assert!(dur > Duration::from_millis(0) && dur <= Duration::from_millis(250));
+            }
+            _ => panic!("Shouldn't happen!"),
+        }
+
+        for (wait, expected) in &[(150, Some(30)), (150, None)] {
+            actix_rt::time::delay_for(Duration::from_millis(*wait)).await;
+            let res = addr
+                .send(ActorMessage::Get("hello_subsecond".to_string()))
+                .await;
+            match res.expect("Failed to send msg") {
+                ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), *expected),
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_clear() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store.clone()).start();
+        for key in &["clear_test:1", "clear_test:2", "clear_test:3", "other_test"] {
+            let res = addr
+                .send(ActorMessage::Set {
+                    key: key.to_string(),
+                    value: 30usize,
+                    expiry: Duration::from_secs(5),
+                })
+                .await;
+            let res = res.expect("Failed to send msg");
+            match res {
+                ActorResponse::Set(c) => match c.await {
+                    Ok(()) => {}
+                    Err(e) => panic!("Shouldn't happen {}", &e),
+                },
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+        let res = addr.send(ActorMessage::Clear("".to_string())).await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Clear(c) => assert!(c.await.is_err()),
+            _ => panic!("Shouldn't happen!"),
+        };
+        let res2 = addr
+            .send(ActorMessage::Clear("clear_test:".to_string()))
+            .await;
+        let res2 = res2.expect("Failed to send msg");
+        match res2 {
+            ActorResponse::Clear(c) => match c.await {
+                Ok(removed) => assert_eq!(removed, 3),
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+        let res3 = addr.send(ActorMessage::Get("other_test".to_string())).await;
+        let res3 = res3.expect("Failed to send msg");
+        match res3 {
+            ActorResponse::Get(c) => match c.await {
+                Ok(d) => assert_eq!(d, Some(30usize)),
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+    }
+
+    #[actix_rt::test]
+    async fn test_stats_prefix() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store.clone())
+            .with_stats_prefix("stats_test:")
+            .start();
+        for key in &["stats_test:1", "stats_test:2", "other_stats_test"] {
+            let res = addr
+                .send(ActorMessage::Set {
+                    key: key.to_string(),
+                    value: 30usize,
+                    expiry: Duration::from_secs(5),
+                })
+                .await;
+            res.expect("Failed to send msg").expect_set().await.unwrap();
+        }
+        let res = addr.send(ActorMessage::Stats).await;
+        let stats = res
+            .expect("Failed to send msg")
+            .expect_stats()
+            .await
+            .unwrap();
+        assert!(stats.connected);
+        assert_eq!(stats.key_count, Some(2));
+    }
+
+    #[cfg(feature = "tokio-runtime")]
+    #[tokio::test]
+    async fn test_tokio_runtime() {
+        init();
+        // Neither call needs a running actix System
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store.clone()).start();
+        let res = addr.send(ActorMessage::Get("hello".to_string())).await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Get(c) => match c.await {
+                Ok(_) => {}
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+    }
+
+    /// Drops the connection held by the actor, as a network failure would
+    struct Disconnect;
+    impl Message for Disconnect {
+        type Result = ();
+    }
+    impl Handler<Disconnect> for RedisStoreActor {
+        type Result = ();
+        fn handle(&mut self, _: Disconnect, _: &mut Self::Context) {
+            self.inner.take();
+        }
+    }
+
+    /// Drops the connection of the main store and restarts it, as a lost connection would
+    struct DropConnection;
+    impl Message for DropConnection {
+        type Result = ();
+    }
+    impl Handler<DropConnection> for RedisStore {
+        type Result = ();
+        fn handle(&mut self, _: DropConnection, ctx: &mut Self::Context) {
+            self.client.take();
+            ctx.stop();
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_connection_events() {
+        init();
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        let store = RedisStore::connect_with_listener(
+            "redis://127.0.0.1/",
+            ReconnectBackoff::new(),
+            move |event| recorded.lock().unwrap().push(event),
+        );
+        let addr = RedisStoreActor::from(store.clone()).start();
+        addr.send(ActorMessage::Get("hello".to_string()))
+            .await
+            .unwrap()
+            .expect_get()
+            .await
+            .unwrap();
+        // Connecting the first time is not a recovery
+        assert!(events.lock().unwrap().is_empty());
+
+        store.send(DropConnection).await.unwrap();
+        for _ in 0..50 {
+            if events.lock().unwrap().len() == 2 {
+                break;
+            }
+            actix::clock::delay_for(Duration::from_millis(20)).await;
+        }
+        let events = events.lock().unwrap();
+        assert_eq!(events[0], ConnectionEvent::Restarting);
+        assert!(matches!(events[1], ConnectionEvent::Reconnected { .. }));
+        assert_eq!(events.len(), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_reconnect() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store.clone()).start();
+        addr.send(Disconnect).await.expect("Failed to send msg");
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hello_reconnect".to_string(),
+                value: 30usize,
+                expiry: Duration::from_secs(5),
+            })
+            .await;
+        let res = res.expect("Failed to send msg");
+        match res {
+            ActorResponse::Set(c) => match c.await {
+                Ok(()) => {}
+                Err(e) => panic!("Shouldn't happen: {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        }
+        // The actor is restarted and picks up the connection again
+        let res2 = addr
+            .send(ActorMessage::Get("hello_reconnect".to_string()))
+            .await;
+        let res2 = res2.expect("Failed to send msg");
+        match res2 {
+            ActorResponse::Get(c) => match c.await {
+                Ok(d) => assert_eq!(d, Some(30usize)),
+                Err(e) => panic!("Shouldn't happen {}", &e),
+            },
+            _ => panic!("Shouldn't happen!"),
+        };
+    }
+
+    /// Connection watching the commands redis runs, so that those on a key can be counted apart
+    /// from the ones of other tests running against the same server
+    struct Monitor {
+        con: redis::Connection,
+    }
+
+    impl Monitor {
+        fn start() -> Self {
+            let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+            let mut con = client.get_connection().unwrap();
+            let monitor = redis::cmd("MONITOR").get_packed_command();
+            con.send_packed_command(&monitor).unwrap();
+            // Commands are only reported once the server confirmed
+            con.recv_response().unwrap();
+            Monitor { con }
+        }
+
+        /// Number of `command`s run on `key` since the monitor started
+        fn calls(mut self, command: &str, key: &str) -> usize {
+            // Marks the end of the commands to count
+            let marker = format!("\"ECHO\" \"{}:monitored\"", key);
+            let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+            let mut con = client.get_connection().unwrap();
+            redis::cmd("ECHO")
+                .arg(format!("{}:monitored", key))
+                .query::<String>(&mut con)
+                .unwrap();
+            let call = format!("\"{}\" \"{}\"", command, key);
+            let mut calls = 0;
+            loop {
+                if let redis::Value::Status(line) = self.con.recv_response().unwrap() {
+                    if line.contains(&marker) {
+                        return calls;
+                    }
+                    if line.contains(&call) {
+                        calls += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends a burst of ten updates to `key`, returning what each of them left and the number of
+    /// `DECRBY` commands it took on `key`
+    async fn burst(addr: &Addr<RedisStoreActor>, key: &str) -> (Vec<usize>, usize) {
+        let set = ActorMessage::Set {
+            key: key.to_string(),
+            value: 100,
+            expiry: Duration::from_secs(60),
+        };
+        addr.send(set).await.unwrap().expect_set().await.unwrap();
+        let monitor = Monitor::start();
+        let updates: Vec<_> = (0..10)
+            .map(|_| {
+                addr.send(ActorMessage::Update {
+                    key: key.to_string(),
+                    value: 1,
+                })
+            })
+            .collect();
+        let mut remaining = Vec::new();
+        for res in futures::future::join_all(updates).await {
+            remaining.push(res.unwrap().expect_update().await.unwrap());
+        }
+        remaining.sort();
+        (remaining, monitor.calls("DECRBY", key))
+    }
+
+    #[actix_rt::test]
+    async fn test_coalescing() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let expected: Vec<usize> = (90..100).collect();
+
+        let addr = RedisStoreActor::from(store.clone()).start();
+        let (remaining, calls) = burst(&addr, "coalescing:off").await;
+        assert_eq!(remaining, expected);
+        assert_eq!(calls, 10);
+
+        let addr = RedisStoreActor::from(store)
+            .with_coalescing(Duration::from_millis(50))
+            .start();
+        let (remaining, calls) = burst(&addr, "coalescing:on").await;
+        assert_eq!(remaining, expected);
+        assert_eq!(calls, 1);
+        // Reads see buffered decrements before they are flushed
+        let update = addr.send(ActorMessage::Update {
+            key: "coalescing:on".to_string(),
+            value: 5,
+        });
+        let res = addr.send(ActorMessage::Get("coalescing:on".to_string()));
+        let (update, res) = futures::join!(update, res);
+        assert_eq!(res.unwrap().expect_get().await.unwrap(), Some(85));
+        assert_eq!(update.unwrap().expect_update().await.unwrap(), 85);
+    }
+
+    #[actix_rt::test]
+    async fn test_check_list() {
+        init();
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let mut con = client.get_async_connection().await.unwrap();
+        redis::pipe()
+            .cmd("SADD")
+            .arg(ALLOWLIST)
+            .arg("lists:allowed")
+            .arg("lists:both")
+            .cmd("SADD")
+            .arg(DENYLIST)
+            .arg("lists:denied")
+            .arg("lists:both")
+            .query_async::<_, ()>(&mut con)
+            .await
+            .unwrap();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store).start();
+        for (key, expected) in &[
+            ("lists:allowed", ListStatus::Allow),
+            ("lists:denied", ListStatus::Deny),
+            ("lists:both", ListStatus::Deny),
+            ("lists:normal", ListStatus::Normal),
+        ] {
+            let res = addr.send(ActorMessage::CheckList(key.to_string())).await;
+            assert_eq!(res.unwrap().expect_check_list().await.unwrap(), *expected);
+        }
+    }
+
+    /// Counts stored as `count=5`
+    struct PrefixedCodec;
+
+    impl ValueCodec for PrefixedCodec {
+        fn encode(&self, count: usize) -> String {
+            format!("count={}", count)
+        }
+
+        fn decode(&self, raw: &str) -> Option<usize> {
+            raw.strip_prefix("count=")?.parse().ok()
+        }
+    }
+
+    #[test]
+    fn test_integer_codec() {
+        assert_eq!(IntegerCodec.encode(42), "42");
+        assert_eq!(IntegerCodec.decode("42"), Some(42));
+        assert_eq!(IntegerCodec.decode("count=42"), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_codec() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store)
+            .with_codec(PrefixedCodec)
+            .start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "codec_test".to_string(),
+                value: 10,
+                expiry: Duration::from_secs(5),
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Set(c) => c.await.unwrap(),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "codec_test".to_string(),
+                value: 3,
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Update(c) => assert_eq!(c.await.unwrap(), 7),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr.send(ActorMessage::Get("codec_test".to_string())).await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), Some(7)),
+            _ => panic!("Shouldn't happen!"),
+        }
+
+        // The value is stored in the format of the codec
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let mut con = client.get_async_connection().await.unwrap();
+        let raw: String = redis::cmd("GET")
+            .arg("codec_test")
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert_eq!(raw, "count=7");
+    }
+
+    #[actix_rt::test]
+    async fn test_codec_concurrent() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store)
+            .with_codec(PrefixedCodec)
+            .start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "codec_burst_test".to_string(),
+                value: 100,
+                expiry: Duration::from_secs(5),
+            })
+            .await;
+        res.expect("Failed to send msg").expect_set().await.unwrap();
+
+        // A burst of decrements of the same key, none of which may be lost
+        let updates = (0..50).map(|_| {
+            let addr = addr.clone();
+            async move {
+                let res = addr
+                    .send(ActorMessage::Update {
+                        key: "codec_burst_test".to_string(),
+                        value: 1,
+                    })
+                    .await;
+                res.expect("Failed to send msg").expect_update().await
+            }
+        });
+        for res in futures::future::join_all(updates).await {
+            res.unwrap();
+        }
+        let res = addr
+            .send(ActorMessage::Get("codec_burst_test".to_string()))
+            .await;
+        let count = res.expect("Failed to send msg").expect_get().await.unwrap();
+        assert_eq!(count, Some(50));
+        let res = addr
+            .send(ActorMessage::Expire("codec_burst_test".to_string()))
+            .await;
+        let ttl = res
+            .expect("Failed to send msg")
+            .expect_expire()
+            .await
+            .unwrap();
+        assert!(ttl > Duration::from_secs(4));
+    }
+
+    #[actix_rt::test]
+    async fn test_grant() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "grant_test".to_string(),
+                value: 2,
+                expiry: Duration::from_secs(5),
+            })
+            .await;
+        res.expect("Failed to send msg").expect_set().await.unwrap();
+        let grant = |key: &str, value| ActorMessage::Grant {
+            key: key.to_string(),
+            value,
+            max: 10,
+        };
+        let res = addr.send(grant("grant_test", 3)).await;
+        let count = res.expect("Failed to send msg").expect_grant().await;
+        assert_eq!(count.unwrap(), Some(5));
+        // Capped at the maximum, without touching the expiry
+        let res = addr.send(grant("grant_test", 20)).await;
+        let count = res.expect("Failed to send msg").expect_grant().await;
+        assert_eq!(count.unwrap(), Some(10));
+        let res = addr
+            .send(ActorMessage::Expire("grant_test".to_string()))
+            .await;
+        let expiry = res.expect("Failed to send msg").expect_expire().await;
+        assert!(expiry.unwrap() > Duration::from_secs(4));
+        let res = addr.send(grant("grant_missing", 3)).await;
+        let count = res.expect("Failed to send msg").expect_grant().await;
+        assert_eq!(count.unwrap(), None);
+    }
+
+    #[actix_rt::test]
+    async fn test_update_bounds() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store).start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "update_test".to_string(),
+                value: 2,
+                expiry: Duration::from_secs(5),
+            })
+            .await;
+        res.expect("Failed to send msg").expect_set().await.unwrap();
+        let update = |key: &str| ActorMessage::Update {
+            key: key.to_string(),
+            value: 3,
+        };
+        // Stops at 0, without touching the expiry
+        let res = addr.send(update("update_test")).await;
+        let count = res.expect("Failed to send msg").expect_update().await;
+        assert_eq!(count.unwrap(), 0);
+        let res = addr
+            .send(ActorMessage::Get("update_test".to_string()))
+            .await;
+        let count = res.expect("Failed to send msg").expect_get().await;
+        assert_eq!(count.unwrap(), Some(0));
+        let res = addr
+            .send(ActorMessage::Expire("update_test".to_string()))
+            .await;
+        let expiry = res.expect("Failed to send msg").expect_expire().await;
+        assert!(expiry.unwrap() > Duration::from_secs(4));
+        // A missing key is not created
+        let res = addr.send(update("update_missing")).await;
+        let count = res.expect("Failed to send msg").expect_update().await;
+        assert!(count.is_err());
+        let res = addr
+            .send(ActorMessage::Get("update_missing".to_string()))
+            .await;
+        let count = res.expect("Failed to send msg").expect_get().await;
+        assert_eq!(count.unwrap(), None);
+    }
+
+    #[test]
+    fn test_window_hash() {
+        let window = Duration::from_secs(60);
+        let (hash, end) = window_hash(window, Duration::from_millis(1_700_000_030_500));
+        assert_eq!(hash, "window:1699999980");
+        assert_eq!(end, Duration::from_secs(1_700_000_040));
+        // Every moment of the window maps to the same hash, and the next window to another one
+        let (same, _) = window_hash(window, Duration::from_secs(1_700_000_039));
+        assert_eq!(same, hash);
+        let (next, _) = window_hash(window, end);
+        assert_eq!(next, "window:1700000040");
+    }
+
+    #[actix_rt::test]
+    async fn test_hash_layout() {
+        init();
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store)
+            .with_hash_layout(Duration::from_secs(60))
+            .start();
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hash_test".to_string(),
+                value: 10,
+                expiry: Duration::from_secs(60),
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Set(c) => c.await.unwrap(),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "hash_test".to_string(),
+                value: 3,
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Update(c) => assert_eq!(c.await.unwrap(), 7),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr.send(ActorMessage::Get("hash_test".to_string())).await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), Some(7)),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr
+            .send(ActorMessage::Expire("hash_test".to_string()))
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Expire(c) => assert!(c.await.unwrap() <= Duration::from_secs(60)),
+            _ => panic!("Shouldn't happen!"),
+        }
+        for expected in &[2, 4] {
+            let res = addr
+                .send(ActorMessage::Increment {
+                    key: "hash_active".to_string(),
+                    value: 2,
+                    expiry: Duration::from_secs(60),
+                })
+                .await;
+            match res.expect("Failed to send msg") {
+                ActorResponse::Increment(c) => assert_eq!(c.await.unwrap(), *expected),
+                _ => panic!("Shouldn't happen!"),
+            }
+        }
+
+        // The counts are fields of the hash, not keys of their own
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let mut con = client.get_async_connection().await.unwrap();
+        let exists: bool = redis::cmd("EXISTS")
+            .arg("hash_test")
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert!(!exists);
+
+        let res = addr
+            .send(ActorMessage::Remove("hash_test".to_string()))
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Remove(c) => assert_eq!(c.await.unwrap(), 7),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let res = addr
+            .send(ActorMessage::Update {
+                key: "hash_test".to_string(),
+                value: 1,
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Update(c) => assert!(c.await.is_err()),
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_hash_window_expires() {
+        init();
+        let window = Duration::from_secs(1);
+        let store = RedisStore::connect("redis://127.0.0.1/");
+        let addr = RedisStoreActor::from(store)
+            .with_hash_layout(window)
+            .start();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let (hash, end) = window_hash(window, now);
+        let res = addr
+            .send(ActorMessage::Set {
+                key: "hash_expiry_test".to_string(),
+                value: 10,
+                expiry: Duration::from_secs(60),
+            })
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Set(c) => c.await.unwrap(),
+            _ => panic!("Shouldn't happen!"),
+        }
+        let client = redis::Client::open("redis://127.0.0.1/").unwrap();
+        let mut con = client.get_async_connection().await.unwrap();
+        let ttl: i64 = redis::cmd("PTTL")
+            .arg(&hash)
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert!(ttl > 0 && ttl <= 1000);
+
+        // The whole hash is gone once the window is over, whatever expiry the entry was given
+        actix_rt::time::delay_for(end - now + Duration::from_millis(100)).await;
+        let exists: bool = redis::cmd("EXISTS")
+            .arg(&hash)
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        assert!(!exists);
+        let res = addr
+            .send(ActorMessage::Get("hash_expiry_test".to_string()))
+            .await;
+        match res.expect("Failed to send msg") {
+            ActorResponse::Get(c) => assert_eq!(c.await.unwrap(), None),
+            _ => panic!("Shouldn't happen!"),
+        }
+    }
 }
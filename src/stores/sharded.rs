@@ -0,0 +1,82 @@
+//! Sharded in memory store for rate limiting
+//!
+//! Splits the keys over several independent [MemoryStore](../memory/struct.MemoryStore.html)s,
+//! each with its own actor running in a dedicated arbiter thread. Combined with
+//! [RateLimiter::sharded](../../middleware/struct.RateLimiter.html#method.sharded), which routes
+//! every client to one of the shards, busy clients no longer all queue up behind a single mailbox.
+use actix::prelude::*;
+use log::*;
+
+use crate::clock::Clock;
+use crate::stores::memory::{MemoryStore, MemoryStoreActor};
+use crate::stores::start_supervised;
+
+/// Type used to create a store made of several concurrent hashmaps
+#[derive(Clone)]
+pub struct ShardedMemoryStore {
+    shards: Vec<MemoryStore>,
+}
+
+impl ShardedMemoryStore {
+    /// Create a new store with the provided number of shards
+    ///
+    /// # Example
+    /// ```rust
+    /// use actix_ratelimit::ShardedMemoryStore;
+    ///
+    /// let store = ShardedMemoryStore::new(8);
+    /// ```
+    ///
+    /// # Panics
+    /// Panics if `shards` is zero.
+    pub fn new(shards: usize) -> Self {
+        assert!(shards > 0, "sharded memory store needs at least one shard");
+        debug!("Creating new ShardedMemoryStore with {} shards", shards);
+        ShardedMemoryStore {
+            shards: (0..shards).map(|_| MemoryStore::new()).collect(),
+        }
+    }
+
+    /// Compute expiries of every shard with `clock`, see
+    /// [MemoryStore::with_clock](../memory/struct.MemoryStore.html#method.with_clock)
+    pub fn with_clock<C: Clock + Clone>(mut self, clock: C) -> Self {
+        self.shards = self
+            .shards
+            .into_iter()
+            .map(|store| store.with_clock(clock.clone()))
+            .collect();
+        self
+    }
+
+    /// Put the client identified by `key` on the allowlist of every shard, see
+    /// [MemoryStore::allow](../memory/struct.MemoryStore.html#method.allow)
+    pub fn allow(&self, key: &str) {
+        self.shards.iter().for_each(|store| store.allow(key));
+    }
+
+    /// Put the client identified by `key` on the denylist of every shard
+    pub fn deny(&self, key: &str) {
+        self.shards.iter().for_each(|store| store.deny(key));
+    }
+
+    /// Take the client identified by `key` off either list of every shard
+    pub fn unlist(&self, key: &str) {
+        self.shards.iter().for_each(|store| store.unlist(key));
+    }
+
+    /// Starts an actor for every shard, each in its own arbiter, and returns their addresses
+    pub fn start(&self) -> Vec<Addr<MemoryStoreActor>> {
+        debug!("Started sharded memory store");
+        self.shards
+            .iter()
+            .map(|store| {
+                let actor = MemoryStoreActor::from(store.clone());
+                if System::is_set() {
+                    Supervisor::start_in_arbiter(&Arbiter::new(), |_| actor)
+                } else {
+                    start_supervised(|_| actor)
+                }
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,8 @@
+//! Builders missing the limit or the interval must not compile
+#![cfg(feature = "memory")]
+
+#[test]
+fn incomplete_builder() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
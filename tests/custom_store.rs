@@ -0,0 +1,80 @@
+//! A store outside of the crate, which only handles the messages the middleware needs by default
+use actix::prelude::*;
+use actix_ratelimit::errors::ARError;
+use actix_ratelimit::{ActorMessage, ActorResponse, RateLimiter};
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use futures::future::ok;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// Keeps counts without ever expiring them
+#[derive(Default)]
+struct MinimalStore {
+    inner: HashMap<String, usize>,
+}
+
+impl Actor for MinimalStore {
+    type Context = Context<Self>;
+}
+
+impl Handler<ActorMessage> for MinimalStore {
+    type Result = ActorResponse;
+    fn handle(&mut self, msg: ActorMessage, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            ActorMessage::Get(key) => {
+                ActorResponse::Get(Box::pin(ok(self.inner.get(&key).copied())))
+            }
+            ActorMessage::Set { key, value, .. } => {
+                self.inner.insert(key, value);
+                ActorResponse::Set(Box::pin(ok(())))
+            }
+            ActorMessage::Update { key, value } => {
+                let count = self.inner.entry(key).or_insert(0);
+                *count = count.saturating_sub(value);
+                ActorResponse::Update(Box::pin(ok(*count)))
+            }
+            ActorMessage::Expire(_) => ActorResponse::Expire(Box::pin(ok(Duration::from_secs(60)))),
+            msg => ActorResponse::unsupported(&msg),
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn test_unknown_messages_turned_down() {
+    let addr = MinimalStore::default().start();
+    let mut app = test::init_service(
+        App::new()
+            .wrap(
+                RateLimiter::new(addr.clone())
+                    .with_interval(Duration::from_secs(60))
+                    .with_max_requests(2),
+            )
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let request = || {
+        test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request()
+    };
+
+    for _ in 0..2 {
+        let res = test::call_service(&mut app, request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+    let res = test::call_service(&mut app, request()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Everything else fails, naming the kind of message, without bringing the store down
+    match RateLimiter::stats(&addr).await {
+        Err(ARError::Unsupported(kind)) => assert_eq!(kind, "Stats"),
+        _ => panic!("Shouldn't happen!"),
+    }
+    match RateLimiter::grant(&addr, "127.0.0.1:8080", 1, 2).await {
+        Err(ARError::Unsupported(kind)) => assert_eq!(kind, "Grant"),
+        _ => panic!("Shouldn't happen!"),
+    }
+    let res = test::call_service(&mut app, request()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+}
@@ -0,0 +1,187 @@
+//! End-to-end tests driving the middleware through an actix-web service
+#![cfg(feature = "memory")]
+
+use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter, TestMemoryStore};
+use actix_web::dev::ServiceResponse;
+use actix_web::http::StatusCode;
+use actix_web::{test, web, App, HttpResponse};
+use std::time::Duration;
+
+const MAX_REQUESTS: usize = 3;
+
+fn header(res: &ServiceResponse, name: &str) -> String {
+    res.headers()
+        .get(name)
+        .unwrap_or_else(|| panic!("missing header {}", name))
+        .to_str()
+        .unwrap()
+        .to_string()
+}
+
+#[actix_rt::test]
+async fn test_limit_end_to_end() {
+    let store = MemoryStore::new();
+    let mut app = test::init_service(
+        App::new()
+            .wrap(
+                RateLimiter::new(MemoryStoreActor::from(store).start())
+                    .with_interval(Duration::from_secs(60))
+                    .with_max_requests(MAX_REQUESTS),
+            )
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let request = || {
+        test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request()
+    };
+
+    for remaining in (0..MAX_REQUESTS).rev() {
+        let res = test::call_service(&mut app, request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "x-ratelimit-limit"), MAX_REQUESTS.to_string());
+        assert_eq!(header(&res, "x-ratelimit-remaining"), remaining.to_string());
+        let reset: u64 = header(&res, "x-ratelimit-reset").parse().unwrap();
+        assert!(reset <= 60);
+        assert!(res.headers().get("retry-after").is_none());
+    }
+
+    let res = test::call_service(&mut app, request()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&res, "x-ratelimit-limit"), MAX_REQUESTS.to_string());
+    assert_eq!(header(&res, "x-ratelimit-remaining"), "0");
+    let reset: u64 = header(&res, "x-ratelimit-reset").parse().unwrap();
+    assert!(reset <= 60);
+    let retry_after: u64 = header(&res, "retry-after").parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 60);
+}
+
+#[actix_rt::test]
+async fn test_clients_limited_apart() {
+    let store = MemoryStore::new();
+    let mut app = test::init_service(
+        App::new()
+            .wrap(
+                RateLimiter::new(MemoryStoreActor::from(store).start())
+                    .with_interval(Duration::from_secs(60))
+                    .with_max_requests(1),
+            )
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let request = |peer: &str| {
+        test::TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .to_request()
+    };
+
+    let res = test::call_service(&mut app, request("127.0.0.1:8080")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let res = test::call_service(&mut app, request("127.0.0.1:8080")).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    let res = test::call_service(&mut app, request("127.0.0.2:8080")).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "x-ratelimit-remaining"), "0");
+}
+
+#[actix_rt::test]
+async fn test_fallback_key() {
+    let store = MemoryStore::new();
+    let mut app = test::init_service(
+        App::new()
+            .wrap(
+                RateLimiter::new(MemoryStoreActor::from(store).start())
+                    .with_interval(Duration::from_secs(60))
+                    .with_max_requests(MAX_REQUESTS)
+                    .with_fallback_key("test-client"),
+            )
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    // Without a peer address every request is counted against the fallback key
+    for remaining in (0..MAX_REQUESTS).rev() {
+        let res = test::call_service(&mut app, test::TestRequest::default().to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, "x-ratelimit-remaining"), remaining.to_string());
+    }
+    let res = test::call_service(&mut app, test::TestRequest::default().to_request()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Clients with an address are still told apart
+    let req = test::TestRequest::default()
+        .peer_addr("127.0.0.1:8080".parse().unwrap())
+        .to_request();
+    let res = test::call_service(&mut app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_seeded_store() {
+    let store = TestMemoryStore::new();
+    // The client already used up all but one of its requests
+    store.set_raw("127.0.0.1:8080", 1, Duration::from_secs(30));
+    let mut app = test::init_service(
+        App::new()
+            .wrap(
+                RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                    .with_interval(Duration::from_secs(60))
+                    .with_max_requests(MAX_REQUESTS),
+            )
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+    let request = || {
+        test::TestRequest::default()
+            .peer_addr("127.0.0.1:8080".parse().unwrap())
+            .to_request()
+    };
+
+    let res = test::call_service(&mut app, request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header(&res, "x-ratelimit-remaining"), "0");
+    let reset: u64 = header(&res, "x-ratelimit-reset").parse().unwrap();
+    assert!(reset <= 30);
+    let res = test::call_service(&mut app, request()).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Giving the client its requests back lets it through again
+    store.set_raw("127.0.0.1:8080", MAX_REQUESTS, Duration::from_secs(30));
+    let res = test::call_service(&mut app, request()).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[actix_rt::test]
+async fn test_inspect_store() {
+    let store = TestMemoryStore::new();
+    let mut app = test::init_service(
+        App::new()
+            .wrap(
+                RateLimiter::new(MemoryStoreActor::from(store.clone()).start())
+                    .with_interval(Duration::from_secs(60))
+                    .with_max_requests(MAX_REQUESTS)
+                    .with_prefix("api:"),
+            )
+            .route("/", web::get().to(HttpResponse::Ok)),
+    )
+    .await;
+
+    for peer in &["127.0.0.1:8080", "127.0.0.1:8080", "127.0.0.2:8080"] {
+        let req = test::TestRequest::default()
+            .peer_addr(peer.parse().unwrap())
+            .to_request();
+        test::call_service(&mut app, req).await;
+    }
+    let snapshot = store.inner_snapshot();
+    assert_eq!(snapshot.len(), 2);
+    let (remaining, ttl) = snapshot["api:127.0.0.1:8080"];
+    assert_eq!(remaining, MAX_REQUESTS - 2);
+    assert!(ttl <= Duration::from_secs(60));
+    assert_eq!(
+        store
+            .get_raw("api:127.0.0.2:8080")
+            .map(|(remaining, _)| remaining),
+        Some(MAX_REQUESTS - 1)
+    );
+}
@@ -0,0 +1,9 @@
+use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+
+#[actix_rt::main]
+async fn main() {
+    let store = MemoryStore::new();
+    let ratelimiter = RateLimiter::builder(MemoryStoreActor::from(store.clone()).start())
+        .max_requests(100)
+        .build();
+}
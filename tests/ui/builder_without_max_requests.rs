@@ -0,0 +1,10 @@
+use actix_ratelimit::{MemoryStore, MemoryStoreActor, RateLimiter};
+use std::time::Duration;
+
+#[actix_rt::main]
+async fn main() {
+    let store = MemoryStore::new();
+    let ratelimiter = RateLimiter::builder(MemoryStoreActor::from(store.clone()).start())
+        .interval(Duration::from_secs(60))
+        .build();
+}